@@ -0,0 +1,16 @@
+//! Enables the `rpk` cfg when the OpenSSL linked against can negotiate raw
+//! public keys (RFC 7250), which it can from 3.2 on.
+
+use std::env;
+
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(rpk)");
+
+    // set by openssl-sys, which is only a dependency with the dtls feature
+    let version = env::var("DEP_OPENSSL_VERSION_NUMBER").ok()
+        .and_then(|version| u64::from_str_radix(&version, 16).ok());
+
+    if version.map_or(false, |version| version >= 0x3020_0000) {
+        println!("cargo:rustc-cfg=rpk");
+    }
+}
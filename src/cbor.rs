@@ -0,0 +1,303 @@
+//! Just enough CBOR to build the structures OSCORE authenticates and derives
+//! its keys from, and to read the messages EDHOC exchanges.
+//! RFC 8949: Concise Binary Object Representation (CBOR)
+
+// decoding is only needed for EDHOC
+#![cfg_attr(not(feature = "edhoc"), allow(dead_code))]
+
+pub const UNSIGNED: u8 = 0;
+pub const NEGATIVE: u8 = 1;
+pub const BYTES: u8 = 2;
+pub const TEXT: u8 = 3;
+pub const ARRAY: u8 = 4;
+pub const MAP: u8 = 5;
+pub const SIMPLE: u8 = 7;
+const FALSE: u8 = 0xf4;
+const TRUE: u8 = 0xf5;
+const NULL: u8 = 0xf6;
+/// How deeply arrays, maps and tags may nest in an item that's skipped; the
+/// structures EDHOC carries need far less.
+const MAX_DEPTH: usize = 16;
+
+/// A data item couldn't be decoded, or wasn't of the type expected.
+#[derive(Debug, PartialEq)]
+pub struct Malformed;
+
+/// The initial byte of a data item, with its argument in as few bytes as
+/// possible.
+/// RFC 8949: 3.  Specification of the CBOR Encoding
+fn head(major: u8, argument: u64, out: &mut Vec<u8>) {
+    let major = major << 5;
+
+    if argument < 24 {
+        out.push(major | argument as u8);
+    } else if argument <= 0xff {
+        out.push(major | 24);
+        out.push(argument as u8);
+    } else if argument <= 0xffff {
+        out.push(major | 25);
+        out.extend_from_slice(&[(argument >> 8) as u8, argument as u8]);
+    } else if argument <= 0xffff_ffff {
+        out.push(major | 26);
+        out.extend((0..4).rev().map(|i| (argument >> (i * 8)) as u8));
+    } else {
+        out.push(major | 27);
+        out.extend((0..8).rev().map(|i| (argument >> (i * 8)) as u8));
+    }
+}
+
+pub fn uint(value: u64, out: &mut Vec<u8>) {
+    head(UNSIGNED, value, out);
+}
+
+pub fn int(value: i64, out: &mut Vec<u8>) {
+    if value < 0 {
+        head(NEGATIVE, !value as u64, out);
+    } else {
+        head(UNSIGNED, value as u64, out);
+    }
+}
+
+pub fn bytes(value: &[u8], out: &mut Vec<u8>) {
+    head(BYTES, value.len() as u64, out);
+    out.extend_from_slice(value);
+}
+
+pub fn text(value: &str, out: &mut Vec<u8>) {
+    head(TEXT, value.len() as u64, out);
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// The start of an array, whose `len` items follow.
+pub fn array(len: usize, out: &mut Vec<u8>) {
+    head(ARRAY, len as u64, out);
+}
+
+/// The start of a map, whose `len` key/value pairs follow.
+pub fn map(len: usize, out: &mut Vec<u8>) {
+    head(MAP, len as u64, out);
+}
+
+pub fn boolean(value: bool, out: &mut Vec<u8>) {
+    out.push(if value { TRUE } else { FALSE });
+}
+
+pub fn null(out: &mut Vec<u8>) {
+    out.push(NULL);
+}
+
+/// Reads the data items of a CBOR sequence one at a time. Indefinite lengths
+/// aren't supported.
+/// RFC 8742: Concise Binary Object Representation (CBOR) Sequences
+pub struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Decoder<'a> {
+        Decoder {
+            data: data,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The items that haven't been read yet.
+    pub fn rest(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// The major type of the next item.
+    pub fn peek(&self) -> Option<u8> {
+        self.data.first().map(|initial| initial >> 5)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Malformed> {
+        if self.data.len() < len {
+            return Err(Malformed);
+        }
+
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+
+        Ok(taken)
+    }
+
+    /// The major type and argument of the next item.
+    fn head(&mut self) -> Result<(u8, u64), Malformed> {
+        let initial = self.take(1)?[0];
+        let len = match initial & 0x1f {
+            info @ 0...23 => return Ok((initial >> 5, info as u64)),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            _ => return Err(Malformed),
+        };
+
+        let argument = self.take(len)?.iter().fold(0, |acc, &b| (acc << 8) | b as u64);
+
+        Ok((initial >> 5, argument))
+    }
+
+    fn expect(&mut self, major: u8) -> Result<u64, Malformed> {
+        match self.head()? {
+            (m, argument) if m == major => Ok(argument),
+            _ => Err(Malformed),
+        }
+    }
+
+    pub fn uint(&mut self) -> Result<u64, Malformed> {
+        self.expect(UNSIGNED)
+    }
+
+    pub fn int(&mut self) -> Result<i64, Malformed> {
+        match self.head()? {
+            (UNSIGNED, value) if value <= i64::MAX as u64 => Ok(value as i64),
+            (NEGATIVE, value) if value <= i64::MAX as u64 => Ok(!(value as i64)),
+            _ => Err(Malformed),
+        }
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], Malformed> {
+        let len = self.expect(BYTES)?;
+
+        self.take(len as usize)
+    }
+
+    pub fn text(&mut self) -> Result<&'a str, Malformed> {
+        let len = self.expect(TEXT)?;
+
+        ::std::str::from_utf8(self.take(len as usize)?).map_err(|_| Malformed)
+    }
+
+    /// The start of an array, giving the number of items that follow.
+    pub fn array(&mut self) -> Result<usize, Malformed> {
+        self.expect(ARRAY).map(|len| len as usize)
+    }
+
+    pub fn boolean(&mut self) -> Result<bool, Malformed> {
+        match self.take(1)?[0] {
+            TRUE => Ok(true),
+            FALSE => Ok(false),
+            _ => Err(Malformed),
+        }
+    }
+
+    /// Skip the next item, whatever it is, giving its encoding.
+    ///
+    /// An item nested more than `MAX_DEPTH` deep is malformed.
+    pub fn item(&mut self) -> Result<&'a [u8], Malformed> {
+        let start = self.data;
+        self.skip(MAX_DEPTH)?;
+
+        Ok(&start[..start.len() - self.data.len()])
+    }
+
+    fn skip(&mut self, depth: usize) -> Result<(), Malformed> {
+        match self.head()? {
+            (BYTES, len) | (TEXT, len) => {
+                self.take(len as usize)?;
+            }
+            (ARRAY, _) | (MAP, _) | (6, _) if depth == 0 => return Err(Malformed),
+            (ARRAY, len) => {
+                for _ in 0..len {
+                    self.skip(depth - 1)?;
+                }
+            }
+            (MAP, len) => {
+                for _ in 0..len.checked_mul(2).ok_or(Malformed)? {
+                    self.skip(depth - 1)?;
+                }
+            }
+            // tags are followed by the item they tag
+            (6, _) => self.skip(depth - 1)?,
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{array, boolean, bytes, int, map, null, text, uint, Decoder, Malformed};
+
+    fn encoded<F: Fn(&mut Vec<u8>)>(f: F) -> Vec<u8> {
+        let mut out = Vec::new();
+        f(&mut out);
+        out
+    }
+
+    /// RFC 8949: Appendix A.  Examples of Encoded CBOR Data Items
+    #[test]
+    fn examples() {
+        assert_eq!(encoded(|out| uint(0, out)), [0x00]);
+        assert_eq!(encoded(|out| uint(23, out)), [0x17]);
+        assert_eq!(encoded(|out| uint(24, out)), [0x18, 0x18]);
+        assert_eq!(encoded(|out| uint(1000, out)), [0x19, 0x03, 0xe8]);
+        assert_eq!(encoded(|out| uint(1000000, out)), [0x1a, 0x00, 0x0f, 0x42, 0x40]);
+        assert_eq!(encoded(|out| uint(1000000000000, out)), [0x1b, 0x00, 0x00, 0x00, 0xe8, 0xd4, 0xa5, 0x10, 0x00]);
+        assert_eq!(encoded(|out| int(-1, out)), [0x20]);
+        assert_eq!(encoded(|out| int(-1000, out)), [0x39, 0x03, 0xe7]);
+        assert_eq!(encoded(|out| bytes(&[1, 2, 3, 4], out)), [0x44, 0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(encoded(|out| text("IETF", out)), [0x64, 0x49, 0x45, 0x54, 0x46]);
+        assert_eq!(encoded(null), [0xf6]);
+
+        let list = encoded(|out| {
+            array(3, out);
+            uint(1, out);
+            uint(2, out);
+            uint(3, out);
+        });
+        assert_eq!(list, [0x83, 0x01, 0x02, 0x03]);
+    }
+
+    #[test]
+    fn decoding() {
+        let sequence = encoded(|out| {
+            uint(1000000, out);
+            int(-1000, out);
+            bytes(&[1, 2, 3, 4], out);
+            text("IETF", out);
+            boolean(true, out);
+            map(1, out);
+            uint(4, out);
+            array(2, out);
+            int(-15, out);
+            null(out);
+        });
+
+        let mut decoder = Decoder::new(&sequence);
+        assert_eq!(decoder.uint(), Ok(1000000));
+        assert_eq!(decoder.int(), Ok(-1000));
+        assert_eq!(decoder.bytes(), Ok(&[1, 2, 3, 4][..]));
+        assert_eq!(decoder.text(), Ok("IETF"));
+        assert_eq!(decoder.boolean(), Ok(true));
+        assert_eq!(decoder.peek(), Some(super::MAP));
+        assert_eq!(decoder.item(), Ok(&[0xa1, 0x04, 0x82, 0x2e, 0xf6][..]));
+        assert!(decoder.is_empty());
+
+        // the wrong type, a truncated item and an indefinite length
+        assert_eq!(Decoder::new(&[0x20]).uint(), Err(Malformed));
+        assert_eq!(Decoder::new(&[0x44, 0x01]).bytes(), Err(Malformed));
+        assert_eq!(Decoder::new(&[0x9f, 0xff]).array(), Err(Malformed));
+    }
+
+    #[test]
+    fn skipping_is_bounded() {
+        let nested = |depth| {
+            let mut out = vec![0x81; depth];
+            out.push(0x00);
+            out
+        };
+        assert!(Decoder::new(&nested(super::MAX_DEPTH)).item().is_ok());
+        assert_eq!(Decoder::new(&nested(super::MAX_DEPTH + 1)).item(), Err(Malformed));
+        assert_eq!(Decoder::new(&nested(100000)).item(), Err(Malformed));
+
+        // a map whose pair count can't be doubled
+        assert_eq!(Decoder::new(&[0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).item(), Err(Malformed));
+    }
+}
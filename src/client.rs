@@ -1,29 +1,116 @@
-use codec::CoapCodec;
 use Endpoint;
-use error::{Error, UrlError};
-use message::{Message, Code};
-use message::option::{Option, Options, UriPath, UriHost, UriQuery, Byteable};
+use endpoint::Scheme;
+#[cfg(feature = "dtls")]
+use dtls::{DtlsConfig, PeerIdentity};
+#[cfg(feature = "edhoc")]
+use edhoc::{self, Initiator};
+#[cfg(feature = "oscore")]
+use error::OscoreError;
+use error::{BlockError, Error, UrlError};
+use link_format::{self, Link};
+use message::{Message, Mtype, Code};
+use message::option::{Option, Options, UriPath, UriHost, UriQuery, ContentFormat, ETag, MaxAge, Observe, Block, Block1, Block2, Size1, Size2, Byteable};
+#[cfg(feature = "oscore")]
+use oscore::{Exchange, SecurityContext};
+use socket::{Registration, Socket};
+use transmission::{back_off, initial_timeout, timer_error, TransmissionParameters};
 
 use std::borrow::Cow;
-use std::net::Ipv4Addr;
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::option::Option as StdOption;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 
+use arrayvec::ArrayVec;
+use futures::future;
 use futures::prelude::*;
 
-use tokio::net::{UdpSocket, UdpFramed};
-use tokio::util::FutureExt;
+use tokio::timer::Delay;
 
 use percent_encoding::percent_decode;
+use rand::{thread_rng, Rng};
 use url::Url;
 
 /// An alias for the futures produced by this library.
 pub type IoFuture<T> = Box<Future<Item = T, Error = Error> + Send>;
+pub type IoStream<T> = Box<Stream<Item = T, Error = Error> + Send>;
 
 pub struct Client {
     /// the remote endpoint to contact
     endpoint: Endpoint,
     /// the message to be sent
     msg: Message,
+    /// timing used for retransmissions
+    params: TransmissionParameters,
+    /// where the request's message ID comes from
+    mids: MessageIds,
+    /// length of the randomly generated request token
+    token_length: usize,
+    /// the socket to send through, if this isn't set a new one is bound, or
+    /// taken from `connections` for coap+tcp:// and coaps+tcp://
+    socket: StdOption<Socket>,
+    /// the connections to servers a TCP request without a socket reuses
+    connections: Connections,
+    /// how long to wait for a separate response once the request has been
+    /// acknowledged, EXCHANGE_LIFETIME if this isn't set
+    separate_timeout: StdOption<Duration>,
+    /// the largest response body a block-wise transfer may reassemble
+    max_body_size: usize,
+    /// the largest block a request body is sent in
+    block_size: usize,
+    /// how the request is carried and secured
+    scheme: Scheme,
+    /// the credentials a socket bound for a coaps or coaps+tcp request uses
+    #[cfg(feature = "dtls")]
+    dtls: StdOption<DtlsConfig>,
+    /// the context each request and its responses are protected with
+    #[cfg(feature = "oscore")]
+    oscore: StdOption<SecurityContext>,
+}
+
+/// The default token length, long enough to not be guessable by an off-path
+/// attacker.
+/// RFC 7252: 5.3.1 & RFC 9175: 4.2
+const DEFAULT_TOKEN_LENGTH: usize = 8;
+
+/// The default limit on the size of a body reassembled from blocks.
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// The default size of the blocks a large request body is sent in.
+const DEFAULT_BLOCK_SIZE: usize = 1024;
+
+/// An allocator of message IDs.
+///
+/// It starts at a random value and increments for every message, cloning it
+/// yields a handle to the same sequence so that several clients can share it.
+/// RFC 7252: 4.4.  Implementation of Message ID
+#[derive(Clone, Debug)]
+pub struct MessageIds {
+    next: Arc<AtomicUsize>,
+}
+
+impl MessageIds {
+    pub fn new() -> MessageIds {
+        MessageIds {
+            next: Arc::new(AtomicUsize::new(thread_rng().gen::<u16>() as usize)),
+        }
+    }
+
+    pub fn next(&self) -> u16 {
+        self.next.fetch_add(1, Ordering::Relaxed) as u16
+    }
+}
+
+/// Generate a cryptographically random token of `length` bytes.
+fn random_token(length: usize) -> ArrayVec<[u8; 8]> {
+    let mut token = [0u8; 8];
+    thread_rng().fill(&mut token[..length]);
+
+    token[..length].iter().cloned().collect()
 }
 
 fn depercent(s: &str) -> Result<String, UrlError> {
@@ -33,17 +120,28 @@ fn depercent(s: &str) -> Result<String, UrlError> {
         .map_err(UrlError::NonUtf8)
 }
 
+/// The scheme of `url`, if it's one we can make requests with.
+/// RFC 7252: 6.4.  Decomposing URIs into Options, step 3
+fn scheme(url: &Url) -> Result<Scheme, UrlError> {
+    match url.scheme() {
+        "coap" => Ok(Scheme::Coap),
+        #[cfg(feature = "dtls")]
+        "coaps" => Ok(Scheme::Coaps),
+        "coap+tcp" => Ok(Scheme::CoapTcp),
+        #[cfg(feature = "dtls")]
+        "coaps+tcp" => Ok(Scheme::CoapsTcp),
+        other => Err(UrlError::UnsupportedScheme(other.to_string())),
+    }
+}
+
 /// RFC 7252: 6.4.  Decomposing URIs into Options
 fn decompose(url: &Url) -> Result<(Endpoint, Options), UrlError> {
     use url::Host;
 
     let mut options = Options::new();
 
-    // Step 3, TODO: Support coaps
-    match url.scheme() {
-        "coap" => (),
-        other => Err(UrlError::UnsupportedScheme(other.to_string()))?,
-    }
+    // Step 3
+    let scheme = scheme(url)?;
 
     // Step 4
     if url.fragment().is_some() {
@@ -51,7 +149,7 @@ fn decompose(url: &Url) -> Result<(Endpoint, Options), UrlError> {
     }
 
     // Step 6
-    let port = url.port().unwrap_or(5683);
+    let port = url.port().unwrap_or(scheme.default_port());
 
     // Step 5
     let endpoint = match url.host().ok_or(UrlError::NonAbsolutePath)? {
@@ -101,21 +199,102 @@ impl Client {
         Client {
             endpoint: Endpoint::Unset,
             msg: Message::new(),
+            params: TransmissionParameters::default(),
+            mids: MessageIds::new(),
+            token_length: DEFAULT_TOKEN_LENGTH,
+            socket: None,
+            connections: Connections::new(),
+            separate_timeout: None,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            block_size: DEFAULT_BLOCK_SIZE,
+            scheme: Scheme::Coap,
+            #[cfg(feature = "dtls")]
+            dtls: None,
+            #[cfg(feature = "oscore")]
+            oscore: None,
         }
     }
 
-    pub fn get(url: &str) -> Result<Client, Error> {
+    /// Build a request with the given method code to the given url.
+    pub fn request(code: Code, url: &str) -> Result<Client, Error> {
         let mut client = Client::new();
         let url = Url::parse(url).map_err(UrlError::Parse)?;
 
         let (endpoint, options) = decompose(&url)?;
 
+        client.scheme = scheme(&url)?;
         client.set_endpoint(endpoint);
+        client.msg.code = code;
         client.msg.options = options;
 
         Ok(client)
     }
 
+    pub fn get(url: &str) -> Result<Client, Error> {
+        Self::request(Code::Get, url)
+    }
+
+    pub fn post(url: &str) -> Result<Client, Error> {
+        Self::request(Code::Post, url)
+    }
+
+    pub fn put(url: &str) -> Result<Client, Error> {
+        Self::request(Code::Put, url)
+    }
+
+    pub fn delete(url: &str) -> Result<Client, Error> {
+        Self::request(Code::Delete, url)
+    }
+
+    /// RFC 8132: 2.  FETCH Method
+    pub fn fetch(url: &str) -> Result<Client, Error> {
+        Self::request(Code::Fetch, url)
+    }
+
+    /// RFC 8132: 3.  PATCH and iPATCH Methods
+    pub fn patch(url: &str) -> Result<Client, Error> {
+        Self::request(Code::Patch, url)
+    }
+
+    /// RFC 8132: 3.  PATCH and iPATCH Methods
+    pub fn ipatch(url: &str) -> Result<Client, Error> {
+        Self::request(Code::IPatch, url)
+    }
+
+    /// Fetch the links the server at `url` describes its resources with.
+    ///
+    /// Only the endpoint and query of `url` are used, the query filtering
+    /// the links returned.
+    /// RFC 6690: 4.  Well-Known Interface
+    pub fn discover(url: &str) -> IoFuture<Vec<Link>> {
+        let mut client = match Self::get(url) {
+            Ok(client) => client,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        client.msg.options.remove::<UriPath>();
+        client.msg.options.push(UriPath::new(".well-known".to_owned()));
+        client.msg.options.push(UriPath::new("core".to_owned()));
+
+        let links = client
+            .send()
+            .and_then(Message::error_for_code)
+            .and_then(|response| Ok(link_format::parse_bytes(&response.payload)?));
+
+        Box::new(links)
+    }
+
+    /// Run an EDHOC key exchange with the server at `url` as `initiator`,
+    /// giving the OSCORE security context it establishes, see `set_oscore`.
+    ///
+    /// Only the endpoint of `url` is used, the key exchange is with its
+    /// `/.well-known/edhoc` resource.
+    /// RFC 9528: Appendix A.2.  Transferring EDHOC over CoAP
+    #[cfg(feature = "edhoc")]
+    pub fn edhoc(url: &str, initiator: &Initiator) -> IoFuture<SecurityContext> {
+        edhoc::initiate(url, initiator)
+    }
+
     pub fn set_endpoint(&mut self, endpoint: Endpoint) {
         self.endpoint = endpoint;
     }
@@ -126,6 +305,185 @@ impl Client {
         self
     }
 
+    pub fn set_parameters(&mut self, params: TransmissionParameters) {
+        self.params = params;
+    }
+
+    pub fn with_parameters(mut self, params: TransmissionParameters) -> Self {
+        self.set_parameters(params);
+
+        self
+    }
+
+    /// Send through a shared socket instead of binding a new one.
+    ///
+    /// The client takes its message IDs and transmission parameters from the
+    /// socket, set them afterwards to override.
+    pub fn set_socket(&mut self, socket: Socket) {
+        self.mids = socket.message_ids();
+        self.params = socket.parameters().clone();
+        self.socket = Some(socket);
+    }
+
+    pub fn with_socket(mut self, socket: Socket) -> Self {
+        self.set_socket(socket);
+
+        self
+    }
+
+    /// Reuse the connections in `connections` for a `coap+tcp://` or
+    /// `coaps+tcp://` request, instead of ones of the client's own.
+    pub fn set_connections(&mut self, connections: Connections) {
+        self.connections = connections;
+    }
+
+    pub fn with_connections(mut self, connections: Connections) -> Self {
+        self.set_connections(connections);
+
+        self
+    }
+
+    /// Set the credentials a `coaps://` or `coaps+tcp://` request
+    /// authenticates with.
+    ///
+    /// They're only used if the client binds its own socket, a shared one
+    /// must have been made with `Socket::bind_dtls` or `Socket::tls`.
+    #[cfg(feature = "dtls")]
+    pub fn set_dtls(&mut self, config: DtlsConfig) {
+        self.dtls = Some(config);
+    }
+
+    #[cfg(feature = "dtls")]
+    pub fn with_dtls(mut self, config: DtlsConfig) -> Self {
+        self.set_dtls(config);
+
+        self
+    }
+
+    /// Protect the request, and verify its responses, with OSCORE.
+    ///
+    /// Each block of a block-wise transfer and each re-registration of an
+    /// observation is protected on its own. Error responses the server sends
+    /// unprotected, such as 4.01 when it doesn't know the context, are
+    /// returned as they are; other unprotected responses are an error.
+    /// RFC 8613: 8.  OSCORE Message Processing
+    #[cfg(feature = "oscore")]
+    pub fn set_oscore(&mut self, context: SecurityContext) {
+        self.oscore = Some(context);
+    }
+
+    #[cfg(feature = "oscore")]
+    pub fn with_oscore(mut self, context: SecurityContext) -> Self {
+        self.set_oscore(context);
+
+        self
+    }
+
+    /// Set how long to wait for a separate response after the server has
+    /// acknowledged the request with an empty ACK, or for any response over
+    /// TCP.
+    /// RFC 7252: 5.2.2.  Separate
+    pub fn set_separate_timeout(&mut self, timeout: Duration) {
+        self.separate_timeout = Some(timeout);
+    }
+
+    pub fn with_separate_timeout(mut self, timeout: Duration) -> Self {
+        self.set_separate_timeout(timeout);
+
+        self
+    }
+
+    /// Choose between sending the request as a confirmable message, the
+    /// default, or a non-confirmable one.
+    ///
+    /// Non-confirmable requests are never retransmitted, their response is
+    /// waited on for NON_LIFETIME.
+    /// RFC 7252: 4.3.  Messages Transmitted without Reliability
+    pub fn set_confirmable(&mut self, confirmable: bool) {
+        self.msg.mtype = if confirmable {
+            Mtype::Confirmable
+        } else {
+            Mtype::NonConfirmable
+        };
+    }
+
+    pub fn non_confirmable(mut self) -> Self {
+        self.set_confirmable(false);
+
+        self
+    }
+
+    pub fn set_message_ids(&mut self, mids: MessageIds) {
+        self.mids = mids;
+    }
+
+    pub fn with_message_ids(mut self, mids: MessageIds) -> Self {
+        self.set_message_ids(mids);
+
+        self
+    }
+
+    /// Set the length of the generated request token, at most 8 bytes.
+    pub fn set_token_length(&mut self, length: usize) {
+        assert!(length <= 8, "token length must be at most 8 bytes");
+
+        self.token_length = length;
+    }
+
+    pub fn with_token_length(mut self, length: usize) -> Self {
+        self.set_token_length(length);
+
+        self
+    }
+
+    pub fn set_payload(&mut self, payload: Vec<u8>) {
+        self.msg.payload = payload;
+    }
+
+    pub fn with_payload(mut self, payload: Vec<u8>) -> Self {
+        self.set_payload(payload);
+
+        self
+    }
+
+    /// Set the largest response body that will be reassembled from a
+    /// block-wise transfer, 1 MiB by default.
+    pub fn set_max_body_size(&mut self, size: usize) {
+        self.max_body_size = size;
+    }
+
+    pub fn with_max_body_size(mut self, size: usize) -> Self {
+        self.set_max_body_size(size);
+
+        self
+    }
+
+    /// Set the size of the blocks a request payload is split into when it is
+    /// too large to send in one message, 1024 bytes by default. This is
+    /// rounded down to a power of two between 16 and 1024.
+    pub fn set_block_size(&mut self, size: usize) {
+        self.block_size = size;
+    }
+
+    pub fn with_block_size(mut self, size: usize) -> Self {
+        self.set_block_size(size);
+
+        self
+    }
+
+    /// Set the Content-Format option describing the payload, replacing any
+    /// previously set format.
+    pub fn set_content_format(&mut self, format: u64) {
+        self.msg.options.remove::<ContentFormat>();
+        self.msg.options.push(ContentFormat::new(format));
+    }
+
+    pub fn with_content_format(mut self, format: u64) -> Self {
+        self.set_content_format(format);
+
+        self
+    }
+
     pub fn set_option<T: Option + Byteable>(&mut self, option: T) {
         self.msg.options.push(option);
     }
@@ -136,162 +494,1193 @@ impl Client {
     }
 
     pub fn send(self) -> IoFuture<Message> {
-        let local_addr = "0.0.0.0:0".parse().unwrap();
+        Box::new(self.send_with_attempts().map(|(msg, _attempts)| msg))
+    }
+
+    /// Send the request, also returning the number of times it had to be
+    /// transmitted before a response arrived.
+    ///
+    /// For a block-wise transfer this is the total over every block.
+    pub fn send_with_attempts(self) -> IoFuture<(Message, u32)> {
+        let max_body_size = self.max_body_size;
+        let block_size = self.block_size;
+
+        let client_request = self
+            .connect()
+            .and_then(move |(context, msg)| Ok(exchange(context, msg, block_size, max_body_size)))
+            .flatten();
+
+        Box::new(client_request)
+    }
+
+    /// Send the request, also returning who the server authenticated as if
+    /// it was a coaps:// or coaps+tcp:// request.
+    #[cfg(feature = "dtls")]
+    pub fn send_with_identity(self) -> IoFuture<(Message, StdOption<PeerIdentity>)> {
+        let max_body_size = self.max_body_size;
+        let block_size = self.block_size;
+
+        let client_request = self
+            .connect()
+            .and_then(move |(context, msg)| {
+                let socket = context.socket.clone();
+                let remote_addr = context.remote_addr;
+
+                Ok(exchange(context, msg, block_size, max_body_size)
+                    .map(move |(msg, _attempts)| (msg, socket.peer_identity(&remote_addr))))
+            })
+            .flatten();
+
+        Box::new(client_request)
+    }
 
-        let Self { endpoint, msg } = self;
-        let client_request = endpoint
+    /// Register interest in the resource and return a stream of its
+    /// notifications, the first being the response to the registration.
+    ///
+    /// If the server doesn't support observing the resource the stream ends
+    /// after that response. Dropping the stream cancels the observation.
+    /// RFC 7641: 3.  Client-Side Requirements
+    pub fn observe(mut self) -> IoStream<Message> {
+        self.msg.options.remove::<Observe>();
+        self.msg.options.push(Observe::new(0));
+
+        let observation = self
+            .connect()
+            .map(|(context, msg)| Observation::new(context, msg))
+            .flatten_stream();
+
+        Box::new(observation)
+    }
+
+    /// Resolve the endpoint and bind a socket if none was given.
+    fn connect(self) -> IoFuture<(Context, Message)> {
+        let bind = self.binder();
+
+        #[cfg(feature = "oscore")]
+        let oscore = self.oscore.clone();
+
+        let Self { endpoint, msg, params, mids, token_length, socket, separate_timeout, .. } = self;
+        let separate_timeout = separate_timeout.unwrap_or_else(|| params.exchange_lifetime());
+
+        let connection = endpoint
             .resolve()
             .and_then(move |remote_addr| {
-                let sock = UdpSocket::bind(&local_addr).unwrap();
-
-                let framed_socket = UdpFramed::new(sock, CoapCodec);
-
-                info!("sending request");
-                let client =  framed_socket
-                    .send((msg, remote_addr))
-                    .and_then(|sock| {
-                        let timeout_time = Instant::now() + Duration::from_millis(1000);
-                        sock
-                            .filter_map(|(msg, _addr)| {
-                                match msg.code {
-                                    Code::Content => {
-                                        Some(msg)
-                                    },
-                                    _ => {
-                                        warn!("Unexpeted Response");
-                                        None
-                                    },
-                                }
-                            })
-                            .take(1)
-                            .collect()
-                            .map(|mut list| {
-                                list.pop().expect("list of one somehow had nothing to pop")
-                            })
-                            .deadline(timeout_time)
-                            .map_err(|_| Error::Timeout)
-                    });
-
-                client
+                let socket = match socket {
+                    Some(socket) => socket,
+                    None => bind(remote_addr)?,
+                };
+
+                let context = Context {
+                    socket: socket,
+                    remote_addr: remote_addr,
+                    params: params,
+                    mids: mids,
+                    token_length: token_length,
+                    separate_timeout: separate_timeout,
+                    #[cfg(feature = "oscore")]
+                    oscore: oscore,
+                };
+
+                Ok((context, msg))
+            });
+
+        Box::new(connection)
+    }
+
+    /// What binds a socket for the request if it wasn't given one.
+    #[cfg(feature = "dtls")]
+    fn binder(&self) -> impl FnOnce(SocketAddr) -> Result<Socket, Error> + Send {
+        let scheme = self.scheme;
+        let dtls = self.dtls.clone();
+        let connections = self.connections.clone();
+
+        move |remote_addr| {
+            let local_addr = "0.0.0.0:0".parse().unwrap();
+
+            match (scheme, dtls) {
+                (Scheme::Coaps, Some(config)) => Socket::bind_dtls(&local_addr, &config),
+                (Scheme::Coaps, None) => {
+                    Err(io::Error::new(io::ErrorKind::InvalidInput, "coaps request without DTLS credentials").into())
+                }
+                (Scheme::CoapsTcp, Some(config)) => connections.tls(remote_addr, &config),
+                (Scheme::CoapsTcp, None) => {
+                    Err(io::Error::new(io::ErrorKind::InvalidInput, "coaps+tcp request without TLS credentials").into())
+                }
+                (Scheme::CoapTcp, _) => Ok(connections.tcp(remote_addr)),
+                (Scheme::Coap, _) => Socket::bind(&local_addr),
             }
-        );
+        }
+    }
 
-        Box::new(client_request)
+    #[cfg(not(feature = "dtls"))]
+    fn binder(&self) -> impl FnOnce(SocketAddr) -> Result<Socket, Error> + Send {
+        let scheme = self.scheme;
+        let connections = self.connections.clone();
+
+        move |remote_addr| match scheme {
+            Scheme::CoapTcp => Ok(connections.tcp(remote_addr)),
+            _ => Socket::bind(&"0.0.0.0:0".parse().unwrap()),
+        }
     }
 }
 
+/// The connections `coap+tcp://` and `coaps+tcp://` requests reuse, a socket
+/// for each server and TLS configuration.
+///
+/// Every client has its own unless it's given some, cloning them yields a
+/// handle to the same connections so that several clients can share them. A
+/// socket is driven on the executor of the first request sent through it,
+/// and replaced by a new one once that executor has shut down.
+/// RFC 8323: 3.  CoAP over TCP
+#[derive(Clone, Default)]
+pub struct Connections {
+    tcp: Arc<Mutex<HashMap<SocketAddr, Socket>>>,
+    #[cfg(feature = "dtls")]
+    tls: Arc<Mutex<Vec<(SocketAddr, DtlsConfig, Socket)>>>,
+}
 
+impl Connections {
+    pub fn new() -> Connections {
+        Connections::default()
+    }
 
-// This doesn't quite work, but leaving it here in case I want to fix & use it
-// in the future.
-#[allow(unused_macros)]
-macro_rules! set_or_with {
-    // Opaque Type Options
-    ($fn:ident($params:tt) {$body: block}) => {
-        pub fn set_$fn($params) {
-            $body
+    /// The socket for `coap+tcp://` requests to `remote_addr`.
+    fn tcp(&self, remote_addr: SocketAddr) -> Socket {
+        let mut tcp = self.tcp.lock().unwrap();
+        match tcp.get(&remote_addr) {
+            Some(socket) if !socket.is_closed() => socket.clone(),
+            _ => {
+                let socket = Socket::tcp();
+                tcp.insert(remote_addr, socket.clone());
+                socket
+            }
         }
+    }
 
-        pub fn with_$fn(mut self, $params) -> Self {
-            set_$fn(&mut self, $params);
+    /// The socket for `coaps+tcp://` requests to `remote_addr` secured with
+    /// `config`.
+    #[cfg(feature = "dtls")]
+    fn tls(&self, remote_addr: SocketAddr, config: &DtlsConfig) -> Result<Socket, Error> {
+        let mut tls = self.tls.lock().unwrap();
+        tls.retain(|&(_, _, ref socket)| !socket.is_closed());
 
-            self
+        if let Some(&(_, _, ref socket)) = tls.iter().find(|&&(addr, ref c, _)| addr == remote_addr && c == config) {
+            return Ok(socket.clone());
         }
+
+        let socket = Socket::tls(config)?;
+        tls.push((remote_addr, config.clone(), socket.clone()));
+        Ok(socket)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::decompose;
-    use endpoint::Endpoint;
-    use message::option::{Option, Options, UriHost, UriPath, UriQuery};
+/// Send `msg`, uploading and downloading its body block-wise as needed.
+fn exchange(context: Context, msg: Message, block_size: usize, max_body_size: usize) -> Download {
+    // Later blocks of the response are requested without the request body.
+    // RFC 7959: 3.3.  Combining Block-Wise POST with Block2
+    let mut template = msg.clone();
+    template.payload.clear();
+    template.options.remove::<Block1>();
+    template.options.remove::<Size1>();
 
-    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    info!("sending request");
+    let upload = Upload::new(context.clone(), msg, Block::szx_for(block_size));
+    Download::new(context, template, max_body_size, Box::new(upload))
+}
 
-    use url::Url;
+/// Everything needed to turn a message into a `Transaction` with the server.
+#[derive(Clone)]
+struct Context {
+    socket: Socket,
+    remote_addr: SocketAddr,
+    params: TransmissionParameters,
+    mids: MessageIds,
+    token_length: usize,
+    separate_timeout: Duration,
+    #[cfg(feature = "oscore")]
+    oscore: StdOption<SecurityContext>,
+}
 
-    #[test]
-    fn uri_decompose_normalization() {
-        let uri1 = Url::parse("coap://example.com:5683/~sensors/temp.xml").unwrap();
-        let uri2 = Url::parse("coap://EXAMPLE.com/%7Esensors/temp.xml").unwrap();
-        let uri3 = Url::parse("coap://EXAMPLE.com:/%7esensors/temp.xml").unwrap();
+impl Context {
+    /// Send `msg` with a fresh message ID and token.
+    fn transact(&self, mut msg: Message) -> Transaction {
+        msg.mid = self.mids.next();
+        msg.token = random_token(self.token_length);
 
-        assert_eq!(decompose(&uri1).unwrap(), decompose(&uri2).unwrap());
-        assert_eq!(decompose(&uri2).unwrap(), decompose(&uri3).unwrap());
+        self.transact_with_token(msg)
     }
 
-    #[test]
-    fn uri_decompose_basic_ipv6() {
-        let uri = Url::parse("coap://[2001:db8::2:1]/").unwrap();
+    /// Send `msg` as-is.
+    fn transact_with_token(&self, msg: Message) -> Transaction {
+        let transaction = Transaction::new(self.socket.clone(),
+                                           self.remote_addr,
+                                           msg,
+                                           self.params.clone(),
+                                           self.separate_timeout);
 
-        let sa_ref = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 2, 1)), 5683);
-        let opt_ref = Options::new();
+        self.secure(transaction)
+    }
 
-        let (endpoint, options) = decompose(&uri).unwrap();
+    /// `msg` as it's sent, for a request that isn't waited on.
+    #[cfg(feature = "oscore")]
+    fn protect(&self, msg: Message) -> Result<Message, Error> {
+        match self.oscore {
+            Some(ref context) => context.protect_request(&msg).map(|(msg, _)| msg),
+            None => Ok(msg),
+        }
+    }
 
-        assert_eq!(endpoint, Endpoint::Resolved(sa_ref));
-        assert_eq!(options, opt_ref);
+    #[cfg(not(feature = "oscore"))]
+    fn protect(&self, msg: Message) -> Result<Message, Error> {
+        Ok(msg)
     }
 
-    #[test]
-    fn uri_decompose_basic_example_net() {
-        let uri = Url::parse("coap://example.net/").unwrap();
+    #[cfg(feature = "oscore")]
+    fn secure(&self, mut transaction: Transaction) -> Transaction {
+        transaction.oscore = self.oscore.clone();
+        transaction
+    }
 
-        let opt_ref = {
-            let mut opts = Options::new();
-            opts.push(UriHost::new("example.net".to_string()));
-            opts
-        };
+    #[cfg(not(feature = "oscore"))]
+    fn secure(&self, transaction: Transaction) -> Transaction {
+        transaction
+    }
+}
 
-        let (endpoint, options) = decompose(&uri).unwrap();
+/// A request whose body may need splitting into several blocks, each of which
+/// is sent in turn. Only the response to the final block is returned.
+///
+/// Bodies that fit in a single block are sent as-is, unless the server asks
+/// for smaller blocks with a 4.13 response.
+/// RFC 7959: 2.5.  Using the Block1 Option
+struct Upload {
+    context: Context,
+    request: Message,
+    body: Vec<u8>,
+    /// whether the body is being sent with Block1 options
+    blockwise: bool,
+    szx: u8,
+    /// the number of the block being sent
+    num: u32,
+    current: Transaction,
+    attempts: u32,
+}
 
-        assert_eq!(endpoint, Endpoint::Unresolved("example.net".to_string(), 5683));
-        assert_eq!(options, opt_ref);
+impl Upload {
+    fn new(context: Context, mut request: Message, szx: u8) -> Upload {
+        let body = mem::replace(&mut request.payload, Vec::new());
+        let blockwise = body.len() > 1 << (szx + 4);
+
+        let current = context.transact(Self::block(&request, &body, blockwise, szx, 0));
+
+        Upload {
+            context: context,
+            request: request,
+            body: body,
+            blockwise: blockwise,
+            szx: szx,
+            num: 0,
+            current: current,
+            attempts: 0,
+        }
     }
 
-    #[test]
-    fn uri_decompose_example_net_well_known_core() {
-        let uri = Url::parse("coap://example.net/.well-known/core").unwrap();
+    /// Build the request carrying block `num` of the body, or the whole body
+    /// if it isn't being sent block-wise. `num` has been checked to fit in
+    /// a block option by `next_block`.
+    fn block(request: &Message, body: &[u8], blockwise: bool, szx: u8, num: u32) -> Message {
+        let mut msg = request.clone();
 
-        let opt_ref = {
-            let mut opts = Options::new();
-            opts.push(UriHost::new("example.net".to_string()));
-            opts.push(UriPath::new(".well-known".to_string()));
-            opts.push(UriPath::new("core".to_string()));
-            opts
-        };
+        if !blockwise {
+            msg.payload = body.to_vec();
+            return msg;
+        }
 
-        let (endpoint, options) = decompose(&uri).unwrap();
+        let block = Block::new(num, false, szx);
+        let end = body.len().min(block.offset() + block.size());
+        let more = end < body.len();
 
-        assert_eq!(endpoint, Endpoint::Unresolved("example.net".to_string(), 5683));
-        assert_eq!(options, opt_ref);
+        msg.payload = body[block.offset()..end].to_vec();
+        msg.options.push(Block1::new(Block::new(num, more, szx)));
+
+        if num == 0 {
+            msg.options.remove::<Size1>();
+            msg.options.push(Size1::new(body.len() as u64));
+        }
+
+        msg
     }
 
-    #[test]
-    fn uri_decompose_punny_unicode() {
-        let uri = Url::parse(
-            "coap://xn--18j4d.example/%E3%81%93%E3%82%93%E3%81%AB%E3%81%A1%E3%81%AF"
-        ).unwrap();
+    fn send_block(&mut self) {
+        debug!("sending block {} of {} bytes", self.num, 1 << (self.szx + 4));
 
-        let opt_ref = {
-            let mut opts = Options::new();
-            opts.push(UriHost::new("xn--18j4d.example".to_string()));
-            opts.push(UriPath::new("こんにちは".to_string()));
-            opts
-        };
+        let msg = Self::block(&self.request, &self.body, self.blockwise, self.szx, self.num);
+        self.current = self.context.transact(msg);
+    }
 
-        let (endpoint, options) = decompose(&uri).unwrap();
+    /// The server has continued the transfer, pick the next block to send,
+    /// switching to the size it asked for if that is smaller.
+    fn next_block(&mut self, block: Block) -> Result<(), Error> {
+        if !self.blockwise || block.num != self.num {
+            return Err(BlockError::Inconsistent.into());
+        }
 
-        assert_eq!(endpoint, Endpoint::Unresolved("xn--18j4d.example".to_string(), 5683));
-        assert_eq!(options, opt_ref);
+        let offset = (self.num as usize + 1) << (self.szx + 4);
+        if offset >= self.body.len() {
+            return Err(BlockError::Inconsistent.into());
+        }
+
+        // a body can have more blocks than Block1 can number once the
+        // server has brought the size down far enough
+        self.szx = self.szx.min(block.szx);
+        self.num = Block::at(offset, false, self.szx).ok_or(BlockError::TooLarge)?.num;
+
+        Ok(())
     }
 
-    #[test]
-    fn uri_decompose_port_evil() {
-        let uri = Url::parse("coap://198.51.100.1:61616//%2F//?%2F%2F&?%26").unwrap();
+    /// The server rejected the body as too large, work out if a smaller block
+    /// size would be acceptable to it. A Size1 option gives the largest body
+    /// it accepts at all, no block size will get a larger one through.
+    /// RFC 7959: 2.9.3.  4.13 Request Entity Too Large
+    /// RFC 7959: 4.  The Size2 and Size1 Options
+    fn renegotiate(&self, response: &Message) -> Result<StdOption<u8>, Error> {
+        if let Some(size) = response.options.try_get::<Size1>()? {
+            if self.body.len() as u64 > size[0].value {
+                return Ok(None);
+            }
+        }
 
-        let sa_ref = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)), 61616);
-        let opt_ref = {
+        let szx = match response.options.try_get::<Block1>()? {
+            Some(block) => block[0].value.szx,
+            None => return Ok(None),
+        };
+
+        if !self.blockwise || szx < self.szx {
+            Ok(Some(szx))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Future for Upload {
+    type Item = (Message, u32);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let (response, attempts) = match self.current.poll()? {
+                Async::Ready(response) => response,
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+
+            self.attempts += attempts;
+
+            match response.code {
+                Code::Continue => {
+                    let block = match response.options.try_get::<Block1>()? {
+                        Some(blocks) => blocks[0].value,
+                        None => return Err(BlockError::Inconsistent.into()),
+                    };
+
+                    self.next_block(block)?;
+                }
+                Code::RequestEntityTooLarge => {
+                    match self.renegotiate(&response)? {
+                        Some(szx) => {
+                            debug!("restarting upload with {} byte blocks", 1 << (szx + 4));
+
+                            self.blockwise = true;
+                            self.szx = szx;
+                            self.num = 0;
+                        }
+                        None => return Ok(Async::Ready((response, self.attempts))),
+                    }
+                }
+                _ => return Ok(Async::Ready((response, self.attempts))),
+            }
+
+            self.send_block();
+        }
+    }
+}
+
+/// A request whose response may be split into several blocks, each of which
+/// is requested in turn and reassembled into a single response.
+/// RFC 7959: 2.4.  Using the Block2 Option
+struct Download {
+    context: Context,
+    request: Message,
+    max_body_size: usize,
+    current: IoFuture<(Message, u32)>,
+    body: Vec<u8>,
+    etag: StdOption<Vec<Vec<u8>>>,
+    attempts: u32,
+}
+
+impl Download {
+    fn new(context: Context,
+           request: Message,
+           max_body_size: usize,
+           first: IoFuture<(Message, u32)>) -> Download {
+        Download {
+            context: context,
+            request: request,
+            max_body_size: max_body_size,
+            current: first,
+            body: Vec::new(),
+            etag: None,
+            attempts: 0,
+        }
+    }
+
+    /// Add the block carried by `response` to the body, returning the block
+    /// to request next, if any.
+    fn append(&mut self, response: &mut Message, block: Block) -> Result<StdOption<Block>, Error> {
+        if block.offset() != self.body.len() || (block.more && response.payload.len() != block.size()) {
+            return Err(BlockError::Inconsistent.into());
+        }
+
+        let etag = response.options.get_raw::<ETag>();
+        if self.body.is_empty() {
+            if let Some(size) = response.options.try_get::<Size2>()? {
+                if size[0].value > self.max_body_size as u64 {
+                    return Err(BlockError::TooLarge.into());
+                }
+            }
+
+            self.etag = etag;
+        } else if etag != self.etag {
+            return Err(BlockError::Changed.into());
+        }
+
+        if self.body.len() + response.payload.len() > self.max_body_size {
+            return Err(BlockError::TooLarge.into());
+        }
+
+        self.body.extend(response.payload.drain(..));
+
+        if block.more {
+            let next = Block::at(self.body.len(), false, block.szx).ok_or(BlockError::TooLarge)?;
+
+            Ok(Some(next))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Future for Download {
+    type Item = (Message, u32);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let (mut response, attempts) = match self.current.poll()? {
+                Async::Ready(response) => response,
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+
+            self.attempts += attempts;
+
+            let block = match response.options.try_get::<Block2>()? {
+                Some(ref blocks) if response.code.class() == 2 => blocks[0].value,
+                _ if self.body.is_empty() => return Ok(Async::Ready((response, self.attempts))),
+                _ => return Err(BlockError::Inconsistent.into()),
+            };
+
+            match self.append(&mut response, block)? {
+                Some(next) => {
+                    debug!("requesting block {} of {} bytes", next.num, next.size());
+
+                    let mut request = self.request.clone();
+                    request.options.remove::<Block2>();
+                    request.options.push(Block2::new(next));
+
+                    self.current = Box::new(self.context.transact(request));
+                }
+                None => {
+                    response.options.remove::<Block2>();
+                    response.payload = mem::replace(&mut self.body, Vec::new());
+
+                    return Ok(Async::Ready((response, self.attempts)));
+                }
+            }
+        }
+    }
+}
+
+/// An observation of a resource, yielding each fresh notification.
+///
+/// The registration is renewed with the same token once the latest
+/// notification's Max-Age has passed without another arriving.
+/// RFC 7641: 3.3.1.  Freshness
+struct Observation {
+    context: Context,
+    request: Message,
+    /// the (re-)registration request in flight, which owns the registration
+    /// until it is answered
+    current: StdOption<Transaction>,
+    registration: StdOption<Registration>,
+    /// sequence number and time of arrival of the newest notification
+    latest: StdOption<(u32, Instant)>,
+    /// fires when the newest notification has gone stale
+    expiry: Delay,
+    /// the server has ended the observation
+    done: bool,
+    /// what notifications are verified against, if the registration was
+    /// protected
+    #[cfg(feature = "oscore")]
+    exchange: StdOption<Exchange>,
+}
+
+impl Observation {
+    fn new(context: Context, mut request: Message) -> Observation {
+        request.mid = context.mids.next();
+        request.token = random_token(context.token_length);
+
+        let current = context.transact_with_token(request.clone());
+        let expiry = Delay::new(Instant::now() + context.params.exchange_lifetime());
+
+        Observation {
+            context: context,
+            request: request,
+            current: Some(current),
+            registration: None,
+            latest: None,
+            expiry: expiry,
+            done: false,
+            #[cfg(feature = "oscore")]
+            exchange: None,
+        }
+    }
+
+    /// Decide whether `notification` should be passed on.
+    fn accept(&mut self, notification: Message) -> Result<StdOption<Message>, Error> {
+        let sequence = match notification.options.try_get::<Observe>()? {
+            Some(ref observe) if notification.code.class() == 2 => observe[0].value as u32,
+            _ => {
+                // RFC 7641: 3.2.  Notifications
+                debug!("observation ended by server");
+                self.done = true;
+                return Ok(Some(notification));
+            }
+        };
+
+        let now = Instant::now();
+
+        if let Some((latest, at)) = self.latest {
+            if !is_fresh(latest, at, sequence, now) {
+                debug!("dropping reordered notification {}", sequence);
+                return Ok(None);
+            }
+        }
+
+        let max_age = match notification.options.try_get::<MaxAge>()? {
+            Some(max_age) => max_age[0].value,
+            None => 60,
+        };
+
+        self.latest = Some((sequence, now));
+        self.expiry.reset(now + Duration::from_secs(max_age));
+
+        Ok(Some(notification))
+    }
+
+    /// Renew the registration once the resource has gone stale.
+    fn reregister(&mut self) {
+        debug!("notifications have gone stale, re-registering");
+
+        let registration = self.registration.take().expect("no registration in flight");
+        let mut request = self.request.clone();
+        request.mid = self.context.mids.next();
+
+        let transaction = Transaction::with_registration(self.context.socket.clone(),
+                                                         self.context.remote_addr,
+                                                         request,
+                                                         self.context.params.clone(),
+                                                         self.context.separate_timeout,
+                                                         registration);
+
+        self.current = Some(self.context.secure(transaction));
+    }
+
+    /// Take over verifying notifications from the registration that has been
+    /// answered.
+    #[cfg(feature = "oscore")]
+    fn registered(&mut self, current: &Transaction) {
+        self.exchange = current.exchange.clone();
+    }
+
+    #[cfg(not(feature = "oscore"))]
+    fn registered(&mut self, _current: &Transaction) {
+    }
+
+    #[cfg(feature = "oscore")]
+    fn unprotect(&self, notification: Message) -> Result<Message, Error> {
+        unprotect(self.exchange.as_ref(), notification)
+    }
+
+    #[cfg(not(feature = "oscore"))]
+    fn unprotect(&self, notification: Message) -> Result<Message, Error> {
+        Ok(notification)
+    }
+}
+
+impl Stream for Observation {
+    type Item = Message;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<StdOption<Self::Item>, Self::Error> {
+        loop {
+            if self.done {
+                return Ok(Async::Ready(None));
+            }
+
+            if let Some(mut current) = self.current.take() {
+                match current.poll()? {
+                    Async::Ready((response, _)) => {
+                        self.registered(&current);
+                        let registration = current.into_registration();
+                        registration.acknowledged();
+                        self.registration = Some(registration);
+
+                        match self.accept(response)? {
+                            Some(notification) => return Ok(Async::Ready(Some(notification))),
+                            None => continue,
+                        }
+                    }
+                    Async::NotReady => {
+                        self.current = Some(current);
+                        return Ok(Async::NotReady);
+                    }
+                }
+            }
+
+            let incoming = self.registration
+                .as_mut()
+                .expect("no registration in flight")
+                .incoming
+                .poll()
+                .expect("receivers never fail");
+
+            match incoming {
+                Async::Ready(Some((msg, addr))) => {
+                    match msg.mtype {
+                        Mtype::Acknowledgement | Mtype::Reset => {
+                            debug!("ignoring late reply to an earlier registration: {:?}", msg);
+                            continue;
+                        }
+                        _ if !msg.code.is_response() => {
+                            warn!("rejecting unexpected message from {}: {:?}", addr, msg);
+                            self.context.socket.send(msg.new_reset(), addr);
+                            continue;
+                        }
+                        Mtype::Confirmable => self.context.socket.send(msg.new_ack(), addr),
+                        Mtype::NonConfirmable => (),
+                    }
+
+                    let msg = match self.unprotect(msg) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            debug!("dropping notification that failed verification: {:?}", e);
+                            continue;
+                        }
+                    };
+
+                    if let Some(notification) = self.accept(msg)? {
+                        return Ok(Async::Ready(Some(notification)));
+                    }
+                }
+                Async::Ready(None) => return Err(closed(&self.context.socket)),
+                Async::NotReady => {
+                    match self.expiry.poll() {
+                        Ok(Async::Ready(())) => self.reregister(),
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(e) => return Err(timer_error(e)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Observation {
+    /// Cancel the observation, any further notifications are answered with a
+    /// reset by the socket once the token has been deregistered.
+    /// RFC 7641: 3.6.  Cancellation
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        let mut deregistration = self.request.clone();
+        deregistration.mtype = Mtype::NonConfirmable;
+        deregistration.mid = self.context.mids.next();
+        deregistration.options.remove::<Observe>();
+        deregistration.options.push(Observe::new(1));
+
+        match self.context.protect(deregistration) {
+            Ok(deregistration) => self.context.socket.send(deregistration, self.context.remote_addr),
+            Err(e) => debug!("couldn't protect deregistration: {:?}", e),
+        }
+    }
+}
+
+/// Whether a notification with sequence number `v2` received at `t2` is newer
+/// than one with `v1` received at `t1`.
+/// RFC 7641: 3.4.  Reordering
+fn is_fresh(v1: u32, t1: Instant, v2: u32, t2: Instant) -> bool {
+    const HALF: u32 = 1 << 23;
+
+    (v1 < v2 && v2 - v1 < HALF) ||
+        (v1 > v2 && v1 - v2 > HALF) ||
+        t2 > t1 + Duration::from_secs(128)
+}
+
+/// A single request and the wait for its response.
+///
+/// Confirmable requests are retransmitted with exponential back-off until
+/// they are acknowledged or `max_retransmit` is exceeded.
+/// RFC 7252: 4.2.  Messages Transmitted Reliably
+///
+/// Over TCP a request is only sent once, the connection delivering it, and
+/// its response is waited for as if it had been acknowledged.
+/// RFC 8323: 3.  CoAP over TCP
+///
+/// If the server acknowledges the request without piggybacking a response on
+/// the ACK the response is waited for separately, and acknowledged itself if
+/// it is confirmable.
+/// RFC 7252: 5.2.2.  Separate
+struct Transaction {
+    socket: Socket,
+    remote_addr: SocketAddr,
+    request: Message,
+    params: TransmissionParameters,
+    separate_timeout: Duration,
+    registration: Registration,
+    /// number of times the request has been transmitted
+    attempts: u32,
+    timeout: Duration,
+    /// started once the socket admits the request
+    timer: StdOption<Delay>,
+    acknowledged: bool,
+    /// the context the request is protected with
+    #[cfg(feature = "oscore")]
+    oscore: StdOption<SecurityContext>,
+    /// what the responses are verified against, once the request is protected
+    #[cfg(feature = "oscore")]
+    exchange: StdOption<Exchange>,
+}
+
+impl Transaction {
+    fn new(socket: Socket,
+           remote_addr: SocketAddr,
+           request: Message,
+           params: TransmissionParameters,
+           separate_timeout: Duration) -> Transaction {
+        let registration = socket.register(remote_addr, request.token.clone());
+
+        Self::with_registration(socket, remote_addr, request, params, separate_timeout, registration)
+    }
+
+    /// Send `request` using an existing registration for its token.
+    fn with_registration(socket: Socket,
+                         remote_addr: SocketAddr,
+                         request: Message,
+                         params: TransmissionParameters,
+                         separate_timeout: Duration,
+                         registration: Registration) -> Transaction {
+        Transaction {
+            socket: socket,
+            remote_addr: remote_addr,
+            request: request,
+            timeout: initial_timeout(&params),
+            params: params,
+            separate_timeout: separate_timeout,
+            registration: registration,
+            attempts: 0,
+            timer: None,
+            acknowledged: false,
+            #[cfg(feature = "oscore")]
+            oscore: None,
+            #[cfg(feature = "oscore")]
+            exchange: None,
+        }
+    }
+
+    /// Protect the request before it's first sent, so that retransmissions
+    /// are of the same message.
+    #[cfg(feature = "oscore")]
+    fn protect(&mut self) -> Result<(), Error> {
+        if let Some(ref context) = self.oscore {
+            let (request, exchange) = context.protect_request(&self.request)?;
+
+            self.request = request;
+            self.exchange = Some(exchange);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "oscore"))]
+    fn protect(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    #[cfg(feature = "oscore")]
+    fn unprotect(&self, response: Message) -> Result<Message, Error> {
+        unprotect(self.exchange.as_ref(), response)
+    }
+
+    #[cfg(not(feature = "oscore"))]
+    fn unprotect(&self, response: Message) -> Result<Message, Error> {
+        Ok(response)
+    }
+
+    fn transmit(&mut self) {
+        self.attempts += 1;
+        self.socket.send(self.request.clone(), self.remote_addr);
+    }
+
+    fn retransmit(&mut self) {
+        self.timeout = back_off(self.timeout);
+        if let Some(ref mut timer) = self.timer {
+            timer.reset(Instant::now() + self.timeout);
+        }
+
+        debug!("retransmitting request, attempt {}", self.attempts + 1);
+        self.transmit();
+    }
+
+    /// Reject a message that doesn't belong to this exchange.
+    ///
+    /// Confirmable and non-confirmable messages are answered with a reset,
+    /// stray acknowledgements and resets are silently ignored.
+    /// RFC 7252: 4.2 & 4.3
+    fn reject(&mut self, msg: Message, addr: SocketAddr) {
+        match msg.mtype {
+            Mtype::Confirmable | Mtype::NonConfirmable => {
+                warn!("rejecting unexpected message from {}: {:?}", addr, msg);
+                self.socket.send(msg.new_reset(), addr);
+            }
+            Mtype::Acknowledgement | Mtype::Reset => {
+                debug!("ignoring unexpected message from {}: {:?}", addr, msg);
+            }
+        }
+    }
+}
+
+impl Future for Transaction {
+    type Item = (Message, u32);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.socket.spawn();
+
+        if self.timer.is_none() {
+            if let Some(ref mut admitted) = self.registration.admitted {
+                match admitted.poll() {
+                    Ok(Async::Ready(())) => (),
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse, "token already in use").into()),
+                }
+            }
+
+            self.registration.admitted = None;
+
+            let reliable = self.socket.is_reliable();
+
+            let wait = if reliable {
+                self.separate_timeout
+            } else if self.request.mtype == Mtype::Confirmable {
+                self.timeout
+            } else {
+                self.params.non_lifetime()
+            };
+
+            self.protect()?;
+            self.timer = Some(Delay::new(Instant::now() + wait));
+            self.transmit();
+
+            if reliable {
+                self.acknowledged = true;
+                self.registration.acknowledged();
+            }
+        }
+
+        while let Async::Ready(incoming) = self.registration.incoming.poll().expect("receivers never fail") {
+            let (msg, addr) = match incoming {
+                Some(incoming) => incoming,
+                None => return Err(closed(&self.socket)),
+            };
+
+            // RFC 7252: 5.3.2.  Request/Response Matching Rules
+            match msg.mtype {
+                Mtype::Acknowledgement | Mtype::Reset if msg.mid != self.request.mid => {
+                    self.reject(msg, addr);
+                    continue;
+                }
+                Mtype::Reset => {
+                    return Err(Error::Reset);
+                }
+                Mtype::Acknowledgement => {
+                    self.acknowledged = true;
+
+                    if msg.code == Code::Empty {
+                        debug!("request acknowledged, waiting for separate response");
+                        self.registration.acknowledged();
+
+                        if let Some(ref mut timer) = self.timer {
+                            timer.reset(Instant::now() + self.separate_timeout);
+                        }
+
+                        continue;
+                    }
+                }
+                Mtype::Confirmable | Mtype::NonConfirmable => (),
+            }
+
+            if msg.code.is_response() && msg.token == self.request.token {
+                if msg.mtype == Mtype::Confirmable {
+                    self.socket.send(msg.new_ack(), addr);
+                }
+
+                info!("response received after {} attempt(s)", self.attempts);
+                return Ok(Async::Ready((self.unprotect(msg)?, self.attempts)));
+            }
+
+            self.reject(msg, addr);
+        }
+
+        loop {
+            match self.timer.as_mut().map(Delay::poll) {
+                Some(Ok(Async::Ready(()))) => (),
+                Some(Ok(Async::NotReady)) | None => break,
+                Some(Err(e)) => return Err(timer_error(e)),
+            }
+
+            let reliable = self.request.mtype == Mtype::Confirmable;
+
+            if !reliable || self.acknowledged || self.attempts > self.params.max_retransmit {
+                return Err(Error::Timeout);
+            }
+
+            self.retransmit();
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+impl Transaction {
+    /// Give up the registration for the request's token, so that it can be
+    /// used to receive further responses.
+    fn into_registration(self) -> Registration {
+        self.registration
+    }
+}
+
+/// Verify a response to a request protected as `exchange`, if it was.
+///
+/// The server can't protect an error response when it couldn't verify the
+/// request, so those are passed on as they are.
+/// RFC 8613: 8.2.  Verifying the Request
+#[cfg(feature = "oscore")]
+fn unprotect(exchange: StdOption<&Exchange>, response: Message) -> Result<Message, Error> {
+    let exchange = match exchange {
+        Some(exchange) => exchange,
+        None => return Ok(response),
+    };
+
+    match exchange.unprotect_response(&response) {
+        Err(Error::Oscore(OscoreError::Unprotected)) if response.code.is_error() => Ok(response),
+        result => result,
+    }
+}
+
+/// Why a request's messages stopped coming: its socket, or its connection,
+/// has closed.
+fn closed(socket: &Socket) -> Error {
+    if socket.is_reliable() {
+        io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed").into()
+    } else {
+        io::Error::new(io::ErrorKind::UnexpectedEof, "socket closed").into()
+    }
+}
+
+
+
+// This doesn't quite work, but leaving it here in case I want to fix & use it
+// in the future.
+#[allow(unused_macros)]
+macro_rules! set_or_with {
+    // Opaque Type Options
+    ($fn:ident($params:tt) {$body: block}) => {
+        pub fn set_$fn($params) {
+            $body
+        }
+
+        pub fn with_$fn(mut self, $params) -> Self {
+            set_$fn(&mut self, $params);
+
+            self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decompose, is_fresh, random_token, Client, Context, MessageIds, Upload};
+    use endpoint::Endpoint;
+    use error::{BlockError, Error, UrlError};
+    use link_format::Link;
+    use router::Router;
+    use server::Server;
+    use socket::Socket;
+    use testing::{recv_message, spawn_server};
+    use message::{Message, Mtype, Code};
+    use message::option::{Option, Options, UriHost, UriPath, UriQuery, ContentFormat, MaxAge, Observe, Block, Block1, Block2, Size1};
+    use transmission::TransmissionParameters;
+
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    use futures::{Future, Stream};
+    use tokio::runtime::Runtime;
+    use url::Url;
+
+    #[test]
+    fn uri_decompose_normalization() {
+        let uri1 = Url::parse("coap://example.com:5683/~sensors/temp.xml").unwrap();
+        let uri2 = Url::parse("coap://EXAMPLE.com/%7Esensors/temp.xml").unwrap();
+        let uri3 = Url::parse("coap://EXAMPLE.com:/%7esensors/temp.xml").unwrap();
+
+        assert_eq!(decompose(&uri1).unwrap(), decompose(&uri2).unwrap());
+        assert_eq!(decompose(&uri2).unwrap(), decompose(&uri3).unwrap());
+    }
+
+    #[test]
+    fn uri_decompose_basic_ipv6() {
+        let uri = Url::parse("coap://[2001:db8::2:1]/").unwrap();
+
+        let sa_ref = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 2, 1)), 5683);
+        let opt_ref = Options::new();
+
+        let (endpoint, options) = decompose(&uri).unwrap();
+
+        assert_eq!(endpoint, Endpoint::Resolved(sa_ref));
+        assert_eq!(options, opt_ref);
+    }
+
+    #[test]
+    #[cfg(feature = "dtls")]
+    fn uri_decompose_coaps() {
+        let uri = Url::parse("coaps://[2001:db8::2:1]/").unwrap();
+
+        let sa_ref = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 2, 1)), 5684);
+
+        let (endpoint, _) = decompose(&uri).unwrap();
+
+        assert_eq!(super::scheme(&uri).unwrap(), ::endpoint::Scheme::Coaps);
+        assert_eq!(endpoint, Endpoint::Resolved(sa_ref));
+    }
+
+    #[test]
+    fn uri_decompose_coap_tcp() {
+        let uri = Url::parse("coap+tcp://[2001:db8::2:1]/").unwrap();
+
+        let sa_ref = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 2, 1)), 5683);
+
+        let (endpoint, _) = decompose(&uri).unwrap();
+
+        assert_eq!(super::scheme(&uri).unwrap(), ::endpoint::Scheme::CoapTcp);
+        assert_eq!(endpoint, Endpoint::Resolved(sa_ref));
+    }
+
+    #[test]
+    #[cfg(feature = "dtls")]
+    fn uri_decompose_coaps_tcp() {
+        let uri = Url::parse("coaps+tcp://198.51.100.1/").unwrap();
+
+        let sa_ref = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)), 5684);
+
+        let (endpoint, _) = decompose(&uri).unwrap();
+
+        assert_eq!(super::scheme(&uri).unwrap(), ::endpoint::Scheme::CoapsTcp);
+        assert_eq!(endpoint, Endpoint::Resolved(sa_ref));
+    }
+
+    #[test]
+    fn uri_decompose_unsupported_scheme() {
+        let uri = Url::parse("http://example.net/").unwrap();
+
+        match decompose(&uri) {
+            Err(UrlError::UnsupportedScheme(ref scheme)) if scheme == "http" => (),
+            other => panic!("expected an unsupported scheme, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn uri_decompose_basic_example_net() {
+        let uri = Url::parse("coap://example.net/").unwrap();
+
+        let opt_ref = {
+            let mut opts = Options::new();
+            opts.push(UriHost::new("example.net".to_string()));
+            opts
+        };
+
+        let (endpoint, options) = decompose(&uri).unwrap();
+
+        assert_eq!(endpoint, Endpoint::Unresolved("example.net".to_string(), 5683));
+        assert_eq!(options, opt_ref);
+    }
+
+    #[test]
+    fn uri_decompose_example_net_well_known_core() {
+        let uri = Url::parse("coap://example.net/.well-known/core").unwrap();
+
+        let opt_ref = {
+            let mut opts = Options::new();
+            opts.push(UriHost::new("example.net".to_string()));
+            opts.push(UriPath::new(".well-known".to_string()));
+            opts.push(UriPath::new("core".to_string()));
+            opts
+        };
+
+        let (endpoint, options) = decompose(&uri).unwrap();
+
+        assert_eq!(endpoint, Endpoint::Unresolved("example.net".to_string(), 5683));
+        assert_eq!(options, opt_ref);
+    }
+
+    #[test]
+    fn uri_decompose_punny_unicode() {
+        let uri = Url::parse(
+            "coap://xn--18j4d.example/%E3%81%93%E3%82%93%E3%81%AB%E3%81%A1%E3%81%AF"
+        ).unwrap();
+
+        let opt_ref = {
+            let mut opts = Options::new();
+            opts.push(UriHost::new("xn--18j4d.example".to_string()));
+            opts.push(UriPath::new("こんにちは".to_string()));
+            opts
+        };
+
+        let (endpoint, options) = decompose(&uri).unwrap();
+
+        assert_eq!(endpoint, Endpoint::Unresolved("xn--18j4d.example".to_string(), 5683));
+        assert_eq!(options, opt_ref);
+    }
+
+    #[test]
+    fn uri_decompose_port_evil() {
+        let uri = Url::parse("coap://198.51.100.1:61616//%2F//?%2F%2F&?%26").unwrap();
+
+        let sa_ref = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)), 61616);
+        let opt_ref = {
             let mut opts = Options::new();
             opts.push(UriPath::new("".to_string()));
             opts.push(UriPath::new("/".to_string()));
@@ -307,4 +1696,666 @@ mod tests {
         assert_eq!(endpoint, Endpoint::Resolved(sa_ref));
         assert_eq!(options, opt_ref);
     }
+
+    fn test_params() -> TransmissionParameters {
+        TransmissionParameters::new().with_ack_timeout(Duration::from_millis(50))
+    }
+
+    #[test]
+    fn retransmits_until_acknowledged() {
+        let server_addr = spawn_server(|server| {
+            // drop the first transmission on the floor
+            recv_message(&server);
+
+            let (request, addr) = recv_message(&server);
+            let reply = request.new_reply().with_code(Code::Content);
+
+            server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+        });
+
+        let request = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params())
+            .send_with_attempts();
+
+        let (response, attempts) = Runtime::new().unwrap().block_on(request).unwrap();
+
+        assert_eq!(response.mtype, Mtype::Acknowledgement);
+        assert_eq!(response.code, Code::Content);
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn error_responses_are_returned() {
+        let server_addr = spawn_server(|server| {
+            let (request, addr) = recv_message(&server);
+            let reply = request.new_reply().with_code(Code::NotFound);
+
+            server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+        });
+
+        let request = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params())
+            .send();
+
+        let response = Runtime::new().unwrap().block_on(request).unwrap();
+
+        assert_eq!(response.code, Code::NotFound);
+
+        match response.error_for_code() {
+            Err(Error::Response(msg)) => assert_eq!(msg.code, Code::NotFound),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mismatched_token_is_reset() {
+        let server_addr = spawn_server(|server| {
+            let (request, addr) = recv_message(&server);
+
+            let stray = Message::new()
+                .with_mtype(Mtype::Confirmable)
+                .with_code(Code::Content)
+                .with_mid(request.mid.wrapping_add(1))
+                .with_token(&[0xde, 0xad]);
+            server.send_to(&stray.to_bytes().unwrap(), addr).unwrap();
+
+            let (reset, _) = recv_message(&server);
+            assert_eq!(reset.mtype, Mtype::Reset);
+            assert_eq!(reset.mid, stray.mid);
+
+            let reply = request.new_reply().with_code(Code::Content);
+            server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+        });
+
+        let request = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params().with_max_retransmit(0))
+            .send();
+
+        let response = Runtime::new().unwrap().block_on(request).unwrap();
+
+        assert_eq!(response.mtype, Mtype::Acknowledgement);
+    }
+
+    #[test]
+    fn foreign_source_is_not_accepted() {
+        let server_addr = spawn_server(|server| {
+            let (request, addr) = recv_message(&server);
+
+            let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let spoofed = request.new_reply().with_code(Code::Deleted);
+            stranger.send_to(&spoofed.to_bytes().unwrap(), addr).unwrap();
+
+            let reply = request.new_reply().with_code(Code::Content);
+            server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+        });
+
+        let request = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params())
+            .send();
+
+        let response = Runtime::new().unwrap().block_on(request).unwrap();
+
+        assert_eq!(response.code, Code::Content);
+    }
+
+    #[test]
+    fn message_ids_increment_and_wrap() {
+        let mids = MessageIds::new();
+        let shared = mids.clone();
+
+        let first = mids.next();
+
+        assert_eq!(shared.next(), first.wrapping_add(1));
+        assert_eq!(mids.next(), first.wrapping_add(2));
+
+        for _ in 0..0x10000 {
+            mids.next();
+        }
+
+        assert_eq!(mids.next(), first.wrapping_add(3));
+    }
+
+    #[test]
+    fn random_tokens() {
+        assert_eq!(random_token(0).len(), 0);
+        assert_eq!(random_token(4).len(), 4);
+        assert_ne!(random_token(8), random_token(8));
+    }
+
+    #[test]
+    fn requests_carry_token_and_message_id() {
+        let server_addr = spawn_server(|server| {
+            let (request, addr) = recv_message(&server);
+            assert_eq!(request.token.len(), 4);
+            assert_eq!(request.mid, 0x1234);
+
+            let reply = request.new_reply().with_code(Code::Content);
+            server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+        });
+
+        let mids = MessageIds::new();
+        while mids.next() != 0x1233 {}
+
+        let request = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params())
+            .with_message_ids(mids)
+            .with_token_length(4)
+            .send();
+
+        let response = Runtime::new().unwrap().block_on(request).unwrap();
+
+        assert_eq!(response.code, Code::Content);
+    }
+
+    #[test]
+    fn method_constructors() {
+        let client = Client::fetch("coap://198.51.100.1/sensors").unwrap()
+            .with_payload(b"{}".to_vec())
+            .with_content_format(50)
+            .with_content_format(60);
+
+        assert_eq!(client.msg.code, Code::Fetch);
+        assert_eq!(client.msg.payload, b"{}");
+        assert_eq!(client.msg.options.get::<UriPath>(), Some(vec![UriPath::new("sensors".to_string())]));
+        assert_eq!(client.msg.options.get::<ContentFormat>(), Some(vec![ContentFormat::new(60)]));
+
+        assert_eq!(Client::post("coap://198.51.100.1/").unwrap().msg.code, Code::Post);
+        assert_eq!(Client::put("coap://198.51.100.1/").unwrap().msg.code, Code::Put);
+        assert_eq!(Client::delete("coap://198.51.100.1/").unwrap().msg.code, Code::Delete);
+        assert_eq!(Client::patch("coap://198.51.100.1/").unwrap().msg.code, Code::Patch);
+        assert_eq!(Client::ipatch("coap://198.51.100.1/").unwrap().msg.code, Code::IPatch);
+    }
+
+    #[test]
+    fn separate_response_is_acknowledged() {
+        let server_addr = spawn_server(|server| {
+            let (request, addr) = recv_message(&server);
+            server.send_to(&request.new_ack().to_bytes().unwrap(), addr).unwrap();
+
+            // outlast the retransmission timeout, nothing should be resent
+            server.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            let mut buf = [0; 1152];
+            assert!(server.recv_from(&mut buf).is_err());
+            server.set_read_timeout(None).unwrap();
+
+            let response = Message::new()
+                .with_mtype(Mtype::Confirmable)
+                .with_code(Code::Content)
+                .with_mid(0x4242)
+                .with_token(&request.token);
+            server.send_to(&response.to_bytes().unwrap(), addr).unwrap();
+
+            let (ack, _) = recv_message(&server);
+            assert_eq!(ack.mtype, Mtype::Acknowledgement);
+            assert_eq!(ack.code, Code::Empty);
+            assert_eq!(ack.mid, 0x4242);
+        });
+
+        let request = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params())
+            .with_separate_timeout(Duration::from_secs(5))
+            .send_with_attempts();
+
+        let (response, attempts) = Runtime::new().unwrap().block_on(request).unwrap();
+
+        assert_eq!(response.mtype, Mtype::Confirmable);
+        assert_eq!(response.code, Code::Content);
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn duplicate_separate_response_is_acknowledged_again() {
+        let (acks_tx, acks_rx) = mpsc::channel();
+
+        let server_addr = spawn_server(move |server| {
+            let (request, addr) = recv_message(&server);
+            server.send_to(&request.new_ack().to_bytes().unwrap(), addr).unwrap();
+
+            let response = Message::new()
+                .with_mtype(Mtype::Confirmable)
+                .with_code(Code::Content)
+                .with_mid(0x4242)
+                .with_token(&request.token);
+
+            // the first acknowledgement is taken to be lost
+            for _ in 0..2 {
+                server.send_to(&response.to_bytes().unwrap(), addr).unwrap();
+
+                let (reply, _) = recv_message(&server);
+                acks_tx.send((reply.mtype, reply.mid)).unwrap();
+            }
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let socket = Socket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let request = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_socket(socket.clone())
+            .with_parameters(test_params())
+            .send();
+        assert_eq!(runtime.block_on(request).unwrap().code, Code::Content);
+
+        // the request is done, but the socket still acknowledges the response
+        for _ in 0..2 {
+            let ack = acks_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(ack, (Mtype::Acknowledgement, 0x4242));
+        }
+    }
+
+    #[test]
+    fn separate_response_timeout() {
+        let server_addr = spawn_server(|server| {
+            let (request, addr) = recv_message(&server);
+            server.send_to(&request.new_ack().to_bytes().unwrap(), addr).unwrap();
+        });
+
+        let request = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params())
+            .with_separate_timeout(Duration::from_millis(100))
+            .send();
+
+        match Runtime::new().unwrap().block_on(request) {
+            Err(Error::Timeout) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn non_confirmable_request() {
+        let server_addr = spawn_server(|server| {
+            let (request, addr) = recv_message(&server);
+            assert_eq!(request.mtype, Mtype::NonConfirmable);
+
+            // well past the retransmission timeout, nothing should be resent
+            server.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            let mut buf = [0; 1152];
+            assert!(server.recv_from(&mut buf).is_err());
+
+            let response = Message::new()
+                .with_mtype(Mtype::NonConfirmable)
+                .with_code(Code::Content)
+                .with_mid(0x4242)
+                .with_token(&request.token);
+            server.send_to(&response.to_bytes().unwrap(), addr).unwrap();
+        });
+
+        let request = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params())
+            .non_confirmable()
+            .send_with_attempts();
+
+        let (response, attempts) = Runtime::new().unwrap().block_on(request).unwrap();
+
+        assert_eq!(response.mtype, Mtype::NonConfirmable);
+        assert_eq!(response.code, Code::Content);
+        assert_eq!(attempts, 1);
+    }
+
+    /// Serve `body` block-wise with 16 byte blocks until the last block has
+    /// been requested.
+    fn serve_blocks(server: UdpSocket, body: &'static [u8]) {
+        loop {
+            let (request, addr) = recv_message(&server);
+
+            let num = request.options.get::<Block2>().map_or(0, |b| b[0].value.num) as usize;
+            let chunk = &body[num * 16..body.len().min((num + 1) * 16)];
+            let more = (num + 1) * 16 < body.len();
+
+            let reply = request.new_reply()
+                .with_code(Code::Content)
+                .with_option(Block2::new(Block::new(num as u32, more, 0)))
+                .with_payload(chunk.to_vec());
+            server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+
+            if !more {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn block_wise_download() {
+        const BODY: &[u8] = b"0123456789abcdef0123456789ABCDEF01234567";
+
+        let server_addr = spawn_server(|server| serve_blocks(server, BODY));
+
+        let request = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params())
+            .send_with_attempts();
+
+        let (response, attempts) = Runtime::new().unwrap().block_on(request).unwrap();
+
+        assert_eq!(response.payload, BODY);
+        assert_eq!(response.options.get::<Block2>(), None);
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn block_wise_download_size_limit() {
+        const BODY: &[u8] = b"0123456789abcdef0123456789ABCDEF01234567";
+
+        let server_addr = spawn_server(|server| serve_blocks(server, BODY));
+
+        let request = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params())
+            .with_max_body_size(32)
+            .send();
+
+        match Runtime::new().unwrap().block_on(request) {
+            Err(Error::Block(BlockError::TooLarge)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn block_wise_upload() {
+        const BODY: &[u8] = b"0123456789abcdef0123456789ABCDEF0123456789abcdef01234567";
+
+        let server_addr = spawn_server(|server| {
+            let mut body = Vec::new();
+
+            loop {
+                let (request, addr) = recv_message(&server);
+
+                let block = match request.options.get::<Block1>() {
+                    Some(block) => block[0].value,
+                    None => {
+                        // only 16 byte blocks will do
+                        let reply = request.new_reply()
+                            .with_code(Code::RequestEntityTooLarge)
+                            .with_option(Block1::new(Block::new(0, false, 0)))
+                            .with_option(Size1::new(1024));
+                        server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+                        continue;
+                    }
+                };
+
+                if block.num == 0 {
+                    assert_eq!(request.options.get::<Size1>(), Some(vec![Size1::new(BODY.len() as u64)]));
+                }
+
+                assert_eq!(block.offset(), body.len());
+                body.extend(request.payload.iter().cloned());
+
+                let code = if block.more { Code::Continue } else { Code::Changed };
+                let reply = request.new_reply()
+                    .with_code(code)
+                    .with_option(Block1::new(block));
+                server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+
+                if !block.more {
+                    assert_eq!(body, BODY);
+                    break;
+                }
+            }
+        });
+
+        let request = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params())
+            .with_payload(BODY.to_vec())
+            .with_block_size(64)
+            .send_with_attempts();
+
+        let (response, attempts) = Runtime::new().unwrap().block_on(request).unwrap();
+
+        assert_eq!(response.code, Code::Changed);
+        assert_eq!(attempts, 5);
+    }
+
+    #[test]
+    fn upload_larger_than_size1_is_not_retried() {
+        let server_addr = spawn_server(|server| {
+            let (request, addr) = recv_message(&server);
+
+            // no block size will get 32 bytes through
+            let reply = request.new_reply()
+                .with_code(Code::RequestEntityTooLarge)
+                .with_option(Block1::new(Block::new(0, false, 0)))
+                .with_option(Size1::new(16));
+            server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+        });
+
+        let request = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params())
+            .with_payload(vec![0; 32])
+            .with_block_size(64)
+            .send_with_attempts();
+
+        let (response, attempts) = Runtime::new().unwrap().block_on(request).unwrap();
+
+        assert_eq!(response.code, Code::RequestEntityTooLarge);
+        assert_eq!(response.options.get::<Size1>(), Some(vec![Size1::new(16)]));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn block_wise_upload_switches_to_smaller_blocks() {
+        const BODY: &[u8] = b"0123456789abcdef0123456789ABCDEF0123456789abcdef01234567";
+
+        let server_addr = spawn_server(|server| {
+            let mut body = Vec::new();
+
+            loop {
+                let (request, addr) = recv_message(&server);
+                let block = request.options.get::<Block1>().unwrap()[0].value;
+
+                assert_eq!(block.offset(), body.len());
+                body.extend(request.payload.iter().cloned());
+
+                // ask for 16 byte blocks after the first
+                let code = if block.more { Code::Continue } else { Code::Changed };
+                let reply = request.new_reply()
+                    .with_code(code)
+                    .with_option(Block1::new(Block::new(block.num, block.more, 0)));
+                server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+
+                if !block.more {
+                    assert_eq!(body, BODY);
+                    break;
+                }
+            }
+        });
+
+        let request = Client::post("coap://127.0.0.1/").unwrap()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params())
+            .with_payload(BODY.to_vec())
+            .with_block_size(32)
+            .send_with_attempts();
+
+        let (response, attempts) = Runtime::new().unwrap().block_on(request).unwrap();
+
+        assert_eq!(response.code, Code::Changed);
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn upload_with_too_many_blocks_fails() {
+        let socket = Socket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let context = Context {
+            socket: socket.clone(),
+            remote_addr: socket.local_addr(),
+            params: test_params(),
+            mids: MessageIds::new(),
+            token_length: 4,
+            separate_timeout: Duration::from_secs(1),
+            #[cfg(feature = "oscore")]
+            oscore: None,
+        };
+
+        // a body of just over 16 MiB in the 16 byte blocks a server asked for
+        let request = Message::new().with_code(Code::Post).with_payload(vec![0; (1 << 24) + 1]);
+        let mut upload = Upload::new(context, request, 0);
+        upload.num = (1 << 20) - 1;
+
+        match upload.next_block(Block::new((1 << 20) - 1, true, 0)) {
+            Err(Error::Block(BlockError::TooLarge)) => (),
+            other => panic!("expected the body to be too large, got {:?}", other),
+        }
+    }
+
+    fn notification(request: &Message, mtype: Mtype, mid: u16, sequence: u64) -> Message {
+        Message::new()
+            .with_mtype(mtype)
+            .with_code(Code::Content)
+            .with_mid(mid)
+            .with_token(&request.token)
+            .with_option(Observe::new(sequence))
+            .with_payload(sequence.to_string().into_bytes())
+    }
+
+    #[test]
+    fn observe_notifications() {
+        let (deregistered_tx, deregistered) = mpsc::channel();
+
+        let server_addr = spawn_server(move |server| {
+            let (request, addr) = recv_message(&server);
+            assert_eq!(request.options.get::<Observe>().unwrap()[0].value, 0);
+
+            let reply = request.new_reply()
+                .with_code(Code::Content)
+                .with_option(Observe::new(5))
+                .with_payload(b"5".to_vec());
+            server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+
+            let con = notification(&request, Mtype::Confirmable, 0x100, 7);
+            server.send_to(&con.to_bytes().unwrap(), addr).unwrap();
+
+            let (ack, _) = recv_message(&server);
+            assert_eq!(ack.mtype, Mtype::Acknowledgement);
+            assert_eq!(ack.mid, 0x100);
+
+            // older than the last one, should be dropped
+            let stale = notification(&request, Mtype::NonConfirmable, 0x101, 6);
+            server.send_to(&stale.to_bytes().unwrap(), addr).unwrap();
+
+            let fresh = notification(&request, Mtype::NonConfirmable, 0x102, 8);
+            server.send_to(&fresh.to_bytes().unwrap(), addr).unwrap();
+
+            let (deregistration, _) = recv_message(&server);
+            assert_eq!(deregistration.token, request.token);
+            deregistered_tx.send(deregistration).unwrap();
+        });
+
+        let notifications = Client::get("coap://127.0.0.1/obs").unwrap()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params())
+            .observe()
+            .take(3)
+            .collect();
+
+        let mut runtime = Runtime::new().unwrap();
+        let notifications = runtime.block_on(notifications).unwrap();
+
+        let payloads: Vec<_> = notifications.into_iter().map(|n| n.payload).collect();
+        assert_eq!(payloads, vec![b"5".to_vec(), b"7".to_vec(), b"8".to_vec()]);
+
+        let deregistration = deregistered.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(deregistration.code, Code::Get);
+        assert_eq!(deregistration.options.get::<Observe>().unwrap()[0].value, 1);
+        assert_eq!(deregistration.options.get::<UriPath>().unwrap()[0].value, "obs");
+    }
+
+    #[test]
+    fn observe_reregisters_when_stale() {
+        let server_addr = spawn_server(|server| {
+            let (request, addr) = recv_message(&server);
+
+            let reply = request.new_reply()
+                .with_code(Code::Content)
+                .with_option(Observe::new(1))
+                .with_option(MaxAge::new(1));
+            server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+
+            let (reregistration, addr) = recv_message(&server);
+            assert_eq!(reregistration.token, request.token);
+            assert_ne!(reregistration.mid, request.mid);
+            assert_eq!(reregistration.options.get::<Observe>().unwrap()[0].value, 0);
+
+            let reply = reregistration.new_reply()
+                .with_code(Code::Content)
+                .with_option(Observe::new(2));
+            server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+        });
+
+        let start = Instant::now();
+        let notifications = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params())
+            .observe()
+            .take(2)
+            .collect();
+
+        let notifications = Runtime::new().unwrap().block_on(notifications).unwrap();
+
+        assert_eq!(notifications.len(), 2);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn observe_unsupported_ends_stream() {
+        let server_addr = spawn_server(|server| {
+            let (request, addr) = recv_message(&server);
+            let reply = request.new_reply().with_code(Code::Content);
+            server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+        });
+
+        let notifications = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_parameters(test_params())
+            .observe()
+            .collect();
+
+        let notifications = Runtime::new().unwrap().block_on(notifications).unwrap();
+
+        assert_eq!(notifications.len(), 1);
+    }
+
+    #[test]
+    fn notification_freshness() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(1);
+
+        assert!(is_fresh(5, now, 6, later));
+        assert!(!is_fresh(6, now, 5, later));
+        // wrapped around
+        assert!(is_fresh((1 << 24) - 1, now, 0, later));
+        assert!(!is_fresh(0, now, (1 << 24) - 1, later));
+        // anything goes after 128 seconds
+        assert!(is_fresh(6, now, 5, now + Duration::from_secs(129)));
+    }
+
+    #[test]
+    fn discover() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let content = |_| Ok::<_, Error>(Message::new().with_code(Code::Content));
+
+        let router = Router::new()
+            .with_route(Code::Get, "/sensors/{id}", content)
+            .with_link("/sensors/{id}", Link::new("/sensors/temp").with_rt("temperature-c").with_title("Outside, north"))
+            .with_link("/sensors/{id}", Link::new("/sensors/light").with_rt("light-lux"));
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let url = format!("coap://{}?rt=temperature*", server.local_addr());
+        runtime.spawn(server.serve(router).map_err(|e| panic!("{:?}", e)));
+
+        let links = runtime.block_on(Client::discover(&url)).unwrap();
+
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].target, "/sensors/temp");
+        assert_eq!(links[0].title(), Some("Outside, north"));
+    }
 }
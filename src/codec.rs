@@ -4,7 +4,11 @@ use tokio_io::codec::{Decoder,Encoder};
 use bytes::BytesMut;
 
 use error::Error;
-use message::Message;
+use message::{Message, Mtype, Error as MessageError};
+
+/// The largest message `CoapStreamCodec` decodes, which is advertised to
+/// peers as our Max-Message-Size.
+pub(crate) const MAX_MESSAGE_SIZE: usize = 64 * 1024;
 
 pub struct CoapCodec;
 
@@ -31,3 +35,171 @@ impl Decoder for CoapCodec {
         }
     }
 }
+
+/// Frames messages on a byte stream, for CoAP over TCP and TLS.
+///
+/// Messages have no type or message ID on a reliable transport. Those of the
+/// messages encoded are left out, and the messages decoded are
+/// non-confirmable with a message ID of 0.
+/// RFC 8323: 3.2.  Message Format
+pub struct CoapStreamCodec;
+
+/// The size of the extended length field for each value of the Len nibble
+/// that has one, and the offset its value is from the length.
+fn extended_length(nibble: u8) -> (usize, usize) {
+    match nibble {
+        13 => (1, 13),
+        14 => (2, 269),
+        15 => (4, 65805),
+        _ => (0, 0),
+    }
+}
+
+impl Encoder for CoapStreamCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn encode(&mut self, msg: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let bytes = msg.to_bytes()?;
+        // the options and payload follow the header and token as they do
+        // over UDP
+        let body = &bytes[4 + msg.token.len()..];
+
+        let nibble = match body.len() {
+            0...12 => body.len() as u8,
+            13...268 => 13,
+            269...65804 => 14,
+            _ => 15,
+        };
+        let (extended, offset) = extended_length(nibble);
+
+        dst.reserve(2 + extended + msg.token.len() + body.len());
+        dst.extend_from_slice(&[nibble << 4 | msg.token.len() as u8]);
+        let len = body.len() - offset;
+        for i in (0..extended).rev() {
+            dst.extend_from_slice(&[(len >> (i * 8)) as u8]);
+        }
+        dst.extend_from_slice(&[msg.code.as_u8()]);
+        dst.extend_from_slice(&msg.token);
+        dst.extend_from_slice(body);
+
+        Ok(())
+    }
+}
+
+impl Decoder for CoapStreamCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let first = match buf.first() {
+            Some(&first) => first,
+            None => return Ok(None),
+        };
+
+        let (extended, offset) = extended_length(first >> 4);
+        let token_length = (first & 0x0f) as usize;
+
+        if token_length > 8 {
+            return Err(MessageError::InvalidToken.into());
+        }
+
+        if buf.len() < 1 + extended {
+            return Ok(None);
+        }
+
+        let len = if extended == 0 {
+            (first >> 4) as usize
+        } else {
+            buf[1..1 + extended].iter().fold(0, |acc, &b| acc << 8 | b as usize) + offset
+        };
+
+        let header_length = 1 + extended + 1 + token_length;
+
+        if header_length + len > MAX_MESSAGE_SIZE {
+            return Err(MessageError::MessageFormat.into());
+        }
+
+        if buf.len() < header_length + len {
+            buf.reserve(header_length + len - buf.len());
+            return Ok(None);
+        }
+
+        let frame = buf.split_to(header_length + len);
+
+        // rebuild the message as it would be sent over UDP to parse it
+        let mut pkt = Vec::with_capacity(4 + token_length + len);
+        pkt.push(1 << 6 | Mtype::NonConfirmable.as_u8() << 4 | token_length as u8);
+        pkt.push(frame[1 + extended]);
+        pkt.extend_from_slice(&[0, 0]);
+        pkt.extend_from_slice(&frame[2 + extended..]);
+
+        Ok(Some(Message::from_bytes(&pkt)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CoapStreamCodec;
+    use message::{Message, Mtype, Code};
+    use message::option::{Option, UriPath};
+
+    use bytes::BytesMut;
+    use tokio_io::codec::{Decoder, Encoder};
+
+    fn encoded(msg: Message) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        CoapStreamCodec.encode(msg, &mut buf).unwrap();
+
+        buf.to_vec()
+    }
+
+    #[test]
+    fn stream_framing() {
+        // an empty CSM
+        assert_eq!(encoded(Message::new().with_code(Code::Csm)), [0x00, 0xe1]);
+
+        let ping = Message::new().with_code(Code::Ping).with_token(&[0x42, 0x43]).with_mid(7);
+        assert_eq!(encoded(ping), [0x02, 0xe2, 0x42, 0x43]);
+
+        let get = Message::new().with_token(&[1]).with_option(UriPath::new("a".to_owned()));
+        assert_eq!(encoded(get), [0x21, 0x01, 0x01, 0xb1, b'a']);
+
+        // the length needs one, two and four extended bytes
+        for &(len, header) in &[(100, &[0xd0, 0x57][..]), (1000, &[0xe0, 0x02, 0xdb]), (70000, &[0xf0, 0x00, 0x00, 0x10, 0x63])] {
+            let msg = Message::new().with_code(Code::Content).with_payload(vec![7; len - 1]);
+            assert_eq!(&encoded(msg)[..header.len()], header);
+        }
+    }
+
+    #[test]
+    fn stream_decoding() {
+        let request = Message::new()
+            .with_token(&[1, 2, 3])
+            .with_option(UriPath::new("sensors".to_owned()))
+            .with_payload(vec![9; 300]);
+        let response = Message::new().with_code(Code::Content).with_payload(vec![1; 20]);
+
+        let mut stream = encoded(request.clone());
+        stream.extend(encoded(response.clone()));
+
+        // a message only comes out once all of it has arrived
+        let mut codec = CoapStreamCodec;
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for chunk in stream.chunks(50) {
+            buf.extend_from_slice(chunk);
+
+            while let Some(msg) = codec.decode(&mut buf).unwrap() {
+                decoded.push(msg);
+            }
+        }
+
+        assert!(buf.is_empty());
+        assert_eq!(decoded, [request.with_mtype(Mtype::NonConfirmable), response.with_mtype(Mtype::NonConfirmable)]);
+
+        // a token longer than 8 bytes, and a message larger than we accept
+        assert!(codec.decode(&mut BytesMut::from(&[0x09, 0x01][..])).is_err());
+        assert!(codec.decode(&mut BytesMut::from(&[0xf0, 0x7f, 0xff, 0xff, 0xff][..])).is_err());
+    }
+}
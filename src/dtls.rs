@@ -0,0 +1,1498 @@
+//! CoAP over DTLS 1.2, for `coaps://` URLs.
+//!
+//! Each peer gets its own DTLS session, set up the first time a message is
+//! sent to it, or when it starts a handshake with us. The records of every
+//! session go through the one UDP socket.
+//! RFC 7252: 9.  Securing CoAP
+//!
+//! The same credentials secure `coaps+tcp://` connections with TLS 1.2, see
+//! `Socket::tls`.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::os::raw::{c_int, c_void};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use foreign_types::ForeignTypeRef;
+use futures::prelude::*;
+use openssl::error::ErrorStack;
+use openssl::ex_data::Index;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private, Public};
+#[cfg(rpk)]
+use openssl::pkey::PKeyRef;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use openssl::ssl::{self, ErrorCode, Ssl, SslContext, SslRef, SslContextBuilder, SslMethod, SslOptions, SslStream, SslVerifyMode, SslVersion};
+use openssl::x509::{X509, X509StoreContextRef};
+use openssl_sys::SSL;
+#[cfg(rpk)]
+use openssl_sys::{EVP_PKEY, SSL_CTX, X509_STORE_CTX};
+use tokio::net::UdpSocket;
+use tokio::timer::Delay;
+
+use error::Error;
+use message::Message;
+
+/// The cipher suite every CoAP implementation using pre-shared keys supports.
+/// RFC 7252: 9.1.3.1.  Pre-Shared Keys
+const PSK_CIPHERS: &str = "PSK-AES128-CCM8";
+
+/// The cipher suite and curve every CoAP implementation using raw public keys
+/// or certificates supports.
+/// RFC 7252: 9.1.3.2.  Raw Public Key Certificates
+const ECDSA_CIPHERS: &str = "ECDHE-ECDSA-AES128-CCM8";
+const CURVES: &str = "P-256";
+
+/// The largest datagram sent, leaving room for IP and UDP headers within a
+/// typical path MTU.
+const MTU: u32 = 1400;
+
+/// How often a stalled handshake is checked on, so that OpenSSL can resend
+/// its last flight.
+/// RFC 6347: 4.2.4.  Timeout and Retransmission
+const HANDSHAKE_POLL: Duration = Duration::from_millis(250);
+
+/// How long a handshake may take before it's given up on, so that sessions
+/// left half-open don't pile up.
+/// RFC 6347: 4.2.4.  Timeout and Retransmission
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many handshakes may be in progress at once, past which peers' new
+/// ones are turned away until some complete or time out.
+const MAX_HALF_OPEN: usize = 64;
+
+/// The certificate types of RFC 7250: 3.  Structure of the Raw Public Key
+/// Extension
+#[cfg(rpk)]
+const CERT_TYPE_X509: u8 = 0;
+#[cfg(rpk)]
+const CERT_TYPE_RPK: u8 = 2;
+
+// Not bound by openssl-sys, the client address is an opaque BIO_ADDR.
+extern "C" {
+    fn DTLSv1_listen(ssl: *mut SSL, client: *mut c_void) -> c_int;
+    fn BIO_ADDR_new() -> *mut c_void;
+    fn BIO_ADDR_free(addr: *mut c_void);
+}
+
+// Raw public keys, from OpenSSL 3.2 on.
+#[cfg(rpk)]
+extern "C" {
+    fn SSL_CTX_set1_client_cert_type(ctx: *mut SSL_CTX, types: *const u8, len: usize) -> c_int;
+    fn SSL_CTX_set1_server_cert_type(ctx: *mut SSL_CTX, types: *const u8, len: usize) -> c_int;
+    fn SSL_get0_peer_rpk(ssl: *const SSL) -> *mut EVP_PKEY;
+    fn X509_STORE_CTX_get0_rpk(ctx: *const X509_STORE_CTX) -> *mut EVP_PKEY;
+}
+
+/// Where the pre-shared key of each identity a peer may present is kept.
+pub trait PskStore: Send + Sync + 'static {
+    /// The key for `identity`, if it's known.
+    fn key(&self, identity: &[u8]) -> Option<Vec<u8>>;
+}
+
+impl PskStore for HashMap<Vec<u8>, Vec<u8>> {
+    fn key(&self, identity: &[u8]) -> Option<Vec<u8>> {
+        self.get(identity).cloned()
+    }
+}
+
+/// Who a peer authenticated as during the DTLS handshake.
+#[derive(Clone, Debug)]
+pub enum PeerIdentity {
+    /// The PSK identity of the key the session was set up with. A client
+    /// sees its own identity, the server having proved it knows the key.
+    Psk(Vec<u8>),
+    /// A certificate that chains up to one of the trusted roots.
+    Certificate(X509),
+    /// One of the pinned raw public keys, as a DER SubjectPublicKeyInfo.
+    RawPublicKey(Vec<u8>),
+}
+
+impl PeerIdentity {
+    /// The common name of the certificate's subject, for certificates.
+    pub fn common_name(&self) -> Option<String> {
+        match *self {
+            PeerIdentity::Certificate(ref cert) => cert.subject_name()
+                .entries_by_nid(Nid::COMMONNAME)
+                .next()
+                .and_then(|entry| entry.data().to_string().ok()),
+            _ => None,
+        }
+    }
+}
+
+/// Which of the protocols a `DtlsConfig` secures sessions are set up with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Protocol {
+    /// DTLS 1.2, for coaps://
+    Dtls,
+    /// TLS 1.2, for coaps+tcp://
+    /// RFC 8323: 9.  Securing CoAP
+    Tls,
+}
+
+/// Our own certificate or raw public key.
+#[derive(Clone)]
+enum Credential {
+    /// a certificate chain, leaf first, and the leaf's key
+    Certificate(Vec<X509>, PKey<Private>),
+    /// a P-256 key presented on its own
+    RawPublicKey(PKey<Private>),
+}
+
+/// The credentials a DTLS socket, or a TLS one, authenticates with, and
+/// authenticates its peers by.
+///
+/// A socket needs an identity, certificate or raw public key to start
+/// sessions with servers, and a key store, certificate or raw public key to
+/// accept sessions from clients. Certificates and raw public keys are used
+/// with TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8 on P-256, and peers presenting
+/// them are only accepted if they chain up to a trusted root or their key is
+/// pinned.
+///
+/// Raw public keys are negotiated as such, which needs OpenSSL 3.2. Built
+/// against an older one, making a socket with a raw public key or pinned
+/// keys fails with an `Unsupported` error.
+#[derive(Clone, Default)]
+pub struct DtlsConfig {
+    identity: Option<(Vec<u8>, Vec<u8>)>,
+    keys: Option<Arc<PskStore>>,
+    credential: Option<Credential>,
+    roots: Vec<X509>,
+    pinned: Vec<PKey<Public>>,
+}
+
+impl DtlsConfig {
+    pub fn new() -> DtlsConfig {
+        DtlsConfig::default()
+    }
+
+    /// Set the PSK identity and key presented to servers.
+    pub fn set_identity(&mut self, identity: &[u8], key: &[u8]) {
+        assert!(!identity.contains(&0), "PSK identities can't contain NUL bytes");
+
+        self.identity = Some((identity.to_vec(), key.to_vec()));
+    }
+
+    pub fn with_identity(mut self, identity: &[u8], key: &[u8]) -> Self {
+        self.set_identity(identity, key);
+        self
+    }
+
+    /// Set where the keys of the identities clients present are looked up.
+    pub fn set_keys<S: PskStore>(&mut self, keys: S) {
+        self.keys = Some(Arc::new(keys));
+    }
+
+    pub fn with_keys<S: PskStore>(mut self, keys: S) -> Self {
+        self.set_keys(keys);
+        self
+    }
+
+    /// Set the certificate chain presented to peers, leaf first, along with
+    /// the leaf's private key.
+    /// RFC 7252: 9.1.3.3.  X.509 Certificates
+    pub fn set_certificate(&mut self, chain: Vec<X509>, key: PKey<Private>) {
+        assert!(!chain.is_empty(), "a certificate chain needs at least one certificate");
+
+        self.credential = Some(Credential::Certificate(chain, key));
+    }
+
+    pub fn with_certificate(mut self, chain: Vec<X509>, key: PKey<Private>) -> Self {
+        self.set_certificate(chain, key);
+        self
+    }
+
+    /// Set the P-256 key presented to peers as a raw public key, instead of
+    /// a certificate.
+    /// RFC 7252: 9.1.3.2 & RFC 7250
+    pub fn set_raw_public_key(&mut self, key: PKey<Private>) {
+        let curve = key.ec_key().ok().and_then(|key| key.group().curve_name());
+        assert!(curve == Some(Nid::X9_62_PRIME256V1), "raw public keys must be on P-256");
+
+        self.credential = Some(Credential::RawPublicKey(key));
+    }
+
+    pub fn with_raw_public_key(mut self, key: PKey<Private>) -> Self {
+        self.set_raw_public_key(key);
+        self
+    }
+
+    /// Trust peers whose certificate chains up to `root`.
+    pub fn add_trusted_root(&mut self, root: X509) {
+        self.roots.push(root);
+    }
+
+    pub fn with_trusted_root(mut self, root: X509) -> Self {
+        self.add_trusted_root(root);
+        self
+    }
+
+    /// Trust peers presenting `key` as their raw public key.
+    pub fn add_pinned_key(&mut self, key: PKey<Public>) {
+        self.pinned.push(key);
+    }
+
+    pub fn with_pinned_key(mut self, key: PKey<Public>) -> Self {
+        self.add_pinned_key(key);
+        self
+    }
+
+    /// The PSK identity presented to servers, if there is one.
+    pub(crate) fn psk_identity(&self) -> Option<Vec<u8>> {
+        self.identity.as_ref().map(|&(ref identity, _)| identity.clone())
+    }
+
+    /// The raw public keys trusted, DER encoded.
+    pub(crate) fn pinned(&self) -> Result<Vec<Vec<u8>>, ErrorStack> {
+        self.pinned.iter().map(|key| key.public_key_to_der()).collect()
+    }
+
+    /// The contexts sessions we start and sessions peers start are set up
+    /// with, where we have the credentials for them.
+    pub(crate) fn contexts(&self, protocol: Protocol) -> io::Result<(Option<SslContext>, Option<SslContext>)> {
+        let raw_public_key = match self.credential {
+            Some(Credential::RawPublicKey(_)) => true,
+            _ => false,
+        };
+        if !cfg!(rpk) && (raw_public_key || !self.pinned.is_empty()) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "raw public keys need OpenSSL 3.2 or later"));
+        }
+
+        let pinned = self.pinned()?;
+        let verifies = !self.roots.is_empty() || !pinned.is_empty();
+
+        let client = if self.identity.is_some() || verifies {
+            let mut builder = self.context_builder(protocol, false, self.identity.is_some(), verifies, &pinned)?;
+
+            if let Some((ref identity, ref key)) = self.identity {
+                let (identity, key) = (identity.clone(), key.clone());
+
+                builder.set_psk_client_callback(move |_, _, identity_out, key_out| {
+                    if identity.len() >= identity_out.len() || key.len() > key_out.len() {
+                        return Ok(0);
+                    }
+
+                    identity_out[..identity.len()].copy_from_slice(&identity);
+                    identity_out[identity.len()] = 0;
+                    key_out[..key.len()].copy_from_slice(&key);
+
+                    Ok(key.len())
+                });
+            }
+
+            Some(builder.build())
+        } else {
+            None
+        };
+
+        let server = if self.keys.is_some() || self.credential.is_some() {
+            let mut builder = self.context_builder(protocol, true, self.keys.is_some(), self.credential.is_some(), &pinned)?;
+
+            if protocol == Protocol::Dtls {
+                enable_cookies(&mut builder)?;
+            }
+
+            if let Some(ref keys) = self.keys {
+                let keys = keys.clone();
+
+                builder.set_psk_server_callback(move |_, identity, key_out| {
+                    match identity.and_then(|identity| keys.key(identity)) {
+                        Some(ref key) if key.len() <= key_out.len() => {
+                            key_out[..key.len()].copy_from_slice(key);
+                            Ok(key.len())
+                        }
+                        // an unknown identity fails the handshake
+                        _ => Ok(0),
+                    }
+                });
+            }
+
+            Some(builder.build())
+        } else {
+            None
+        };
+
+        Ok((client, server))
+    }
+
+    fn context_builder(&self,
+                       protocol: Protocol,
+                       server: bool,
+                       psk: bool,
+                       ecdsa: bool,
+                       pinned: &[Vec<u8>]) -> Result<SslContextBuilder, ErrorStack> {
+        let (method, version) = match protocol {
+            Protocol::Dtls => (SslMethod::dtls(), SslVersion::DTLS1_2),
+            Protocol::Tls => (SslMethod::tls(), SslVersion::TLS1_2),
+        };
+
+        let mut builder = SslContextBuilder::new(method)?;
+
+        builder.set_min_proto_version(Some(version))?;
+        builder.set_max_proto_version(Some(version))?;
+
+        if protocol == Protocol::Dtls {
+            // the MTU is set on each session, there's no real socket to ask
+            builder.set_options(SslOptions::NO_QUERY_MTU);
+        }
+
+        let ciphers: Vec<_> = [(psk, PSK_CIPHERS), (ecdsa, ECDSA_CIPHERS)].iter()
+            .filter(|&&(enabled, _)| enabled)
+            .map(|&(_, ciphers)| ciphers)
+            .collect();
+        builder.set_cipher_list(&ciphers.join(":"))?;
+        // OpenSSL 3.2 moved the CCM_8 suites CoAP mandates to level 0
+        builder.set_security_level(0);
+
+        if !ecdsa {
+            return Ok(builder);
+        }
+
+        builder.set_groups_list(CURVES)?;
+
+        match self.credential {
+            Some(Credential::Certificate(ref chain, ref key)) => {
+                builder.set_certificate(&chain[0])?;
+                for cert in &chain[1..] {
+                    builder.add_extra_chain_cert(cert.clone())?;
+                }
+                builder.set_private_key(key)?;
+                builder.check_private_key()?;
+            }
+            // the public key is sent on its own, in place of a certificate
+            Some(Credential::RawPublicKey(ref key)) => builder.set_private_key(key)?,
+            None => (),
+        }
+
+        self.set_cert_types(&mut builder, server, !pinned.is_empty())?;
+
+        for root in &self.roots {
+            builder.cert_store_mut().add_cert(root.clone())?;
+        }
+
+        if !self.roots.is_empty() || !pinned.is_empty() {
+            let pinned = pinned.to_vec();
+
+            // there's nothing to chain a raw public key up to, it's trusted
+            // if it's pinned
+            builder.set_verify_callback(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT, move |verified, context| {
+                verified || presented_key(context).map_or(false, |key| pinned.contains(&key))
+            });
+        }
+
+        Ok(builder)
+    }
+
+    /// Offer to present a raw public key if that's our credential, and to
+    /// accept one from peers if `accepts`, certificates still being accepted
+    /// if there are trusted roots.
+    /// RFC 7250: 4.  Negotiation of the Raw Public Key
+    #[cfg(rpk)]
+    fn set_cert_types(&self, builder: &mut SslContextBuilder, server: bool, accepts: bool) -> Result<(), ErrorStack> {
+        type SetCertTypes = unsafe extern "C" fn(*mut SSL_CTX, *const u8, usize) -> c_int;
+
+        let (ours, theirs): (SetCertTypes, SetCertTypes) = if server {
+            (SSL_CTX_set1_server_cert_type, SSL_CTX_set1_client_cert_type)
+        } else {
+            (SSL_CTX_set1_client_cert_type, SSL_CTX_set1_server_cert_type)
+        };
+
+        let mut presented = Vec::new();
+        if let Some(Credential::RawPublicKey(_)) = self.credential {
+            presented.push(CERT_TYPE_RPK);
+        }
+
+        let mut accepted = Vec::new();
+        if accepts {
+            accepted.push(CERT_TYPE_RPK);
+            if !self.roots.is_empty() {
+                accepted.push(CERT_TYPE_X509);
+            }
+        }
+
+        for &(set, ref types) in &[(ours, presented), (theirs, accepted)] {
+            if !types.is_empty() && unsafe { set(builder.as_ptr(), types.as_ptr(), types.len()) } != 1 {
+                return Err(ErrorStack::get());
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(rpk))]
+    fn set_cert_types(&self, _: &mut SslContextBuilder, _: bool, _: bool) -> Result<(), ErrorStack> {
+        Ok(())
+    }
+}
+
+/// Configurations are equal if they have the same credentials and trust the
+/// same peers, so that they'd set up the same sessions.
+impl PartialEq for DtlsConfig {
+    fn eq(&self, other: &DtlsConfig) -> bool {
+        let keys = match (&self.keys, &other.keys) {
+            (&Some(ref a), &Some(ref b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+
+        let credential = match (&self.credential, &other.credential) {
+            (&Some(Credential::Certificate(ref a, ref a_key)), &Some(Credential::Certificate(ref b, ref b_key))) => {
+                a == b && a_key.public_eq(b_key)
+            }
+            (&Some(Credential::RawPublicKey(ref a)), &Some(Credential::RawPublicKey(ref b))) => a.public_eq(b),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+
+        let pinned = self.pinned.len() == other.pinned.len()
+            && self.pinned.iter().zip(&other.pinned).all(|(a, b)| a.public_eq(b));
+
+        self.identity == other.identity && keys && credential && self.roots == other.roots && pinned
+    }
+}
+
+/// Have clients prove they can receive at the address they send from before
+/// a session is set up for them, with a cookie bound to that address.
+/// RFC 6347: 4.2.1.  Denial-of-Service Countermeasures
+fn enable_cookies(builder: &mut SslContextBuilder) -> Result<(), ErrorStack> {
+    let mut secret = [0; 32];
+    rand_bytes(&mut secret)?;
+    let secret = PKey::hmac(&secret)?;
+    let verify_secret = secret.clone();
+
+    builder.set_options(SslOptions::COOKIE_EXCHANGE);
+
+    builder.set_cookie_generate_cb(move |ssl, cookie_out| {
+        let cookie = cookie(&secret, ssl)?;
+        cookie_out[..cookie.len()].copy_from_slice(&cookie);
+
+        Ok(cookie.len())
+    });
+
+    builder.set_cookie_verify_cb(move |ssl, cookie_in| {
+        match cookie(&verify_secret, ssl) {
+            Ok(ref cookie) => cookie.len() == cookie_in.len() && memcmp::eq(cookie, cookie_in),
+            Err(_) => false,
+        }
+    });
+
+    Ok(())
+}
+
+/// The cookie a session's peer is expected to return, a MAC of its address.
+fn cookie(secret: &PKey<Private>, ssl: &SslRef) -> Result<Vec<u8>, ErrorStack> {
+    let peer = ssl.ex_data(peer_index()).map(|peer| peer.to_string()).unwrap_or_default();
+
+    let mut signer = Signer::new(MessageDigest::sha256(), secret)?;
+    signer.update(peer.as_bytes())?;
+    signer.sign_to_vec()
+}
+
+/// Where a session keeps the address of its peer.
+fn peer_index() -> Index<Ssl, SocketAddr> {
+    static INDEX: OnceLock<Index<Ssl, SocketAddr>> = OnceLock::new();
+
+    *INDEX.get_or_init(|| Ssl::new_ex_index().expect("failed to allocate an SSL ex data index"))
+}
+
+/// The raw public key a peer presented in place of a certificate, DER
+/// encoded.
+#[cfg(rpk)]
+fn presented_key(context: &X509StoreContextRef) -> Option<Vec<u8>> {
+    unsafe {
+        let key = X509_STORE_CTX_get0_rpk(context.as_ptr());
+        if key.is_null() {
+            return None;
+        }
+
+        PKeyRef::<Public>::from_ptr(key).public_key_to_der().ok()
+    }
+}
+
+#[cfg(not(rpk))]
+fn presented_key(_: &X509StoreContextRef) -> Option<Vec<u8>> {
+    None
+}
+
+/// The raw public key the peer of an established session authenticated
+/// with, DER encoded.
+#[cfg(rpk)]
+fn peer_key(ssl: &SslRef) -> Option<Vec<u8>> {
+    unsafe {
+        let key = SSL_get0_peer_rpk(ssl.as_ptr());
+        if key.is_null() {
+            return None;
+        }
+
+        PKeyRef::<Public>::from_ptr(key).public_key_to_der().ok()
+    }
+}
+
+#[cfg(not(rpk))]
+fn peer_key(_: &SslRef) -> Option<Vec<u8>> {
+    None
+}
+
+/// The datagrams of a single session, between it and the UDP socket.
+#[derive(Default)]
+struct Datagrams {
+    incoming: VecDeque<Vec<u8>>,
+    outgoing: VecDeque<Vec<u8>>,
+}
+
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.incoming.pop_front() {
+            Some(datagram) => {
+                let len = datagram.len().min(buf.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                Ok(len)
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.push_back(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Session {
+    stream: SslStream<Datagrams>,
+    established: bool,
+    /// messages sent before the handshake completed
+    pending: Vec<Message>,
+    /// prods the handshake along while it's in progress
+    timer: Delay,
+    /// when the handshake started
+    started: Instant,
+}
+
+impl Session {
+    fn new(context: &SslContext, connect: bool, peer: SocketAddr) -> Result<Session, ErrorStack> {
+        let mut ssl = Ssl::new(context)?;
+        ssl.set_mtu(MTU)?;
+        ssl.set_ex_data(peer_index(), peer);
+
+        if connect {
+            ssl.set_connect_state();
+        } else {
+            ssl.set_accept_state();
+        }
+
+        Ok(Session {
+            stream: SslStream::new(ssl, Datagrams::default())?,
+            established: false,
+            pending: Vec::new(),
+            timer: Delay::new(Instant::now() + HANDSHAKE_POLL),
+            started: Instant::now(),
+        })
+    }
+
+    /// A session for `peer` if `datagram` is a ClientHello with a valid
+    /// cookie. Any other ClientHello is answered with a HelloVerifyRequest,
+    /// added to `outgoing`, and nothing of it is kept.
+    /// RFC 6347: 4.2.1.  Denial-of-Service Countermeasures
+    fn accept(context: &SslContext,
+              peer: SocketAddr,
+              datagram: Vec<u8>,
+              outgoing: &mut VecDeque<(Vec<u8>, SocketAddr)>)
+              -> Result<Option<Session>, ErrorStack> {
+        let mut session = Session::new(context, false, peer)?;
+        session.stream.get_mut().incoming.push_back(datagram);
+
+        let listened = unsafe {
+            let client = BIO_ADDR_new();
+            if client.is_null() {
+                return Err(ErrorStack::get());
+            }
+
+            let listened = DTLSv1_listen(session.stream.ssl().as_ptr(), client);
+            BIO_ADDR_free(client);
+            listened
+        };
+
+        outgoing.extend(session.stream.get_mut().outgoing.drain(..).map(|d| (d, peer)));
+
+        match listened {
+            1 => Ok(Some(session)),
+            0 => Ok(None),
+            _ => Err(ErrorStack::get()),
+        }
+    }
+
+    /// Make what progress can be made with the datagrams received so far,
+    /// adding the messages they carried to `incoming`.
+    fn drive(&mut self, peer: SocketAddr, incoming: &mut VecDeque<(Message, SocketAddr)>) -> Result<(), ssl::Error> {
+        if !self.established {
+            match self.stream.do_handshake() {
+                Ok(()) => {
+                    debug!("DTLS session with {} established", peer);
+                    self.established = true;
+
+                    for msg in self.pending.split_off(0) {
+                        self.send(msg)?;
+                    }
+                }
+                Err(ref e) if would_block(e) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut buf = [0; 2048];
+
+        loop {
+            match self.stream.ssl_read(&mut buf) {
+                Ok(len) => match Message::from_bytes(&buf[..len]) {
+                    Ok(msg) => incoming.push_back((msg, peer)),
+                    Err(e) => debug!("dropping undecodable message from {}: {:?}", peer, e),
+                },
+                Err(ref e) if would_block(e) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn send(&mut self, msg: Message) -> Result<(), ssl::Error> {
+        if !self.established {
+            self.pending.push(msg);
+            return Ok(());
+        }
+
+        match msg.to_bytes() {
+            Ok(bytes) => {
+                self.stream.ssl_write(&bytes)?;
+            }
+            Err(e) => error!("failed to encode message: {:?}", e),
+        }
+
+        Ok(())
+    }
+}
+
+/// The peers of the sessions still handshaking whose timer has fired.
+fn stalled(sessions: &mut HashMap<SocketAddr, Session>) -> Vec<SocketAddr> {
+    let mut stalled = Vec::new();
+
+    for (&peer, session) in sessions.iter_mut() {
+        if session.established {
+            continue;
+        }
+
+        while let Ok(Async::Ready(())) = session.timer.poll() {
+            session.timer.reset(Instant::now() + HANDSHAKE_POLL);
+            stalled.push(peer);
+        }
+    }
+
+    stalled
+}
+
+pub(crate) fn would_block(e: &ssl::Error) -> bool {
+    e.code() == ErrorCode::WANT_READ || e.code() == ErrorCode::WANT_WRITE
+}
+
+/// Whether `datagram` starts with a ClientHello in the first epoch, which
+/// means the peer may have lost its session and be starting a new one.
+/// RFC 6347: 4.2.8.  Establishing New Associations with Existing Parameters
+fn is_client_hello(datagram: &[u8]) -> bool {
+    // content type handshake, epoch 0, handshake type client_hello
+    datagram.len() > 13 && datagram[0] == 22 && datagram[3..5] == [0, 0] && datagram[13] == 1
+}
+
+/// Who each peer with an established session authenticated as.
+pub(crate) type Identities = Arc<Mutex<HashMap<SocketAddr, PeerIdentity>>>;
+
+/// Who the peer of an established session authenticated as, given the PSK
+/// identity we present and the keys we pin.
+pub(crate) fn peer_identity(ssl: &SslRef, psk_identity: &Option<Vec<u8>>, pinned: &[Vec<u8>]) -> Option<PeerIdentity> {
+    if let Some(key) = peer_key(ssl) {
+        return if pinned.contains(&key) {
+            Some(PeerIdentity::RawPublicKey(key))
+        } else {
+            None
+        };
+    }
+
+    if let Some(cert) = ssl.peer_certificate() {
+        return Some(PeerIdentity::Certificate(cert));
+    }
+
+    ssl.psk_identity()
+        .map(|identity| identity.to_vec())
+        .or_else(|| psk_identity.clone())
+        .map(PeerIdentity::Psk)
+}
+
+/// A stream and sink of messages, like a `UdpFramed<CoapCodec>`, that
+/// secures them with DTLS.
+pub(crate) struct DtlsTransport {
+    socket: UdpSocket,
+    /// for the sessions we start, if we have credentials for them
+    client: Option<SslContext>,
+    /// for the sessions peers start, if we have credentials for them
+    server: Option<SslContext>,
+    /// the PSK identity we present
+    psk_identity: Option<Vec<u8>>,
+    /// the raw public keys we trust, DER encoded
+    pinned: Vec<Vec<u8>>,
+    sessions: HashMap<SocketAddr, Session>,
+    /// new sessions started by peers we have an established one with, which
+    /// only take its place once their handshake completes
+    restarts: HashMap<SocketAddr, Session>,
+    identities: Identities,
+    incoming: VecDeque<(Message, SocketAddr)>,
+    outgoing: VecDeque<(Vec<u8>, SocketAddr)>,
+    /// how long a handshake may take before it's dropped
+    handshake_timeout: Duration,
+    /// how many handshakes may be in progress at once
+    max_half_open: usize,
+}
+
+impl DtlsTransport {
+    pub fn new(socket: UdpSocket, config: &DtlsConfig) -> Result<DtlsTransport, Error> {
+        let (client, server) = config.contexts(Protocol::Dtls)?;
+        let pinned = config.pinned().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        Ok(DtlsTransport {
+            socket: socket,
+            client: client,
+            server: server,
+            psk_identity: config.psk_identity(),
+            pinned: pinned,
+            sessions: HashMap::new(),
+            restarts: HashMap::new(),
+            identities: Identities::default(),
+            incoming: VecDeque::new(),
+            outgoing: VecDeque::new(),
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            max_half_open: MAX_HALF_OPEN,
+        })
+    }
+
+    pub fn identities(&self) -> Identities {
+        self.identities.clone()
+    }
+
+    fn receive(&mut self, datagram: Vec<u8>, peer: SocketAddr) {
+        let established = self.sessions.get(&peer).map_or(false, |s| s.established);
+        let restarted = established && is_client_hello(&datagram) && !self.restarts.contains_key(&peer);
+
+        if restarted || !self.sessions.contains_key(&peer) {
+            let half_open = self.half_open();
+
+            let session = match self.server {
+                Some(ref context) => Session::accept(context, peer, datagram, &mut self.outgoing),
+                None => {
+                    debug!("ignoring DTLS datagram from {}, not accepting sessions", peer);
+                    return;
+                }
+            };
+
+            match session {
+                Ok(Some(_)) if half_open >= self.max_half_open => {
+                    debug!("turning away DTLS handshake from {}, too many in progress", peer);
+                }
+                // the established session is kept until the new one is, so
+                // a forged ClientHello can't tear it down
+                Ok(Some(session)) if restarted => {
+                    debug!("{} is starting a new DTLS session", peer);
+                    self.restarts.insert(peer, session);
+                    self.drive_restart(peer);
+                }
+                Ok(Some(session)) => {
+                    self.sessions.insert(peer, session);
+                    self.drive(peer);
+                }
+                Ok(None) => (),
+                Err(e) => error!("failed to accept DTLS session: {}", e),
+            }
+
+            return;
+        }
+
+        // Records belonging to the other session are discarded by OpenSSL,
+        // so while a new one is being set up both are given every datagram.
+        if let Some(restart) = self.restarts.get_mut(&peer) {
+            restart.stream.get_mut().incoming.push_back(datagram.clone());
+        }
+
+        if let Some(session) = self.sessions.get_mut(&peer) {
+            session.stream.get_mut().incoming.push_back(datagram);
+        }
+
+        self.drive_restart(peer);
+        self.drive(peer);
+    }
+
+    /// The number of handshakes in progress.
+    fn half_open(&self) -> usize {
+        self.sessions.values().filter(|session| !session.established).count() + self.restarts.len()
+    }
+
+    /// Progress the new session `peer` is starting, replacing the established
+    /// one once its handshake completes, or dropping it if it has failed.
+    /// RFC 6347: 4.2.8.  Establishing New Associations with Existing Parameters
+    fn drive_restart(&mut self, peer: SocketAddr) {
+        let (result, established) = match self.restarts.get_mut(&peer) {
+            Some(restart) => {
+                let result = restart.drive(peer, &mut self.incoming);
+
+                let outgoing = &mut self.outgoing;
+                outgoing.extend(restart.stream.get_mut().outgoing.drain(..).map(|d| (d, peer)));
+
+                (result, restart.established)
+            }
+            None => return,
+        };
+
+        if let Err(e) = result {
+            warn!("new DTLS session with {} failed, keeping the old one: {}", peer, e);
+            self.restarts.remove(&peer);
+        } else if established {
+            let restart = self.restarts.remove(&peer).unwrap();
+
+            let identity = peer_identity(restart.stream.ssl(), &self.psk_identity, &self.pinned);
+            debug!("{} authenticated as {:?} in a new session", peer, identity);
+
+            let mut identities = self.identities.lock().unwrap();
+            match identity {
+                Some(identity) => identities.insert(peer, identity),
+                None => identities.remove(&peer),
+            };
+
+            self.sessions.insert(peer, restart);
+        }
+    }
+
+    /// Progress the session with `peer`, dropping it if it has failed, and
+    /// queue what it has to send.
+    fn drive(&mut self, peer: SocketAddr) {
+        let failed = match self.sessions.get_mut(&peer) {
+            Some(session) => {
+                let established = session.established;
+                let result = session.drive(peer, &mut self.incoming);
+
+                if session.established && !established {
+                    let identity = peer_identity(session.stream.ssl(), &self.psk_identity, &self.pinned);
+                    debug!("{} authenticated as {:?}", peer, identity);
+
+                    if let Some(identity) = identity {
+                        self.identities.lock().unwrap().insert(peer, identity);
+                    }
+                }
+
+                let outgoing = &mut self.outgoing;
+                outgoing.extend(session.stream.get_mut().outgoing.drain(..).map(|d| (d, peer)));
+
+                result.err()
+            }
+            None => None,
+        };
+
+        if let Some(e) = failed {
+            if e.code() == ErrorCode::ZERO_RETURN {
+                debug!("DTLS session with {} closed", peer);
+            } else {
+                warn!("DTLS session with {} failed: {}", peer, e);
+            }
+
+            self.sessions.remove(&peer);
+            self.identities.lock().unwrap().remove(&peer);
+        }
+    }
+
+    /// Prod every session that's still handshaking whose timer has fired,
+    /// dropping those that have taken too long.
+    fn poll_handshakes(&mut self) {
+        let now = Instant::now();
+        let timeout = self.handshake_timeout;
+        let unexpired = |peer: &SocketAddr, session: &mut Session| {
+            let expired = !session.established && now >= session.started + timeout;
+            if expired {
+                debug!("DTLS handshake with {} timed out", peer);
+            }
+
+            !expired
+        };
+
+        self.sessions.retain(&unexpired);
+        self.restarts.retain(&unexpired);
+
+        for peer in stalled(&mut self.restarts) {
+            self.drive_restart(peer);
+        }
+
+        for peer in stalled(&mut self.sessions) {
+            self.drive(peer);
+        }
+    }
+
+    fn flush(&mut self) -> Poll<(), Error> {
+        while let Some((datagram, peer)) = self.outgoing.pop_front() {
+            if let Async::NotReady = self.socket.poll_send_to(&datagram, &peer)? {
+                self.outgoing.push_front((datagram, peer));
+                return Ok(Async::NotReady);
+            }
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Stream for DtlsTransport {
+    type Item = (Message, SocketAddr);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Error> {
+        let mut buf = [0; 2048];
+
+        while let Async::Ready((len, peer)) = self.socket.poll_recv_from(&mut buf)? {
+            self.receive(buf[..len].to_vec(), peer);
+        }
+
+        self.poll_handshakes();
+        self.flush()?;
+
+        match self.incoming.pop_front() {
+            Some(incoming) => Ok(Async::Ready(Some(incoming))),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Sink for DtlsTransport {
+    type SinkItem = (Message, SocketAddr);
+    type SinkError = Error;
+
+    fn start_send(&mut self, (msg, peer): Self::SinkItem) -> StartSend<Self::SinkItem, Error> {
+        if !self.sessions.contains_key(&peer) {
+            let session = match self.client {
+                Some(ref context) => Session::new(context, true, peer),
+                None => {
+                    warn!("dropping message to {}, no DTLS identity to connect with", peer);
+                    return Ok(AsyncSink::Ready);
+                }
+            };
+
+            match session {
+                Ok(session) => {
+                    debug!("starting DTLS session with {}", peer);
+                    self.sessions.insert(peer, session);
+                }
+                Err(e) => {
+                    error!("failed to start DTLS session: {}", e);
+                    return Ok(AsyncSink::Ready);
+                }
+            }
+        }
+
+        let sent = self.sessions.get_mut(&peer).map(|session| session.send(msg));
+        if let Some(Err(e)) = sent {
+            warn!("failed to send to {} over DTLS: {}", peer, e);
+        }
+
+        self.drive(peer);
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DtlsConfig, DtlsTransport, PeerIdentity, Protocol, Session};
+    use client::Client;
+    use error::Error;
+    use message::{Message, Code};
+    use message::option::UriPath;
+    use server::{Request, Server};
+    use transmission::TransmissionParameters;
+
+    use std::collections::{HashMap, VecDeque};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use futures::{future, Future};
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private, Public};
+    use openssl::x509::{X509, X509Name};
+    use openssl::x509::extension::BasicConstraints;
+    use tokio::net::UdpSocket;
+    use tokio::runtime::Runtime;
+
+    const KEY: &[u8] = b"secretPSK";
+
+    fn psk_config() -> DtlsConfig {
+        let mut keys = HashMap::new();
+        keys.insert(b"Client_identity".to_vec(), KEY.to_vec());
+
+        DtlsConfig::new().with_keys(keys)
+    }
+
+    fn serve(runtime: &mut Runtime) -> SocketAddr {
+        serve_with(runtime, &psk_config())
+    }
+
+    fn serve_with(runtime: &mut Runtime, config: &DtlsConfig) -> SocketAddr {
+        let server = Server::bind_dtls(&"127.0.0.1:0".parse().unwrap(), config).unwrap();
+        let server_addr = server.local_addr();
+
+        let handler = |request: Request| {
+            let path = request.message().options.get::<UriPath>().unwrap_or_default();
+            let path: Vec<_> = path.into_iter().map(|segment| segment.value).collect();
+
+            Ok::<_, Error>(Message::new()
+                .with_code(Code::Content)
+                .with_payload(path.join("/").into_bytes()))
+        };
+        runtime.spawn(server.serve(handler).map_err(|e| panic!("{:?}", e)));
+
+        server_addr
+    }
+
+    /// A server replying with who the client authenticated as.
+    fn serve_identities(runtime: &mut Runtime, config: &DtlsConfig) -> SocketAddr {
+        let server = Server::bind_dtls(&"127.0.0.1:0".parse().unwrap(), config).unwrap();
+        let server_addr = server.local_addr();
+
+        let handler = |request: Request| {
+            Ok::<_, Error>(Message::new()
+                .with_code(Code::Content)
+                .with_payload(describe(request.peer_identity())))
+        };
+        runtime.spawn(server.serve(handler).map_err(|e| panic!("{:?}", e)));
+
+        server_addr
+    }
+
+    fn describe(identity: Option<PeerIdentity>) -> Vec<u8> {
+        match identity {
+            Some(PeerIdentity::Psk(identity)) => identity,
+            Some(ref identity @ PeerIdentity::Certificate(_)) => identity.common_name().unwrap().into_bytes(),
+            Some(PeerIdentity::RawPublicKey(key)) => key,
+            None => b"nobody".to_vec(),
+        }
+    }
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn public(key: &PKey<Private>) -> PKey<Public> {
+        PKey::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap()
+    }
+
+    /// A certificate for `key` named `name`, signed by `issuer` or by itself
+    /// as a CA.
+    fn certificate(name: &str, key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>) -> X509 {
+        let mut subject = X509Name::builder().unwrap();
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+        let subject = subject.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&*BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder.set_not_before(&*Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&*Asn1Time::days_from_now(1).unwrap()).unwrap();
+
+        match issuer {
+            Some((cert, issuer_key)) => {
+                builder.set_issuer_name(cert.subject_name()).unwrap();
+                builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                builder.set_issuer_name(&subject).unwrap();
+                builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+                builder.sign(key, MessageDigest::sha256()).unwrap();
+            }
+        }
+
+        builder.build()
+    }
+
+    fn expect_timeout<T: ::std::fmt::Debug>(result: Result<T, Error>) {
+        match result {
+            Err(Error::Timeout) => (),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+
+    fn impatient() -> TransmissionParameters {
+        TransmissionParameters::new()
+            .with_ack_timeout(Duration::from_millis(100))
+            .with_max_retransmit(1)
+    }
+
+    #[test]
+    fn requests_over_dtls() {
+        let mut runtime = Runtime::new().unwrap();
+        let server_addr = serve(&mut runtime);
+
+        let config = DtlsConfig::new().with_identity(b"Client_identity", KEY);
+
+        for path in &["a", "b/c"] {
+            let url = format!("coaps://{}/{}", server_addr, path);
+            let request = Client::get(&url).unwrap()
+                .with_dtls(config.clone())
+                .send();
+
+            let response = runtime.block_on(request).unwrap();
+            assert_eq!(response.code, Code::Content);
+            assert_eq!(response.payload, path.as_bytes());
+        }
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let mut runtime = Runtime::new().unwrap();
+        let server_addr = serve(&mut runtime);
+
+        let config = DtlsConfig::new().with_identity(b"Client_identity", b"wrong");
+
+        let url = format!("coaps://{}/a", server_addr);
+        let request = Client::get(&url).unwrap()
+            .with_parameters(impatient())
+            .with_dtls(config)
+            .send();
+
+        expect_timeout(runtime.block_on(request));
+    }
+
+    #[test]
+    fn plain_coap_is_ignored() {
+        let mut runtime = Runtime::new().unwrap();
+        let server_addr = serve(&mut runtime);
+
+        let url = format!("coap://{}/a", server_addr);
+        let request = Client::get(&url).unwrap()
+            .with_parameters(impatient())
+            .send();
+
+        expect_timeout(runtime.block_on(request));
+    }
+
+    #[test]
+    fn credentials_are_required() {
+        let request = Client::get("coaps://127.0.0.1/a").unwrap().send();
+
+        match Runtime::new().unwrap().block_on(request) {
+            Err(Error::Io(_)) => (),
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn psk_identity_is_exposed() {
+        let mut runtime = Runtime::new().unwrap();
+        let server_addr = serve_identities(&mut runtime, &psk_config());
+
+        let url = format!("coaps://{}/", server_addr);
+        let request = Client::get(&url).unwrap()
+            .with_dtls(DtlsConfig::new().with_identity(b"Client_identity", KEY))
+            .send_with_identity();
+
+        let (response, identity) = runtime.block_on(request).unwrap();
+        assert_eq!(response.payload, b"Client_identity");
+        assert_eq!(describe(identity), b"Client_identity");
+    }
+
+    #[test]
+    fn certificates_are_verified() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let ca_key = key();
+        let ca = certificate("ca", &ca_key, None);
+
+        let server_key = key();
+        let server_cert = certificate("coap-server", &server_key, Some((&ca, &ca_key)));
+        let server_config = DtlsConfig::new()
+            .with_certificate(vec![server_cert], server_key)
+            .with_trusted_root(ca.clone());
+        let server_addr = serve_identities(&mut runtime, &server_config);
+
+        let client_key = key();
+        let client_cert = certificate("device-42", &client_key, Some((&ca, &ca_key)));
+        let client_config = DtlsConfig::new()
+            .with_certificate(vec![client_cert], client_key)
+            .with_trusted_root(ca.clone());
+
+        let url = format!("coaps://{}/", server_addr);
+        let request = Client::get(&url).unwrap()
+            .with_dtls(client_config)
+            .send_with_identity();
+
+        let (response, identity) = runtime.block_on(request).unwrap();
+        assert_eq!(response.payload, b"device-42");
+        assert_eq!(identity.and_then(|identity| identity.common_name()), Some("coap-server".to_owned()));
+
+        // a certificate from elsewhere isn't accepted
+        let other_key = key();
+        let other = certificate("other", &other_key, None);
+        let stranger_key = key();
+        let stranger = certificate("stranger", &stranger_key, Some((&other, &other_key)));
+        let stranger_config = DtlsConfig::new()
+            .with_certificate(vec![stranger], stranger_key)
+            .with_trusted_root(ca);
+
+        let request = Client::get(&url).unwrap()
+            .with_parameters(impatient())
+            .with_dtls(stranger_config)
+            .send();
+        expect_timeout(runtime.block_on(request));
+    }
+
+    #[test]
+    #[cfg(rpk)]
+    fn raw_public_keys_are_pinned() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let server_key = key();
+        let client_key = key();
+
+        let server_config = DtlsConfig::new()
+            .with_raw_public_key(server_key.clone())
+            .with_pinned_key(public(&client_key));
+        let server_addr = serve_identities(&mut runtime, &server_config);
+
+        let client_config = DtlsConfig::new()
+            .with_raw_public_key(client_key.clone())
+            .with_pinned_key(public(&server_key));
+
+        let url = format!("coaps://{}/", server_addr);
+        let request = Client::get(&url).unwrap()
+            .with_dtls(client_config)
+            .send_with_identity();
+
+        let (response, identity) = runtime.block_on(request).unwrap();
+        assert_eq!(response.payload, client_key.public_key_to_der().unwrap());
+        assert_eq!(describe(identity), server_key.public_key_to_der().unwrap());
+
+        // an unpinned key isn't accepted
+        let stranger_config = DtlsConfig::new()
+            .with_raw_public_key(key())
+            .with_pinned_key(public(&server_key));
+
+        let request = Client::get(&url).unwrap()
+            .with_parameters(impatient())
+            .with_dtls(stranger_config)
+            .send();
+        expect_timeout(runtime.block_on(request));
+    }
+
+    #[test]
+    #[cfg(not(rpk))]
+    fn raw_public_keys_need_openssl_3_2() {
+        use std::io;
+
+        let unsupported = |config: DtlsConfig| config.contexts(Protocol::Dtls).err().map(|e| e.kind());
+
+        assert_eq!(unsupported(DtlsConfig::new().with_raw_public_key(key())), Some(io::ErrorKind::Unsupported));
+        assert_eq!(unsupported(DtlsConfig::new().with_pinned_key(public(&key()))), Some(io::ErrorKind::Unsupported));
+    }
+
+    /// A server transport, and the peer address its sessions are tested with.
+    fn transport() -> (DtlsTransport, SocketAddr) {
+        let socket = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+        (DtlsTransport::new(socket, &psk_config()).unwrap(), "127.0.0.1:5684".parse().unwrap())
+    }
+
+    fn client_session(peer: SocketAddr) -> Session {
+        let config = DtlsConfig::new().with_identity(b"Client_identity", KEY);
+        let (client, _) = config.contexts(Protocol::Dtls).unwrap();
+
+        Session::new(&client.unwrap(), true, peer).unwrap()
+    }
+
+    /// Carry datagrams between `client` and `transport` until neither has
+    /// anything more to send, returning the messages the client received.
+    fn exchange(client: &mut Session, transport: &mut DtlsTransport, peer: SocketAddr) -> VecDeque<(Message, SocketAddr)> {
+        let mut received = VecDeque::new();
+
+        loop {
+            client.drive(peer, &mut received).unwrap();
+
+            let sent: Vec<_> = client.stream.get_mut().outgoing.drain(..).collect();
+            let sent_any = !sent.is_empty();
+            for datagram in sent {
+                transport.receive(datagram, peer);
+            }
+
+            let replies: Vec<_> = transport.outgoing.drain(..).map(|(datagram, _)| datagram).collect();
+            if !sent_any && replies.is_empty() {
+                return received;
+            }
+
+            client.stream.get_mut().incoming.extend(replies);
+        }
+    }
+
+    /// Have `client` send `transport` its ClientHello from `peer`, then again
+    /// with the cookie it's asked for, leaving the handshake half-open.
+    fn open(client: &mut Session, transport: &mut DtlsTransport, peer: SocketAddr) {
+        for _ in 0..2 {
+            client.drive(peer, &mut VecDeque::new()).unwrap();
+
+            let sent: Vec<_> = client.stream.get_mut().outgoing.drain(..).collect();
+            for datagram in sent {
+                transport.receive(datagram, peer);
+            }
+
+            let replies: Vec<_> = transport.outgoing.drain(..).map(|(datagram, _)| datagram).collect();
+            client.stream.get_mut().incoming.extend(replies);
+        }
+    }
+
+    fn ping() -> Message {
+        Message::new().with_code(Code::Get).with_mid(1)
+    }
+
+    #[test]
+    fn cookies_are_required() {
+        Runtime::new().unwrap().block_on(future::lazy(|| {
+            let (mut transport, peer) = transport();
+            let mut client = client_session(peer);
+
+            client.drive(peer, &mut VecDeque::new()).unwrap();
+            let hello = client.stream.get_mut().outgoing.pop_front().unwrap();
+            transport.receive(hello, peer);
+
+            // a HelloVerifyRequest comes back, and nothing is kept
+            let (reply, _) = transport.outgoing.pop_front().unwrap();
+            assert_eq!((reply[0], reply[13]), (22, 3));
+            assert!(transport.outgoing.is_empty());
+            assert!(transport.sessions.is_empty());
+
+            client.stream.get_mut().incoming.push_back(reply);
+            exchange(&mut client, &mut transport, peer);
+            assert!(client.established);
+            assert!(transport.sessions[&peer].established);
+
+            Ok::<_, ()>(())
+        })).unwrap();
+    }
+
+    #[test]
+    fn established_sessions_survive_new_handshakes() {
+        Runtime::new().unwrap().block_on(future::lazy(|| {
+            let (mut transport, peer) = transport();
+
+            let mut client = client_session(peer);
+            exchange(&mut client, &mut transport, peer);
+            assert!(transport.identities.lock().unwrap().contains_key(&peer));
+
+            // a ClientHello from the same address doesn't disturb the session
+            let mut restarted = client_session(peer);
+            restarted.drive(peer, &mut VecDeque::new()).unwrap();
+            let hello = restarted.stream.get_mut().outgoing.pop_front().unwrap();
+            transport.receive(hello, peer);
+            let verify: Vec<_> = transport.outgoing.drain(..).map(|(datagram, _)| datagram).collect();
+            assert!(transport.restarts.is_empty());
+
+            // nor does one with a cookie
+            restarted.stream.get_mut().incoming.extend(verify);
+            restarted.drive(peer, &mut VecDeque::new()).unwrap();
+            let hello = restarted.stream.get_mut().outgoing.pop_front().unwrap();
+            transport.receive(hello, peer);
+            let flight: Vec<_> = transport.outgoing.drain(..).map(|(datagram, _)| datagram).collect();
+
+            assert!(transport.restarts.contains_key(&peer));
+            assert!(transport.sessions[&peer].established);
+            assert!(transport.identities.lock().unwrap().contains_key(&peer));
+
+            client.send(ping()).unwrap();
+            exchange(&mut client, &mut transport, peer);
+            assert_eq!(transport.incoming.pop_front().map(|(msg, _)| msg), Some(ping()));
+
+            // until the new handshake completes and replaces it
+            restarted.stream.get_mut().incoming.extend(flight);
+            exchange(&mut restarted, &mut transport, peer);
+            assert!(restarted.established);
+            assert!(transport.restarts.is_empty());
+            assert!(transport.identities.lock().unwrap().contains_key(&peer));
+
+            restarted.send(ping()).unwrap();
+            exchange(&mut restarted, &mut transport, peer);
+            assert_eq!(transport.incoming.pop_front().map(|(msg, _)| msg), Some(ping()));
+
+            Ok::<_, ()>(())
+        })).unwrap();
+    }
+
+    #[test]
+    fn half_open_handshakes_expire() {
+        Runtime::new().unwrap().block_on(future::lazy(|| {
+            let (mut transport, peer) = transport();
+
+            let mut client = client_session(peer);
+            exchange(&mut client, &mut transport, peer);
+
+            open(&mut client_session(peer), &mut transport, peer);
+            assert!(transport.restarts.contains_key(&peer));
+
+            let stranger: SocketAddr = "127.0.0.1:5685".parse().unwrap();
+            open(&mut client_session(stranger), &mut transport, stranger);
+            assert!(transport.sessions.contains_key(&stranger));
+
+            transport.handshake_timeout = Duration::from_secs(0);
+            transport.poll_handshakes();
+
+            assert!(transport.restarts.is_empty());
+            assert!(!transport.sessions.contains_key(&stranger));
+            assert!(transport.sessions[&peer].established);
+
+            Ok::<_, ()>(())
+        })).unwrap();
+    }
+
+    #[test]
+    fn half_open_handshakes_are_capped() {
+        Runtime::new().unwrap().block_on(future::lazy(|| {
+            let (mut transport, peer) = transport();
+            transport.max_half_open = 1;
+
+            let mut client = client_session(peer);
+            open(&mut client, &mut transport, peer);
+            assert!(transport.sessions.contains_key(&peer));
+
+            // another peer's handshake is turned away while that one's open
+            let stranger: SocketAddr = "127.0.0.1:5685".parse().unwrap();
+            let mut other = client_session(stranger);
+            open(&mut other, &mut transport, stranger);
+            assert!(!transport.sessions.contains_key(&stranger));
+
+            // and let through once it has completed
+            exchange(&mut client, &mut transport, peer);
+            assert!(transport.sessions[&peer].established);
+
+            let mut other = client_session(stranger);
+            exchange(&mut other, &mut transport, stranger);
+            assert!(other.established);
+            assert!(transport.sessions[&stranger].established);
+
+            Ok::<_, ()>(())
+        })).unwrap();
+    }
+}
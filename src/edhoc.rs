@@ -0,0 +1,1617 @@
+//! The EDHOC key exchange, establishing OSCORE security contexts without
+//! provisioning master secrets by hand.
+//! RFC 9528: Ephemeral Diffie-Hellman Over COSE (EDHOC)
+//!
+//! The Initiator, a client, POSTs message_1 and then message_3 to the
+//! Responder's `/.well-known/edhoc` resource, and gets message_2 and message_4
+//! back in the responses. Each party authenticates either with a signature or
+//! with a static Diffie-Hellman key, as agreed in the method.
+//! RFC 9528: Appendix A.2.  Transferring EDHOC over CoAP
+
+use std::collections::HashMap;
+use std::fmt;
+use std::option::Option as StdOption;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future;
+use futures::prelude::*;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::derive::Deriver;
+use openssl::ec::{EcGroup, EcKey, EcKeyRef, EcPoint};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::md::Md;
+use openssl::memcmp;
+use openssl::nid::Nid;
+use openssl::pkey::{HasPublic, Id, PKey, PKeyRef, Private, Public};
+use openssl::pkey_ctx::{HkdfMode, PkeyCtx};
+use openssl::sha::sha256;
+use openssl::sign::{Signer, Verifier};
+use openssl::x509::X509;
+use rand::{thread_rng, Rng};
+use url::Url;
+
+use cbor::{self, Decoder};
+use client::{Client, IoFuture};
+use error::{EdhocError, Error, UrlError};
+use message::{Message, Code};
+use message::option::{Option, ContentFormat};
+use oscore::{self, ContextStore, SecurityContext};
+use server::{Handler, Request};
+
+/// application/edhoc+cbor-seq, the format of message_2, message_4 and error
+/// messages.
+pub const CONTENT_FORMAT: u64 = 64;
+
+/// application/cid-edhoc+cbor-seq, the format of message_1 and message_3,
+/// which are sent after a connection identifier.
+pub const CID_CONTENT_FORMAT: u64 = 65;
+
+const HASH_LENGTH: usize = 32;
+const KEY_LENGTH: usize = 16;
+const IV_LENGTH: usize = 13;
+/// The length of a MAC standing in for a signature.
+const MAC_LENGTH: usize = 8;
+
+const OSCORE_SECRET_LENGTH: usize = 16;
+const OSCORE_SALT_LENGTH: usize = 8;
+
+/// How long the Responder waits for message_3 after sending message_2.
+const PENDING_LIFETIME: Duration = Duration::from_secs(60);
+
+/// RFC 9528: 6.  Error Handling
+const ERR_UNSPECIFIED: i64 = 1;
+const ERR_WRONG_SUITE: i64 = 2;
+const ERR_UNKNOWN_CREDENTIAL: i64 = 3;
+
+/// How the Initiator and the Responder each authenticate, with a signature or
+/// with a static Diffie-Hellman key.
+/// RFC 9528: 3.2.  Method
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    /// both parties sign
+    SignatureSignature,
+    /// the Initiator signs, the Responder uses a static DH key
+    SignatureStaticDh,
+    /// the Initiator uses a static DH key, the Responder signs
+    StaticDhSignature,
+    /// both parties use static DH keys
+    StaticDhStaticDh,
+}
+
+impl Method {
+    fn value(self) -> u64 {
+        match self {
+            Method::SignatureSignature => 0,
+            Method::SignatureStaticDh => 1,
+            Method::StaticDhSignature => 2,
+            Method::StaticDhStaticDh => 3,
+        }
+    }
+
+    fn from_value(value: u64) -> StdOption<Method> {
+        match value {
+            0 => Some(Method::SignatureSignature),
+            1 => Some(Method::SignatureStaticDh),
+            2 => Some(Method::StaticDhSignature),
+            3 => Some(Method::StaticDhStaticDh),
+            _ => None,
+        }
+    }
+
+    fn initiator_signs(self) -> bool {
+        self == Method::SignatureSignature || self == Method::SignatureStaticDh
+    }
+
+    fn responder_signs(self) -> bool {
+        self == Method::SignatureSignature || self == Method::StaticDhSignature
+    }
+}
+
+/// The algorithms a key exchange uses. Both use AES-CCM-16-64-128 and
+/// SHA-256, for EDHOC and for the OSCORE context it establishes.
+/// RFC 9528: 3.6.  Cipher Suites
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CipherSuite {
+    /// X25519 key agreement and EdDSA signatures with Ed25519
+    Suite0,
+    /// P-256 key agreement and ES256 signatures
+    Suite2,
+}
+
+impl CipherSuite {
+    fn value(self) -> i64 {
+        match self {
+            CipherSuite::Suite0 => 0,
+            CipherSuite::Suite2 => 2,
+        }
+    }
+
+    /// The suite a key belongs to, and whether it can sign and do key
+    /// agreement.
+    fn of<T: HasPublic>(key: &PKeyRef<T>) -> StdOption<(CipherSuite, bool, bool)> {
+        match key.id() {
+            Id::ED25519 => Some((CipherSuite::Suite0, true, false)),
+            Id::X25519 => Some((CipherSuite::Suite0, false, true)),
+            Id::EC => {
+                let p256 = key.ec_key().ok()
+                    .and_then(|key| key.group().curve_name())
+                    .map_or(false, |curve| curve == Nid::X9_62_PRIME256V1);
+
+                if p256 {
+                    Some((CipherSuite::Suite2, true, true))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A party's authentication credential, CRED_x, and how it's referred to in
+/// messages, ID_CRED_x.
+/// RFC 9528: 3.5.2 & 3.5.3
+#[derive(Clone, Debug)]
+pub struct Credential {
+    /// ID_CRED_x, a map of COSE header parameters
+    id: Vec<u8>,
+    /// the key ID, if that's all `id` holds
+    kid: StdOption<Vec<u8>>,
+    /// CRED_x, exactly as it's authenticated
+    bytes: Vec<u8>,
+    key: PKey<Public>,
+}
+
+impl Credential {
+    /// A CWT Claims Set with `subject` as its `sub` claim, confirming `key`
+    /// as a COSE_Key identified by `kid`.
+    /// RFC 9528: 3.5.2.  Authentication Credentials
+    pub fn ccs<T: HasPublic>(kid: &[u8], subject: &str, key: &PKeyRef<T>) -> Result<Credential, Error> {
+        let mut cose_key = Vec::new();
+
+        // RFC 9053: 7.1 & 7.2, with the labels in deterministic order
+        match key.id() {
+            Id::ED25519 | Id::X25519 => {
+                let curve = if key.id() == Id::ED25519 { 6 } else { 4 };
+
+                cbor::map(4, &mut cose_key);
+                cbor::uint(1, &mut cose_key);
+                cbor::uint(1, &mut cose_key);
+                cbor::uint(2, &mut cose_key);
+                cbor::bytes(kid, &mut cose_key);
+                cbor::int(-1, &mut cose_key);
+                cbor::uint(curve, &mut cose_key);
+                cbor::int(-2, &mut cose_key);
+                cbor::bytes(&key.raw_public_key().map_err(oscore::crypto_error)?, &mut cose_key);
+            }
+            Id::EC => {
+                let ec_key = key.ec_key().map_err(oscore::crypto_error)?;
+                let (x, y) = coordinates(&ec_key).map_err(oscore::crypto_error)?;
+
+                cbor::map(5, &mut cose_key);
+                cbor::uint(1, &mut cose_key);
+                cbor::uint(2, &mut cose_key);
+                cbor::uint(2, &mut cose_key);
+                cbor::bytes(kid, &mut cose_key);
+                cbor::int(-1, &mut cose_key);
+                cbor::uint(1, &mut cose_key);
+                cbor::int(-2, &mut cose_key);
+                cbor::bytes(&x, &mut cose_key);
+                cbor::int(-3, &mut cose_key);
+                cbor::bytes(&y, &mut cose_key);
+            }
+            _ => return Err(EdhocError::Unsupported.into()),
+        }
+
+        let mut ccs = Vec::new();
+        cbor::map(2, &mut ccs);
+        cbor::uint(2, &mut ccs);
+        cbor::text(subject, &mut ccs);
+        cbor::uint(8, &mut ccs);
+        cbor::map(1, &mut ccs);
+        cbor::uint(1, &mut ccs);
+        ccs.extend_from_slice(&cose_key);
+
+        Ok(Credential {
+            id: kid_map(kid),
+            kid: Some(kid.to_vec()),
+            bytes: ccs,
+            key: public_key(key)?,
+        })
+    }
+
+    /// An X.509 certificate, identified by its SHA-256/64 thumbprint.
+    /// RFC 9528: 3.5.3.  Identifying Credentials
+    pub fn certificate(cert: &X509) -> Result<Credential, Error> {
+        let der = cert.to_der().map_err(oscore::crypto_error)?;
+
+        // RFC 9360: 2.  X.509 COSE Header Parameters
+        let mut id = Vec::new();
+        cbor::map(1, &mut id);
+        cbor::uint(34, &mut id);
+        cbor::array(2, &mut id);
+        cbor::int(-15, &mut id);
+        cbor::bytes(&sha256(&der)[..8], &mut id);
+
+        let mut bytes = Vec::new();
+        cbor::bytes(&der, &mut bytes);
+
+        Ok(Credential {
+            id: id,
+            kid: None,
+            bytes: bytes,
+            key: cert.public_key().map_err(oscore::crypto_error)?,
+        })
+    }
+
+    /// The key ID the credential is identified by, if any.
+    pub fn kid(&self) -> StdOption<&[u8]> {
+        self.kid.as_ref().map(Vec::as_slice)
+    }
+
+    pub fn public_key(&self) -> &PKeyRef<Public> {
+        &self.key
+    }
+
+    /// ID_CRED_x as it's sent, just the key ID if that's all there is to it.
+    /// RFC 9528: 3.5.3.2.  Compact Encoding of ID_CRED Fields for 'kid'
+    fn encode_id(&self, out: &mut Vec<u8>) {
+        match self.kid {
+            Some(ref kid) => identifier(kid, out),
+            None => out.extend_from_slice(&self.id),
+        }
+    }
+}
+
+/// A party's own credential and the private key it authenticates with, which
+/// decides the cipher suite it's used with.
+#[derive(Clone)]
+pub struct Identity {
+    credential: Credential,
+    key: PKey<Private>,
+    suite: CipherSuite,
+    signs: bool,
+    agrees: bool,
+}
+
+impl Identity {
+    /// Authenticate with `key`, which is an Ed25519 key to sign with, an
+    /// X25519 key for static DH, or a P-256 key for either.
+    pub fn new(credential: Credential, key: PKey<Private>) -> Result<Identity, Error> {
+        let (suite, signs, agrees) = CipherSuite::of(&key).ok_or(EdhocError::Unsupported)?;
+
+        Ok(Identity {
+            credential: credential,
+            key: key,
+            suite: suite,
+            signs: signs,
+            agrees: agrees,
+        })
+    }
+
+    pub fn credential(&self) -> &Credential {
+        &self.credential
+    }
+
+    pub fn suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    /// Whether the key can authenticate as the method has it.
+    fn can_authenticate(&self, signature: bool) -> bool {
+        if signature { self.signs } else { self.agrees }
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("credential", &self.credential)
+            .field("suite", &self.suite)
+            .finish()
+    }
+}
+
+/// The credentials of the peers a party accepts, found by their ID_CRED.
+#[derive(Clone, Debug, Default)]
+struct Peers {
+    credentials: HashMap<Vec<u8>, Credential>,
+}
+
+impl Peers {
+    fn insert(&mut self, credential: Credential) {
+        self.credentials.insert(credential.id.clone(), credential);
+    }
+
+    fn get(&self, id: &[u8], suite: CipherSuite) -> Result<&Credential, EdhocError> {
+        let credential = self.credentials.get(id).ok_or(EdhocError::UnknownCredential)?;
+
+        match CipherSuite::of(&credential.key) {
+            Some((s, _, _)) if s == suite => Ok(credential),
+            _ => Err(EdhocError::AuthenticationFailed),
+        }
+    }
+}
+
+/// The Initiator of key exchanges, with the method it uses and the
+/// Responders it trusts. See `Client::edhoc`.
+#[derive(Clone, Debug)]
+pub struct Initiator {
+    method: Method,
+    identity: Identity,
+    peers: Peers,
+}
+
+impl Initiator {
+    /// Panics if `identity`'s key can't authenticate the way `method` has
+    /// the Initiator do.
+    pub fn new(method: Method, identity: Identity) -> Initiator {
+        assert!(identity.can_authenticate(method.initiator_signs()),
+                "the Initiator's key can't be used with {:?}", method);
+
+        Initiator {
+            method: method,
+            identity: identity,
+            peers: Peers::default(),
+        }
+    }
+
+    /// Trust a Responder with `credential`.
+    pub fn add_peer(&mut self, credential: Credential) {
+        self.peers.insert(credential);
+    }
+
+    pub fn with_peer(mut self, credential: Credential) -> Self {
+        self.add_peer(credential);
+        self
+    }
+
+    /// Send message_1 with the ephemeral key `x`, offering `suites` the last
+    /// of which is the one selected.
+    /// RFC 9528: 5.2.1.  Formatting of Message 1
+    fn message_1(&self, suites: &[i64], x: PKey<Private>, c_i: Vec<u8>) -> Result<(Started, Vec<u8>), Error> {
+        let mut message_1 = Vec::new();
+        cbor::uint(self.method.value(), &mut message_1);
+        if suites.len() == 1 {
+            cbor::int(suites[0], &mut message_1);
+        } else {
+            cbor::array(suites.len(), &mut message_1);
+            for &suite in suites {
+                cbor::int(suite, &mut message_1);
+            }
+        }
+        cbor::bytes(&encode_public(&x).map_err(oscore::crypto_error)?, &mut message_1);
+        identifier(&c_i, &mut message_1);
+
+        let started = Started {
+            x: x,
+            c_i: c_i,
+            hash_1: sha256(&message_1).to_vec(),
+        };
+
+        Ok((started, message_1))
+    }
+
+    /// Verify message_2 and answer it with message_3.
+    /// RFC 9528: 5.3.3 & 5.4.2
+    fn message_3(&self, started: Started, message_2: &[u8]) -> Result<(Established, Vec<u8>), Error> {
+        let suite = self.identity.suite;
+        let method = self.method;
+
+        let mut decoder = Decoder::new(message_2);
+        let g_y_ciphertext_2 = decoder.bytes().map_err(EdhocError::from)?;
+        if !decoder.is_empty() || g_y_ciphertext_2.len() <= HASH_LENGTH {
+            return Err(EdhocError::Malformed.into());
+        }
+
+        let (g_y, ciphertext_2) = g_y_ciphertext_2.split_at(HASH_LENGTH);
+        let g_y_key = decode_public(suite, g_y)?;
+
+        let mut th_2 = Vec::new();
+        cbor::bytes(g_y, &mut th_2);
+        cbor::bytes(&started.hash_1, &mut th_2);
+        let th_2 = sha256(&th_2).to_vec();
+
+        let g_xy = ecdh(&started.x, &g_y_key)?;
+        let prk_2e = extract(&th_2, &g_xy)?;
+        let keystream_2 = kdf(&prk_2e, 0, &th_2, ciphertext_2.len())?;
+        let plaintext_2 = xor(ciphertext_2, &keystream_2);
+
+        let mut decoder = Decoder::new(&plaintext_2);
+        let c_r = decode_identifier(&mut decoder)?;
+        let id_cred_r = decode_id_cred(&mut decoder)?;
+        let signature_or_mac_2 = decoder.bytes().map_err(EdhocError::from)?;
+        let ead_2 = decoder.rest();
+        skip_ead(&mut decoder)?;
+
+        let cred_r = self.peers.get(&id_cred_r, suite)?;
+
+        let prk_3e2m = if method.responder_signs() {
+            prk_2e.clone()
+        } else {
+            let salt_3e2m = kdf(&prk_2e, 1, &th_2, HASH_LENGTH)?;
+            extract(&salt_3e2m, &ecdh(&started.x, &cred_r.key)?)?
+        };
+
+        let mut context_2 = Vec::new();
+        identifier(&c_r, &mut context_2);
+        context_2.extend_from_slice(&id_cred_r);
+        let external_2 = external(&th_2, &cred_r.bytes, ead_2);
+        context_2.extend_from_slice(&external_2);
+
+        let mac_length_2 = if method.responder_signs() { HASH_LENGTH } else { MAC_LENGTH };
+        let mac_2 = kdf(&prk_3e2m, 2, &context_2, mac_length_2)?;
+        authenticate(method.responder_signs(), &cred_r.key, &id_cred_r, &external_2, &mac_2, signature_or_mac_2)?;
+
+        let th_3 = transcript(&th_2, &plaintext_2, &cred_r.bytes);
+
+        let prk_4e3m = if method.initiator_signs() {
+            prk_3e2m.clone()
+        } else {
+            let salt_4e3m = kdf(&prk_3e2m, 5, &th_3, HASH_LENGTH)?;
+            extract(&salt_4e3m, &ecdh(&self.identity.key, &g_y_key)?)?
+        };
+
+        let cred_i = &self.identity.credential;
+        let external_3 = external(&th_3, &cred_i.bytes, &[]);
+        let mut context_3 = cred_i.id.clone();
+        context_3.extend_from_slice(&external_3);
+
+        let mac_length_3 = if method.initiator_signs() { HASH_LENGTH } else { MAC_LENGTH };
+        let mac_3 = kdf(&prk_4e3m, 6, &context_3, mac_length_3)?;
+        let signature_or_mac_3 = if method.initiator_signs() {
+            sign(&self.identity.key, &sig_structure(&cred_i.id, &external_3, &mac_3)).map_err(oscore::crypto_error)?
+        } else {
+            mac_3
+        };
+
+        let mut plaintext_3 = Vec::new();
+        cred_i.encode_id(&mut plaintext_3);
+        cbor::bytes(&signature_or_mac_3, &mut plaintext_3);
+
+        let k_3 = kdf(&prk_3e2m, 3, &th_3, KEY_LENGTH)?;
+        let iv_3 = kdf(&prk_3e2m, 4, &th_3, IV_LENGTH)?;
+        let ciphertext_3 = oscore::seal(&k_3, &iv_3, &enc_structure(&th_3), &plaintext_3)?;
+
+        let mut message_3 = Vec::new();
+        cbor::bytes(&ciphertext_3, &mut message_3);
+
+        let th_4 = transcript(&th_3, &plaintext_3, &cred_i.bytes);
+        let established = Established::new(prk_4e3m, th_4, started.c_i, c_r)?;
+
+        Ok((established, message_3))
+    }
+}
+
+/// The Initiator's side of a key exchange, between message_1 and message_2.
+struct Started {
+    x: PKey<Private>,
+    c_i: Vec<u8>,
+    /// H(message_1)
+    hash_1: Vec<u8>,
+}
+
+/// The Responder's side of a key exchange, between message_2 and message_3.
+struct Pending {
+    method: Method,
+    suite: CipherSuite,
+    y: PKey<Private>,
+    c_i: Vec<u8>,
+    prk_3e2m: Vec<u8>,
+    th_3: Vec<u8>,
+    sent: Instant,
+}
+
+/// A completed key exchange, the keys it established.
+struct Established {
+    prk_4e3m: Vec<u8>,
+    th_4: Vec<u8>,
+    prk_out: Vec<u8>,
+    c_i: Vec<u8>,
+    c_r: Vec<u8>,
+}
+
+impl Established {
+    fn new(prk_4e3m: Vec<u8>, th_4: Vec<u8>, c_i: Vec<u8>, c_r: Vec<u8>) -> Result<Established, Error> {
+        let prk_out = kdf(&prk_4e3m, 7, &th_4, HASH_LENGTH)?;
+
+        Ok(Established {
+            prk_4e3m: prk_4e3m,
+            th_4: th_4,
+            prk_out: prk_out,
+            c_i: c_i,
+            c_r: c_r,
+        })
+    }
+
+    /// RFC 9528: 4.2.1.  EDHOC_Exporter
+    fn exporter(&self, label: u64, context: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+        let prk_exporter = kdf(&self.prk_out, 10, &[], HASH_LENGTH)?;
+
+        kdf(&prk_exporter, label, context, len)
+    }
+
+    /// message_4, confirming the key exchange to the Initiator, with no
+    /// external authorization data to carry.
+    /// RFC 9528: 5.5.2.  Responder Processing of Message 4
+    fn message_4(&self) -> Result<Vec<u8>, Error> {
+        let (k_4, iv_4) = self.keys_4()?;
+        let ciphertext_4 = oscore::seal(&k_4, &iv_4, &enc_structure(&self.th_4), &[])?;
+
+        let mut message_4 = Vec::new();
+        cbor::bytes(&ciphertext_4, &mut message_4);
+
+        Ok(message_4)
+    }
+
+    /// RFC 9528: 5.5.3.  Initiator Processing of Message 4
+    fn verify_message_4(&self, message_4: &[u8]) -> Result<(), Error> {
+        let (k_4, iv_4) = self.keys_4()?;
+
+        let mut decoder = Decoder::new(message_4);
+        let ciphertext_4 = decoder.bytes().map_err(EdhocError::from)?;
+        let plaintext_4 = oscore::open(&k_4, &iv_4, &enc_structure(&self.th_4), ciphertext_4)
+            .map_err(|_| EdhocError::AuthenticationFailed)?;
+
+        skip_ead(&mut Decoder::new(&plaintext_4))?;
+
+        Ok(())
+    }
+
+    fn keys_4(&self) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        Ok((kdf(&self.prk_4e3m, 8, &self.th_4, KEY_LENGTH)?, kdf(&self.prk_4e3m, 9, &self.th_4, IV_LENGTH)?))
+    }
+
+    /// The OSCORE context the key exchange established, the Initiator
+    /// sending with the Responder's connection identifier and the Responder
+    /// with the Initiator's.
+    /// RFC 9528: Appendix A.1.  Deriving the OSCORE Security Context
+    fn security_context(&self, initiator: bool) -> Result<SecurityContext, Error> {
+        let master_secret = self.exporter(0, &[], OSCORE_SECRET_LENGTH)?;
+        let master_salt = self.exporter(1, &[], OSCORE_SALT_LENGTH)?;
+
+        if initiator {
+            SecurityContext::new(&master_secret, &master_salt, None, &self.c_r, &self.c_i)
+        } else {
+            SecurityContext::new(&master_secret, &master_salt, None, &self.c_i, &self.c_r)
+        }
+    }
+}
+
+/// Run a key exchange with the Responder at `url` as `initiator`.
+pub(crate) fn initiate(url: &str, initiator: &Initiator) -> IoFuture<SecurityContext> {
+    let mut url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(e) => return Box::new(future::err(UrlError::Parse(e).into())),
+    };
+    url.set_path("/.well-known/edhoc");
+    url.set_query(None);
+
+    let initiator = initiator.clone();
+
+    let suite = initiator.identity.suite;
+    let started = generate(suite).map_err(oscore::crypto_error).and_then(|x| {
+        // the Responder picks a different identifier if this one is taken
+        initiator.message_1(&[suite.value()], x, connection_id(|_| false))
+    });
+    let (started, message_1) = match started {
+        Ok(started) => started,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    let mut payload = Vec::new();
+    cbor::boolean(true, &mut payload);
+    payload.extend_from_slice(&message_1);
+
+    let exchange = post(&url, payload)
+        .and_then(|response| reply(&response))
+        .and_then(move |message_2| {
+            let (established, message_3) = initiator.message_3(started, &message_2)?;
+
+            let mut payload = Vec::new();
+            identifier(&established.c_r, &mut payload);
+            payload.extend_from_slice(&message_3);
+
+            Ok(post(&url, payload).map(move |response| (established, response)))
+        })
+        .flatten()
+        .and_then(|(established, response)| {
+            established.verify_message_4(&reply(&response)?)?;
+
+            established.security_context(true)
+        });
+
+    Box::new(exchange)
+}
+
+/// POST an EDHOC message to the Responder.
+fn post(url: &Url, payload: Vec<u8>) -> IoFuture<Message> {
+    match Client::post(url.as_str()) {
+        Ok(client) => client
+            .with_content_format(CID_CONTENT_FORMAT)
+            .with_payload(payload)
+            .send(),
+        Err(e) => Box::new(future::err(e)),
+    }
+}
+
+/// The EDHOC message a response carries, or why the Responder sent an error
+/// message instead.
+fn reply(response: &Message) -> Result<Vec<u8>, Error> {
+    if response.code.class() == 2 {
+        return Ok(response.payload.clone());
+    }
+
+    let mut decoder = Decoder::new(&response.payload);
+    let error = match decoder.int() {
+        Ok(ERR_UNSPECIFIED) => EdhocError::Rejected(decoder.text().unwrap_or("").to_owned()),
+        Ok(ERR_WRONG_SUITE) => EdhocError::Unsupported,
+        Ok(ERR_UNKNOWN_CREDENTIAL) => EdhocError::Rejected("Unknown credential referenced".to_owned()),
+        _ => return Err(Error::Response(response.clone())),
+    };
+
+    Err(error.into())
+}
+
+/// The Responder of key exchanges, to be routed POSTs to `/.well-known/edhoc`.
+/// The security context each one establishes is added to its `ContextStore`,
+/// for a server with the same store to use, see `Server::set_oscore`.
+#[derive(Clone)]
+pub struct Responder {
+    /// an identity for each cipher suite supported, in order of preference
+    identities: Vec<Identity>,
+    peers: Peers,
+    contexts: ContextStore,
+    /// the key exchanges awaiting message_3, by the Responder's connection
+    /// identifier
+    pending: Arc<Mutex<HashMap<Vec<u8>, Pending>>>,
+}
+
+impl Responder {
+    pub fn new(identity: Identity, contexts: ContextStore) -> Responder {
+        Responder {
+            identities: vec![identity],
+            peers: Peers::default(),
+            contexts: contexts,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Support the cipher suite of `identity` too, preferring those added
+    /// earlier.
+    ///
+    /// Panics if there's already an identity for that suite.
+    pub fn add_identity(&mut self, identity: Identity) {
+        assert!(self.identity(identity.suite).is_none(), "there's already an identity for {:?}", identity.suite);
+
+        self.identities.push(identity);
+    }
+
+    pub fn with_identity(mut self, identity: Identity) -> Self {
+        self.add_identity(identity);
+        self
+    }
+
+    /// Trust an Initiator with `credential`.
+    pub fn add_peer(&mut self, credential: Credential) {
+        self.peers.insert(credential);
+    }
+
+    pub fn with_peer(mut self, credential: Credential) -> Self {
+        self.add_peer(credential);
+        self
+    }
+
+    fn identity(&self, suite: CipherSuite) -> StdOption<&Identity> {
+        self.identities.iter().find(|identity| identity.suite == suite)
+    }
+
+    /// A connection identifier not used by any security context or pending
+    /// key exchange, nor by the Initiator.
+    fn connection_id(&self, c_i: &[u8]) -> Vec<u8> {
+        let pending = self.pending.lock().unwrap();
+
+        connection_id(|id| {
+            id == c_i || pending.contains_key(id) || self.contexts.get(id, None).is_some()
+        })
+    }
+
+    /// Verify message_1 and answer it with message_2, using the ephemeral
+    /// key `y` and connection identifier `c_r`.
+    /// RFC 9528: 5.2.3 & 5.3.2
+    fn message_2(&self, message_1: &[u8], y: StdOption<PKey<Private>>, c_r: StdOption<Vec<u8>>) -> Result<Vec<u8>, Refusal> {
+        let mut decoder = Decoder::new(message_1);
+        let method = Method::from_value(decoder.uint()?).ok_or(Refusal::Error(EdhocError::Unsupported.into()))?;
+        let suites = match decoder.peek() {
+            Some(cbor::ARRAY) => {
+                let len = decoder.array()?;
+                (0..len).map(|_| decoder.int()).collect::<Result<Vec<_>, _>>()?
+            }
+            _ => vec![decoder.int()?],
+        };
+        let g_x = decoder.bytes()?;
+        let c_i = decode_identifier(&mut decoder)?;
+        skip_ead(&mut decoder)?;
+
+        // the selected suite comes last, and none the Initiator prefers to it
+        // may be supported
+        // RFC 9528: 6.3.  Cipher Suite Negotiation
+        let supported: Vec<_> = suites.iter()
+            .map(|&value| self.identities.iter().find(|identity| identity.suite.value() == value))
+            .collect();
+        let identity = match supported.split_last() {
+            Some((&Some(identity), preferred)) if preferred.iter().all(StdOption::is_none) => identity,
+            _ => return Err(Refusal::WrongSuite),
+        };
+
+        if !identity.can_authenticate(method.responder_signs()) {
+            return Err(Refusal::Error(EdhocError::Unsupported.into()));
+        }
+
+        let suite = identity.suite;
+        let g_x = decode_public(suite, g_x)?;
+        let y = match y {
+            Some(y) => y,
+            None => generate(suite).map_err(oscore::crypto_error)?,
+        };
+        let c_r = c_r.unwrap_or_else(|| self.connection_id(&c_i));
+
+        let g_y = encode_public(&y).map_err(oscore::crypto_error)?;
+        let mut th_2 = Vec::new();
+        cbor::bytes(&g_y, &mut th_2);
+        cbor::bytes(&sha256(message_1), &mut th_2);
+        let th_2 = sha256(&th_2).to_vec();
+
+        let g_xy = ecdh(&y, &g_x)?;
+        let prk_2e = extract(&th_2, &g_xy)?;
+
+        let prk_3e2m = if method.responder_signs() {
+            prk_2e.clone()
+        } else {
+            let salt_3e2m = kdf(&prk_2e, 1, &th_2, HASH_LENGTH)?;
+            extract(&salt_3e2m, &ecdh(&identity.key, &g_x)?)?
+        };
+
+        let cred_r = &identity.credential;
+        let external_2 = external(&th_2, &cred_r.bytes, &[]);
+        let mut context_2 = Vec::new();
+        identifier(&c_r, &mut context_2);
+        context_2.extend_from_slice(&cred_r.id);
+        context_2.extend_from_slice(&external_2);
+
+        let mac_length_2 = if method.responder_signs() { HASH_LENGTH } else { MAC_LENGTH };
+        let mac_2 = kdf(&prk_3e2m, 2, &context_2, mac_length_2)?;
+        let signature_or_mac_2 = if method.responder_signs() {
+            sign(&identity.key, &sig_structure(&cred_r.id, &external_2, &mac_2)).map_err(oscore::crypto_error)?
+        } else {
+            mac_2
+        };
+
+        let mut plaintext_2 = Vec::new();
+        identifier(&c_r, &mut plaintext_2);
+        cred_r.encode_id(&mut plaintext_2);
+        cbor::bytes(&signature_or_mac_2, &mut plaintext_2);
+
+        let keystream_2 = kdf(&prk_2e, 0, &th_2, plaintext_2.len())?;
+        let mut g_y_ciphertext_2 = g_y;
+        g_y_ciphertext_2.extend_from_slice(&xor(&plaintext_2, &keystream_2));
+
+        let mut message_2 = Vec::new();
+        cbor::bytes(&g_y_ciphertext_2, &mut message_2);
+
+        let pending = Pending {
+            method: method,
+            suite: suite,
+            y: y,
+            c_i: c_i,
+            prk_3e2m: prk_3e2m,
+            th_3: transcript(&th_2, &plaintext_2, &cred_r.bytes),
+            sent: Instant::now(),
+        };
+
+        let mut all_pending = self.pending.lock().unwrap();
+        all_pending.retain(|_, pending| pending.sent.elapsed() < PENDING_LIFETIME);
+        all_pending.insert(c_r, pending);
+
+        Ok(message_2)
+    }
+
+    /// Verify message_3, completing the key exchange.
+    /// RFC 9528: 5.4.3.  Responder Processing of Message 3
+    fn message_3(&self, c_r: Vec<u8>, message_3: &[u8]) -> Result<Established, Refusal> {
+        let pending = match self.pending.lock().unwrap().remove(&c_r) {
+            Some(ref pending) if pending.sent.elapsed() >= PENDING_LIFETIME => None,
+            pending => pending,
+        };
+        let pending = pending.ok_or(Refusal::Error(EdhocError::UnknownConnection.into()))?;
+        let method = pending.method;
+
+        let mut decoder = Decoder::new(message_3);
+        let ciphertext_3 = decoder.bytes()?;
+        if !decoder.is_empty() {
+            return Err(EdhocError::Malformed.into());
+        }
+
+        let k_3 = kdf(&pending.prk_3e2m, 3, &pending.th_3, KEY_LENGTH)?;
+        let iv_3 = kdf(&pending.prk_3e2m, 4, &pending.th_3, IV_LENGTH)?;
+        let plaintext_3 = oscore::open(&k_3, &iv_3, &enc_structure(&pending.th_3), ciphertext_3)
+            .map_err(|_| EdhocError::AuthenticationFailed)?;
+
+        let mut decoder = Decoder::new(&plaintext_3);
+        let id_cred_i = decode_id_cred(&mut decoder)?;
+        let signature_or_mac_3 = decoder.bytes()?;
+        let ead_3 = decoder.rest();
+        skip_ead(&mut decoder)?;
+
+        let cred_i = self.peers.get(&id_cred_i, pending.suite)?;
+
+        let prk_4e3m = if method.initiator_signs() {
+            pending.prk_3e2m.clone()
+        } else {
+            let salt_4e3m = kdf(&pending.prk_3e2m, 5, &pending.th_3, HASH_LENGTH)?;
+            extract(&salt_4e3m, &ecdh(&pending.y, &cred_i.key)?)?
+        };
+
+        let external_3 = external(&pending.th_3, &cred_i.bytes, ead_3);
+        let mut context_3 = id_cred_i.clone();
+        context_3.extend_from_slice(&external_3);
+
+        let mac_length_3 = if method.initiator_signs() { HASH_LENGTH } else { MAC_LENGTH };
+        let mac_3 = kdf(&prk_4e3m, 6, &context_3, mac_length_3)?;
+        authenticate(method.initiator_signs(), &cred_i.key, &id_cred_i, &external_3, &mac_3, signature_or_mac_3)?;
+
+        let th_4 = transcript(&pending.th_3, &plaintext_3, &cred_i.bytes);
+
+        Ok(Established::new(prk_4e3m, th_4, pending.c_i, c_r)?)
+    }
+
+    /// RFC 9528: 6.  Error Handling
+    fn error_message(&self, refusal: &Refusal) -> Message {
+        let mut error = Vec::new();
+
+        match *refusal {
+            Refusal::WrongSuite => {
+                cbor::int(ERR_WRONG_SUITE, &mut error);
+                if self.identities.len() == 1 {
+                    cbor::int(self.identities[0].suite.value(), &mut error);
+                } else {
+                    cbor::array(self.identities.len(), &mut error);
+                    for identity in &self.identities {
+                        cbor::int(identity.suite.value(), &mut error);
+                    }
+                }
+            }
+            Refusal::Error(Error::Edhoc(EdhocError::UnknownCredential)) => {
+                cbor::int(ERR_UNKNOWN_CREDENTIAL, &mut error);
+                cbor::boolean(true, &mut error);
+            }
+            Refusal::Error(ref e) => {
+                let diagnostic = match *e {
+                    Error::Edhoc(EdhocError::Malformed) => "Malformed message",
+                    Error::Edhoc(EdhocError::Unsupported) => "Unsupported method",
+                    Error::Edhoc(EdhocError::AuthenticationFailed) => "Authentication failed",
+                    Error::Edhoc(EdhocError::UnknownConnection) => "Unknown connection identifier",
+                    _ => "Internal error",
+                };
+
+                cbor::int(ERR_UNSPECIFIED, &mut error);
+                cbor::text(diagnostic, &mut error);
+            }
+        }
+
+        Message::new()
+            .with_code(Code::BadRequest)
+            .with_option(ContentFormat::new(CONTENT_FORMAT))
+            .with_payload(error)
+    }
+}
+
+impl fmt::Debug for Responder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Responder")
+            .field("identities", &self.identities)
+            .field("peers", &self.peers)
+            .finish()
+    }
+}
+
+impl Handler for Responder {
+    fn handle(&self, request: Request) -> IoFuture<Message> {
+        if request.message().code != Code::Post {
+            return Box::new(future::ok(Message::new().with_code(Code::MethodNotAllowed)));
+        }
+
+        let payload = &request.message().payload;
+        let mut decoder = Decoder::new(payload);
+
+        // message_1 follows `true`, message_3 the Responder's connection
+        // identifier
+        // RFC 9528: A.2.  Transferring EDHOC over CoAP
+        let reply = if decoder.peek() == Some(cbor::SIMPLE) {
+            match decoder.boolean() {
+                Ok(true) => self.message_2(decoder.rest(), None, None),
+                _ => Err(EdhocError::Malformed.into()),
+            }
+        } else {
+            decode_identifier(&mut decoder)
+                .map_err(Refusal::from)
+                .and_then(|c_r| self.message_3(c_r, decoder.rest()))
+                .and_then(|established| {
+                    let context = established.security_context(false)?;
+                    let message_4 = established.message_4()?;
+
+                    debug!("established OSCORE context {:?} with {}", context, request.peer());
+                    self.contexts.insert(context);
+
+                    Ok(message_4)
+                })
+        };
+
+        let response = match reply {
+            Ok(message) => Message::new()
+                .with_code(Code::Changed)
+                .with_option(ContentFormat::new(CONTENT_FORMAT))
+                .with_payload(message),
+            Err(refusal) => {
+                debug!("refusing EDHOC message from {}: {:?}", request.peer(), refusal);
+                self.error_message(&refusal)
+            }
+        };
+
+        Box::new(future::ok(response))
+    }
+}
+
+/// Why the Responder didn't go on with a key exchange.
+#[derive(Debug)]
+enum Refusal {
+    /// the Initiator should select a suite from the Responder's
+    WrongSuite,
+    Error(Error),
+}
+
+impl From<Error> for Refusal {
+    fn from(e: Error) -> Refusal {
+        Refusal::Error(e)
+    }
+}
+
+impl From<EdhocError> for Refusal {
+    fn from(e: EdhocError) -> Refusal {
+        Refusal::Error(e.into())
+    }
+}
+
+impl From<cbor::Malformed> for Refusal {
+    fn from(_: cbor::Malformed) -> Refusal {
+        EdhocError::Malformed.into()
+    }
+}
+
+/// A random connection identifier, a single byte encoded as an int unless
+/// `taken` has all of those.
+/// RFC 9528: 3.3.2.  Representation of Byte String Identifiers
+fn connection_id<F: Fn(&[u8]) -> bool>(taken: F) -> Vec<u8> {
+    let free: Vec<_> = (0..0x18).chain(0x20..0x38)
+        .map(|byte| vec![byte])
+        .filter(|id| !taken(id))
+        .collect();
+
+    let mut rng = thread_rng();
+    if !free.is_empty() {
+        return free[rng.gen_range(0..free.len())].clone();
+    }
+
+    loop {
+        let id = rng.gen::<[u8; 4]>().to_vec();
+        if !taken(&id) {
+            return id;
+        }
+    }
+}
+
+/// A connection identifier or key ID, as an int if it's a single byte
+/// encoding one, otherwise as a byte string.
+/// RFC 9528: 3.3.2.  Representation of Byte String Identifiers
+fn identifier(id: &[u8], out: &mut Vec<u8>) {
+    if id.len() == 1 && (id[0] < 0x18 || (id[0] >= 0x20 && id[0] < 0x38)) {
+        out.push(id[0]);
+    } else {
+        cbor::bytes(id, out);
+    }
+}
+
+fn decode_identifier(decoder: &mut Decoder) -> Result<Vec<u8>, EdhocError> {
+    match decoder.peek() {
+        Some(cbor::UNSIGNED) | Some(cbor::NEGATIVE) => {
+            let item = decoder.item()?;
+            if item.len() == 1 {
+                Ok(item.to_vec())
+            } else {
+                Err(EdhocError::Malformed)
+            }
+        }
+        _ => Ok(decoder.bytes()?.to_vec()),
+    }
+}
+
+/// ID_CRED_x as a map, however it was sent.
+fn decode_id_cred(decoder: &mut Decoder) -> Result<Vec<u8>, EdhocError> {
+    match decoder.peek() {
+        Some(cbor::MAP) => Ok(decoder.item()?.to_vec()),
+        _ => Ok(kid_map(&decode_identifier(decoder)?)),
+    }
+}
+
+/// The ID_CRED_x of a credential identified only by its key ID.
+fn kid_map(kid: &[u8]) -> Vec<u8> {
+    let mut id = Vec::new();
+    cbor::map(1, &mut id);
+    cbor::uint(4, &mut id);
+    cbor::bytes(kid, &mut id);
+    id
+}
+
+/// Skip the external authorization data ending a message, none being
+/// understood, unless there's a critical item.
+/// RFC 9528: 3.8.  External Authorization Data (EAD)
+fn skip_ead(decoder: &mut Decoder) -> Result<(), EdhocError> {
+    while !decoder.is_empty() {
+        let label = decoder.int()?;
+        if decoder.peek() == Some(cbor::BYTES) {
+            decoder.bytes()?;
+        }
+
+        if label < 0 {
+            return Err(EdhocError::Malformed);
+        }
+    }
+
+    Ok(())
+}
+
+/// The data a MAC or signature covers besides ID_CRED_x: << TH, CRED, ? EAD >>
+fn external(th: &[u8], cred: &[u8], ead: &[u8]) -> Vec<u8> {
+    let mut external = Vec::new();
+    cbor::bytes(th, &mut external);
+    external.extend_from_slice(cred);
+    external.extend_from_slice(ead);
+    external
+}
+
+/// The next transcript hash, of the previous one, a plaintext and the
+/// credential it authenticated.
+/// RFC 9528: 5.3.2 & 5.4.2
+fn transcript(th: &[u8], plaintext: &[u8], cred: &[u8]) -> Vec<u8> {
+    let mut input = Vec::new();
+    cbor::bytes(th, &mut input);
+    input.extend_from_slice(plaintext);
+    input.extend_from_slice(cred);
+
+    sha256(&input).to_vec()
+}
+
+/// What a signature in message_2 or message_3 covers.
+/// RFC 9528: 5.3.2 & RFC 9052: 4.4
+fn sig_structure(id_cred: &[u8], external: &[u8], mac: &[u8]) -> Vec<u8> {
+    let mut sig_structure = Vec::new();
+    cbor::array(4, &mut sig_structure);
+    cbor::text("Signature1", &mut sig_structure);
+    cbor::bytes(id_cred, &mut sig_structure);
+    cbor::bytes(external, &mut sig_structure);
+    cbor::bytes(mac, &mut sig_structure);
+    sig_structure
+}
+
+/// The additional data message_3 and message_4 are encrypted with.
+/// RFC 9528: 5.4.2 & RFC 9052: 5.3
+fn enc_structure(th: &[u8]) -> Vec<u8> {
+    let mut enc_structure = Vec::new();
+    cbor::array(3, &mut enc_structure);
+    cbor::text("Encrypt0", &mut enc_structure);
+    cbor::bytes(&[], &mut enc_structure);
+    cbor::bytes(th, &mut enc_structure);
+    enc_structure
+}
+
+/// Verify the Signature_or_MAC field of message_2 or message_3.
+fn authenticate(signature: bool,
+                key: &PKeyRef<Public>,
+                id_cred: &[u8],
+                external: &[u8],
+                mac: &[u8],
+                signature_or_mac: &[u8]) -> Result<(), EdhocError> {
+    let valid = if signature {
+        verify(key, &sig_structure(id_cred, external, mac), signature_or_mac).unwrap_or(false)
+    } else {
+        mac.len() == signature_or_mac.len() && memcmp::eq(mac, signature_or_mac)
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(EdhocError::AuthenticationFailed)
+    }
+}
+
+fn xor(data: &[u8], keystream: &[u8]) -> Vec<u8> {
+    data.iter().zip(keystream).map(|(a, b)| a ^ b).collect()
+}
+
+/// RFC 9528: 4.1.1.  EDHOC_Extract
+fn extract(salt: &[u8], ikm: &[u8]) -> Result<Vec<u8>, Error> {
+    let extract = || -> Result<Vec<u8>, ErrorStack> {
+        let mut ctx = PkeyCtx::new_id(Id::HKDF)?;
+        ctx.derive_init()?;
+        ctx.set_hkdf_mode(HkdfMode::EXTRACT_ONLY)?;
+        ctx.set_hkdf_md(Md::sha256())?;
+        ctx.set_hkdf_salt(salt)?;
+        ctx.set_hkdf_key(ikm)?;
+
+        let mut prk = vec![0; HASH_LENGTH];
+        ctx.derive(Some(&mut prk))?;
+
+        Ok(prk)
+    };
+
+    extract().map_err(oscore::crypto_error)
+}
+
+/// RFC 9528: 4.1.2.  EDHOC_Expand and EDHOC_KDF
+fn kdf(prk: &[u8], label: u64, context: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+    let mut info = Vec::new();
+    cbor::uint(label, &mut info);
+    cbor::bytes(context, &mut info);
+    cbor::uint(len as u64, &mut info);
+
+    let expand = || -> Result<Vec<u8>, ErrorStack> {
+        let mut ctx = PkeyCtx::new_id(Id::HKDF)?;
+        ctx.derive_init()?;
+        ctx.set_hkdf_mode(HkdfMode::EXPAND_ONLY)?;
+        ctx.set_hkdf_md(Md::sha256())?;
+        ctx.set_hkdf_key(prk)?;
+        ctx.add_hkdf_info(&info)?;
+
+        let mut okm = vec![0; len];
+        ctx.derive(Some(&mut okm))?;
+
+        Ok(okm)
+    };
+
+    expand().map_err(oscore::crypto_error)
+}
+
+fn p256() -> Result<EcGroup, ErrorStack> {
+    EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)
+}
+
+/// A new ephemeral key.
+fn generate(suite: CipherSuite) -> Result<PKey<Private>, ErrorStack> {
+    match suite {
+        CipherSuite::Suite0 => PKey::generate_x25519(),
+        CipherSuite::Suite2 => {
+            let group = p256()?;
+            PKey::from_ec_key(EcKey::generate(&group)?)
+        }
+    }
+}
+
+fn public_key<T: HasPublic>(key: &PKeyRef<T>) -> Result<PKey<Public>, Error> {
+    let der = key.public_key_to_der().map_err(oscore::crypto_error)?;
+
+    PKey::public_key_from_der(&der).map_err(oscore::crypto_error)
+}
+
+fn coordinates<T: HasPublic>(key: &EcKeyRef<T>) -> Result<(Vec<u8>, Vec<u8>), ErrorStack> {
+    let mut ctx = BigNumContext::new()?;
+    let mut x = BigNum::new()?;
+    let mut y = BigNum::new()?;
+    key.public_key().affine_coordinates_gfp(key.group(), &mut x, &mut y, &mut ctx)?;
+
+    Ok((x.to_vec_padded(32)?, y.to_vec_padded(32)?))
+}
+
+/// An ephemeral public key as it's sent, just the x-coordinate of a P-256
+/// point.
+/// RFC 9528: 3.7.  Ephemeral Public Keys
+fn encode_public<T: HasPublic>(key: &PKeyRef<T>) -> Result<Vec<u8>, ErrorStack> {
+    match key.id() {
+        Id::EC => {
+            let ec_key = key.ec_key()?;
+            let (x, _) = coordinates(&ec_key)?;
+            Ok(x)
+        }
+        _ => key.raw_public_key(),
+    }
+}
+
+/// Either point with the x-coordinate will do for key agreement, so a P-256
+/// key is taken to have an even y-coordinate.
+fn decode_public(suite: CipherSuite, bytes: &[u8]) -> Result<PKey<Public>, EdhocError> {
+    let decode = || -> Result<PKey<Public>, ErrorStack> {
+        match suite {
+            CipherSuite::Suite0 => PKey::public_key_from_raw_bytes(bytes, Id::X25519),
+            CipherSuite::Suite2 => {
+                let group = p256()?;
+                let mut compressed = vec![0x02];
+                compressed.extend_from_slice(bytes);
+
+                let mut ctx = BigNumContext::new()?;
+                let point = EcPoint::from_bytes(&group, &compressed, &mut ctx)?;
+                PKey::from_ec_key(EcKey::from_public_key(&group, &point)?)
+            }
+        }
+    };
+
+    if bytes.len() != 32 {
+        return Err(EdhocError::Malformed);
+    }
+
+    decode().map_err(|_| EdhocError::Malformed)
+}
+
+fn ecdh(private: &PKeyRef<Private>, public: &PKeyRef<Public>) -> Result<Vec<u8>, EdhocError> {
+    let derive = || -> Result<Vec<u8>, ErrorStack> {
+        let mut deriver = Deriver::new(private)?;
+        deriver.set_peer(public)?;
+        deriver.derive_to_vec()
+    };
+
+    // the peer's static key is for another curve
+    derive().map_err(|_| EdhocError::AuthenticationFailed)
+}
+
+/// A signature with the fixed-size encoding COSE uses for ECDSA.
+/// RFC 9053: 2.1.  ECDSA
+fn sign(key: &PKeyRef<Private>, data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    match key.id() {
+        Id::EC => {
+            let ec_key = key.ec_key()?;
+            let signature = EcdsaSig::sign(&sha256(data), &ec_key)?;
+
+            let mut encoded = signature.r().to_vec_padded(32)?;
+            encoded.extend_from_slice(&signature.s().to_vec_padded(32)?);
+            Ok(encoded)
+        }
+        _ => Signer::new_without_digest(key)?.sign_oneshot_to_vec(data),
+    }
+}
+
+fn verify(key: &PKeyRef<Public>, data: &[u8], signature: &[u8]) -> Result<bool, ErrorStack> {
+    match key.id() {
+        Id::EC => {
+            if signature.len() != 64 {
+                return Ok(false);
+            }
+
+            let r = BigNum::from_slice(&signature[..32])?;
+            let s = BigNum::from_slice(&signature[32..])?;
+            let ec_key = key.ec_key()?;
+            EcdsaSig::from_private_components(r, s)?.verify(&sha256(data), &ec_key)
+        }
+        _ => Verifier::new_without_digest(key)?.verify_oneshot(signature, data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_public, encode_public, p256, CipherSuite, Credential, Established, Identity, Initiator, Method, Responder};
+    use client::Client;
+    use error::{EdhocError, Error};
+    use message::{Message, Code};
+    use oscore::ContextStore;
+    use router::Router;
+    use server::{Request, Server};
+
+    use futures::Future;
+    use openssl::bn::{BigNum, BigNumContext};
+    use openssl::ec::{EcKey, EcPoint};
+    use openssl::pkey::{Id, PKey, Private};
+    use openssl::x509::X509;
+    use tokio::runtime::Runtime;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn p256_key(d: &str) -> PKey<Private> {
+        let group = p256().unwrap();
+        let d = BigNum::from_slice(&hex(d)).unwrap();
+        let mut point = EcPoint::new(&group).unwrap();
+        point.mul_generator2(&group, &d, &mut BigNumContext::new().unwrap()).unwrap();
+
+        PKey::from_ec_key(EcKey::from_private_components(&group, &d, &point).unwrap()).unwrap()
+    }
+
+    fn identity(kid: u8, subject: &str, key: PKey<Private>) -> Identity {
+        Identity::new(Credential::ccs(&[kid], subject, &key).unwrap(), key).unwrap()
+    }
+
+    fn ed25519(kid: u8) -> Identity {
+        identity(kid, "ed25519", PKey::generate_ed25519().unwrap())
+    }
+
+    fn x25519(kid: u8) -> Identity {
+        identity(kid, "x25519", PKey::generate_x25519().unwrap())
+    }
+
+    fn p256_identity(kid: u8) -> Identity {
+        identity(kid, "p-256", PKey::from_ec_key(EcKey::generate(&p256().unwrap()).unwrap()).unwrap())
+    }
+
+    /// Run a key exchange between the two without CoAP, up to message_4.
+    fn handshake(initiator: &Initiator, responder: &Responder) -> Result<(Established, Established), Error> {
+        let suite = initiator.identity.suite;
+        let x = super::generate(suite).unwrap();
+        let (started, message_1) = initiator.message_1(&[suite.value()], x, vec![0x05])?;
+
+        let message_2 = responder.message_2(&message_1, None, Some(vec![0x06])).map_err(|refusal| match refusal {
+            super::Refusal::Error(e) => e,
+            super::Refusal::WrongSuite => EdhocError::Unsupported.into(),
+        })?;
+
+        let (initiated, message_3) = initiator.message_3(started, &message_2)?;
+        let responded = responder.message_3(vec![0x06], &message_3).map_err(|refusal| match refusal {
+            super::Refusal::Error(e) => e,
+            super::Refusal::WrongSuite => EdhocError::Unsupported.into(),
+        })?;
+
+        initiated.verify_message_4(&responded.message_4()?)?;
+
+        Ok((initiated, responded))
+    }
+
+    fn raw_key(key: &str, id: Id) -> PKey<Private> {
+        PKey::private_key_from_raw_bytes(&hex(key), id).unwrap()
+    }
+
+    /// RFC 9529: 2.  Authentication with Signatures, X.509 Certificates Identified by 'x5t'
+    #[test]
+    fn signature_trace() {
+        let i = raw_key("4c5b25878f507c6b9dae68fbd4fd3ff997533db0af00b25d324ea28e6c213bc8", Id::ED25519);
+        let r = raw_key("ef140ff900b0ab03f0c08d879cbbd4b31ea71e6e7ee7ffcb7e7955777a332799", Id::ED25519);
+        let x = raw_key("892ec28e5cb6669108470539500b705e60d008d347c5817ee9f3327c8a87bb03", Id::X25519);
+        let y = raw_key("e69c23fbf81bc435942446837fe827bf206c8fa10a39db47449e5a813421e1e8", Id::X25519);
+
+        assert_eq!(encode_public(&x).unwrap(), hex("31f82c7b5b9cbbf0f194d913cc12ef1532d328ef32632a4881a1c0701e237f04"));
+        assert_eq!(encode_public(&y).unwrap(), hex("dc88d2d51da5ed67fc4616356bc8ca74ef9ebe8b387e623a360ba480b9b29d1c"));
+
+        let cert_i = X509::from_der(&hex("3081ee3081a1a003020102020462319ea0300506032b6570301d311b301906035504030c124544484f4320526f6f742045643235353139301e170d3232303331363038323430305a170d3239313233313233303030305a30223120301e06035504030c174544484f4320496e69746961746f722045643235353139302a300506032b6570032100ed06a8ae61a829ba5fa54525c9d07f48dd44a302f43e0f23d8cc20b73085141e300506032b6570034100521241d8b3a770996bcfc9b9ead4e7e0a1c0db353a3bdf2910b39275ae48b756015981850d27db6734e37f67212267dd05eeff27b9e7a813fa574b72a00b430b")).unwrap();
+        let cert_r = X509::from_der(&hex("3081ee3081a1a003020102020462319ec4300506032b6570301d311b301906035504030c124544484f4320526f6f742045643235353139301e170d3232303331363038323433365a170d3239313233313233303030305a30223120301e06035504030c174544484f4320526573706f6e6465722045643235353139302a300506032b6570032100a1db47b95184854ad12a0c1a354e418aace33aa0f2c662c00b3ac55de92f9359300506032b6570034100b723bc01eab0928e8b2b6c98de19cc3823d46e7d6987b032478fecfaf14537a1af14cc8be829c6b73044101837eb4abc949565d86dce51cfae52ab82c152cb02")).unwrap();
+
+        let cred_i = Credential::certificate(&cert_i).unwrap();
+        assert_eq!(cred_i.id, hex("a11822822e48c24ab2fd7643c79f"));
+
+        let cred_r = Credential::certificate(&cert_r).unwrap();
+        assert_eq!(cred_r.id, hex("a11822822e4879f2a41b510c1f9b"));
+
+        let initiator = Initiator::new(Method::SignatureSignature, Identity::new(cred_i.clone(), i).unwrap())
+            .with_peer(cred_r.clone());
+        let responder = Responder::new(Identity::new(cred_r, r).unwrap(), ContextStore::new())
+            .with_peer(cred_i);
+
+        let (started, message_1) = initiator.message_1(&[0], x, vec![0x2d]).unwrap();
+        assert_eq!(message_1, hex("0000582031f82c7b5b9cbbf0f194d913cc12ef1532d328ef32632a4881a1c0701e237f042d"));
+
+        let message_2 = responder.message_2(&message_1, Some(y), Some(vec![0x27])).unwrap();
+        assert_eq!(message_2, hex("5871dc88d2d51da5ed67fc4616356bc8ca74ef9ebe8b387e623a360ba480b9b29d1c765d51447a0adbf63fec7c6e09490d4e625700990d9ed8321202047780b90ac93474458d7104d4ce7bb6dc499ae669882f550fff7f243f9b18e0a52f177d57fd019ca79874f0ff33da7537392f6f468430"));
+
+        let (initiated, message_3) = initiator.message_3(started, &message_2).unwrap();
+        assert_eq!(message_3, hex("5858148c3ab1e6e7393d7adbeba3587c93ee953b6317314f6ef6f85976681f17159c2f34a0da6a9989647086ca4b8434789ef0a35771a22ac8f2a840eac7f4ae778c2c6d5fa6b78d3fac5dae29414a20297b301a4e877f9aa880"));
+
+        let responded = responder.message_3(vec![0x27], &message_3).unwrap();
+        let message_4 = responded.message_4().unwrap();
+        assert_eq!(message_4, hex("489229b27bd8979c66"));
+        initiated.verify_message_4(&message_4).unwrap();
+
+        for established in &[initiated, responded] {
+            assert_eq!(established.prk_out, hex("3e92b376914d513c7ba4faf0d9fc1e783f4e15f6cfc2ad236160c558e890e2c7"));
+            assert_eq!(established.exporter(0, &[], 16).unwrap(), hex("ac704fbdfa396b3313cd93bbd61ad5e8"));
+            assert_eq!(established.exporter(1, &[], 8).unwrap(), hex("ec99c6c0de824172"));
+        }
+    }
+
+    /// RFC 9529: 3.  Authentication with Static DH, CCS Identified by 'kid'
+    #[test]
+    fn static_dh_trace() {
+        let i = p256_key("fb13adeb6518cee5f88417660841142e830a81fe334380a953406a1305e8706b");
+        let r = p256_key("72cc4761dbd4c78f758931aa589d348d1ef874a7e303ede2f140dcf3e6aa4aac");
+        let x = p256_key("368ec1f69aeb659ba37d5a8d45b21bdc0299dceaa8ef235f3ca42ce3530f9525");
+        let y = p256_key("e2f4126777205e853b437d6eaca1e1f753cdcc3e2c69fa884b0a1a640977e418");
+
+        assert_eq!(encode_public(&x).unwrap(), hex("8af6f430ebe18d34184017a9a11bf511c8dff8f834730b96c1b7c8dbca2fc3b6"));
+        assert_eq!(encode_public(&y).unwrap(), hex("419701d7f00a26c2dc587a36dd752549f33763c893422c8ea0f955a13a4ff5d5"));
+
+        let cred_i = Credential::ccs(&[0x2b], "42-50-31-FF-EF-37-32-39", &i).unwrap();
+        assert_eq!(cred_i.bytes, hex("a2027734322d35302d33312d46462d45462d33372d33322d333908a101a5010202412b2001215820ac75e9ece3e50bfc8ed60399889522405c47bf16df96660a41298cb4307f7eb62258206e5de611388a4b8a8211334ac7d37ecb52a387d257e6db3c2a93df21ff3affc8"));
+        assert_eq!(cred_i.id, hex("a104412b"));
+
+        let cred_r = Credential::ccs(&[0x32], "example.edu", &r).unwrap();
+        assert_eq!(cred_r.bytes, hex("a2026b6578616d706c652e65647508a101a501020241322001215820bbc34960526ea4d32e940cad2a234148ddc21791a12afbcbac93622046dd44f02258204519e257236b2a0ce2023f0931f1f386ca7afda64fcde0108c224c51eabf6072"));
+
+        let initiator = Initiator::new(Method::StaticDhStaticDh, Identity::new(cred_i.clone(), i).unwrap())
+            .with_peer(cred_r.clone());
+        let responder = Responder::new(Identity::new(cred_r, r).unwrap(), ContextStore::new())
+            .with_peer(cred_i);
+
+        // the Initiator prefers suite 6, which the Responder doesn't support
+        let (started, message_1) = initiator.message_1(&[6, 2], x, vec![0x37]).unwrap();
+        assert_eq!(message_1, hex("0382060258208af6f430ebe18d34184017a9a11bf511c8dff8f834730b96c1b7c8dbca2fc3b637"));
+
+        let message_2 = responder.message_2(&message_1, Some(y), Some(vec![0x27])).unwrap();
+        assert_eq!(message_2, hex("582b419701d7f00a26c2dc587a36dd752549f33763c893422c8ea0f955a13a4ff5d59862a1eef9e0e7e1886fcd"));
+
+        let (initiated, message_3) = initiator.message_3(started, &message_2).unwrap();
+        assert_eq!(message_3, hex("52e562097bc417dd5919485ac7891ffd90a9fc"));
+
+        let responded = responder.message_3(vec![0x27], &message_3).unwrap();
+        initiated.verify_message_4(&responded.message_4().unwrap()).unwrap();
+
+        for established in &[initiated, responded] {
+            assert_eq!(established.prk_out, hex("2c71afc1a9338a940bb3529ca734b886f30d1aba0b4dc51beeaeabdfea9ecbf8"));
+            assert_eq!(established.exporter(0, &[], 16).unwrap(), hex("f9868f6a3aca78a05d1485b35030b162"));
+            assert_eq!(established.exporter(1, &[], 8).unwrap(), hex("ada24c7dbfc85eeb"));
+        }
+    }
+
+    #[test]
+    fn every_method_and_suite() {
+        let methods = [
+            Method::SignatureSignature,
+            Method::SignatureStaticDh,
+            Method::StaticDhSignature,
+            Method::StaticDhStaticDh,
+        ];
+
+        for &method in &methods {
+            let (initiator, responder) = if method.initiator_signs() {
+                (ed25519(1), if method.responder_signs() { ed25519(2) } else { x25519(2) })
+            } else {
+                (x25519(1), if method.responder_signs() { ed25519(2) } else { x25519(2) })
+            };
+            let suite_0 = (initiator, responder);
+            let suite_2 = (p256_identity(1), p256_identity(2));
+
+            for &(ref i, ref r) in &[suite_0, suite_2] {
+                let initiator = Initiator::new(method, i.clone()).with_peer(r.credential().clone());
+                let responder = Responder::new(r.clone(), ContextStore::new()).with_peer(i.credential().clone());
+
+                let (initiated, responded) = handshake(&initiator, &responder).unwrap();
+                assert_eq!(initiated.prk_out, responded.prk_out, "{:?} {:?}", method, i.suite());
+
+                let sender = initiated.security_context(true).unwrap();
+                let recipient = responded.security_context(false).unwrap();
+                assert_eq!(sender.sender_id(), recipient.recipient_id());
+                assert_eq!(sender.recipient_id(), recipient.sender_id());
+            }
+        }
+    }
+
+    #[test]
+    fn peers_are_authenticated() {
+        let (i, r) = (ed25519(1), ed25519(2));
+
+        // the Initiator doesn't know the Responder
+        let initiator = Initiator::new(Method::SignatureSignature, i.clone());
+        let responder = Responder::new(r.clone(), ContextStore::new()).with_peer(i.credential().clone());
+        match handshake(&initiator, &responder) {
+            Err(Error::Edhoc(EdhocError::UnknownCredential)) => (),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+
+        // an impostor with the Responder's credential but another key
+        let impostor = Identity::new(r.credential().clone(), PKey::generate_ed25519().unwrap()).unwrap();
+        let initiator = initiator.with_peer(r.credential().clone());
+        let responder = Responder::new(impostor, ContextStore::new()).with_peer(i.credential().clone());
+        match handshake(&initiator, &responder) {
+            Err(Error::Edhoc(EdhocError::AuthenticationFailed)) => (),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+
+        // the Responder doesn't know the Initiator
+        let responder = Responder::new(r, ContextStore::new());
+        match handshake(&initiator, &responder) {
+            Err(Error::Edhoc(EdhocError::UnknownCredential)) => (),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn deeply_nested_id_cred_is_malformed() {
+        let (mut i, r) = (ed25519(1), ed25519(2));
+
+        // ID_CRED_I = {4: [[[...[0]...]]]}, nested far deeper than anything
+        // EDHOC sends
+        let mut id = vec![0xa1, 0x04];
+        id.extend(vec![0x81; 10000]);
+        id.push(0x00);
+        i.credential.id = id;
+        i.credential.kid = None;
+
+        let initiator = Initiator::new(Method::SignatureSignature, i.clone()).with_peer(r.credential().clone());
+        let responder = Responder::new(r, ContextStore::new()).with_peer(i.credential().clone());
+        match handshake(&initiator, &responder) {
+            Err(Error::Edhoc(EdhocError::Malformed)) => (),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn cipher_suite_negotiation() {
+        let (i, r) = (ed25519(1), p256_identity(2));
+
+        let initiator = Initiator::new(Method::SignatureSignature, i.clone()).with_peer(r.credential().clone());
+        let responder = Responder::new(r.clone(), ContextStore::new()).with_peer(i.credential().clone());
+        match handshake(&initiator, &responder) {
+            Err(Error::Edhoc(EdhocError::Unsupported)) => (),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+
+        let error = responder.error_message(&super::Refusal::WrongSuite);
+        assert_eq!(error.code, Code::BadRequest);
+        assert_eq!(error.payload, [0x02, 0x02]);
+
+        // it's fine once the Responder has an identity for suite 0 as well
+        let responder = responder.with_identity(ed25519(3)).with_peer(i.credential().clone());
+        let initiator = initiator.with_peer(responder.identities[1].credential().clone());
+        handshake(&initiator, &responder).unwrap();
+
+        // suite 2 is offered after 0, which the Responder supports and so
+        // should have been selected
+        let x = super::generate(CipherSuite::Suite2).unwrap();
+        let initiator = Initiator::new(Method::SignatureSignature, p256_identity(4));
+        let (_, message_1) = initiator.message_1(&[0, 2], x, vec![0x05]).unwrap();
+        match responder.message_2(&message_1, None, None) {
+            Err(super::Refusal::WrongSuite) => (),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn ephemeral_keys_are_checked() {
+        // beyond the field P-256 is over
+        assert!(decode_public(CipherSuite::Suite2, &[0xff; 32]).is_err());
+        assert!(decode_public(CipherSuite::Suite2, &[1; 31]).is_err());
+        assert!(decode_public(CipherSuite::Suite0, &[9; 33]).is_err());
+        assert!(decode_public(CipherSuite::Suite0, &[9; 32]).is_ok());
+    }
+
+    #[test]
+    fn key_exchange_over_coap() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let (i, r) = (x25519(1), ed25519(2));
+        let contexts = ContextStore::new();
+
+        let router = Router::new()
+            .with_route(Code::Post, "/.well-known/edhoc", Responder::new(r.clone(), contexts.clone())
+                .with_peer(i.credential().clone()))
+            .with_route(Code::Get, "/secret", |request: Request| {
+                let code = if request.oscore_context().is_some() { Code::Content } else { Code::Unauthorized };
+
+                Ok::<_, Error>(Message::new().with_code(code))
+            });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).unwrap()
+            .with_oscore(contexts.clone());
+        let url = format!("coap://{}", server.local_addr());
+        runtime.spawn(server.serve(router).map_err(|e| panic!("{:?}", e)));
+
+        let initiator = Initiator::new(Method::StaticDhSignature, i).with_peer(r.credential().clone());
+        let context = runtime.block_on(Client::edhoc(&url, &initiator)).unwrap();
+        assert!(contexts.get(context.sender_id(), None).is_some());
+
+        let request = Client::get(&format!("{}/secret", url)).unwrap()
+            .with_oscore(context)
+            .send();
+        assert_eq!(runtime.block_on(request).unwrap().code, Code::Content);
+
+        // a Responder that doesn't trust the Initiator refuses it
+        let stranger = Initiator::new(Method::StaticDhSignature, x25519(3)).with_peer(r.credential().clone());
+        match runtime.block_on(Client::edhoc(&url, &stranger)) {
+            Err(Error::Edhoc(EdhocError::Rejected(_))) => (),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+}
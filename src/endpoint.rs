@@ -9,6 +9,28 @@ use tokio_dns;
 use error::Error;
 use client::IoFuture;
 
+/// The URI schemes a request can be made with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scheme {
+    /// RFC 7252: 6.1.  coap URI Scheme
+    Coap,
+    /// RFC 7252: 6.2.  coaps URI Scheme
+    Coaps,
+    /// RFC 8323: 8.1.  coap+tcp URI Scheme
+    CoapTcp,
+    /// RFC 8323: 8.2.  coaps+tcp URI Scheme
+    CoapsTcp,
+}
+
+impl Scheme {
+    pub fn default_port(&self) -> u16 {
+        match *self {
+            Scheme::Coap | Scheme::CoapTcp => 5683,
+            Scheme::Coaps | Scheme::CoapsTcp => 5684,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Endpoint {
     Unset,
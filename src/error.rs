@@ -1,4 +1,7 @@
+#[cfg(feature = "edhoc")]
+use cbor::Malformed;
 use message::Error as MessageError;
+use message::Message;
 use std::io::Error as IoError;
 use std::str::Utf8Error;
 use url::ParseError;
@@ -20,17 +23,99 @@ pub enum UrlError {
     __AlwaysWildcardMatchThisListMayChange,
 }
 
+#[derive(Debug)]
+pub enum BlockError {
+    /// The body exceeded the configured maximum size, or had more blocks
+    /// than a block option can number
+    TooLarge,
+    /// A block didn't follow on from the previous one
+    Inconsistent,
+    /// The representation changed while it was being transferred
+    Changed,
+
+    #[doc(hidden)]
+    __AlwaysWildcardMatchThisListMayChange,
+}
+
+#[derive(Debug)]
+pub enum LinkFormatError {
+    /// The document was not valid utf8
+    NonUtf8(Utf8Error),
+    /// The document was malformed at the given byte offset
+    Syntax(usize),
+
+    #[doc(hidden)]
+    __AlwaysWildcardMatchThisListMayChange,
+}
+
+/// Why a message couldn't be protected or verified with OSCORE.
+#[cfg(feature = "oscore")]
+#[derive(Debug)]
+pub enum OscoreError {
+    /// The OSCORE option or the decrypted message was malformed
+    Malformed,
+    /// There's no security context for the key ID the request was sent with
+    UnknownContext,
+    /// The message was already received, or is too old to tell
+    Replay,
+    /// The message failed authentication
+    DecryptionFailed,
+    /// A response to a protected request wasn't protected
+    Unprotected,
+    /// The sender sequence numbers have run out, the context must be renewed
+    SequenceExhausted,
+
+    #[doc(hidden)]
+    __AlwaysWildcardMatchThisListMayChange,
+}
+
+/// Why an EDHOC key exchange failed.
+#[cfg(feature = "edhoc")]
+#[derive(Debug)]
+pub enum EdhocError {
+    /// A message was malformed, or carried critical external authorization
+    /// data that isn't supported
+    Malformed,
+    /// The peers have no cipher suite and method in common
+    Unsupported,
+    /// The peer's credential isn't one of those trusted
+    UnknownCredential,
+    /// The peer's signature or MAC didn't verify, or a message didn't decrypt
+    AuthenticationFailed,
+    /// A message_3 didn't follow a message_2, or came too late
+    UnknownConnection,
+    /// The peer sent an error message, with its diagnostic
+    Rejected(String),
+
+    #[doc(hidden)]
+    __AlwaysWildcardMatchThisListMayChange,
+}
+
 /// All errors returned from this crate.
 #[derive(Debug)]
 pub enum Error {
     /// A timeout was reached while waiting for a reply or event
     Timeout,
+    /// The peer rejected the message with a reset
+    Reset,
     /// A message was unable to be parsed successfully.
     Message(MessageError),
     /// The system IO returned an error.
     Io(IoError),
     /// Error when attempting to parse a url
     Url(UrlError),
+    /// The server replied with a 4.xx or 5.xx response code
+    Response(Message),
+    /// A block-wise transfer could not be completed
+    Block(BlockError),
+    /// A link-format document could not be parsed
+    LinkFormat(LinkFormatError),
+    /// A message could not be protected or verified with OSCORE
+    #[cfg(feature = "oscore")]
+    Oscore(OscoreError),
+    /// An EDHOC key exchange failed
+    #[cfg(feature = "edhoc")]
+    Edhoc(EdhocError),
 
     #[doc(hidden)]
     __AlwaysWildcardMatchThisListWillChange,
@@ -42,6 +127,39 @@ impl From<UrlError> for Error {
     }
 }
 
+impl From<BlockError> for Error {
+    fn from(e: BlockError) -> Error {
+        Error::Block(e)
+    }
+}
+
+impl From<LinkFormatError> for Error {
+    fn from(e: LinkFormatError) -> Error {
+        Error::LinkFormat(e)
+    }
+}
+
+#[cfg(feature = "oscore")]
+impl From<OscoreError> for Error {
+    fn from(e: OscoreError) -> Error {
+        Error::Oscore(e)
+    }
+}
+
+#[cfg(feature = "edhoc")]
+impl From<EdhocError> for Error {
+    fn from(e: EdhocError) -> Error {
+        Error::Edhoc(e)
+    }
+}
+
+#[cfg(feature = "edhoc")]
+impl From<Malformed> for EdhocError {
+    fn from(_: Malformed) -> EdhocError {
+        EdhocError::Malformed
+    }
+}
+
 impl From<MessageError> for Error {
     fn from(e: MessageError) -> Error {
         Error::Message(e)
@@ -12,12 +12,45 @@ extern crate arrayvec;
 extern crate log;
 extern crate url;
 extern crate percent_encoding;
+extern crate rand;
+#[cfg(any(feature = "dtls", feature = "oscore"))]
+extern crate openssl;
+#[cfg(feature = "dtls")]
+extern crate openssl_sys;
+#[cfg(feature = "dtls")]
+extern crate foreign_types;
 
 pub mod client;
+#[cfg(feature = "oscore")]
+mod cbor;
 pub mod codec;
+#[cfg(feature = "dtls")]
+pub mod dtls;
+#[cfg(feature = "edhoc")]
+pub mod edhoc;
 pub mod endpoint;
 pub mod error;
+pub mod link_format;
 pub mod message;
+#[cfg(feature = "oscore")]
+pub mod oscore;
+pub mod router;
+pub mod server;
+pub mod socket;
+mod tcp;
+#[cfg(test)]
+mod testing;
+pub mod transmission;
 
-pub use client::Client;
-pub use endpoint::Endpoint;
+pub use client::{Client, Connections, MessageIds};
+#[cfg(feature = "dtls")]
+pub use dtls::{DtlsConfig, PeerIdentity, PskStore};
+#[cfg(feature = "edhoc")]
+pub use edhoc::{Credential, Identity, Initiator, Responder};
+pub use endpoint::{Endpoint, Scheme};
+#[cfg(feature = "oscore")]
+pub use oscore::{ContextStore, SecurityContext};
+pub use router::Router;
+pub use server::{Handler, Observable, Request, Server};
+pub use socket::Socket;
+pub use transmission::TransmissionParameters;
@@ -0,0 +1,447 @@
+//! RFC 6690: Constrained RESTful Environments (CoRE) Link Format
+//!
+//! The `application/link-format` documents servers describe their resources
+//! with, most notably at `/.well-known/core`.
+
+use std::fmt;
+use std::option::Option as StdOption;
+use std::str;
+
+use error::LinkFormatError;
+
+/// Content-Format of `application/link-format` documents.
+pub const CONTENT_FORMAT: u64 = 40;
+
+/// A link to a resource with its target attributes.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Link {
+    /// the URI-reference the link points to, usually an absolute path
+    pub target: String,
+    /// attribute names and values, in order; an attribute can appear more
+    /// than once and need not have a value
+    pub attributes: Vec<(String, StdOption<String>)>,
+}
+
+impl Link {
+    pub fn new(target: &str) -> Link {
+        Link {
+            target: target.to_owned(),
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn with_attribute(mut self, name: &str, value: &str) -> Self {
+        self.attributes.push((name.to_owned(), Some(value.to_owned())));
+        self
+    }
+
+    /// Add an attribute without a value, like `obs`.
+    pub fn with_flag(mut self, name: &str) -> Self {
+        self.attributes.push((name.to_owned(), None));
+        self
+    }
+
+    /// Resource type, RFC 6690: 3.1.
+    pub fn with_rt(self, rt: &str) -> Self {
+        self.with_attribute("rt", rt)
+    }
+
+    /// Interface description, RFC 6690: 3.2.
+    pub fn with_if(self, interface: &str) -> Self {
+        self.with_attribute("if", interface)
+    }
+
+    /// Content-Format hint, RFC 7252: 7.2.1.
+    pub fn with_ct(self, ct: u64) -> Self {
+        self.with_attribute("ct", &ct.to_string())
+    }
+
+    /// Maximum size estimate, RFC 6690: 3.3.
+    pub fn with_sz(self, sz: usize) -> Self {
+        self.with_attribute("sz", &sz.to_string())
+    }
+
+    /// Observable, RFC 7641: 6.
+    pub fn with_obs(self) -> Self {
+        self.with_flag("obs")
+    }
+
+    pub fn with_title(self, title: &str) -> Self {
+        self.with_attribute("title", title)
+    }
+
+    /// The value of the first attribute called `name`, `Some("")` if it has
+    /// no value.
+    pub fn attribute(&self, name: &str) -> StdOption<&str> {
+        self.attribute_all(name).into_iter().next()
+    }
+
+    /// The values of every attribute called `name`.
+    pub fn attribute_all(&self, name: &str) -> Vec<&str> {
+        self.attributes.iter()
+            .filter(|&&(ref n, _)| n == name)
+            .map(|&(_, ref value)| value.as_ref().map_or("", String::as_str))
+            .collect()
+    }
+
+    /// The values of every attribute called `name`, with space separated
+    /// lists split into their members, as used by `rt`, `if` and `ct`.
+    pub fn values(&self, name: &str) -> Vec<&str> {
+        self.attribute_all(name)
+            .into_iter()
+            .flat_map(|value| value.split_whitespace())
+            .collect()
+    }
+
+    pub fn rt(&self) -> Vec<&str> {
+        self.values("rt")
+    }
+
+    pub fn interfaces(&self) -> Vec<&str> {
+        self.values("if")
+    }
+
+    /// The Content-Formats the resource can be requested in, ignoring any
+    /// that aren't numbers.
+    pub fn ct(&self) -> Vec<u64> {
+        self.values("ct").into_iter().filter_map(|ct| ct.parse().ok()).collect()
+    }
+
+    pub fn sz(&self) -> StdOption<usize> {
+        self.attribute("sz").and_then(|sz| sz.parse().ok())
+    }
+
+    pub fn obs(&self) -> bool {
+        self.attribute("obs").is_some()
+    }
+
+    pub fn title(&self) -> StdOption<&str> {
+        self.attribute("title")
+    }
+
+    /// Whether the link passes the filter `name=pattern` of a
+    /// `/.well-known/core` query, where `pattern` may end in a `*` to match
+    /// any value with that prefix.
+    ///
+    /// `href` filters on the target. Values of space separated attributes,
+    /// like `rt`, match if any one of them does.
+    /// RFC 6690: 4.1.  Query Filtering
+    pub fn matches(&self, name: &str, pattern: &str) -> bool {
+        let matches = |value: &str| {
+            if pattern.ends_with('*') {
+                value.starts_with(&pattern[..pattern.len() - 1])
+            } else {
+                value == pattern
+            }
+        };
+
+        if name == "href" {
+            return matches(&self.target);
+        }
+
+        self.attributes.iter()
+            .filter(|&&(ref n, _)| n == name)
+            .any(|&(_, ref value)| {
+                let value = value.as_ref().map_or("", String::as_str);
+
+                matches(value) || value.split(' ').any(&matches)
+            })
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{}>", self.target)?;
+
+        for &(ref name, ref value) in &self.attributes {
+            match *value {
+                None => write!(f, ";{}", name)?,
+                Some(ref value) if is_cardinal(name, value) => write!(f, ";{}={}", name, value)?,
+                Some(ref value) => write!(f, ";{}=\"{}\"", name, escape(value))?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `value` can go unquoted: only attributes whose grammar allows a
+/// bare number, RFC 6690: 2 (`sz`) and RFC 7252: 7.2.1 (`ct`).
+fn is_cardinal(name: &str, value: &str) -> bool {
+    (name == "sz" || name == "ct") && !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Quote `"` and `\` for use in a quoted-string.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Parse an `application/link-format` document.
+///
+/// Whitespace is allowed around the separators, as some servers put each
+/// link on a line of its own.
+/// RFC 6690: 2.  Link Format
+pub fn parse(document: &str) -> Result<Vec<Link>, LinkFormatError> {
+    let mut parser = Parser { document: document, position: 0 };
+    let mut links = Vec::new();
+
+    parser.skip_whitespace();
+    if parser.peek().is_none() {
+        return Ok(links);
+    }
+
+    loop {
+        links.push(parser.link()?);
+
+        parser.skip_whitespace();
+        match parser.next() {
+            None => return Ok(links),
+            Some(',') => parser.skip_whitespace(),
+            Some(_) => return Err(parser.error()),
+        }
+    }
+}
+
+/// Parse an `application/link-format` payload.
+pub fn parse_bytes(payload: &[u8]) -> Result<Vec<Link>, LinkFormatError> {
+    parse(str::from_utf8(payload).map_err(LinkFormatError::NonUtf8)?)
+}
+
+struct Parser<'a> {
+    document: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> StdOption<char> {
+        self.document[self.position..].chars().next()
+    }
+
+    fn next(&mut self) -> StdOption<char> {
+        let c = self.peek();
+        self.position += c.map_or(0, char::len_utf8);
+        c
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map_or(false, char::is_whitespace) {
+            self.next();
+        }
+    }
+
+    /// Take characters up to the first one `end` matches.
+    fn take_until<F: Fn(char) -> bool>(&mut self, end: F) -> &'a str {
+        let start = self.position;
+        while self.peek().map_or(false, |c| !end(c)) {
+            self.next();
+        }
+
+        &self.document[start..self.position]
+    }
+
+    /// The error for the character last read.
+    fn error(&self) -> LinkFormatError {
+        let position = self.document[..self.position]
+            .char_indices()
+            .next_back()
+            .map_or(0, |(i, _)| i);
+
+        LinkFormatError::Syntax(position)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), LinkFormatError> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(_) => Err(self.error()),
+            None => Err(LinkFormatError::Syntax(self.position)),
+        }
+    }
+
+    /// link-value = "<" URI-Reference ">" *( ";" link-param )
+    fn link(&mut self) -> Result<Link, LinkFormatError> {
+        self.expect('<')?;
+        let target = self.take_until(|c| c == '>').to_owned();
+        self.expect('>')?;
+
+        let mut link = Link {
+            target: target,
+            attributes: Vec::new(),
+        };
+
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(';') {
+                return Ok(link);
+            }
+
+            self.next();
+            self.skip_whitespace();
+            link.attributes.push(self.param()?);
+        }
+    }
+
+    /// link-param = parmname [ "=" ( ptoken / quoted-string ) ]
+    fn param(&mut self) -> Result<(String, StdOption<String>), LinkFormatError> {
+        let name = self.take_until(|c| c == '=' || c == ';' || c == ',' || c == '"' || c.is_whitespace());
+        if name.is_empty() {
+            return Err(LinkFormatError::Syntax(self.position));
+        }
+
+        self.skip_whitespace();
+        if self.peek() != Some('=') {
+            return Ok((name.to_owned(), None));
+        }
+
+        self.next();
+        self.skip_whitespace();
+
+        let value = if self.peek() == Some('"') {
+            self.quoted_string()?
+        } else {
+            self.take_until(|c| c == ';' || c == ',' || c.is_whitespace()).to_owned()
+        };
+
+        Ok((name.to_owned(), Some(value)))
+    }
+
+    /// A quoted-string, where a backslash escapes the character following it.
+    fn quoted_string(&mut self) -> Result<String, LinkFormatError> {
+        self.expect('"')?;
+
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(value),
+                Some('\\') => match self.next() {
+                    Some(c) => value.push(c),
+                    None => return Err(LinkFormatError::Syntax(self.position)),
+                },
+                Some(c) => value.push(c),
+                None => return Err(LinkFormatError::Syntax(self.position)),
+            }
+        }
+    }
+}
+
+/// Write `links` as an `application/link-format` document.
+pub fn serialize(links: &[Link]) -> String {
+    links.iter()
+        .map(Link::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, serialize, Link};
+    use error::LinkFormatError;
+
+    #[test]
+    fn serialize_links() {
+        let links = vec![
+            Link::new("/sensors/temp").with_rt("temperature-c").with_if("sensor").with_ct(0).with_obs(),
+            Link::new("/sensors/light").with_title("Light \"lux\", a\\b").with_sz(64),
+        ];
+
+        assert_eq!(serialize(&links),
+                   "</sensors/temp>;rt=\"temperature-c\";if=\"sensor\";ct=0;obs,\
+                    </sensors/light>;title=\"Light \\\"lux\\\", a\\\\b\";sz=64");
+    }
+
+    #[test]
+    fn only_numeric_attributes_are_unquoted() {
+        let link = Link::new("/42").with_title("42").with_rt("7").with_attribute("ct", "40").with_sz(0);
+
+        assert_eq!(link.to_string(), "</42>;title=\"42\";rt=\"7\";ct=40;sz=0");
+    }
+
+    #[test]
+    fn query_filtering() {
+        let link = Link::new("/sensors/temp").with_rt("temperature-c outdoor").with_ct(0).with_obs();
+
+        assert!(link.matches("href", "/sensors/temp"));
+        assert!(link.matches("href", "/sensors*"));
+        assert!(!link.matches("href", "/sensors"));
+        assert!(link.matches("rt", "outdoor"));
+        assert!(link.matches("rt", "temperature*"));
+        assert!(link.matches("rt", "temperature-c outdoor"));
+        assert!(!link.matches("rt", "light"));
+        assert!(link.matches("ct", "0"));
+        assert!(link.matches("obs", ""));
+        assert!(!link.matches("title", "*"));
+    }
+
+    #[test]
+    fn parse_links() {
+        let document = "</sensors/temp>;rt=\"temperature-c outdoor\";if=sensor;ct=\"0 40\";obs,\n\
+                        </sensors/light> ; title=\"Light, \\\"lux\\\"\";sz=64 ;rt=light,\
+                        <coap://[2001:db8::1]/a>;anchor=\"/\";rel=describedby";
+
+        let links = parse(document).unwrap();
+        assert_eq!(links.len(), 3);
+
+        assert_eq!(links[0].target, "/sensors/temp");
+        assert_eq!(links[0].rt(), vec!["temperature-c", "outdoor"]);
+        assert_eq!(links[0].interfaces(), vec!["sensor"]);
+        assert_eq!(links[0].ct(), vec![0, 40]);
+        assert!(links[0].obs());
+
+        assert_eq!(links[1].target, "/sensors/light");
+        assert_eq!(links[1].title(), Some("Light, \"lux\""));
+        assert_eq!(links[1].sz(), Some(64));
+        assert!(!links[1].obs());
+
+        assert_eq!(links[2].target, "coap://[2001:db8::1]/a");
+        assert_eq!(links[2].attribute("anchor"), Some("/"));
+        assert_eq!(links[2].attribute("rel"), Some("describedby"));
+    }
+
+    #[test]
+    fn parse_multi_valued_attributes() {
+        let links = parse("</a>;rt=one;rt=\"two three\"").unwrap();
+
+        assert_eq!(links[0].attribute_all("rt"), vec!["one", "two three"]);
+        assert_eq!(links[0].rt(), vec!["one", "two", "three"]);
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let links = vec![
+            Link::new("/sensors/temp").with_rt("temperature-c").with_ct(0).with_obs(),
+            Link::new("/a,b").with_title("x;y,\"z\"\\").with_attribute("empty", ""),
+        ];
+
+        assert_eq!(parse(&serialize(&links)).unwrap(), links);
+        assert_eq!(parse("").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        match parse("</a>;rt=\"unterminated") {
+            Err(LinkFormatError::Syntax(21)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        match parse("</a>,/b") {
+            Err(LinkFormatError::Syntax(5)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        match parse("</a> </b>") {
+            Err(LinkFormatError::Syntax(5)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        assert!(parse("</a>;=1").is_err());
+        assert!(parse("</a").is_err());
+    }
+}
@@ -4,7 +4,7 @@ use self::option::Options;
 
 use arrayvec::ArrayVec;
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Clone, Debug)]
 pub struct Message {
     pub version: u8,
     pub mtype: Mtype,
@@ -23,7 +23,7 @@ pub enum Error {
     UnrecognizedCriticalOption, // TODO: use
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 #[repr(u8)]
 pub enum Mtype {
     Confirmable,
@@ -53,18 +53,22 @@ impl Mtype {
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum Code {
     Empty,
     Get,
     Post,
     Put,
     Delete,
+    Fetch,
+    Patch,
+    IPatch,
     Created,
     Deleted,
     Valid,
     Changed,
     Content,
+    Continue,
     BadRequest,
     Unauthorized,
     BadOption,
@@ -72,6 +76,7 @@ pub enum Code {
     NotFound,
     MethodNotAllowed,
     NotAcceptable,
+    RequestEntityIncomplete,
     PreconditionFailed,
     RequestEntityTooLarge,
     UnsupportedContentFormat,
@@ -81,6 +86,12 @@ pub enum Code {
     ServiceUnavailable,
     GatewayTimeout,
     ProxyingNotSupported,
+    /// RFC 8323: 5.  CoAP over Reliable Transports: Signaling
+    Csm,
+    Ping,
+    Pong,
+    Release,
+    Abort,
     Unknown(u8),
 }
 
@@ -92,11 +103,15 @@ impl Code {
             2 => Code::Post,
             3 => Code::Put,
             4 => Code::Delete,
+            5 => Code::Fetch,
+            6 => Code::Patch,
+            7 => Code::IPatch,
             65 => Code::Created,
             66 => Code::Deleted,
             67 => Code::Valid,
             68 => Code::Changed,
             69 => Code::Content,
+            95 => Code::Continue,
             128 => Code::BadRequest,
             129 => Code::Unauthorized,
             130 => Code::BadOption,
@@ -104,15 +119,21 @@ impl Code {
             132 => Code::NotFound,
             133 => Code::MethodNotAllowed,
             134 => Code::NotAcceptable,
+            136 => Code::RequestEntityIncomplete,
             140 => Code::PreconditionFailed,
             141 => Code::RequestEntityTooLarge,
-            142 => Code::UnsupportedContentFormat,
+            143 => Code::UnsupportedContentFormat,
             160 => Code::InternalServerError,
             161 => Code::NotImplemented,
             162 => Code::BadGateway,
             163 => Code::ServiceUnavailable,
             164 => Code::GatewayTimeout,
             165 => Code::ProxyingNotSupported,
+            225 => Code::Csm,
+            226 => Code::Ping,
+            227 => Code::Pong,
+            228 => Code::Release,
+            229 => Code::Abort,
             _ => Code::Unknown(raw_code),
         }
     }
@@ -124,11 +145,15 @@ impl Code {
             Code::Post => Self::build(0, 02),
             Code::Put => Self::build(0, 03),
             Code::Delete => Self::build(0, 04),
+            Code::Fetch => Self::build(0, 05),
+            Code::Patch => Self::build(0, 06),
+            Code::IPatch => Self::build(0, 07),
             Code::Created => Self::build(2, 01),
             Code::Deleted => Self::build(2, 02),
             Code::Valid => Self::build(2, 03),
             Code::Changed => Self::build(2, 04),
             Code::Content => Self::build(2, 05),
+            Code::Continue => Self::build(2, 31),
             Code::BadRequest => Self::build(4, 00),
             Code::Unauthorized => Self::build(4, 01),
             Code::BadOption => Self::build(4, 02),
@@ -136,6 +161,7 @@ impl Code {
             Code::NotFound => Self::build(4, 04),
             Code::MethodNotAllowed => Self::build(4, 05),
             Code::NotAcceptable => Self::build(4, 06),
+            Code::RequestEntityIncomplete => Self::build(4, 08),
             Code::PreconditionFailed => Self::build(4, 12),
             Code::RequestEntityTooLarge => Self::build(4, 13),
             Code::UnsupportedContentFormat => Self::build(4, 15),
@@ -145,6 +171,11 @@ impl Code {
             Code::ServiceUnavailable => Self::build(5, 03),
             Code::GatewayTimeout => Self::build(5, 04),
             Code::ProxyingNotSupported => Self::build(5, 05),
+            Code::Csm => Self::build(7, 01),
+            Code::Ping => Self::build(7, 02),
+            Code::Pong => Self::build(7, 03),
+            Code::Release => Self::build(7, 04),
+            Code::Abort => Self::build(7, 05),
             Code::Unknown(code) => code,
         }
     }
@@ -161,6 +192,32 @@ impl Code {
     pub fn detail(&self) -> u8 {
         self.as_u8() & 0x1F
     }
+
+    /// Is this a method code, ie 0.01-0.31
+    pub fn is_request(&self) -> bool {
+        self.class() == 0 && *self != Code::Empty
+    }
+
+    /// Is this a response code, ie 2.xx, 4.xx or 5.xx
+    pub fn is_response(&self) -> bool {
+        match self.class() {
+            2 | 4 | 5 => true,
+            _ => false,
+        }
+    }
+
+    /// Is this a signaling code, ie 7.xx, only used over reliable transports
+    pub fn is_signaling(&self) -> bool {
+        self.class() == 7
+    }
+
+    /// Is this a client (4.xx) or server (5.xx) error response code
+    pub fn is_error(&self) -> bool {
+        match self.class() {
+            4 | 5 => true,
+            _ => false,
+        }
+    }
 }
 
 impl Message {
@@ -177,12 +234,31 @@ impl Message {
         }
     }
 
+    /// Build a response to this request, piggybacked on its acknowledgement.
+    ///
+    /// A server that can't answer straight away should acknowledge the request
+    /// with `new_ack` instead and send the response in a message of its own,
+    /// as `Server` does.
     pub fn new_reply(&self) -> Self {
         Self::new().with_token(&self.token)
                    .with_mid(self.mid)
                    .with_mtype(Mtype::Acknowledgement)
     }
 
+    /// Build the empty acknowledgement for this message.
+    pub fn new_ack(&self) -> Self {
+        Self::new().with_mid(self.mid)
+                   .with_mtype(Mtype::Acknowledgement)
+                   .with_code(Code::Empty)
+    }
+
+    /// Build the empty reset used to reject this message.
+    pub fn new_reset(&self) -> Self {
+        Self::new().with_mid(self.mid)
+                   .with_mtype(Mtype::Reset)
+                   .with_code(Code::Empty)
+    }
+
     pub fn with_mtype(mut self, mtype: Mtype) -> Self {
         self.mtype = mtype;
         self
@@ -217,6 +293,16 @@ impl Message {
         self
     }
 
+    /// Turn a 4.xx or 5.xx response into an `Error::Response`, passing any
+    /// other message through unchanged.
+    pub fn error_for_code(self) -> Result<Message, ::error::Error> {
+        if self.code.is_error() {
+            Err(::error::Error::Response(self))
+        } else {
+            Ok(self)
+        }
+    }
+
     pub fn from_bytes(pkt: &[u8]) -> Result<Message, Error> {
         let mut i: usize;
 
@@ -251,37 +337,18 @@ impl Message {
                 break;
             }
 
-            // Note: length errors for 13 & 14 will be caught in the check below.
-            let delta = match pkt[i] >> 4 {
-                d @ 0...12 => d as u16,
-                13 => {
-                    i += 1;
-                    pkt[i] as u16 + 13
-                }
-                14 => {
-                    i += 2;
-                    (((pkt[i - 1] as u16) << 8) | pkt[i] as u16) + 269
-                }
-                15 => return Err(Error::MessageFormat),
-                _ => unreachable!(),
-            };
-            let length = match pkt[i] & 0x0F {
-                d @ 0...12 => d as u16,
-                13 => {
-                    i += 1;
-                    pkt[i] as u16 + 13
-                }
-                14 => {
-                    i += 2;
-                    ((pkt[i - 1] as u16) << 8) | pkt[i] as u16 + 269
-                }
-                15 => return Err(Error::MessageFormat),
-                _ => unreachable!(),
-            };
-
+            let header = pkt[i];
             i += 1;
 
-            let option_number = option_number_offset + delta;
+            let delta = extended_value(header >> 4, pkt, &mut i)?;
+            let length = extended_value(header & 0x0F, pkt, &mut i)?;
+
+            let option_number = option_number_offset as u32 + delta;
+            if option_number > u16::max_value() as u32 {
+                return Err(Error::MessageFormat);
+            }
+
+            let option_number = option_number as u16;
             option_number_offset = option_number;
 
             if length >= 65000 {
@@ -358,6 +425,30 @@ impl Message {
 }
 
 
+/// Decode an option delta or length nibble, reading any extended bytes that
+/// follow the option header.
+/// RFC 7252: 3.1.  Option Format
+fn extended_value(nibble: u8, pkt: &[u8], i: &mut usize) -> Result<u32, Error> {
+    match nibble {
+        0...12 => Ok(nibble as u32),
+        13 => {
+            let value = *pkt.get(*i).ok_or(Error::MessageFormat)? as u32 + 13;
+            *i += 1;
+            Ok(value)
+        }
+        14 => {
+            if pkt.len() < *i + 2 {
+                return Err(Error::MessageFormat);
+            }
+
+            let value = ((pkt[*i] as u32) << 8 | pkt[*i + 1] as u32) + 269;
+            *i += 2;
+            Ok(value)
+        }
+        _ => Err(Error::MessageFormat),
+    }
+}
+
 #[test]
 fn test_msg_parse_empty() {
     let ref_bin = [64, 0, 0, 0];
@@ -488,3 +579,90 @@ fn test_msg_encode_get_con_with_opts() {
         assert_eq!(test_bin[i], ref_bin[i]);
     }
 }
+
+#[test]
+fn test_code_round_trip() {
+    for raw in 0..=255u8 {
+        assert_eq!(Code::from_u8(raw).as_u8(), raw);
+    }
+
+    assert_eq!(Code::from_u8(5), Code::Fetch);
+    assert_eq!(Code::from_u8(6), Code::Patch);
+    assert_eq!(Code::from_u8(7), Code::IPatch);
+    assert_eq!(Code::from_u8(136), Code::RequestEntityIncomplete);
+    assert_eq!(Code::from_u8(225), Code::Csm);
+    assert_eq!(Code::from_u8(229), Code::Abort);
+    assert!(Code::Ping.is_signaling() && !Code::Ping.is_request());
+}
+
+#[test]
+fn test_msg_parse_truncated_option_header() {
+    assert_eq!(Message::from_bytes(&[0x40, 0x01, 0x00, 0x00, 0xD0]), Err(Error::MessageFormat));
+    assert_eq!(Message::from_bytes(&[0x40, 0x01, 0x00, 0x00, 0xE0, 0x01]), Err(Error::MessageFormat));
+    assert_eq!(Message::from_bytes(&[0x40, 0x01, 0x00, 0x00, 0x0D]), Err(Error::MessageFormat));
+}
+
+#[test]
+fn test_msg_round_trip_extended_option_delta() {
+    use self::option::{Option, Options, ProxyUri};
+
+    let mut opts = Options::new();
+    opts.push(ProxyUri::new(::std::iter::repeat('a').take(300).collect()));
+
+    let msg = Message::new().with_code(Code::Content);
+    let msg = Message { options: opts, ..msg };
+
+    assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+}
+
+#[test]
+fn test_block_option_encoding() {
+    use self::option::{Block, Block2, Byteable, Option};
+
+    let block = Block2::new(Block::new(5, true, 2));
+
+    assert_eq!(block.to_bytes().as_ref(), [0x5A]);
+    assert_eq!(Block2::from_bytes(&[0x5A]).unwrap(), block);
+    assert_eq!(block.value.size(), 64);
+    assert_eq!(block.value.offset(), 320);
+
+    let block = Block2::from_bytes(&[]).unwrap();
+    assert_eq!(block.value, Block::new(0, false, 0));
+
+    let block = Block2::from_bytes(&[0x12, 0x34, 0x56]).unwrap();
+    assert_eq!(block.value, Block::new(0x12345, false, 6));
+
+    assert!(Block2::from_bytes(&[0x0F]).is_err());
+    assert!(Block2::from_bytes(&[0, 0, 0, 0]).is_err());
+
+    assert_eq!(Block::szx_for(16), 0);
+    assert_eq!(Block::szx_for(100), 2);
+    assert_eq!(Block::szx_for(1024), 6);
+    assert_eq!(Block::szx_for(4096), 6);
+}
+
+#[test]
+fn test_block_at_offset() {
+    use self::option::Block;
+
+    assert_eq!(Block::at(0, true, 2), Some(Block::new(0, true, 2)));
+    assert_eq!(Block::at(320, false, 2), Some(Block::new(5, false, 2)));
+    assert_eq!(Block::at(1024, false, 4), Some(Block::new(4, false, 4)));
+    assert_eq!(Block::at(((1 << 20) - 1) * 16, false, 0), Some(Block::new((1 << 20) - 1, false, 0)));
+    assert_eq!(Block::at((1 << 20) * 16, false, 0), None);
+}
+
+#[test]
+fn test_msg_round_trip_extended_option_headers() {
+    use self::option::{Block, Block2, Option, Options, ProxyUri, Size2};
+
+    let mut opts = Options::new();
+    opts.push(Block2::new(Block::new(1, true, 2)));
+    opts.push(Size2::new(300));
+    opts.push(ProxyUri::new(::std::iter::repeat('a').take(300).collect()));
+
+    let msg = Message::new().with_code(Code::Content);
+    let msg = Message { options: opts, ..msg };
+
+    assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+}
@@ -5,7 +5,7 @@ use message::Error;
 
 use std::option::Option as StdOption;
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Clone, Debug)]
 pub struct Options {
     pub map: BTreeMap<u16, Vec<Vec<u8>>>,
 }
@@ -43,11 +43,28 @@ impl Options {
                       .collect())
     }
 
+    /// Like `get`, but returns an error rather than panicking if any of the
+    /// values are malformed.
+    pub fn try_get<T: Option>(&self) -> Result<StdOption<Vec<T>>, Error> {
+        match self.map.get(&<T as Option>::NUMBER) {
+            Some(values) => values.iter()
+                                  .map(|v| <T as Option>::from_bytes(v.as_ref()))
+                                  .collect::<Result<Vec<T>, Error>>()
+                                  .map(Some),
+            None => Ok(None),
+        }
+    }
+
     pub fn get_raw<T: Option>(&self) -> StdOption<Vec<Vec<u8>>> {
         self.map
             .get(&<T as Option>::NUMBER)
             .map(|v| v.to_owned() )
     }
+
+    /// Remove every value of the option.
+    pub fn remove<T: Option>(&mut self) {
+        self.map.remove(&<T as Option>::NUMBER);
+    }
 }
 
 pub struct RawOptionsIterator<'a> {
@@ -275,7 +292,7 @@ macro_rules! option {
     ($num: expr, $name: ident, uint, $min: expr, $max: expr) => {
         #[derive(PartialEq, Eq, Debug)]
         pub struct $name {
-            value: u64
+            pub value: u64
         }
 
         impl Option for $name {
@@ -326,6 +343,123 @@ macro_rules! option {
                 }
             }
         }
+    };
+
+    // Block Type Options
+    ($num: expr, $name: ident, block, $min: expr, $max: expr) => {
+        #[derive(PartialEq, Eq, Clone, Copy, Debug)]
+        pub struct $name {
+            pub value: Block
+        }
+
+        impl Option for $name {
+            const NUMBER: u16 = $num;
+            type Format = Block;
+
+            fn new(value: Block) -> Self {
+                $name{value: value}
+            }
+
+            fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+                if bytes.len() >= $min as usize && bytes.len() <= $max as usize {
+                    Ok($name{value: Block::from_value(bytes_to_value(bytes))?})
+                } else {
+                    Err(Error::MessageFormat)
+                }
+            }
+
+        }
+
+        impl Byteable for $name {
+            fn number(&self) -> u16 {
+                $num
+            }
+
+            fn to_bytes(&self) -> Cow<[u8]> {
+                Cow::Owned(value_to_bytes(self.value.value()))
+            }
+
+            fn bytes_len(&self) -> usize {
+                value_to_bytes(self.value.value()).len()
+            }
+        }
+    }
+}
+
+/// The value of a block option, carried as a uint.
+/// RFC 7959: 2.2.  Structure of a Block Option
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Block {
+    /// the number of the block within the sequence of blocks
+    pub num: u32,
+    /// whether more blocks follow this one
+    pub more: bool,
+    /// the block size exponent, the size is 2**(SZX + 4) bytes
+    pub szx: u8,
+}
+
+impl Block {
+    pub fn new(num: u32, more: bool, szx: u8) -> Block {
+        assert!(num < 1 << 20, "block number out of range");
+        assert!(szx <= 6, "block size exponent out of range");
+
+        Block {
+            num: num,
+            more: more,
+            szx: szx,
+        }
+    }
+
+    /// The block of size 2**(`szx` + 4) bytes containing the byte at
+    /// `offset`, or `None` if its number doesn't fit in a block option.
+    pub fn at(offset: usize, more: bool, szx: u8) -> StdOption<Block> {
+        let num = offset >> (szx as usize + 4);
+
+        if num < 1 << 20 {
+            Some(Block::new(num as u32, more, szx))
+        } else {
+            None
+        }
+    }
+
+    /// The largest size exponent whose block size doesn't exceed `size`, with
+    /// the smallest block size being 16 bytes.
+    pub fn szx_for(size: usize) -> u8 {
+        let mut szx = 0;
+
+        while szx < 6 && 1 << (szx + 5) <= size {
+            szx += 1;
+        }
+
+        szx
+    }
+
+    /// The block size in bytes.
+    pub fn size(&self) -> usize {
+        1 << (self.szx as usize + 4)
+    }
+
+    /// The offset of the first byte of this block within the body.
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    fn from_value(value: u64) -> Result<Block, Error> {
+        let szx = (value & 0x07) as u8;
+
+        if szx == 7 {
+            return Err(Error::MessageFormat);
+        }
+
+        Ok(Block {
+            num: (value >> 4) as u32,
+            more: value & 0x08 != 0,
+            szx: szx,
+        })
+    }
+
+    fn value(&self) -> u64 {
+        (self.num as u64) << 4 | (self.more as u64) << 3 | self.szx as u64
     }
 }
 
@@ -371,12 +505,16 @@ options![
     (6, Observe, uint, 0, 4),
     (7, UriPort, uint, 0, 2),
     (8, LocationPath, string, 0, 255),
+    (9, Oscore, opaque, 0, 255),
     (11, UriPath, string, 0, 255),
     (12, ContentFormat, uint, 0, 2),
     (14, MaxAge, uint, 0, 4),
     (15, UriQuery, string, 0, 255),
     (17, Accept, uint, 0, 2),
     (20, LocationQuery, string, 0, 255),
+    (23, Block2, block, 0, 3),
+    (27, Block1, block, 0, 3),
+    (28, Size2, uint, 0, 4),
     (35, ProxyUri, string, 1, 1034),
     (29, ProxyScheme, string, 1, 255),
     (60, Size1, uint, 0, 4),
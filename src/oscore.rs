@@ -0,0 +1,982 @@
+//! Object security for requests and responses, protecting them end to end
+//! even where they pass through proxies that DTLS would be terminated at.
+//! RFC 8613: Object Security for Constrained RESTful Environments (OSCORE)
+//!
+//! Only the mandatory to implement algorithms are supported, AES-CCM-16-64-128
+//! for encryption and HKDF SHA-256 for deriving keys.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::option::Option as StdOption;
+use std::sync::{Arc, Mutex};
+
+use openssl::error::ErrorStack;
+use openssl::md::Md;
+use openssl::pkey::Id;
+use openssl::pkey_ctx::PkeyCtx;
+use openssl::cipher::Cipher;
+use openssl::cipher_ctx::CipherCtx;
+
+use cbor;
+use error::{Error, OscoreError};
+use message::{Message, Code};
+use message::option::{Option, Options, Observe, Oscore, ProxyScheme, ProxyUri, UriHost, UriPort};
+
+/// The COSE algorithm identifier of AES-CCM-16-64-128.
+/// RFC 8152: 10.2.  AES CCM
+const AES_CCM_16_64_128: i64 = 10;
+
+const KEY_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 13;
+const TAG_LENGTH: usize = 8;
+
+/// The longest Sender or Recipient ID the nonce has room for.
+/// RFC 8613: 3.3.  Constraints on the Security Context
+const MAX_ID_LENGTH: usize = NONCE_LENGTH - 6;
+
+/// The largest sender sequence number, a Partial IV being at most 5 bytes.
+/// RFC 8613: 7.2.1.  Sender Sequence Number
+const MAX_SEQUENCE_NUMBER: u64 = (1 << 40) - 1;
+
+/// How many sequence numbers below the highest received are remembered.
+/// RFC 8613: 7.4.  Replay Protection
+const REPLAY_WINDOW: u64 = 32;
+
+pub(crate) fn crypto_error(e: ErrorStack) -> Error {
+    io::Error::new(io::ErrorKind::Other, e).into()
+}
+
+/// The sequence numbers received recently, to reject any received again.
+#[derive(Default)]
+struct ReplayWindow {
+    highest: StdOption<u64>,
+    /// bit `n` is set if `highest - n` has been received
+    seen: u32,
+}
+
+impl ReplayWindow {
+    /// Record `sequence` as received, unless it already was or is too old to
+    /// tell.
+    fn accept(&mut self, sequence: u64) -> Result<(), OscoreError> {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.highest = Some(sequence);
+                self.seen = 1;
+                return Ok(());
+            }
+        };
+
+        if sequence > highest {
+            let shift = sequence - highest;
+            self.seen = if shift < REPLAY_WINDOW { self.seen << shift | 1 } else { 1 };
+            self.highest = Some(sequence);
+            return Ok(());
+        }
+
+        let age = highest - sequence;
+        if age >= REPLAY_WINDOW || self.seen & 1 << age != 0 {
+            return Err(OscoreError::Replay);
+        }
+
+        self.seen |= 1 << age;
+        Ok(())
+    }
+}
+
+struct State {
+    /// the next sender sequence number
+    sequence: u64,
+    replay: ReplayWindow,
+}
+
+struct Shared {
+    sender_id: Vec<u8>,
+    recipient_id: Vec<u8>,
+    id_context: StdOption<Vec<u8>>,
+    sender_key: Vec<u8>,
+    recipient_key: Vec<u8>,
+    common_iv: Vec<u8>,
+    state: Mutex<State>,
+}
+
+/// The keys and state shared with one peer.
+///
+/// Cloning a context yields a handle to the same one, so that sequence
+/// numbers are never reused between clones.
+/// RFC 8613: 3.  The Security Context
+#[derive(Clone)]
+pub struct SecurityContext {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for SecurityContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SecurityContext")
+            .field("sender_id", &self.shared.sender_id)
+            .field("recipient_id", &self.shared.recipient_id)
+            .field("id_context", &self.shared.id_context)
+            .finish()
+    }
+}
+
+/// HKDF SHA-256 of `secret`, expanded to `len` bytes.
+fn hkdf(salt: &[u8], secret: &[u8], info: &[u8], len: usize) -> Result<Vec<u8>, ErrorStack> {
+    let mut ctx = PkeyCtx::new_id(Id::HKDF)?;
+    ctx.derive_init()?;
+    ctx.set_hkdf_md(Md::sha256())?;
+    ctx.set_hkdf_key(secret)?;
+    // an absent salt is the same as one of zeros
+    if !salt.is_empty() {
+        ctx.set_hkdf_salt(salt)?;
+    }
+    ctx.add_hkdf_info(info)?;
+
+    let mut out = vec![0; len];
+    ctx.derive(Some(&mut out))?;
+
+    Ok(out)
+}
+
+/// The info a key or the Common IV is derived with.
+/// RFC 8613: 3.2.1.  Derivation of Sender Key, Recipient Key, and Common IV
+fn info(id: &[u8], id_context: StdOption<&[u8]>, kind: &str, len: usize) -> Vec<u8> {
+    let mut info = Vec::new();
+
+    cbor::array(5, &mut info);
+    cbor::bytes(id, &mut info);
+    match id_context {
+        Some(id_context) => cbor::bytes(id_context, &mut info),
+        None => cbor::null(&mut info),
+    }
+    cbor::int(AES_CCM_16_64_128, &mut info);
+    cbor::text(kind, &mut info);
+    cbor::uint(len as u64, &mut info);
+
+    info
+}
+
+impl SecurityContext {
+    /// Derive the context with the peer whose own Sender ID is our
+    /// `recipient_id`, and vice versa. The ID Context, if any, tells apart
+    /// contexts whose IDs are the same.
+    ///
+    /// Panics if either ID is longer than 7 bytes, or the ID Context longer
+    /// than 255.
+    /// RFC 8613: 3.2.  Establishment of Security Context Parameters
+    pub fn new(master_secret: &[u8],
+               master_salt: &[u8],
+               id_context: StdOption<&[u8]>,
+               sender_id: &[u8],
+               recipient_id: &[u8]) -> Result<SecurityContext, Error> {
+        assert!(sender_id.len() <= MAX_ID_LENGTH, "sender ID must be at most 7 bytes");
+        assert!(recipient_id.len() <= MAX_ID_LENGTH, "recipient ID must be at most 7 bytes");
+        assert!(id_context.map_or(true, |id_context| id_context.len() <= 255), "ID context must be at most 255 bytes");
+
+        let derive = |id: &[u8], kind, len| hkdf(master_salt, master_secret, &info(id, id_context, kind, len), len);
+
+        let sender_key = derive(sender_id, "Key", KEY_LENGTH).map_err(crypto_error)?;
+        let recipient_key = derive(recipient_id, "Key", KEY_LENGTH).map_err(crypto_error)?;
+        let common_iv = derive(&[], "IV", NONCE_LENGTH).map_err(crypto_error)?;
+
+        Ok(SecurityContext {
+            shared: Arc::new(Shared {
+                sender_id: sender_id.to_vec(),
+                recipient_id: recipient_id.to_vec(),
+                id_context: id_context.map(<[u8]>::to_vec),
+                sender_key: sender_key,
+                recipient_key: recipient_key,
+                common_iv: common_iv,
+                state: Mutex::new(State {
+                    sequence: 0,
+                    replay: ReplayWindow::default(),
+                }),
+            }),
+        })
+    }
+
+    pub fn sender_id(&self) -> &[u8] {
+        &self.shared.sender_id
+    }
+
+    pub fn recipient_id(&self) -> &[u8] {
+        &self.shared.recipient_id
+    }
+
+    pub fn id_context(&self) -> StdOption<&[u8]> {
+        self.shared.id_context.as_ref().map(Vec::as_slice)
+    }
+
+    /// The sequence number the next message we protect is sent with.
+    pub fn sequence_number(&self) -> u64 {
+        self.shared.state.lock().unwrap().sequence
+    }
+
+    /// Carry on from sequence number `sequence`, such as one stored before a
+    /// reboot. It must never go backwards.
+    /// RFC 8613: 7.5.1.  Sequence Number
+    pub fn set_sequence_number(&self, sequence: u64) {
+        self.shared.state.lock().unwrap().sequence = sequence;
+    }
+
+    /// The Partial IV of the next message we protect.
+    fn next_partial_iv(&self) -> Result<Vec<u8>, OscoreError> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if state.sequence > MAX_SEQUENCE_NUMBER {
+            return Err(OscoreError::SequenceExhausted);
+        }
+
+        let piv = partial_iv(state.sequence);
+        state.sequence += 1;
+
+        Ok(piv)
+    }
+
+    /// The nonce of a message sent by `id` with Partial IV `piv`.
+    /// RFC 8613: 5.2.  AEAD Nonce
+    fn nonce(&self, id: &[u8], piv: &[u8]) -> Vec<u8> {
+        let mut nonce = vec![0; NONCE_LENGTH];
+
+        nonce[0] = id.len() as u8;
+        nonce[NONCE_LENGTH - 5 - id.len()..NONCE_LENGTH - 5].copy_from_slice(id);
+        nonce[NONCE_LENGTH - piv.len()..].copy_from_slice(piv);
+
+        for (n, iv) in nonce.iter_mut().zip(&self.shared.common_iv) {
+            *n ^= iv;
+        }
+
+        nonce
+    }
+
+    /// Protect `request`, returning the message to send in its place and the
+    /// exchange to protect and verify its responses with.
+    ///
+    /// Its options for proxies stay outside, the rest are encrypted along
+    /// with its code and payload.
+    /// RFC 8613: 8.1.  Protecting the Request
+    pub fn protect_request(&self, request: &Message) -> Result<(Message, Exchange), Error> {
+        let piv = self.next_partial_iv()?;
+        let kid = self.shared.sender_id.clone();
+
+        let (inner, outer) = split(request, true);
+        let plaintext = plaintext(request.code, inner, &request.payload)?;
+        let ciphertext = seal(&self.shared.sender_key, &self.nonce(&kid, &piv), &aad(&kid, &piv), &plaintext)?;
+
+        let header = Header {
+            piv: piv.clone(),
+            kid_context: self.shared.id_context.clone(),
+            kid: Some(kid.clone()),
+        };
+
+        // RFC 8613: 4.2.  The Outer Header
+        let code = if request.options.get_raw::<Observe>().is_some() { Code::Fetch } else { Code::Post };
+        let protected = outer_message(request, code, outer, &header, ciphertext);
+
+        let exchange = Exchange {
+            context: self.clone(),
+            kid: kid,
+            piv: piv,
+        };
+
+        Ok((protected, exchange))
+    }
+
+    /// Verify and decrypt `request`, returning it as it was before it was
+    /// protected and the exchange to protect its responses with.
+    /// RFC 8613: 8.2.  Verifying the Request
+    pub fn unprotect_request(&self, request: &Message) -> Result<(Message, Exchange), Error> {
+        let header = header(request)?.ok_or(OscoreError::Malformed)?;
+        let kid = header.kid.ok_or(OscoreError::Malformed)?;
+
+        if kid != self.shared.recipient_id || header.kid_context != self.shared.id_context {
+            return Err(OscoreError::UnknownContext.into());
+        }
+        if header.piv.is_empty() {
+            return Err(OscoreError::Malformed.into());
+        }
+
+        let piv = header.piv;
+        let plaintext = open(&self.shared.recipient_key, &self.nonce(&kid, &piv), &aad(&kid, &piv), &request.payload)?;
+        self.shared.state.lock().unwrap().replay.accept(sequence_number(&piv))?;
+
+        let unprotected = inner_message(request, &plaintext, true)?;
+
+        let exchange = Exchange {
+            context: self.clone(),
+            kid: kid,
+            piv: piv,
+        };
+
+        Ok((unprotected, exchange))
+    }
+}
+
+/// A protected request, and what its responses are bound to.
+#[derive(Clone, Debug)]
+pub struct Exchange {
+    context: SecurityContext,
+    /// the Sender ID of the client that sent the request
+    kid: Vec<u8>,
+    /// the Partial IV the request was sent with
+    piv: Vec<u8>,
+}
+
+impl Exchange {
+    /// The context the request was protected with.
+    pub fn context(&self) -> &SecurityContext {
+        &self.context
+    }
+
+    /// Protect the response to the request, using the request's nonce.
+    /// RFC 8613: 8.3.  Protecting the Response
+    pub fn protect_response(&self, response: &Message) -> Result<Message, Error> {
+        self.protect(response, None)
+    }
+
+    /// Protect a further response to the request, such as an Observe
+    /// notification, with a Partial IV of its own.
+    /// RFC 8613: 4.1.3.5.2.  Observe Option in Responses
+    pub fn protect_notification(&self, response: &Message) -> Result<Message, Error> {
+        let piv = self.context.next_partial_iv()?;
+
+        self.protect(response, Some(piv))
+    }
+
+    fn protect(&self, response: &Message, piv: StdOption<Vec<u8>>) -> Result<Message, Error> {
+        let shared = &self.context.shared;
+
+        let (nonce, header) = match piv {
+            Some(piv) => (self.context.nonce(&shared.sender_id, &piv), Header { piv: piv, ..Header::default() }),
+            None => (self.context.nonce(&self.kid, &self.piv), Header::default()),
+        };
+
+        let (inner, outer) = split(response, false);
+        let plaintext = plaintext(response.code, inner, &response.payload)?;
+        let ciphertext = seal(&shared.sender_key, &nonce, &aad(&self.kid, &self.piv), &plaintext)?;
+
+        // RFC 8613: 4.2.  The Outer Header
+        let code = if response.options.get_raw::<Observe>().is_some() { Code::Content } else { Code::Changed };
+
+        Ok(outer_message(response, code, outer, &header, ciphertext))
+    }
+
+    /// Verify and decrypt a response to the request.
+    /// RFC 8613: 8.4.  Verifying the Response
+    pub fn unprotect_response(&self, response: &Message) -> Result<Message, Error> {
+        let shared = &self.context.shared;
+        let header = header(response)?.ok_or(OscoreError::Unprotected)?;
+
+        let nonce = if header.piv.is_empty() {
+            self.context.nonce(&self.kid, &self.piv)
+        } else {
+            self.context.nonce(&shared.recipient_id, &header.piv)
+        };
+
+        let plaintext = open(&shared.recipient_key, &nonce, &aad(&self.kid, &self.piv), &response.payload)?;
+        if !header.piv.is_empty() {
+            shared.state.lock().unwrap().replay.accept(sequence_number(&header.piv))?;
+        }
+
+        inner_message(response, &plaintext, false)
+    }
+}
+
+/// Contexts are identified by their Recipient ID and ID Context.
+type Key = (Vec<u8>, StdOption<Vec<u8>>);
+
+/// The security contexts a server shares with its clients, found by each
+/// one's Recipient ID and ID Context.
+///
+/// Clones share the same contexts, so they can be added to while the server
+/// is running.
+#[derive(Clone, Debug, Default)]
+pub struct ContextStore {
+    contexts: Arc<Mutex<HashMap<Key, SecurityContext>>>,
+}
+
+impl ContextStore {
+    pub fn new() -> ContextStore {
+        ContextStore::default()
+    }
+
+    /// Add `context`, replacing any with the same Recipient ID and ID
+    /// Context.
+    pub fn insert(&self, context: SecurityContext) {
+        let key = (context.shared.recipient_id.clone(), context.shared.id_context.clone());
+
+        self.contexts.lock().unwrap().insert(key, context);
+    }
+
+    pub fn get(&self, recipient_id: &[u8], id_context: StdOption<&[u8]>) -> StdOption<SecurityContext> {
+        let key = (recipient_id.to_vec(), id_context.map(<[u8]>::to_vec));
+
+        self.contexts.lock().unwrap().get(&key).cloned()
+    }
+
+    pub fn remove(&self, recipient_id: &[u8], id_context: StdOption<&[u8]>) -> StdOption<SecurityContext> {
+        let key = (recipient_id.to_vec(), id_context.map(<[u8]>::to_vec));
+
+        self.contexts.lock().unwrap().remove(&key)
+    }
+
+    /// Verify and decrypt `request` with the context its key ID picks out,
+    /// if it's protected at all.
+    pub fn unprotect_request(&self, request: &Message) -> Result<StdOption<(Message, Exchange)>, Error> {
+        let header = match header(request)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        let kid = header.kid.ok_or(OscoreError::Malformed)?;
+        let context = self.get(&kid, header.kid_context.as_ref().map(Vec::as_slice))
+            .ok_or(OscoreError::UnknownContext)?;
+
+        context.unprotect_request(request).map(Some)
+    }
+}
+
+/// The value of the OSCORE option.
+/// RFC 8613: 6.1.  OSCORE Option
+#[derive(Debug, Default, PartialEq)]
+struct Header {
+    piv: Vec<u8>,
+    kid_context: StdOption<Vec<u8>>,
+    kid: StdOption<Vec<u8>>,
+}
+
+impl Header {
+    fn encode(&self) -> Vec<u8> {
+        if *self == Header::default() {
+            return Vec::new();
+        }
+
+        let mut flags = self.piv.len() as u8;
+        if self.kid.is_some() {
+            flags |= 0x08;
+        }
+        if self.kid_context.is_some() {
+            flags |= 0x10;
+        }
+
+        let mut value = vec![flags];
+        value.extend_from_slice(&self.piv);
+        if let Some(ref kid_context) = self.kid_context {
+            value.push(kid_context.len() as u8);
+            value.extend_from_slice(kid_context);
+        }
+        if let Some(ref kid) = self.kid {
+            value.extend_from_slice(kid);
+        }
+
+        value
+    }
+
+    fn decode(value: &[u8]) -> Result<Header, OscoreError> {
+        let (flags, mut rest) = match value.split_first() {
+            Some((&flags, rest)) => (flags, rest),
+            None => return Ok(Header::default()),
+        };
+
+        let n = (flags & 0x07) as usize;
+        if flags & 0xe0 != 0 || n > 5 || rest.len() < n {
+            return Err(OscoreError::Malformed);
+        }
+
+        let piv = rest[..n].to_vec();
+        rest = &rest[n..];
+
+        let kid_context = if flags & 0x10 != 0 {
+            let s = *rest.first().ok_or(OscoreError::Malformed)? as usize;
+            if rest.len() < 1 + s {
+                return Err(OscoreError::Malformed);
+            }
+
+            let kid_context = rest[1..1 + s].to_vec();
+            rest = &rest[1 + s..];
+            Some(kid_context)
+        } else {
+            None
+        };
+
+        let kid = if flags & 0x08 != 0 {
+            Some(rest.to_vec())
+        } else if rest.is_empty() {
+            None
+        } else {
+            return Err(OscoreError::Malformed);
+        };
+
+        Ok(Header {
+            piv: piv,
+            kid_context: kid_context,
+            kid: kid,
+        })
+    }
+}
+
+/// The OSCORE option of `msg`, if it has one.
+fn header(msg: &Message) -> Result<StdOption<Header>, OscoreError> {
+    match msg.options.get_raw::<Oscore>() {
+        Some(ref values) if values.len() == 1 => Header::decode(&values[0]).map(Some),
+        Some(_) => Err(OscoreError::Malformed),
+        None => Ok(None),
+    }
+}
+
+/// Sequence number `sequence` in as few bytes as possible, at least one.
+/// RFC 8613: 6.1.  OSCORE Option
+fn partial_iv(sequence: u64) -> Vec<u8> {
+    let bytes: Vec<u8> = (0..5).rev()
+        .map(|i| (sequence >> (i * 8)) as u8)
+        .skip_while(|&byte| byte == 0)
+        .collect();
+
+    if bytes.is_empty() { vec![0] } else { bytes }
+}
+
+fn sequence_number(piv: &[u8]) -> u64 {
+    piv.iter().fold(0, |sequence, &byte| sequence << 8 | byte as u64)
+}
+
+/// Whether option `number` is left outside the ciphertext for proxies to
+/// see. Observe is sent both inside and outside of requests, and only outside
+/// of responses.
+/// RFC 8613: 4.1.  Option Classes
+fn is_outer(number: u16) -> bool {
+    let outer = [UriHost::NUMBER, UriPort::NUMBER, ProxyUri::NUMBER, ProxyScheme::NUMBER, Oscore::NUMBER];
+
+    outer.contains(&number)
+}
+
+/// The options of `msg` to encrypt, and those to leave outside.
+fn split(msg: &Message, request: bool) -> (Options, Options) {
+    let mut inner = Options::new();
+    let mut outer = Options::new();
+
+    for (&number, values) in &msg.options.map {
+        if number == Oscore::NUMBER {
+            continue;
+        }
+
+        let (is_inner, is_outer) = match number {
+            Observe::NUMBER => (request, true),
+            number if is_outer(number) => (false, true),
+            _ => (true, false),
+        };
+
+        for value in values {
+            if is_inner {
+                inner.push_raw(number, value.clone());
+            }
+            if is_outer {
+                outer.push_raw(number, value.clone());
+            }
+        }
+    }
+
+    (inner, outer)
+}
+
+/// The code, options and payload that are encrypted, serialized as they
+/// would be in a message.
+/// RFC 8613: 5.3.  Plaintext
+fn plaintext(code: Code, options: Options, payload: &[u8]) -> Result<Vec<u8>, Error> {
+    let inner = Message {
+        code: code,
+        options: options,
+        payload: payload.to_vec(),
+        ..Message::new()
+    };
+
+    // the same as the message without its header and token
+    let bytes = inner.to_bytes()?;
+    let mut plaintext = vec![bytes[1]];
+    plaintext.extend_from_slice(&bytes[4..]);
+
+    Ok(plaintext)
+}
+
+/// The message `protected` carried as its ciphertext.
+fn inner_message(protected: &Message, plaintext: &[u8], request: bool) -> Result<Message, Error> {
+    let (&code, rest) = plaintext.split_first().ok_or(OscoreError::Malformed)?;
+
+    let mut bytes = vec![0x40, code, 0, 0];
+    bytes.extend_from_slice(rest);
+    let inner = Message::from_bytes(&bytes).map_err(|_| OscoreError::Malformed)?;
+
+    // any options meant to be inside that were put outside are ignored
+    let mut options = inner.options;
+    for (&number, values) in &protected.options.map {
+        let keep = match number {
+            Oscore::NUMBER => false,
+            Observe::NUMBER => !request,
+            number => is_outer(number),
+        };
+
+        if keep {
+            for value in values {
+                options.push_raw(number, value.clone());
+            }
+        }
+    }
+
+    Ok(Message {
+        version: protected.version,
+        mtype: protected.mtype,
+        code: inner.code,
+        mid: protected.mid,
+        token: protected.token.clone(),
+        options: options,
+        payload: inner.payload,
+    })
+}
+
+/// The message sent in place of `msg`.
+fn outer_message(msg: &Message, code: Code, mut options: Options, header: &Header, ciphertext: Vec<u8>) -> Message {
+    options.push_raw(Oscore::NUMBER, header.encode());
+
+    Message {
+        version: msg.version,
+        mtype: msg.mtype,
+        code: code,
+        mid: msg.mid,
+        token: msg.token.clone(),
+        options: options,
+        payload: ciphertext,
+    }
+}
+
+/// The additional authenticated data binding a message to the request with
+/// key ID `kid` and Partial IV `piv`.
+/// RFC 8613: 5.4.  Additional Authenticated Data
+fn aad(kid: &[u8], piv: &[u8]) -> Vec<u8> {
+    let mut external_aad = Vec::new();
+    cbor::array(5, &mut external_aad);
+    cbor::uint(1, &mut external_aad);
+    cbor::array(1, &mut external_aad);
+    cbor::int(AES_CCM_16_64_128, &mut external_aad);
+    cbor::bytes(kid, &mut external_aad);
+    cbor::bytes(piv, &mut external_aad);
+    // no Class I options are defined
+    cbor::bytes(&[], &mut external_aad);
+
+    let mut aad = Vec::new();
+    cbor::array(3, &mut aad);
+    cbor::text("Encrypt0", &mut aad);
+    cbor::bytes(&[], &mut aad);
+    cbor::bytes(&external_aad, &mut aad);
+
+    aad
+}
+
+/// An AES-CCM cipher context for `key` and `nonce`, the tag length having
+/// to be set before either.
+fn ccm(encrypt: bool, key: &[u8], nonce: &[u8], tag: &[u8], data_len: usize) -> Result<CipherCtx, ErrorStack> {
+    let mut ctx = CipherCtx::new()?;
+
+    if encrypt {
+        ctx.encrypt_init(Some(Cipher::aes_128_ccm()), None, None)?;
+        ctx.set_tag_length(tag.len())?;
+    } else {
+        ctx.decrypt_init(Some(Cipher::aes_128_ccm()), None, None)?;
+        ctx.set_tag(tag)?;
+    }
+    ctx.set_iv_length(nonce.len())?;
+
+    if encrypt {
+        ctx.encrypt_init(None, Some(key), Some(nonce))?;
+    } else {
+        ctx.decrypt_init(None, Some(key), Some(nonce))?;
+    }
+    ctx.set_data_len(data_len)?;
+
+    Ok(ctx)
+}
+
+pub(crate) fn seal(key: &[u8], nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let seal = || -> Result<Vec<u8>, ErrorStack> {
+        let mut tag = [0; TAG_LENGTH];
+        let mut ctx = ccm(true, key, nonce, &tag, plaintext.len())?;
+
+        let mut ciphertext = Vec::new();
+        ctx.cipher_update(aad, None)?;
+        ctx.cipher_update_vec(plaintext, &mut ciphertext)?;
+        ctx.cipher_final_vec(&mut ciphertext)?;
+        ctx.tag(&mut tag)?;
+
+        ciphertext.extend_from_slice(&tag);
+        Ok(ciphertext)
+    };
+
+    seal().map_err(crypto_error)
+}
+
+pub(crate) fn open(key: &[u8], nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, OscoreError> {
+    if ciphertext.len() < TAG_LENGTH {
+        return Err(OscoreError::Malformed);
+    }
+
+    let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - TAG_LENGTH);
+
+    let open = || -> Result<Vec<u8>, ErrorStack> {
+        let mut ctx = ccm(false, key, nonce, tag, ciphertext.len())?;
+
+        // CCM authenticates as it decrypts, there's nothing to finalize
+        let mut plaintext = Vec::new();
+        ctx.cipher_update(aad, None)?;
+        ctx.cipher_update_vec(ciphertext, &mut plaintext)?;
+
+        Ok(plaintext)
+    };
+
+    open().map_err(|_| OscoreError::DecryptionFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ContextStore, Header, ReplayWindow, SecurityContext};
+    use error::{Error, OscoreError};
+    use message::{Message, Code};
+    use message::option::{Option, Observe, Oscore, UriPath};
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    const MASTER_SECRET: &str = "0102030405060708090a0b0c0d0e0f10";
+    const MASTER_SALT: &str = "9e7ca92223786340";
+    const ID_CONTEXT: &str = "37cbf3210017a2d3";
+
+    /// RFC 8613: C.1.  Test Vector 1: Key Derivation with Master Salt
+    fn vector_1(client: bool) -> SecurityContext {
+        let (sender, recipient) = if client { (&[][..], &[1][..]) } else { (&[1][..], &[][..]) };
+
+        SecurityContext::new(&hex(MASTER_SECRET), &hex(MASTER_SALT), None, sender, recipient).unwrap()
+    }
+
+    /// RFC 8613: C.2.  Test Vector 2: Key Derivation without Master Salt
+    fn vector_2(client: bool) -> SecurityContext {
+        let (sender, recipient) = if client { (&[0][..], &[1][..]) } else { (&[1][..], &[0][..]) };
+
+        SecurityContext::new(&hex(MASTER_SECRET), &[], None, sender, recipient).unwrap()
+    }
+
+    /// RFC 8613: C.3.  Test Vector 3: Key Derivation with ID Context
+    fn vector_3(client: bool) -> SecurityContext {
+        let (sender, recipient) = if client { (&[][..], &[1][..]) } else { (&[1][..], &[][..]) };
+        let id_context = hex(ID_CONTEXT);
+
+        SecurityContext::new(&hex(MASTER_SECRET), &hex(MASTER_SALT), Some(&id_context), sender, recipient).unwrap()
+    }
+
+    /// GET coap://localhost/tv1
+    const REQUEST: &str = "44015d1f00003974396c6f63616c686f737483747631";
+
+    /// 2.05 Content "Hello World!"
+    const RESPONSE: &str = "64455d1f00003974ff48656c6c6f20576f726c6421";
+
+    fn protect(context: &SecurityContext, request: &str) -> Message {
+        context.set_sequence_number(20);
+
+        let request = Message::from_bytes(&hex(request)).unwrap();
+        let (protected, _) = context.protect_request(&request).unwrap();
+
+        protected
+    }
+
+    fn expect(result: Result<Message, Error>, expected: OscoreError) {
+        match result {
+            Err(Error::Oscore(ref e)) if format!("{:?}", e) == format!("{:?}", expected) => (),
+            other => panic!("expected {:?}, got {:?}", expected, other),
+        }
+    }
+
+    #[test]
+    fn key_derivation() {
+        let cases = [
+            (vector_1(true), "f0910ed7295e6ad4b54fc793154302ff", "ffb14e093c94c9cac9471648b4f98710", "4622d4dd6d944168eefb54987c"),
+            (vector_2(true), "321b26943253c7ffb6003b0b64d74041", "e57b5635815177cd679ab4bcec9d7dda", "be35ae297d2dace910c52e99f9"),
+            (vector_3(true), "af2a1300a5e95788b356336eeecd2b92", "e39a0c7c77b43f03b4b39ab9a268699f", "2ca58fb85ff1b81c0b7181b85e"),
+        ];
+
+        for &(ref context, sender_key, recipient_key, common_iv) in &cases {
+            assert_eq!(context.shared.sender_key, hex(sender_key));
+            assert_eq!(context.shared.recipient_key, hex(recipient_key));
+            assert_eq!(context.shared.common_iv, hex(common_iv));
+        }
+
+        // the server's keys are the other way around
+        let server = vector_1(false);
+        assert_eq!(server.shared.sender_key, hex("ffb14e093c94c9cac9471648b4f98710"));
+        assert_eq!(server.shared.recipient_key, hex("f0910ed7295e6ad4b54fc793154302ff"));
+
+        // RFC 8613: C.1.1 & C.1.2
+        assert_eq!(vector_1(true).nonce(&[], &[0]), hex("4622d4dd6d944168eefb54987c"));
+        assert_eq!(vector_1(true).nonce(&[1], &[0]), hex("4722d4dd6d944169eefb54987c"));
+    }
+
+    /// RFC 8613: C.4, C.5 & C.6.  Test Vectors 4, 5 & 6: OSCORE Request, Client
+    #[test]
+    fn protect_request() {
+        assert_eq!(protect(&vector_1(true), REQUEST).to_bytes().unwrap(),
+                   hex("44025d1f00003974396c6f63616c686f7374620914ff612f1092f1776f1c1668b3825e"));
+        assert_eq!(protect(&vector_2(true), REQUEST).to_bytes().unwrap(),
+                   hex("44025d1f00003974396c6f63616c686f737463091400ff4ed339a5a379b0b8bc731fffb0"));
+        assert_eq!(protect(&vector_3(true), REQUEST).to_bytes().unwrap(),
+                   hex("44025d1f00003974396c6f63616c686f73746b19140837cbf3210017a2d3ff72cd7273fd331ac45cffbe55c3"));
+
+        // and the server recovers the original from each
+        let original = Message::from_bytes(&hex(REQUEST)).unwrap();
+        let pairs = [(vector_1(true), vector_1(false)), (vector_2(true), vector_2(false)), (vector_3(true), vector_3(false))];
+        for &(ref client, ref server) in &pairs {
+            let (unprotected, _) = server.unprotect_request(&protect(&client, REQUEST)).unwrap();
+            assert_eq!(unprotected, original);
+        }
+    }
+
+    /// RFC 8613: C.7 & C.8.  Test Vectors 7 & 8: OSCORE Response, Server
+    #[test]
+    fn protect_response() {
+        let client = vector_1(true);
+        let server = vector_1(false);
+
+        let (_, exchange) = server.unprotect_request(&protect(&client, REQUEST)).unwrap();
+        let response = Message::from_bytes(&hex(RESPONSE)).unwrap();
+
+        let protected = exchange.protect_response(&response).unwrap();
+        assert_eq!(protected.to_bytes().unwrap(),
+                   hex("64445d1f0000397490ffdbaad1e9a7e7b2a813d3c31524378303cdafae119106"));
+
+        let notification = exchange.protect_notification(&response).unwrap();
+        assert_eq!(notification.to_bytes().unwrap(),
+                   hex("64445d1f00003974920100ff4d4c13669384b67354b2b6175ff4b8658c666a6cf88e"));
+    }
+
+    #[test]
+    fn responses_are_verified() {
+        let client = vector_1(true);
+        let server = vector_1(false);
+
+        let request = Message::new().with_code(Code::Get).with_option(UriPath::new("tv1".to_owned()));
+        let (protected, client_exchange) = client.protect_request(&request).unwrap();
+        let (_, server_exchange) = server.unprotect_request(&protected).unwrap();
+
+        let response = Message::from_bytes(&hex(RESPONSE)).unwrap();
+        let protected = server_exchange.protect_response(&response).unwrap();
+        assert_eq!(client_exchange.unprotect_response(&protected).unwrap(), response);
+
+        // notifications keep their Observe option outside, and can't be replayed
+        let notification = response.clone().with_option(Observe::new(7));
+        let protected = server_exchange.protect_notification(&notification).unwrap();
+        assert_eq!(protected.code, Code::Content);
+        assert_eq!(protected.options.get::<Observe>().unwrap()[0].value, 7);
+        assert_eq!(client_exchange.unprotect_response(&protected).unwrap(), notification);
+        expect(client_exchange.unprotect_response(&protected), OscoreError::Replay);
+
+        // bound to the request it answers
+        let (_, other_exchange) = client.protect_request(&request).unwrap();
+        let protected = server_exchange.protect_response(&response).unwrap();
+        expect(other_exchange.unprotect_response(&protected), OscoreError::DecryptionFailed);
+
+        expect(client_exchange.unprotect_response(&response), OscoreError::Unprotected);
+    }
+
+    #[test]
+    fn requests_are_verified() {
+        let client = vector_1(true);
+        let server = vector_1(false);
+
+        let request = protect(&client, REQUEST);
+        assert!(server.unprotect_request(&request).is_ok());
+
+        let result = server.unprotect_request(&request).map(|(msg, _)| msg);
+        expect(result, OscoreError::Replay);
+
+        let mut tampered = protect(&client, REQUEST);
+        tampered.payload[0] ^= 1;
+        let result = server.unprotect_request(&tampered).map(|(msg, _)| msg);
+        expect(result, OscoreError::DecryptionFailed);
+
+        // a different client's context doesn't match
+        let result = vector_1(true).unprotect_request(&protect(&vector_2(true), REQUEST)).map(|(msg, _)| msg);
+        expect(result, OscoreError::UnknownContext);
+    }
+
+    #[test]
+    fn contexts_are_found_by_key_id() {
+        let store = ContextStore::new();
+        store.insert(vector_1(false));
+        store.insert(vector_2(false));
+        store.insert(vector_3(false));
+
+        for client in &[vector_1(true), vector_2(true), vector_3(true)] {
+            let (unprotected, exchange) = store.unprotect_request(&protect(client, REQUEST)).unwrap().unwrap();
+            assert_eq!(unprotected, Message::from_bytes(&hex(REQUEST)).unwrap());
+            assert_eq!(exchange.context().recipient_id(), client.sender_id());
+            assert_eq!(exchange.context().id_context(), client.id_context());
+        }
+
+        let plain = Message::from_bytes(&hex(REQUEST)).unwrap();
+        assert!(store.unprotect_request(&plain).unwrap().is_none());
+
+        store.remove(&[], None);
+        let result = store.unprotect_request(&protect(&vector_1(true), REQUEST)).map(|_| Message::new());
+        expect(result, OscoreError::UnknownContext);
+    }
+
+    #[test]
+    fn header_encoding() {
+        let header = Header {
+            piv: vec![0x14],
+            kid_context: Some(hex(ID_CONTEXT)),
+            kid: Some(vec![]),
+        };
+        assert_eq!(header.encode(), hex("19140837cbf3210017a2d3"));
+        assert_eq!(Header::decode(&header.encode()).unwrap(), header);
+
+        assert_eq!(Header::default().encode(), Vec::<u8>::new());
+        assert_eq!(Header::decode(&[]).unwrap(), Header::default());
+
+        for malformed in &[&[0x20][..], &[0x06, 0, 0, 0, 0, 0, 0], &[0x02, 0x14], &[0x11, 0x14, 0x08, 0x37], &[0x01, 0x14, 0x01]] {
+            assert!(Header::decode(malformed).is_err());
+        }
+
+        let mut request = protect(&vector_1(true), REQUEST);
+        request.options.push_raw(Oscore::NUMBER, vec![]);
+        let result = vector_1(false).unprotect_request(&request).map(|(msg, _)| msg);
+        expect(result, OscoreError::Malformed);
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::default();
+
+        for &sequence in &[5, 3, 40, 39, 9] {
+            assert!(window.accept(sequence).is_ok());
+        }
+
+        for &sequence in &[5, 40, 39, 9, 8, 0] {
+            assert!(window.accept(sequence).is_err());
+        }
+
+        assert!(window.accept(10).is_ok());
+        assert!(window.accept(100).is_ok());
+        assert!(window.accept(69).is_ok());
+        assert!(window.accept(68).is_err());
+    }
+
+    #[test]
+    fn sequence_numbers_run_out() {
+        let client = vector_1(true);
+        let request = Message::from_bytes(&hex(REQUEST)).unwrap();
+
+        client.set_sequence_number((1 << 40) - 1);
+        let (protected, _) = client.protect_request(&request).unwrap();
+        assert_eq!(protected.options.get_raw::<Oscore>().unwrap()[0], hex("0dffffffffff"));
+
+        let result = client.protect_request(&request).map(|(msg, _)| msg);
+        expect(result, OscoreError::SequenceExhausted);
+    }
+}
@@ -0,0 +1,452 @@
+//! Dispatching requests to handlers by method and path.
+//!
+//! A route's path pattern is made of `/`-separated segments, each either a
+//! literal that must match exactly, a `{name}` that matches any single segment
+//! and captures it as a parameter of the request, or, as the last segment, a
+//! `*` that matches any number of remaining segments, which are captured
+//! joined by `/` as the parameter `*`.
+//!
+//! ```text
+//! /sensors             only /sensors
+//! /sensors/{id}        /sensors/1, /sensors/temp, but not /sensors/1/name
+//! /static/*            /static, /static/a, /static/a/b
+//! ```
+//!
+//! The router lists the resources its routes serve in the `/.well-known/core`
+//! document, unless a route overrides it. A route for a pattern of literal
+//! segments is listed by its path alone, one given links with `add_link` is
+//! listed by those links and their attributes instead, which lets a pattern
+//! with captures list the resources it serves.
+//! RFC 6690: 4.  Well-Known Interface
+
+use std::collections::HashMap;
+use std::option::Option as StdOption;
+
+use futures::future;
+
+use client::IoFuture;
+use link_format::{self, Link};
+use message::{Message, Code};
+use message::option::{ContentFormat, Option, UriPath};
+use server::{Handler, Request};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Capture(String),
+    Wildcard,
+}
+
+/// A parsed path pattern.
+#[derive(Debug, Clone, PartialEq)]
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Pattern {
+        let pattern = pattern.trim_start_matches('/');
+
+        let segments: Vec<_> = if pattern.is_empty() {
+            Vec::new()
+        } else {
+            pattern.split('/').map(|segment| {
+                if segment == "*" {
+                    Segment::Wildcard
+                } else if segment.starts_with('{') && segment.ends_with('}') && segment.len() > 2 {
+                    Segment::Capture(segment[1..segment.len() - 1].to_owned())
+                } else {
+                    Segment::Literal(segment.to_owned())
+                }
+            }).collect()
+        };
+
+        if let Some(position) = segments.iter().position(|s| *s == Segment::Wildcard) {
+            assert!(position == segments.len() - 1, "`*` must be the last segment of a route");
+        }
+
+        Pattern {
+            segments: segments,
+        }
+    }
+
+    /// The path the pattern matches, if it's made of only literal segments.
+    fn literal(&self) -> StdOption<String> {
+        let mut path = String::new();
+
+        for segment in &self.segments {
+            match *segment {
+                Segment::Literal(ref literal) => {
+                    path.push('/');
+                    path.push_str(literal);
+                }
+                _ => return None,
+            }
+        }
+
+        if path.is_empty() {
+            path.push('/');
+        }
+
+        Some(path)
+    }
+
+    /// Match `path` against the pattern, returning the captured parameters.
+    fn matches(&self, path: &[String]) -> StdOption<HashMap<String, String>> {
+        let mut params = HashMap::new();
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            match *segment {
+                Segment::Wildcard => {
+                    params.insert("*".to_owned(), path[i..].join("/"));
+                    return Some(params);
+                }
+                _ if i >= path.len() => return None,
+                Segment::Literal(ref literal) => {
+                    if *literal != path[i] {
+                        return None;
+                    }
+                }
+                Segment::Capture(ref name) => {
+                    params.insert(name.clone(), path[i].clone());
+                }
+            }
+        }
+
+        if self.segments.len() == path.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+}
+
+struct Route {
+    method: Code,
+    pattern: Pattern,
+    handler: Box<Handler>,
+    /// how the resources it serves are described in `/.well-known/core`
+    links: Vec<Link>,
+}
+
+impl Route {
+    /// The links listing the route in `/.well-known/core`, and whether they
+    /// were given rather than made up from the pattern.
+    fn links(&self) -> Vec<(Link, bool)> {
+        if self.links.is_empty() {
+            self.pattern.literal().map(|path| (Link::new(&path), false)).into_iter().collect()
+        } else {
+            self.links.iter().map(|link| (link.clone(), true)).collect()
+        }
+    }
+}
+
+/// A `Handler` passing each request on to the first route matching its method
+/// and path.
+///
+/// Requests for a path no route matches are answered with 4.04 Not Found,
+/// those for a path only routes for other methods match with 4.05 Method Not
+/// Allowed.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Route `method` requests for paths matching `pattern` to `handler`.
+    ///
+    /// Panics if `pattern` has a `*` anywhere other than its last segment.
+    pub fn add_route<H: Handler>(&mut self, method: Code, pattern: &str, handler: H) {
+        assert!(method.is_request(), "routes must be for a request method");
+
+        self.routes.push(Route {
+            method: method,
+            pattern: Pattern::parse(pattern),
+            handler: Box::new(handler),
+            links: Vec::new(),
+        });
+    }
+
+    pub fn with_route<H: Handler>(mut self, method: Code, pattern: &str, handler: H) -> Self {
+        self.add_route(method, pattern, handler);
+        self
+    }
+
+    /// List `link` in `/.well-known/core` for the last route added for
+    /// `pattern`, in place of the path the route is otherwise listed by. A
+    /// route can be given any number of links.
+    ///
+    /// Panics if there's no route for `pattern`, or if the target of `link`
+    /// isn't a path matching it.
+    pub fn add_link(&mut self, pattern: &str, link: Link) {
+        let pattern = Pattern::parse(pattern);
+
+        let path: Vec<String> = link.target.trim_start_matches('/')
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(str::to_owned)
+            .collect();
+        assert!(link.target.starts_with('/') && pattern.matches(&path).is_some(),
+                "a route's link must be to a path it serves");
+
+        let route = self.routes.iter_mut().rev().find(|route| route.pattern == pattern);
+        route.expect("links can only be added to a route").links.push(link);
+    }
+
+    pub fn with_link(mut self, pattern: &str, link: Link) -> Self {
+        self.add_link(pattern, link);
+        self
+    }
+
+    /// The links to every resource routed to, a given link taking the place
+    /// of one made up for the same path.
+    fn links(&self) -> Vec<Link> {
+        let mut links: Vec<(Link, bool)> = Vec::new();
+
+        for (link, given) in self.routes.iter().flat_map(Route::links) {
+            match links.iter().position(|&(ref listed, _)| listed.target == link.target) {
+                Some(i) if given && !links[i].1 => links[i] = (link, given),
+                Some(_) => (),
+                None => links.push((link, given)),
+            }
+        }
+
+        links.into_iter().map(|(link, _)| link).collect()
+    }
+
+    /// The `/.well-known/core` document, with only the links passing every
+    /// filter in the query.
+    /// RFC 6690: 4.1.  Query Filtering
+    fn well_known_core(&self, request: &Request) -> Message {
+        let links: Vec<_> = self.links().into_iter()
+            .filter(|link| {
+                request.query_pairs().iter().all(|&(ref name, ref pattern)| link.matches(name, pattern))
+            })
+            .collect();
+
+        Message::new()
+            .with_code(Code::Content)
+            .with_option(ContentFormat::new(link_format::CONTENT_FORMAT))
+            .with_payload(link_format::serialize(&links).into_bytes())
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, mut request: Request) -> IoFuture<Message> {
+        let path: Vec<String> = match request.message().options.try_get::<UriPath>() {
+            Ok(path) => path.unwrap_or_default().into_iter().map(|segment| segment.value).collect(),
+            Err(_) => return Box::new(future::ok(Message::new().with_code(Code::BadOption))),
+        };
+
+        let mut found = false;
+
+        for route in &self.routes {
+            let params = match route.pattern.matches(&path) {
+                Some(params) => params,
+                None => continue,
+            };
+
+            found = true;
+
+            if route.method == request.message().code {
+                request.params = params;
+                return route.handler.handle(request);
+            }
+        }
+
+        if !found && path.len() == 2 && path[0] == ".well-known" && path[1] == "core" {
+            let response = match request.message().code {
+                Code::Get => self.well_known_core(&request),
+                _ => Message::new().with_code(Code::MethodNotAllowed),
+            };
+
+            return Box::new(future::ok(response));
+        }
+
+        let code = if found { Code::MethodNotAllowed } else { Code::NotFound };
+        debug!("no route for {:?} /{}, {:?}", request.message().code, path.join("/"), code);
+
+        Box::new(future::ok(Message::new().with_code(code)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pattern, Router};
+    use error::Error;
+    use link_format::Link;
+    use message::{Message, Code};
+    use message::option::{ContentFormat, Option, UriPath, UriQuery};
+    use server::{Handler, Request};
+
+    use futures::Future;
+
+    fn path(path: &[&str]) -> Vec<String> {
+        path.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn request(code: Code, path: &str) -> Request {
+        let mut msg = Message::new().with_code(code);
+
+        let (path, query) = match path.find('?') {
+            Some(i) => (&path[..i], Some(&path[i + 1..])),
+            None => (path, None),
+        };
+
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            msg.options.push(UriPath::new(segment.to_owned()));
+        }
+
+        for arg in query.into_iter().flat_map(|query| query.split('&')) {
+            msg.options.push(UriQuery::new(arg.to_owned()));
+        }
+
+        Request::new(msg, "127.0.0.1:5683".parse().unwrap())
+    }
+
+    fn respond(router: &Router, request: Request) -> Message {
+        router.handle(request).wait().unwrap()
+    }
+
+    #[test]
+    fn pattern_matching() {
+        let root = Pattern::parse("/");
+        assert!(root.matches(&path(&[])).is_some());
+        assert!(root.matches(&path(&["a"])).is_none());
+
+        let capture = Pattern::parse("/sensors/{id}");
+        assert_eq!(capture.matches(&path(&["sensors", "7"])).unwrap()["id"], "7");
+        assert!(capture.matches(&path(&["sensors"])).is_none());
+        assert!(capture.matches(&path(&["sensors", "7", "name"])).is_none());
+        assert!(capture.matches(&path(&["actuators", "7"])).is_none());
+
+        let wildcard = Pattern::parse("/static/*");
+        assert_eq!(wildcard.matches(&path(&["static"])).unwrap()["*"], "");
+        assert_eq!(wildcard.matches(&path(&["static", "a", "b"])).unwrap()["*"], "a/b");
+        assert!(wildcard.matches(&path(&["other", "a"])).is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn wildcard_must_be_last() {
+        Pattern::parse("/a/*/b");
+    }
+
+    #[test]
+    fn routes_by_method_and_path() {
+        let router = Router::new()
+            .with_route(Code::Get, "/sensors/{id}", |request: Request| {
+                let payload = format!("{} {}",
+                                      request.param("id").unwrap(),
+                                      request.query("unit").unwrap_or("-"));
+
+                Ok::<_, Error>(Message::new().with_code(Code::Content).with_payload(payload.into_bytes()))
+            })
+            .with_route(Code::Put, "/sensors/{id}", |_| Ok::<_, Error>(Message::new().with_code(Code::Changed)))
+            .with_route(Code::Get, "/files/*", |request: Request| {
+                let payload = request.param("*").unwrap().as_bytes().to_vec();
+
+                Ok::<_, Error>(Message::new().with_code(Code::Content).with_payload(payload))
+            });
+
+        let response = respond(&router, request(Code::Get, "/sensors/7?unit=C&verbose"));
+        assert_eq!(response.code, Code::Content);
+        assert_eq!(response.payload, b"7 C");
+
+        let response = respond(&router, request(Code::Put, "/sensors/7"));
+        assert_eq!(response.code, Code::Changed);
+
+        let response = respond(&router, request(Code::Get, "/files/a/b.txt"));
+        assert_eq!(response.payload, b"a/b.txt");
+
+        let response = respond(&router, request(Code::Delete, "/sensors/7"));
+        assert_eq!(response.code, Code::MethodNotAllowed);
+
+        let response = respond(&router, request(Code::Get, "/actuators/7"));
+        assert_eq!(response.code, Code::NotFound);
+
+        let mut malformed = Message::new().with_code(Code::Get);
+        malformed.options.push_raw(UriPath::NUMBER, vec![0xff]);
+        let response = respond(&router, Request::new(malformed, "127.0.0.1:5683".parse().unwrap()));
+        assert_eq!(response.code, Code::BadOption);
+    }
+
+    #[test]
+    fn query_pairs() {
+        let request = request(Code::Get, "/?a=1&b&c=x=y&a=2");
+
+        assert_eq!(request.query("a"), Some("1"));
+        assert_eq!(request.query("b"), Some(""));
+        assert_eq!(request.query("c"), Some("x=y"));
+        assert_eq!(request.query("d"), None);
+        assert_eq!(request.query_pairs().len(), 4);
+    }
+
+    #[test]
+    fn well_known_core() {
+        let content = |_| Ok::<_, Error>(Message::new().with_code(Code::Content));
+
+        let router = Router::new()
+            .with_route(Code::Get, "/sensors/{id}", content)
+            .with_link("/sensors/{id}", Link::new("/sensors/temp").with_rt("temperature-c").with_ct(0).with_obs())
+            .with_link("/sensors/{id}", Link::new("/sensors/light").with_rt("light-lux").with_if("sensor"))
+            .with_route(Code::Get, "/config", content)
+            .with_route(Code::Put, "/config", content)
+            .with_link("/config", Link::new("/config").with_title("Configuration"))
+            .with_route(Code::Get, "/actuators/{id}", content);
+
+        let response = respond(&router, request(Code::Get, "/.well-known/core"));
+        assert_eq!(response.code, Code::Content);
+        assert_eq!(response.options.get::<ContentFormat>().unwrap()[0].value, 40);
+        assert_eq!(response.payload,
+                   &b"</sensors/temp>;rt=\"temperature-c\";ct=0;obs,\
+                      </sensors/light>;rt=\"light-lux\";if=\"sensor\",\
+                      </config>;title=\"Configuration\""[..]);
+
+        let response = respond(&router, request(Code::Get, "/.well-known/core?rt=temperature-c"));
+        assert_eq!(response.payload, &b"</sensors/temp>;rt=\"temperature-c\";ct=0;obs"[..]);
+
+        let response = respond(&router, request(Code::Get, "/.well-known/core?href=/sensors*"));
+        assert_eq!(String::from_utf8(response.payload).unwrap().matches('<').count(), 2);
+
+        let response = respond(&router, request(Code::Get, "/.well-known/core?rt=nothing"));
+        assert_eq!(response.code, Code::Content);
+        assert!(response.payload.is_empty());
+
+        let response = respond(&router, request(Code::Post, "/.well-known/core"));
+        assert_eq!(response.code, Code::MethodNotAllowed);
+    }
+
+    #[test]
+    fn routes_are_listed_by_path() {
+        let content = |_| Ok::<_, Error>(Message::new().with_code(Code::Content));
+
+        let router = Router::new()
+            .with_route(Code::Get, "/", content)
+            .with_route(Code::Get, "/ip", content)
+            .with_route(Code::Get, "/files/*", content);
+
+        let response = respond(&router, request(Code::Get, "/.well-known/core"));
+        assert_eq!(response.payload, &b"</>,</ip>"[..]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn link_must_be_to_a_routed_path() {
+        Router::new()
+            .with_route(Code::Get, "/sensors/{id}", |_| Ok::<_, Error>(Message::new().with_code(Code::Content)))
+            .with_link("/sensors/{id}", Link::new("/actuators/7"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn link_needs_a_route() {
+        Router::new()
+            .with_route(Code::Get, "/sensors/{id}", |_| Ok::<_, Error>(Message::new().with_code(Code::Content)))
+            .with_link("/sensors/*", Link::new("/sensors/7"));
+    }
+}
@@ -0,0 +1,556 @@
+//! Block-wise transfers of large request and response bodies.
+//! RFC 7959: 2.  Block-Wise Transfers
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::option::Option as StdOption;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future;
+use futures::prelude::*;
+
+use client::IoFuture;
+use message::{Message, Code};
+use message::option::{Option, Block, Block1, Block2, Observe, Size1, Size2};
+use super::{Handler, Request};
+
+/// A transfer is identified by the endpoint it's with, the method, and the
+/// options making up the cache key other than the block options themselves,
+/// so it can be continued with a different token.
+/// RFC 7959: 2.4 & 2.5
+type Key = (SocketAddr, u8, Vec<(u16, Vec<u8>)>);
+
+enum Transfer {
+    /// the blocks of a request body received so far
+    Upload(Vec<u8>),
+    /// a response whose later blocks are yet to be requested
+    Download(Message),
+}
+
+struct Entry {
+    expires: Instant,
+    transfer: Transfer,
+}
+
+/// The transfers in progress, each remembered for `lifetime` after its last
+/// block, and at most `capacity` at a time, the oldest being forgotten early
+/// to make room.
+struct Transfers {
+    entries: HashMap<Key, Entry>,
+    /// keys in the order they were last stored
+    order: VecDeque<Key>,
+    capacity: usize,
+    lifetime: Duration,
+}
+
+impl Transfers {
+    fn new(capacity: usize, lifetime: Duration) -> Transfers {
+        Transfers {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            capacity: capacity,
+            lifetime: lifetime,
+        }
+    }
+
+    fn take(&mut self, key: &Key) -> StdOption<Transfer> {
+        let now = Instant::now();
+
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.order.retain(|k| k != key);
+
+                if entry.expires > now {
+                    Some(entry.transfer)
+                } else {
+                    None
+                }
+            }
+            None => None,
+        }
+    }
+
+    fn store(&mut self, key: Key, transfer: Transfer) {
+        let now = Instant::now();
+
+        if self.entries.remove(&key).is_some() {
+            self.order.retain(|k| *k != key);
+        }
+
+        while let Some(oldest) = self.order.front().cloned() {
+            let expired = self.entries.get(&oldest).map_or(true, |entry| entry.expires <= now);
+            if !expired && self.entries.len() < self.capacity {
+                break;
+            }
+
+            if !expired {
+                debug!("block-wise transfer cache full, forgetting transfer with {}", oldest.0);
+            }
+
+            self.entries.remove(&oldest);
+            self.order.pop_front();
+        }
+
+        self.order.push_back(key.clone());
+        self.entries.insert(key, Entry {
+            expires: now + self.lifetime,
+            transfer: transfer,
+        });
+    }
+}
+
+/// A handler wrapped so that request bodies arriving in Block1 blocks are
+/// reassembled before it's called, and responses too large for a single
+/// block are sent in Block2 blocks.
+///
+/// Responses the handler already gave a Block2 option are passed through
+/// untouched.
+pub struct Blockwise<H> {
+    handler: H,
+    /// the largest block sent or asked for, as a size exponent
+    szx: u8,
+    /// the largest request body that will be reassembled
+    max_body_size: usize,
+    transfers: Arc<Mutex<Transfers>>,
+}
+
+impl<H: Handler> Blockwise<H> {
+    pub fn new(handler: H, block_size: usize, max_body_size: usize, capacity: usize, lifetime: Duration) -> Blockwise<H> {
+        Blockwise {
+            handler: handler,
+            szx: Block::szx_for(block_size),
+            max_body_size: max_body_size,
+            transfers: Arc::new(Mutex::new(Transfers::new(capacity, lifetime))),
+        }
+    }
+
+    /// The block starting where the requested `block` does, in a size this
+    /// server sends, or `None` if its number doesn't fit in a block option.
+    /// RFC 7959: 2.4.  Using the Block2 Option
+    fn reduce(&self, block: Block) -> StdOption<Block> {
+        Block::at(block.offset(), false, block.szx.min(self.szx))
+    }
+
+    /// Add `block` of a request body to the transfer, returning the reply to
+    /// send straight away unless the body is now complete, in which case the
+    /// request carries the whole of it.
+    /// RFC 7959: 2.5.  Using the Block1 Option
+    fn receive(&self, key: &Key, request: &mut Message, block: Block) -> StdOption<Message> {
+        let too_large = || {
+            Message::new()
+                .with_code(Code::RequestEntityTooLarge)
+                .with_option(Size1::new(self.max_body_size as u64))
+        };
+
+        match request.options.try_get::<Size1>() {
+            Ok(Some(ref size)) if size[0].value as usize > self.max_body_size => return Some(too_large()),
+            Ok(_) => (),
+            Err(_) => return Some(Message::new().with_code(Code::BadOption)),
+        }
+
+        if block.more && request.payload.len() != block.size() {
+            return Some(Message::new().with_code(Code::BadRequest));
+        }
+
+        let mut transfers = self.transfers.lock().unwrap();
+
+        let mut body = match transfers.take(key) {
+            Some(Transfer::Upload(body)) => body,
+            _ => Vec::new(),
+        };
+
+        if block.num == 0 {
+            body.clear();
+        } else if body.len() != block.offset() {
+            // RFC 7959: 2.9.2.  4.08 Request Entity Incomplete
+            return Some(Message::new().with_code(Code::RequestEntityIncomplete));
+        }
+
+        // RFC 7959: 2.9.3.  4.13 Request Entity Too Large
+        if body.len() + request.payload.len() > self.max_body_size {
+            return Some(too_large());
+        }
+
+        body.extend_from_slice(&request.payload);
+
+        if block.more {
+            transfers.store(key.clone(), Transfer::Upload(body));
+
+            // the next block may be asked for in a smaller size, as long as
+            // it starts where the client expects
+            let szx = block.szx.min(self.szx);
+            return Some(Message::new()
+                .with_code(Code::Continue)
+                .with_option(Block1::new(Block::new(block.num, true, szx))));
+        }
+
+        request.payload = body;
+        request.options.remove::<Block1>();
+        request.options.remove::<Size1>();
+
+        None
+    }
+}
+
+impl<H: Handler> Handler for Blockwise<H> {
+    fn handle(&self, mut request: Request) -> IoFuture<Message> {
+        let block1 = request.message.options.try_get::<Block1>().map(|b| b.map(|b| b[0].value));
+        let block2 = request.message.options.try_get::<Block2>().map(|b| b.map(|b| b[0].value));
+
+        let (block1, block2) = match (block1, block2) {
+            (Ok(block1), Ok(None)) => (block1, None),
+            (Ok(block1), Ok(Some(block2))) => match self.reduce(block2) {
+                Some(block2) => (block1, Some(block2)),
+                None => return Box::new(future::ok(Message::new().with_code(Code::BadOption))),
+            },
+            _ => return Box::new(future::ok(Message::new().with_code(Code::BadOption))),
+        };
+
+        let key = cache_key(request.peer, &request.message);
+
+        // later blocks of a response come from the one that was split, so the
+        // representation doesn't change part way through
+        if let (None, Some(block)) = (block1, block2) {
+            if block.num > 0 {
+                let transfer = self.transfers.lock().unwrap().take(&key);
+
+                if let Some(Transfer::Download(response)) = transfer {
+                    let reply = split(&self.transfers, key, response, block);
+                    return Box::new(future::ok(reply));
+                }
+            }
+        }
+
+        if let Some(block) = block1 {
+            if let Some(reply) = self.receive(&key, &mut request.message, block) {
+                return Box::new(future::ok(reply));
+            }
+        }
+
+        request.message.options.remove::<Block2>();
+        request.message.options.remove::<Size2>();
+
+        let transfers = self.transfers.clone();
+        let szx = block2.map_or(self.szx, |block| block.szx);
+        let num = block2.map_or(0, |block| block.num);
+
+        let response = self.handler.handle(request).map(move |mut response| {
+            if let Some(block) = block1 {
+                // RFC 7959: 3.3.  Combining Block-Wise POST with Block2
+                response.options.push(Block1::new(Block::new(block.num, false, block.szx)));
+            }
+
+            if response.options.get_raw::<Block2>().is_some() {
+                return response;
+            }
+
+            if num == 0 && response.payload.len() <= 1 << (szx + 4) {
+                return response;
+            }
+
+            split(&transfers, key, response, Block::new(num, false, szx))
+        });
+
+        Box::new(response)
+    }
+}
+
+/// The reply carrying `block` of `response`, the rest being kept for the
+/// client to ask for.
+/// RFC 7959: 2.4.  Using the Block2 Option
+fn split(transfers: &Mutex<Transfers>, key: Key, mut response: Message, block: Block) -> Message {
+    let total = response.payload.len();
+
+    if block.offset() >= total && block.num > 0 {
+        return Message::new().with_code(Code::BadOption);
+    }
+
+    let end = total.min(block.offset() + block.size());
+    let more = end < total;
+
+    let mut reply = Message {
+        payload: response.payload[block.offset()..end].to_vec(),
+        ..response.clone()
+    };
+
+    reply.options.push(Block2::new(Block::new(block.num, more, block.szx)));
+    if block.num == 0 {
+        reply.options.push(Size2::new(total as u64));
+    }
+
+    if more {
+        response.options.remove::<Block1>();
+        transfers.lock().unwrap().store(key, Transfer::Download(response));
+    }
+
+    reply
+}
+
+/// The key identifying the transfer `request` is part of.
+fn cache_key(peer: SocketAddr, request: &Message) -> Key {
+    let options = request.options.map.iter()
+        .filter(|&(&number, _)| is_cache_key(number))
+        .flat_map(|(&number, values)| values.iter().map(move |value| (number, value.clone())))
+        .collect();
+
+    (peer, request.code.as_u8(), options)
+}
+
+/// Whether option `number` is part of the cache key. The block options are
+/// left out, as is Observe so that the rest of a notification can be fetched.
+/// RFC 7252: 5.4.6, RFC 7641: 2 & RFC 7959: 2.10
+fn is_cache_key(number: u16) -> bool {
+    let no_cache_key = number & 0x1e == 0x1c;
+    let excluded = [Block1::NUMBER, Block2::NUMBER, Observe::NUMBER];
+
+    !no_cache_key && !excluded.contains(&number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Blockwise, Transfer, Transfers};
+    use error::Error;
+    use message::{Message, Code};
+    use message::option::{Option, Block, Block1, Block2, Size1, Size2, UriPath};
+    use server::{Handler, Request};
+
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use futures::Future;
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:5683".parse().unwrap()
+    }
+
+    fn handle<H: Handler>(handler: &H, request: Message) -> Message {
+        handler.handle(Request::new(request, peer())).wait().unwrap()
+    }
+
+    fn block1(msg: &Message) -> Block {
+        msg.options.get::<Block1>().unwrap()[0].value
+    }
+
+    fn block2(msg: &Message) -> Block {
+        msg.options.get::<Block2>().unwrap()[0].value
+    }
+
+    fn get(token: u8, block: Block) -> Message {
+        Message::new()
+            .with_code(Code::Get)
+            .with_token(&[token])
+            .with_option(UriPath::new("large".to_owned()))
+            .with_option(Block2::new(block))
+    }
+
+    fn put(body: &[u8], block: Block) -> Message {
+        let end = body.len().min(block.offset() + block.size());
+
+        Message::new()
+            .with_code(Code::Put)
+            .with_option(UriPath::new("upload".to_owned()))
+            .with_option(Block1::new(block))
+            .with_payload(body[block.offset()..end].to_vec())
+    }
+
+    /// A handler for a 100 byte resource, counting how often it's rendered.
+    fn large_resource() -> (Arc<AtomicUsize>, impl Handler) {
+        let renders = Arc::new(AtomicUsize::new(0));
+        let counter = renders.clone();
+
+        let handler = move |request: Request| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            assert!(request.message().options.get::<Block2>().is_none());
+
+            Ok::<_, Error>(Message::new()
+                .with_code(Code::Content)
+                .with_payload((0..100).collect()))
+        };
+
+        (renders, handler)
+    }
+
+    /// A handler echoing the body it was given.
+    fn echo(request: Request) -> Result<Message, Error> {
+        assert!(request.message().options.get::<Block1>().is_none());
+
+        Ok(Message::new()
+            .with_code(Code::Changed)
+            .with_payload(request.into_message().payload))
+    }
+
+    fn blockwise<H: Handler>(handler: H, block_size: usize, max_body_size: usize) -> Blockwise<H> {
+        Blockwise::new(handler, block_size, max_body_size, 16, Duration::from_secs(60))
+    }
+
+    #[test]
+    fn large_response_is_split() {
+        let (renders, handler) = large_resource();
+        let server = blockwise(handler, 32, 1024);
+
+        let first = handle(&server, Message::new().with_code(Code::Get).with_option(UriPath::new("large".to_owned())));
+        assert_eq!(block2(&first), Block::new(0, true, 1));
+        assert_eq!(first.options.get::<Size2>().unwrap()[0].value, 100);
+        assert_eq!(first.payload, (0..32).collect::<Vec<u8>>());
+
+        // later blocks can use a new token, and come from the same rendering
+        let second = handle(&server, get(2, Block::new(1, false, 1)));
+        assert_eq!(block2(&second), Block::new(1, true, 1));
+        assert!(second.options.get::<Size2>().is_none());
+        assert_eq!(second.payload, (32..64).collect::<Vec<u8>>());
+
+        let last = handle(&server, get(3, Block::new(3, false, 1)));
+        assert_eq!(block2(&last), Block::new(3, false, 1));
+        assert_eq!(last.payload, (96..100).collect::<Vec<u8>>());
+
+        assert_eq!(renders.load(Ordering::SeqCst), 1);
+
+        // the transfer is over, so this is rendered afresh and is past the end
+        let past_end = handle(&server, get(4, Block::new(7, false, 1)));
+        assert_eq!(past_end.code, Code::BadOption);
+        assert_eq!(renders.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn client_chooses_smaller_blocks() {
+        let (_, handler) = large_resource();
+        let server = blockwise(handler, 1024, 1024);
+
+        let first = handle(&server, get(1, Block::new(0, false, 0)));
+        assert_eq!(block2(&first), Block::new(0, true, 0));
+        assert_eq!(first.payload.len(), 16);
+
+        let (_, handler) = large_resource();
+        let server = blockwise(handler, 32, 1024);
+
+        let first = handle(&server, get(1, Block::new(0, false, 6)));
+        assert_eq!(block2(&first), Block::new(0, true, 1));
+
+        // without asking, small responses are left alone
+        let small = blockwise(echo, 32, 1024);
+        let response = handle(&small, Message::new().with_code(Code::Get).with_payload(vec![1; 32]));
+        assert!(response.options.get::<Block2>().is_none());
+    }
+
+    #[test]
+    fn later_block_in_smaller_size_starts_at_same_offset() {
+        let handler = |_| {
+            Ok::<_, Error>(Message::new()
+                .with_code(Code::Content)
+                .with_payload((0..3000).map(|i| (i % 251) as u8).collect()))
+        };
+        let body: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let server = blockwise(handler, 256, 1024);
+
+        // block 1 of 1024 bytes starts at byte 1024, which is block 4 of 256
+        let first = handle(&server, get(1, Block::new(1, false, 6)));
+        assert_eq!(block2(&first), Block::new(4, true, 4));
+        assert_eq!(&first.payload[..], &body[1024..1280]);
+
+        // and likewise from the transfer kept for the rest
+        let second = handle(&server, get(2, Block::new(2, false, 6)));
+        assert_eq!(block2(&second), Block::new(8, true, 4));
+        assert_eq!(&second.payload[..], &body[2048..2304]);
+    }
+
+    #[test]
+    fn upload_is_reassembled() {
+        let server = blockwise(echo, 1024, 1024);
+        let body: Vec<u8> = (0..40).collect();
+
+        let first = handle(&server, put(&body, Block::new(0, true, 0)));
+        assert_eq!(first.code, Code::Continue);
+        assert_eq!(block1(&first), Block::new(0, true, 0));
+
+        let second = handle(&server, put(&body, Block::new(1, true, 0)));
+        assert_eq!(second.code, Code::Continue);
+
+        let last = handle(&server, put(&body, Block::new(2, false, 0)));
+        assert_eq!(last.code, Code::Changed);
+        assert_eq!(block1(&last), Block::new(2, false, 0));
+        assert_eq!(last.payload, body);
+    }
+
+    #[test]
+    fn upload_negotiates_smaller_blocks() {
+        let server = blockwise(echo, 16, 1024);
+        let body: Vec<u8> = (0..80).collect();
+
+        let first = handle(&server, put(&body, Block::new(0, true, 1)));
+        assert_eq!(block1(&first), Block::new(0, true, 0));
+
+        // carries on from byte 32 in 16 byte blocks
+        handle(&server, put(&body, Block::new(2, true, 0)));
+        handle(&server, put(&body, Block::new(3, true, 0)));
+
+        // the echoed body is too large for one block too
+        // RFC 7959: 3.3.  Combining Block-Wise POST with Block2
+        let last = handle(&server, put(&body, Block::new(4, false, 0)));
+        assert_eq!(block1(&last), Block::new(4, false, 0));
+        assert_eq!(block2(&last), Block::new(0, true, 0));
+        assert_eq!(&last.payload[..], &body[..16]);
+
+        let rest = Message::new()
+            .with_code(Code::Put)
+            .with_option(UriPath::new("upload".to_owned()))
+            .with_option(Block2::new(Block::new(4, false, 0)));
+        let rest = handle(&server, rest);
+        assert_eq!(rest.code, Code::Changed);
+        assert!(rest.options.get::<Block1>().is_none());
+        assert_eq!(block2(&rest), Block::new(4, false, 0));
+        assert_eq!(&rest.payload[..], &body[64..]);
+    }
+
+    #[test]
+    fn upload_out_of_order_is_incomplete() {
+        let server = blockwise(echo, 1024, 1024);
+        let body: Vec<u8> = (0..40).collect();
+
+        let response = handle(&server, put(&body, Block::new(1, true, 0)));
+        assert_eq!(response.code, Code::RequestEntityIncomplete);
+
+        handle(&server, put(&body, Block::new(0, true, 0)));
+        let response = handle(&server, put(&body, Block::new(2, false, 0)));
+        assert_eq!(response.code, Code::RequestEntityIncomplete);
+    }
+
+    #[test]
+    fn upload_too_large() {
+        let server = blockwise(echo, 1024, 32);
+        let body: Vec<u8> = (0..48).collect();
+
+        handle(&server, put(&body, Block::new(0, true, 0)));
+        handle(&server, put(&body, Block::new(1, true, 0)));
+
+        let response = handle(&server, put(&body, Block::new(2, false, 0)));
+        assert_eq!(response.code, Code::RequestEntityTooLarge);
+        assert_eq!(response.options.get::<Size1>().unwrap()[0].value, 32);
+
+        // announced up front
+        let request = put(&body, Block::new(0, true, 0)).with_option(Size1::new(48));
+        let response = handle(&server, request);
+        assert_eq!(response.code, Code::RequestEntityTooLarge);
+    }
+
+    #[test]
+    fn transfers_are_bounded() {
+        let mut transfers = Transfers::new(2, Duration::from_secs(60));
+        let key = |n| (peer(), 1, vec![(11, vec![n])]);
+
+        for n in 0..3 {
+            transfers.store(key(n), Transfer::Upload(vec![n]));
+        }
+
+        assert_eq!(transfers.entries.len(), 2);
+        assert!(transfers.take(&key(0)).is_none());
+        assert!(transfers.take(&key(2)).is_some());
+        assert!(transfers.take(&key(2)).is_none());
+
+        let mut transfers = Transfers::new(2, Duration::from_millis(0));
+        transfers.store(key(0), Transfer::Upload(vec![0]));
+        assert!(transfers.take(&key(0)).is_none());
+    }
+}
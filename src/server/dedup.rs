@@ -0,0 +1,162 @@
+//! RFC 7252: 4.5.  Message Deduplication
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use message::Message;
+
+/// What is known about an incoming message.
+#[derive(Debug, PartialEq)]
+pub enum Seen {
+    /// the message is new, and has been recorded
+    New,
+    /// the message is a duplicate of one still being handled, or of a
+    /// non-confirmable one
+    Pending,
+    /// the message is a duplicate of one that was answered with this reply
+    Answered(Message),
+}
+
+struct Entry {
+    expires: Instant,
+    reply: Option<Message>,
+}
+
+/// The messages received in the recent past, keyed by sender and message ID,
+/// along with the reply each confirmable one was sent.
+///
+/// Each is remembered for the lifetime it was recorded with, but only up to
+/// `capacity` at a time, the oldest being forgotten early to make room.
+pub struct Deduplicator {
+    entries: HashMap<(SocketAddr, u16), Entry>,
+    /// keys in the order they were recorded
+    order: VecDeque<(SocketAddr, u16)>,
+    capacity: usize,
+}
+
+impl Deduplicator {
+    pub fn new(capacity: usize) -> Deduplicator {
+        Deduplicator {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            capacity: capacity,
+        }
+    }
+
+    /// Look up the message `mid` from `peer`, recording it for `lifetime` if
+    /// it's new.
+    pub fn check(&mut self, peer: SocketAddr, mid: u16, lifetime: Duration) -> Seen {
+        let now = Instant::now();
+        self.expire(now);
+
+        let key = (peer, mid);
+
+        match self.entries.get(&key) {
+            Some(entry) if entry.expires > now => {
+                return match entry.reply {
+                    Some(ref reply) => Seen::Answered(reply.clone()),
+                    None => Seen::Pending,
+                };
+            }
+            _ => (),
+        }
+
+        if self.entries.len() >= self.capacity {
+            self.evict();
+        }
+
+        if self.entries.contains_key(&key) {
+            // expired, but not yet reached the front of the queue
+            self.order.retain(|k| *k != key);
+        }
+
+        self.order.push_back(key);
+        self.entries.insert(key, Entry {
+            expires: now + lifetime,
+            reply: None,
+        });
+
+        Seen::New
+    }
+
+    /// Remember `reply` as the answer to the message `mid` from `peer`, for
+    /// replaying to duplicates.
+    pub fn answer(&mut self, peer: SocketAddr, mid: u16, reply: Message) {
+        if let Some(entry) = self.entries.get_mut(&(peer, mid)) {
+            entry.reply = Some(reply);
+        }
+    }
+
+    /// Forget the entries at the front of the queue that have expired.
+    fn expire(&mut self, now: Instant) {
+        while let Some(key) = self.order.front().cloned() {
+            match self.entries.get(&key) {
+                Some(entry) if entry.expires > now => break,
+                _ => (),
+            }
+
+            self.entries.remove(&key);
+            self.order.pop_front();
+        }
+    }
+
+    fn evict(&mut self) {
+        if let Some(key) = self.order.pop_front() {
+            debug!("deduplication cache full, forgetting message {} from {}", key.1, key.0);
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Deduplicator, Seen};
+    use message::{Message, Code};
+
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn duplicates_are_recognised() {
+        let peer: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:5684".parse().unwrap();
+        let lifetime = Duration::from_secs(60);
+        let mut cache = Deduplicator::new(16);
+
+        assert_eq!(cache.check(peer, 1, lifetime), Seen::New);
+        assert_eq!(cache.check(peer, 1, lifetime), Seen::Pending);
+        assert_eq!(cache.check(other, 1, lifetime), Seen::New);
+
+        let reply = Message::new().with_code(Code::Content).with_mid(1);
+        cache.answer(peer, 1, reply.clone());
+        assert_eq!(cache.check(peer, 1, lifetime), Seen::Answered(reply));
+    }
+
+    #[test]
+    fn entries_expire() {
+        let peer: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+        let mut cache = Deduplicator::new(16);
+
+        assert_eq!(cache.check(peer, 1, Duration::from_millis(10)), Seen::New);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.check(peer, 1, Duration::from_millis(10)), Seen::New);
+        assert_eq!(cache.entries.len(), 1);
+    }
+
+    #[test]
+    fn capacity_is_bounded() {
+        let peer: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+        let lifetime = Duration::from_secs(60);
+        let mut cache = Deduplicator::new(4);
+
+        for mid in 0..10 {
+            assert_eq!(cache.check(peer, mid, lifetime), Seen::New);
+        }
+
+        assert_eq!(cache.entries.len(), 4);
+        assert_eq!(cache.check(peer, 9, lifetime), Seen::Pending);
+        assert_eq!(cache.check(peer, 0, lifetime), Seen::New);
+    }
+}
@@ -0,0 +1,99 @@
+//! Reliable delivery of the confirmable messages a server sends on its own,
+//! such as separate responses and notifications.
+//! RFC 7252: 4.2.  Messages Transmitted Reliably
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use tokio::timer::Delay;
+
+use error::Error;
+use message::{Message, Mtype};
+use socket::{ExpectedReply, Socket};
+use transmission::{back_off, initial_timeout, timer_error};
+
+/// A confirmable message, retransmitted with exponential back-off until it is
+/// acknowledged, reset, or `max_retransmit` is exceeded.
+///
+/// These are responses rather than requests, so they aren't held back by
+/// NSTART.
+pub struct Delivery {
+    socket: Socket,
+    peer: SocketAddr,
+    msg: Message,
+    reply: ExpectedReply,
+    max_retransmit: u32,
+    /// number of times the message has been transmitted
+    attempts: u32,
+    timeout: Duration,
+    /// started on the first transmission
+    timer: Option<Delay>,
+}
+
+impl Delivery {
+    pub fn new(socket: Socket, peer: SocketAddr, msg: Message) -> Delivery {
+        debug_assert_eq!(msg.mtype, Mtype::Confirmable);
+
+        let reply = socket.expect_reply(peer, msg.mid);
+        let params = socket.parameters().clone();
+
+        Delivery {
+            socket: socket,
+            peer: peer,
+            msg: msg,
+            reply: reply,
+            max_retransmit: params.max_retransmit,
+            attempts: 0,
+            timeout: initial_timeout(&params),
+            timer: None,
+        }
+    }
+
+    fn transmit(&mut self) {
+        self.attempts += 1;
+        self.socket.send(self.msg.clone(), self.peer);
+    }
+}
+
+impl Future for Delivery {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        self.socket.spawn();
+
+        if self.timer.is_none() {
+            self.timer = Some(Delay::new(Instant::now() + self.timeout));
+            self.transmit();
+        }
+
+        match self.reply.reply.poll() {
+            Ok(Async::Ready(ref reply)) if reply.mtype == Mtype::Reset => return Err(Error::Reset),
+            Ok(Async::Ready(_)) => return Ok(Async::Ready(())),
+            Ok(Async::NotReady) => (),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "socket closed").into()),
+        }
+
+        loop {
+            match self.timer.as_mut().map(Delay::poll) {
+                Some(Ok(Async::Ready(()))) => (),
+                Some(Ok(Async::NotReady)) | None => return Ok(Async::NotReady),
+                Some(Err(e)) => return Err(timer_error(e)),
+            }
+
+            if self.attempts > self.max_retransmit {
+                return Err(Error::Timeout);
+            }
+
+            self.timeout = back_off(self.timeout);
+            if let Some(ref mut timer) = self.timer {
+                timer.reset(Instant::now() + self.timeout);
+            }
+
+            debug!("retransmitting to {}, attempt {}", self.peer, self.attempts + 1);
+            self.transmit();
+        }
+    }
+}
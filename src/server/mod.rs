@@ -0,0 +1,825 @@
+//! A CoAP server answering the requests that arrive on a `Socket`.
+//!
+//! Each request is passed to a `Handler` as it arrives, so a slow handler
+//! doesn't hold up any other request. The response it produces is sent back
+//! piggybacked on the ACK of a confirmable request, or as a non-confirmable
+//! message for a non-confirmable one.
+//! RFC 7252: 5.2.  Responses
+//!
+//! If the handler takes longer than the separate response delay to answer a
+//! confirmable request, the request is acknowledged straight away and the
+//! response follows in a confirmable message of its own.
+//! RFC 7252: 5.2.2.  Separate
+//!
+//! Requests that are duplicates of one received recently aren't handled
+//! again. A duplicate confirmable request is answered with the reply the
+//! original got, a duplicate non-confirmable one is ignored.
+//! RFC 7252: 4.5.  Message Deduplication
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::option::Option as StdOption;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use futures::prelude::*;
+use futures::sync::mpsc;
+use tokio;
+use tokio::timer::Delay;
+
+use client::IoFuture;
+#[cfg(feature = "dtls")]
+use dtls::{DtlsConfig, PeerIdentity};
+use error::Error;
+use message::{Message, Mtype, Code};
+use message::option::{UriPath, UriQuery};
+#[cfg(feature = "oscore")]
+use oscore::{ContextStore, Exchange, SecurityContext};
+use socket::Socket;
+use transmission::TransmissionParameters;
+
+use self::block::Blockwise;
+use self::dedup::{Deduplicator, Seen};
+use self::delivery::Delivery;
+#[cfg(feature = "oscore")]
+use self::protected::Protected;
+
+pub use self::observe::Observable;
+
+mod block;
+mod dedup;
+mod delivery;
+mod observe;
+#[cfg(feature = "oscore")]
+mod protected;
+
+/// The default number of recently received messages remembered for
+/// deduplication.
+const DEFAULT_DEDUP_CAPACITY: usize = 1024;
+
+/// The default size of the blocks a large response body is sent in.
+const DEFAULT_BLOCK_SIZE: usize = 1024;
+
+/// The default limit on the size of a request body reassembled from blocks.
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// The default number of block-wise transfers kept track of at once.
+const DEFAULT_TRANSFER_CAPACITY: usize = 64;
+
+/// A request received by the server.
+#[derive(Clone, Debug)]
+pub struct Request {
+    message: Message,
+    peer: SocketAddr,
+    /// the path segments captured by the route the request was matched to
+    pub(crate) params: HashMap<String, String>,
+    query: Vec<(String, String)>,
+    /// the socket the request arrived on, if it came from a `Server`
+    pub(crate) socket: StdOption<Socket>,
+    /// the OSCORE exchange the request was unprotected in, which the response
+    /// is protected in
+    #[cfg(feature = "oscore")]
+    pub(crate) oscore: StdOption<Exchange>,
+}
+
+impl Request {
+    /// A request for `message`. Malformed query arguments are left out, the
+    /// server answers those requests with 4.02 before they get this far.
+    pub fn new(message: Message, peer: SocketAddr) -> Request {
+        let query = message.options.try_get::<UriQuery>()
+            .unwrap_or_default()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|arg| {
+                let mut pair = arg.value.splitn(2, '=');
+                let key = pair.next().unwrap_or("").to_owned();
+                let value = pair.next().unwrap_or("").to_owned();
+
+                (key, value)
+            })
+            .collect();
+
+        Request {
+            message: message,
+            peer: peer,
+            params: HashMap::new(),
+            query: query,
+            socket: None,
+            #[cfg(feature = "oscore")]
+            oscore: None,
+        }
+    }
+
+    /// The path segment captured as `name` by the route, see `Router`.
+    pub fn param(&self, name: &str) -> StdOption<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    pub fn params(&self) -> &HashMap<String, String> {
+        &self.params
+    }
+
+    /// The value of the first query argument named `key`. Arguments without
+    /// a `=` have an empty value.
+    pub fn query(&self, key: &str) -> StdOption<&str> {
+        self.query.iter()
+            .find(|&&(ref k, _)| k == key)
+            .map(|&(_, ref v)| v.as_str())
+    }
+
+    /// Every query argument as a key/value pair, in order.
+    pub fn query_pairs(&self) -> &[(String, String)] {
+        &self.query
+    }
+
+    pub fn message(&self) -> &Message {
+        &self.message
+    }
+
+    /// The address the request came from.
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Who the client authenticated as, if the request came over DTLS.
+    #[cfg(feature = "dtls")]
+    pub fn peer_identity(&self) -> StdOption<PeerIdentity> {
+        self.socket.as_ref().and_then(|socket| socket.peer_identity(&self.peer))
+    }
+
+    /// The security context the request was protected with, if it came
+    /// protected with OSCORE.
+    #[cfg(feature = "oscore")]
+    pub fn oscore_context(&self) -> StdOption<&SecurityContext> {
+        self.oscore.as_ref().map(Exchange::context)
+    }
+
+    pub fn into_message(self) -> Message {
+        self.message
+    }
+}
+
+/// Produces the response to a request.
+///
+/// Only the code, options and payload of the response are used, the server
+/// takes care of its type, message ID and token. A handler that fails is
+/// answered with 5.00 Internal Server Error.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: Request) -> IoFuture<Message>;
+}
+
+impl<F, R> Handler for F
+    where F: Fn(Request) -> R + Send + Sync + 'static,
+          R: IntoFuture<Item = Message, Error = Error>,
+          R::Future: Send + 'static
+{
+    fn handle(&self, request: Request) -> IoFuture<Message> {
+        Box::new(self(request).into_future())
+    }
+}
+
+pub struct Server {
+    socket: Socket,
+    dedup_capacity: usize,
+    separate_delay: StdOption<Duration>,
+    block_size: usize,
+    max_body_size: usize,
+    transfer_capacity: usize,
+    #[cfg(feature = "oscore")]
+    oscore: StdOption<ContextStore>,
+}
+
+impl Server {
+    pub fn bind(addr: &SocketAddr) -> Result<Server, Error> {
+        Ok(Server::new(Socket::bind(addr)?))
+    }
+
+    /// Serve `coaps://` requests from the clients whose keys are in
+    /// `config`.
+    #[cfg(feature = "dtls")]
+    pub fn bind_dtls(addr: &SocketAddr, config: &DtlsConfig) -> Result<Server, Error> {
+        Ok(Server::new(Socket::bind_dtls(addr, config)?))
+    }
+
+    /// Serve the requests arriving on `socket`, which can still be used to
+    /// send requests of our own.
+    pub fn new(socket: Socket) -> Server {
+        Server {
+            socket: socket,
+            dedup_capacity: DEFAULT_DEDUP_CAPACITY,
+            separate_delay: None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            transfer_capacity: DEFAULT_TRANSFER_CAPACITY,
+            #[cfg(feature = "oscore")]
+            oscore: None,
+        }
+    }
+
+    pub fn with_parameters(mut self, params: TransmissionParameters) -> Self {
+        self.socket = self.socket.with_parameters(params);
+        self
+    }
+
+    /// Set how many recently received messages are remembered, and their
+    /// replies kept, for deduplication. Once full the oldest are forgotten
+    /// before their EXCHANGE_LIFETIME is up.
+    pub fn set_dedup_capacity(&mut self, capacity: usize) {
+        assert!(capacity > 0, "deduplication capacity must be at least 1");
+
+        self.dedup_capacity = capacity;
+    }
+
+    pub fn with_dedup_capacity(mut self, capacity: usize) -> Self {
+        self.set_dedup_capacity(capacity);
+        self
+    }
+
+    /// Set how long a handler has to answer a confirmable request before the
+    /// request is acknowledged and the response sent separately. Defaults to
+    /// half of ACK_TIMEOUT, so the client doesn't retransmit in the meantime.
+    pub fn set_separate_delay(&mut self, delay: Duration) {
+        self.separate_delay = Some(delay);
+    }
+
+    pub fn with_separate_delay(mut self, delay: Duration) -> Self {
+        self.set_separate_delay(delay);
+        self
+    }
+
+    /// Set the size of the blocks a response payload is split into when it
+    /// is too large to send in one, rounded down to a power of two between 16
+    /// and 1024 bytes. Clients can ask for smaller blocks.
+    /// RFC 7959: 2.4.  Using the Block2 Option
+    pub fn set_block_size(&mut self, size: usize) {
+        self.block_size = size;
+    }
+
+    pub fn with_block_size(mut self, size: usize) -> Self {
+        self.set_block_size(size);
+        self
+    }
+
+    /// Set the largest request body that will be reassembled from Block1
+    /// blocks, 1 MiB by default. Larger ones get a 4.13 response.
+    pub fn set_max_body_size(&mut self, size: usize) {
+        self.max_body_size = size;
+    }
+
+    pub fn with_max_body_size(mut self, size: usize) -> Self {
+        self.set_max_body_size(size);
+        self
+    }
+
+    /// Set how many partial block-wise transfers are kept track of at once.
+    /// Once full the oldest are forgotten before their EXCHANGE_LIFETIME is
+    /// up.
+    pub fn set_transfer_capacity(&mut self, capacity: usize) {
+        assert!(capacity > 0, "transfer capacity must be at least 1");
+
+        self.transfer_capacity = capacity;
+    }
+
+    pub fn with_transfer_capacity(mut self, capacity: usize) -> Self {
+        self.set_transfer_capacity(capacity);
+        self
+    }
+
+    /// Unprotect OSCORE requests with the security contexts in `contexts`,
+    /// and protect their responses. Requests without the OSCORE option are
+    /// still handled as they are, a handler can tell them apart with
+    /// `Request::oscore_context`.
+    /// RFC 8613: 8.  OSCORE Message Processing
+    #[cfg(feature = "oscore")]
+    pub fn set_oscore(&mut self, contexts: ContextStore) {
+        self.oscore = Some(contexts);
+    }
+
+    #[cfg(feature = "oscore")]
+    pub fn with_oscore(mut self, contexts: ContextStore) -> Self {
+        self.set_oscore(contexts);
+        self
+    }
+
+    pub fn socket(&self) -> &Socket {
+        &self.socket
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.socket.local_addr()
+    }
+
+    /// Answer every request with `handler`.
+    ///
+    /// Request bodies sent block-wise are reassembled before the handler is
+    /// called, and responses too large for a single block are sent block-wise.
+    /// OSCORE requests are unprotected before that, see `set_oscore`.
+    ///
+    /// The returned future must be run on a tokio executor, it doesn't
+    /// complete.
+    pub fn serve<H: Handler>(self, handler: H) -> IoFuture<()> {
+        let requests = self.socket.listen();
+        let separate_delay = self.separate_delay.unwrap_or_else(|| self.socket.parameters().ack_timeout / 2);
+        let handler = Blockwise::new(
+            handler,
+            self.block_size,
+            self.max_body_size,
+            self.transfer_capacity,
+            self.socket.parameters().exchange_lifetime(),
+        );
+        #[cfg(feature = "oscore")]
+        let handler = Protected::new(handler, self.oscore);
+
+        Box::new(Serve {
+            socket: self.socket,
+            handler: Arc::new(handler),
+            requests: requests,
+            dedup: Arc::new(Mutex::new(Deduplicator::new(self.dedup_capacity))),
+            separate_delay: separate_delay,
+        })
+    }
+}
+
+struct Serve<H> {
+    socket: Socket,
+    handler: Arc<H>,
+    requests: mpsc::UnboundedReceiver<(Message, SocketAddr)>,
+    dedup: Arc<Mutex<Deduplicator>>,
+    separate_delay: Duration,
+}
+
+impl<H: Handler> Serve<H> {
+    fn respond(&self, request: Message, peer: SocketAddr) {
+        info!("--> {:?}", request);
+
+        let params = self.socket.parameters();
+        let lifetime = match request.mtype {
+            Mtype::Confirmable => params.exchange_lifetime(),
+            _ => params.non_lifetime(),
+        };
+
+        match self.dedup.lock().unwrap().check(peer, request.mid, lifetime) {
+            Seen::New => (),
+            Seen::Pending => {
+                debug!("ignoring duplicate of message {} from {}", request.mid, peer);
+                return;
+            }
+            Seen::Answered(reply) => {
+                debug!("replaying reply to duplicate of message {} from {}", request.mid, peer);
+                self.socket.send(reply, peer);
+                return;
+            }
+        }
+
+        let socket = self.socket.clone();
+        let dedup = self.dedup.clone();
+        let confirmable = request.mtype == Mtype::Confirmable;
+        let mut reply = reply_to(&request, &socket);
+        let ack = request.new_ack();
+
+        let response = if is_readable(&request) {
+            let mut request = Request::new(request, peer);
+            request.socket = Some(self.socket.clone());
+
+            self.handler.handle(request)
+        } else {
+            debug!("rejecting request {} from {} with a malformed Uri-Path or Uri-Query", request.mid, peer);
+            Box::new(future::ok(Message::new().with_code(Code::BadOption)))
+        };
+
+        let response = response
+            .then(|result| -> Result<Message, ()> {
+                Ok(result.unwrap_or_else(|e| {
+                    error!("handler failed: {:?}", e);
+                    Message::new().with_code(Code::InternalServerError)
+                }))
+            });
+
+        let response = if confirmable {
+            let timeout = Delay::new(Instant::now() + self.separate_delay);
+
+            Either::A(response.select2(timeout).then(move |result| {
+                let pending = match result {
+                    Ok(Either::A((response, _))) => return Either::A(future::ok((response, true))),
+                    Ok(Either::B(((), pending))) => pending,
+                    Err(Either::A(((), _))) => unreachable!("responses never fail"),
+                    Err(Either::B((e, pending))) => {
+                        error!("separate response timer failed: {:?}", e);
+                        pending
+                    }
+                };
+
+                debug!("handler is taking a while, acknowledging {} from {}", ack.mid, peer);
+
+                dedup.lock().unwrap().answer(peer, ack.mid, ack.clone());
+                socket.send(ack, peer);
+
+                Either::B(pending.map(|response| (response, false)))
+            }))
+        } else {
+            Either::B(response.map(|response| (response, true)))
+        };
+
+        let socket = self.socket.clone();
+        let dedup = self.dedup.clone();
+
+        let response = response.and_then(move |(response, piggybacked)| {
+            if !piggybacked {
+                reply.mtype = Mtype::Confirmable;
+                reply.mid = socket.message_ids().next();
+            }
+
+            let reply = Message {
+                code: response.code,
+                options: response.options,
+                payload: response.payload,
+                ..reply
+            };
+
+            info!("<-- {:?}", reply);
+
+            if piggybacked {
+                if confirmable {
+                    dedup.lock().unwrap().answer(peer, reply.mid, reply.clone());
+                }
+
+                socket.send(reply, peer);
+                Either::A(future::ok(()))
+            } else {
+                Either::B(Delivery::new(socket, peer, reply).map_err(move |e| {
+                    warn!("separate response to {} was not acknowledged: {:?}", peer, e);
+                }))
+            }
+        });
+
+        tokio::spawn(response);
+    }
+}
+
+impl<H: Handler> Future for Serve<H> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        self.socket.spawn();
+
+        loop {
+            match self.requests.poll().expect("receivers never fail") {
+                Async::Ready(Some((request, peer))) => self.respond(request, peer),
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Whether the Uri-Path and Uri-Query options of `request` can be read. A
+/// request with malformed ones is answered with 4.02 Bad Option rather than
+/// handled.
+/// RFC 7252: 5.4.1.  Critical/Elective
+pub(crate) fn is_readable(request: &Message) -> bool {
+    request.options.try_get::<UriPath>().is_ok() && request.options.try_get::<UriQuery>().is_ok()
+}
+
+/// The message a response to `request` is sent in, without a code yet.
+///
+/// A confirmable request's response is piggybacked on its ACK, a
+/// non-confirmable request's is sent non-confirmable with a fresh message ID.
+/// RFC 7252: 5.2.1 & 5.2.3
+fn reply_to(request: &Message, socket: &Socket) -> Message {
+    let reply = request.new_reply();
+
+    match request.mtype {
+        Mtype::NonConfirmable => reply
+            .with_mtype(Mtype::NonConfirmable)
+            .with_mid(socket.message_ids().next()),
+        _ => reply,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Request, Server};
+    use client::Client;
+    use endpoint::Endpoint;
+    use error::Error;
+    use message::{Message, Mtype, Code};
+    use message::option::{Option, UriPath, UriQuery};
+    use testing::recv_message;
+    use transmission::TransmissionParameters;
+
+    use std::net::UdpSocket;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    use futures::future::{join_all, Future};
+    use tokio::runtime::Runtime;
+    use tokio::timer::Delay;
+
+    fn echo_path(request: Request) -> Result<Message, Error> {
+        let path = request.message().options.get::<UriPath>().unwrap_or_default();
+        let path: Vec<_> = path.into_iter().map(|segment| segment.value).collect();
+
+        Ok(Message::new()
+            .with_code(Code::Content)
+            .with_payload(path.join("/").into_bytes()))
+    }
+
+    #[test]
+    fn piggybacked_and_non_confirmable_replies() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr();
+        runtime.spawn(server.serve(echo_path).map_err(|e| panic!("{:?}", e)));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let request = Message::new()
+            .with_mtype(Mtype::Confirmable)
+            .with_code(Code::Get)
+            .with_mid(0x1234)
+            .with_token(&[1, 2, 3])
+            .with_option(UriPath::new("a".to_owned()))
+            .with_option(UriPath::new("b".to_owned()));
+        client.send_to(&request.to_bytes().unwrap(), server_addr).unwrap();
+
+        let (reply, _) = recv_message(&client);
+        assert_eq!(reply.mtype, Mtype::Acknowledgement);
+        assert_eq!(reply.mid, 0x1234);
+        assert_eq!(&reply.token[..], &[1, 2, 3]);
+        assert_eq!(reply.code, Code::Content);
+        assert_eq!(reply.payload, b"a/b");
+
+        let request = request.with_mtype(Mtype::NonConfirmable).with_mid(0x1235);
+        client.send_to(&request.to_bytes().unwrap(), server_addr).unwrap();
+
+        let (reply, _) = recv_message(&client);
+        assert_eq!(reply.mtype, Mtype::NonConfirmable);
+        assert_eq!(&reply.token[..], &[1, 2, 3]);
+        assert_eq!(reply.code, Code::Content);
+    }
+
+    #[test]
+    fn malformed_uri_is_bad_option() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr();
+        runtime.spawn(server.serve(echo_path).map_err(|e| panic!("{:?}", e)));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // a Uri-Path that isn't UTF-8, and a Uri-Query longer than 255 bytes
+        let mut bad_path = Message::new()
+            .with_mtype(Mtype::Confirmable)
+            .with_code(Code::Get)
+            .with_mid(0x1234)
+            .with_token(&[1]);
+        bad_path.options.push_raw(UriPath::NUMBER, vec![0xff, 0xfe]);
+
+        let mut bad_query = bad_path.clone().with_mid(0x1235);
+        bad_query.options.remove::<UriPath>();
+        bad_query.options.push_raw(UriQuery::NUMBER, vec![b'a'; 300]);
+
+        for request in &[bad_path, bad_query] {
+            client.send_to(&request.to_bytes().unwrap(), server_addr).unwrap();
+
+            let (reply, _) = recv_message(&client);
+            assert_eq!(reply.mid, request.mid);
+            assert_eq!(reply.code, Code::BadOption);
+        }
+
+        // and the server is still serving
+        let request = Message::new()
+            .with_mtype(Mtype::Confirmable)
+            .with_code(Code::Get)
+            .with_mid(0x1236)
+            .with_option(UriPath::new("a".to_owned()));
+        client.send_to(&request.to_bytes().unwrap(), server_addr).unwrap();
+
+        let (reply, _) = recv_message(&client);
+        assert_eq!(reply.code, Code::Content);
+        assert_eq!(reply.payload, b"a");
+    }
+
+    #[test]
+    fn requests_are_handled_concurrently() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr();
+
+        // the first request only completes after the second has been answered
+        let handler = |request: Request| {
+            let delay = match request.message().options.get::<UriPath>() {
+                Some(ref path) if path[0].value == "slow" => Duration::from_millis(300),
+                _ => Duration::from_millis(0),
+            };
+
+            Delay::new(Instant::now() + delay)
+                .map_err(|_| Error::Timeout)
+                .map(|()| Message::new().with_code(Code::Content))
+        };
+        runtime.spawn(server.serve(handler).map_err(|e| panic!("{:?}", e)));
+
+        let url = format!("coap://{}/slow", server_addr);
+        let slow = Client::get(&url).unwrap()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .send();
+        let fast = Client::get("coap://127.0.0.1/fast").unwrap()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .send()
+            .map(|response| (response, Instant::now()));
+
+        let started = Instant::now();
+        let (slow, (fast, fast_done)) = runtime.block_on(slow.join(fast)).unwrap();
+
+        assert_eq!(slow.code, Code::Content);
+        assert_eq!(fast.code, Code::Content);
+        assert!(fast_done - started < Duration::from_millis(300));
+    }
+
+    #[test]
+    fn block_wise_transfers() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).unwrap()
+            .with_block_size(64)
+            .with_max_body_size(1024);
+        let server_addr = server.local_addr();
+
+        let handler = |request: Request| {
+            let mut payload = request.into_message().payload;
+            payload.reverse();
+
+            Ok::<_, Error>(Message::new().with_code(Code::Changed).with_payload(payload))
+        };
+        runtime.spawn(server.serve(handler).map_err(|e| panic!("{:?}", e)));
+
+        let body: Vec<u8> = (0..1000).map(|n| n as u8).collect();
+        let request = Client::post("coap://127.0.0.1/reverse").unwrap()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_payload(body.clone())
+            .with_block_size(256)
+            .send();
+
+        let response = runtime.block_on(request).unwrap();
+        assert_eq!(response.code, Code::Changed);
+        assert_eq!(response.payload, body.iter().rev().cloned().collect::<Vec<_>>());
+
+        let request = Client::post("coap://127.0.0.1/reverse").unwrap()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_payload(vec![0; 2000])
+            .send();
+
+        let response = runtime.block_on(request).unwrap();
+        assert_eq!(response.code, Code::RequestEntityTooLarge);
+    }
+
+    #[test]
+    fn failing_handler_is_internal_server_error() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr();
+        runtime.spawn(server.serve(|_| Err::<Message, _>(Error::Timeout)).map_err(|e| panic!("{:?}", e)));
+
+        let responses = join_all((0..3).map(move |_| {
+            Client::new()
+                .with_endpoint(Endpoint::Resolved(server_addr))
+                .send()
+        }));
+
+        for response in runtime.block_on(responses).unwrap() {
+            assert_eq!(response.code, Code::InternalServerError);
+        }
+    }
+
+    #[test]
+    fn duplicates_are_not_handled_again() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counter = handled.clone();
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr();
+        let handler = move |_| {
+            let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+
+            Ok::<_, Error>(Message::new().with_code(Code::Changed).with_payload(count.to_string().into_bytes()))
+        };
+        runtime.spawn(server.serve(handler).map_err(|e| panic!("{:?}", e)));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let request = Message::new()
+            .with_mtype(Mtype::Confirmable)
+            .with_code(Code::Post)
+            .with_mid(0x1234)
+            .with_token(&[1]);
+
+        client.send_to(&request.to_bytes().unwrap(), server_addr).unwrap();
+        let (first, _) = recv_message(&client);
+        client.send_to(&request.to_bytes().unwrap(), server_addr).unwrap();
+        let (second, _) = recv_message(&client);
+
+        assert_eq!(first, second);
+        assert_eq!(first.payload, b"1");
+
+        let request = request.with_mtype(Mtype::NonConfirmable).with_mid(0x1235);
+        client.send_to(&request.to_bytes().unwrap(), server_addr).unwrap();
+        let (reply, _) = recv_message(&client);
+        assert_eq!(reply.payload, b"2");
+
+        // the duplicate gets no reply at all
+        client.send_to(&request.to_bytes().unwrap(), server_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let mut buf = [0; 1152];
+        assert!(client.recv_from(&mut buf).is_err());
+
+        assert_eq!(handled.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn slow_handlers_get_separate_responses() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).unwrap()
+            .with_parameters(TransmissionParameters::new().with_ack_timeout(Duration::from_millis(50)))
+            .with_separate_delay(Duration::from_millis(50));
+        let server_addr = server.local_addr();
+        let handler = |_| {
+            Delay::new(Instant::now() + Duration::from_millis(300))
+                .map_err(|_| Error::Timeout)
+                .map(|()| Message::new().with_code(Code::Content).with_payload(b"slow".to_vec()))
+        };
+        runtime.spawn(server.serve(handler).map_err(|e| panic!("{:?}", e)));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let request = Message::new()
+            .with_mtype(Mtype::Confirmable)
+            .with_code(Code::Get)
+            .with_mid(0x1234)
+            .with_token(&[1, 2, 3]);
+        client.send_to(&request.to_bytes().unwrap(), server_addr).unwrap();
+
+        let (ack, _) = recv_message(&client);
+        assert_eq!(ack.mtype, Mtype::Acknowledgement);
+        assert_eq!(ack.code, Code::Empty);
+        assert_eq!(ack.mid, 0x1234);
+        assert!(ack.token.is_empty());
+
+        // a retransmission of the request is answered with the ACK again
+        client.send_to(&request.to_bytes().unwrap(), server_addr).unwrap();
+        let (again, _) = recv_message(&client);
+        assert_eq!(again, ack);
+
+        let (response, _) = recv_message(&client);
+        assert_eq!(response.mtype, Mtype::Confirmable);
+        assert_eq!(response.code, Code::Content);
+        assert_eq!(&response.token[..], &[1, 2, 3]);
+        assert_eq!(response.payload, b"slow");
+
+        // unacknowledged, so it is sent again
+        let (retransmission, _) = recv_message(&client);
+        assert_eq!(retransmission, response);
+
+        client.send_to(&response.new_ack().to_bytes().unwrap(), server_addr).unwrap();
+
+        client.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        let mut buf = [0; 1152];
+        assert!(client.recv_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn client_receives_separate_response() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).unwrap()
+            .with_separate_delay(Duration::from_millis(10));
+        let server_addr = server.local_addr();
+        let handler = |_| {
+            Delay::new(Instant::now() + Duration::from_millis(100))
+                .map_err(|_| Error::Timeout)
+                .map(|()| Message::new().with_code(Code::Content))
+        };
+        runtime.spawn(server.serve(handler).map_err(|e| panic!("{:?}", e)));
+
+        let request = Client::new()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .send();
+        let response = runtime.block_on(request).unwrap();
+
+        assert_eq!(response.mtype, Mtype::Confirmable);
+        assert_eq!(response.code, Code::Content);
+    }
+}
@@ -0,0 +1,601 @@
+//! RFC 7641: 4.  Server-Side Requirements
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::option::Option as StdOption;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::future;
+use futures::prelude::*;
+use futures::sync::mpsc;
+use tokio;
+
+use client::IoFuture;
+use error::Error;
+use message::{Message, Mtype, Code};
+use message::option::{Option, Observe};
+use socket::{ExpectedReply, Socket, Token};
+use super::{Handler, Request};
+use super::delivery::Delivery;
+
+/// The longest an observer goes without a confirmable notification, which
+/// checks that it's still interested.
+/// RFC 7641: 4.5.  Transmission
+const MAX_CON_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Observers are identified by their endpoint and the token they registered
+/// with.
+type Key = (SocketAddr, Token);
+
+struct Shared<H> {
+    handler: H,
+    /// the id of each observer's task and the channel telling it about changes
+    observers: Mutex<HashMap<Key, (usize, mpsc::UnboundedSender<()>)>>,
+    next_id: AtomicUsize,
+    /// incremented each time the resource changes
+    sequence: AtomicUsize,
+    con_interval: Duration,
+}
+
+impl<H> Shared<H> {
+    /// The Observe sequence number of the current state of the resource.
+    fn sequence(&self) -> u64 {
+        (self.sequence.load(Ordering::SeqCst) & 0xff_ffff) as u64
+    }
+
+    /// Forget the observer `key`, provided it's still the one with `id`.
+    fn remove(&self, key: &Key, id: usize) {
+        let mut observers = self.observers.lock().unwrap();
+
+        if observers.get(key).map_or(false, |&(current, _)| current == id) {
+            debug!("removing observer {:?} of {}", key.1, key.0);
+            observers.remove(key);
+        }
+    }
+}
+
+/// A resource clients can observe, its current state being whatever the
+/// wrapped handler returns for a GET request.
+///
+/// A GET with Observe 0 that gets a 2.xx response registers the client as an
+/// observer, and each call to `notify` then sends every observer the response
+/// to its original request again. A GET with Observe 1, or without the
+/// option, from the same endpoint with the same token cancels the
+/// registration, as does a reset in reply to a notification or a confirmable
+/// notification that isn't acknowledged. A response other than 2.xx is sent
+/// as a final notification and ends the observation.
+///
+/// Notifications are non-confirmable, except at least once per `con_interval`,
+/// 24 hours unless set lower.
+pub struct Observable<H> {
+    shared: Arc<Shared<H>>,
+}
+
+impl<H> Clone for Observable<H> {
+    fn clone(&self) -> Self {
+        Observable {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<H: Handler> Observable<H> {
+    pub fn new(handler: H) -> Observable<H> {
+        Observable {
+            shared: Arc::new(Shared {
+                handler: handler,
+                observers: Mutex::new(HashMap::new()),
+                next_id: AtomicUsize::new(0),
+                sequence: AtomicUsize::new(0),
+                con_interval: MAX_CON_INTERVAL,
+            }),
+        }
+    }
+
+    /// Set the longest an observer goes without a confirmable notification,
+    /// zero making every notification confirmable. It can't be more than 24
+    /// hours.
+    ///
+    /// Panics if the resource has already been cloned.
+    pub fn with_con_interval(mut self, interval: Duration) -> Self {
+        assert!(interval <= MAX_CON_INTERVAL, "confirmable notifications must be sent at least every 24 hours");
+
+        Arc::get_mut(&mut self.shared)
+            .expect("can't configure an observable resource once it's shared")
+            .con_interval = interval;
+        self
+    }
+
+    /// The resource has changed, send every observer a notification.
+    pub fn notify(&self) {
+        self.shared.sequence.fetch_add(1, Ordering::SeqCst);
+
+        for &(_, ref changes) in self.shared.observers.lock().unwrap().values() {
+            let _ = changes.unbounded_send(());
+        }
+    }
+
+    fn deregister(&self, key: &Key) {
+        if self.shared.observers.lock().unwrap().remove(key).is_some() {
+            debug!("removing observer {:?} of {}", key.1, key.0);
+        }
+    }
+
+    /// The number of clients currently observing the resource.
+    pub fn observers(&self) -> usize {
+        self.shared.observers.lock().unwrap().len()
+    }
+
+    /// Add an observer, unless it's already registered.
+    /// RFC 7641: 4.1.  Request
+    fn register(&self, socket: Socket, request: Request) {
+        let key = (request.peer(), request.message().token.clone());
+        let mut observers = self.shared.observers.lock().unwrap();
+
+        if observers.contains_key(&key) {
+            return;
+        }
+
+        debug!("adding observer {:?} of {}", key.1, key.0);
+
+        let id = self.shared.next_id.fetch_add(1, Ordering::SeqCst);
+        let (changes_tx, changes_rx) = mpsc::unbounded();
+        observers.insert(key.clone(), (id, changes_tx));
+
+        tokio::spawn(Observer {
+            shared: self.shared.clone(),
+            key: key,
+            id: id,
+            socket: socket,
+            request: request,
+            changes: changes_rx,
+            changed: false,
+            state: State::Idle(None),
+            last_confirmed: Instant::now(),
+            last: false,
+        });
+    }
+}
+
+impl<H: Handler> Handler for Observable<H> {
+    fn handle(&self, request: Request) -> IoFuture<Message> {
+        if request.message().code != Code::Get {
+            return self.shared.handler.handle(request);
+        }
+
+        let key = (request.peer(), request.message().token.clone());
+        let observe = match request.message().options.try_get::<Observe>() {
+            Ok(observe) => observe.map(|observe| observe[0].value),
+            Err(_) => return Box::new(future::ok(Message::new().with_code(Code::BadOption))),
+        };
+
+        match (observe, request.socket.clone()) {
+            (Some(0), Some(socket)) => {
+                let observable = self.clone();
+                let original = request.clone();
+
+                let response = self.shared.handler.handle(request).map(move |mut response| {
+                    response.options.remove::<Observe>();
+
+                    if response.code.class() == 2 {
+                        observable.register(socket, original);
+                        response.options.push(Observe::new(observable.shared.sequence()));
+                    } else {
+                        observable.deregister(&key);
+                    }
+
+                    response
+                });
+
+                Box::new(response)
+            }
+            _ => {
+                // RFC 7641: 3.6.  Cancellation
+                self.deregister(&key);
+                self.shared.handler.handle(request)
+            }
+        }
+    }
+}
+
+enum State {
+    /// waiting for a change, with the last non-confirmable notification sent
+    /// in case it is reset
+    Idle(StdOption<ExpectedReply>),
+    /// getting the current state of the resource
+    Rendering(IoFuture<Message>),
+    /// waiting for a confirmable notification to be acknowledged
+    Confirming(Delivery),
+}
+
+/// The task sending notifications to a single observer, which ends once the
+/// observer is removed.
+struct Observer<H> {
+    shared: Arc<Shared<H>>,
+    key: Key,
+    id: usize,
+    socket: Socket,
+    /// the request the observer registered with, as it was handled
+    request: Request,
+    changes: mpsc::UnboundedReceiver<()>,
+    /// the resource changed since the last notification was rendered
+    changed: bool,
+    state: State,
+    last_confirmed: Instant,
+    /// the notification being sent is the final one
+    last: bool,
+}
+
+impl<H: Handler> Observer<H> {
+    /// Send the notification carrying `response`.
+    /// RFC 7641: 4.2.  Notifications
+    fn notify(&mut self, response: Message) {
+        let peer = self.key.0;
+        let success = response.code.class() == 2;
+        let confirmable = !success || self.last_confirmed.elapsed() >= self.shared.con_interval;
+
+        let mut notification = Message {
+            mtype: if confirmable { Mtype::Confirmable } else { Mtype::NonConfirmable },
+            mid: self.socket.message_ids().next(),
+            token: self.key.1.clone(),
+            ..response
+        };
+
+        notification.options.remove::<Observe>();
+        if success {
+            notification.options.push(Observe::new(self.shared.sequence()));
+        } else {
+            self.last = true;
+        }
+
+        let notification = match self.protect(notification) {
+            Ok(notification) => notification,
+            Err(e) => {
+                error!("failed to protect notification to {}: {:?}", peer, e);
+                self.state = State::Idle(None);
+                self.last = true;
+                return;
+            }
+        };
+
+        info!("<-- {:?}", notification);
+
+        if confirmable {
+            self.state = State::Confirming(Delivery::new(self.socket.clone(), peer, notification));
+        } else {
+            let reply = self.socket.expect_reply(peer, notification.mid);
+            self.socket.send(notification, peer);
+            self.state = State::Idle(Some(reply));
+        }
+    }
+}
+
+impl<H> Observer<H> {
+    /// Protect a notification to an observer that registered over OSCORE.
+    /// RFC 8613: 4.1.3.5.2.  Observe Option in Responses
+    #[cfg(feature = "oscore")]
+    fn protect(&self, notification: Message) -> Result<Message, Error> {
+        match self.request.oscore {
+            Some(ref exchange) => exchange.protect_notification(&notification),
+            None => Ok(notification),
+        }
+    }
+
+    #[cfg(not(feature = "oscore"))]
+    fn protect(&self, notification: Message) -> Result<Message, Error> {
+        Ok(notification)
+    }
+}
+
+impl<H: Handler> Future for Observer<H> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            loop {
+                match self.changes.poll().expect("receivers never fail") {
+                    Async::Ready(Some(())) => self.changed = true,
+                    // deregistered
+                    Async::Ready(None) => return Ok(Async::Ready(())),
+                    Async::NotReady => break,
+                }
+            }
+
+            let next = match self.state {
+                State::Idle(ref mut reply) => {
+                    if let Some(Ok(Async::Ready(_))) = reply.as_mut().map(|reply| reply.reply.poll()) {
+                        debug!("notification reset by {}", self.key.0);
+                        break;
+                    }
+
+                    if self.last {
+                        break;
+                    }
+
+                    if !self.changed {
+                        return Ok(Async::NotReady);
+                    }
+
+                    self.changed = false;
+
+                    State::Rendering(self.shared.handler.handle(self.request.clone()))
+                }
+                State::Rendering(ref mut response) => {
+                    let response = match response.poll() {
+                        Ok(Async::Ready(response)) => response,
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(e) => {
+                            error!("handler failed: {:?}", e);
+                            Message::new().with_code(Code::InternalServerError)
+                        }
+                    };
+
+                    self.notify(response);
+                    continue;
+                }
+                State::Confirming(ref mut delivery) => {
+                    match delivery.poll() {
+                        Ok(Async::Ready(())) => {
+                            self.last_confirmed = Instant::now();
+                            State::Idle(None)
+                        }
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(e) => {
+                            debug!("notification to {} failed: {:?}", self.key.0, e);
+                            break;
+                        }
+                    }
+                }
+            };
+
+            self.state = next;
+        }
+
+        self.shared.remove(&self.key, self.id);
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Observable;
+    use client::Client;
+    use endpoint::Endpoint;
+    use error::Error;
+    use message::{Message, Mtype, Code};
+    use message::option::{Option, Observe};
+    use server::{Request, Server};
+    use testing::{recv_message, send_message};
+    use transmission::TransmissionParameters;
+
+    use std::net::{SocketAddr, UdpSocket};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use futures::{Future, Stream};
+    use tokio::runtime::Runtime;
+
+    fn observe_value(msg: &Message) -> u64 {
+        msg.options.get::<Observe>().unwrap()[0].value
+    }
+
+    /// Wait for the number of observers to settle on `count`.
+    fn wait_for_observers<H>(observable: &Observable<H>, count: usize) where H: ::server::Handler {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while observable.observers() != count {
+            assert!(Instant::now() < deadline, "expected {} observers", count);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// A resource whose representation is the value of a counter.
+    fn counter() -> (Arc<AtomicUsize>, impl Fn(Request) -> Result<Message, Error> + Send + Sync + 'static) {
+        let value = Arc::new(AtomicUsize::new(0));
+        let handler_value = value.clone();
+
+        let handler = move |_: Request| {
+            let payload = handler_value.load(Ordering::SeqCst).to_string();
+            Ok(Message::new().with_code(Code::Content).with_payload(payload.into_bytes()))
+        };
+
+        (value, handler)
+    }
+
+    fn serve<H>(runtime: &mut Runtime, observable: &Observable<H>) -> SocketAddr where H: ::server::Handler {
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).unwrap()
+            .with_parameters(TransmissionParameters::new()
+                .with_ack_timeout(Duration::from_millis(50))
+                .with_max_retransmit(1));
+        let server_addr = server.local_addr();
+        runtime.spawn(server.serve(observable.clone()).map_err(|e| panic!("{:?}", e)));
+
+        server_addr
+    }
+
+    fn register(client: &UdpSocket, server_addr: SocketAddr) -> Message {
+        let request = Message::new()
+            .with_mtype(Mtype::Confirmable)
+            .with_code(Code::Get)
+            .with_mid(0x10)
+            .with_token(&[7])
+            .with_option(Observe::new(0));
+        send_message(client, &request, server_addr);
+
+        let (reply, _) = recv_message(client);
+        assert_eq!(reply.mtype, Mtype::Acknowledgement);
+        assert_eq!(reply.code, Code::Content);
+
+        reply
+    }
+
+    #[test]
+    fn notifications_until_reset() {
+        let mut runtime = Runtime::new().unwrap();
+        let (value, handler) = counter();
+        let observable = Observable::new(handler);
+        let server_addr = serve(&mut runtime, &observable);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let reply = register(&client, server_addr);
+        assert_eq!(observe_value(&reply), 0);
+        assert_eq!(reply.payload, b"0");
+        assert_eq!(observable.observers(), 1);
+
+        let mut last_mid = None;
+        for expected in 1..3 {
+            value.store(expected, Ordering::SeqCst);
+            observable.notify();
+
+            let (notification, _) = recv_message(&client);
+            assert_eq!(notification.mtype, Mtype::NonConfirmable);
+            assert_eq!(&notification.token[..], &[7]);
+            assert_eq!(observe_value(&notification), expected as u64);
+            assert_eq!(notification.payload, expected.to_string().into_bytes());
+            assert_ne!(Some(notification.mid), last_mid);
+            last_mid = Some(notification.mid);
+        }
+
+        let reset = Message::new().with_mtype(Mtype::Reset).with_mid(last_mid.unwrap());
+        send_message(&client, &reset, server_addr);
+        wait_for_observers(&observable, 0);
+    }
+
+    #[test]
+    fn confirmable_notifications_and_cancellation() {
+        let mut runtime = Runtime::new().unwrap();
+        let (_, handler) = counter();
+        let observable = Observable::new(handler).with_con_interval(Duration::from_secs(0));
+        let server_addr = serve(&mut runtime, &observable);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        register(&client, server_addr);
+
+        observable.notify();
+        let (notification, _) = recv_message(&client);
+        assert_eq!(notification.mtype, Mtype::Confirmable);
+        assert_eq!(observe_value(&notification), 1);
+        send_message(&client, &notification.new_ack(), server_addr);
+
+        // a GET without Observe on the same token cancels the registration
+        let request = Message::new()
+            .with_mtype(Mtype::Confirmable)
+            .with_code(Code::Get)
+            .with_mid(0x11)
+            .with_token(&[7]);
+        send_message(&client, &request, server_addr);
+
+        let (reply, _) = recv_message(&client);
+        assert_eq!(reply.code, Code::Content);
+        assert!(reply.options.get::<Observe>().is_none());
+        assert_eq!(observable.observers(), 0);
+    }
+
+    #[test]
+    fn unacknowledged_notification_removes_observer() {
+        let mut runtime = Runtime::new().unwrap();
+        let (_, handler) = counter();
+        let observable = Observable::new(handler).with_con_interval(Duration::from_secs(0));
+        let server_addr = serve(&mut runtime, &observable);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        register(&client, server_addr);
+
+        observable.notify();
+        let (first, _) = recv_message(&client);
+        let (retransmission, _) = recv_message(&client);
+        assert_eq!(first.mtype, Mtype::Confirmable);
+        assert_eq!(retransmission.mid, first.mid);
+
+        wait_for_observers(&observable, 0);
+    }
+
+    #[test]
+    fn error_response_is_not_observed() {
+        let mut runtime = Runtime::new().unwrap();
+        let observable = Observable::new(|_| Ok::<_, Error>(Message::new().with_code(Code::NotFound)));
+        let server_addr = serve(&mut runtime, &observable);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let request = Message::new()
+            .with_mtype(Mtype::Confirmable)
+            .with_code(Code::Get)
+            .with_mid(0x10)
+            .with_option(Observe::new(0));
+        send_message(&client, &request, server_addr);
+
+        let (reply, _) = recv_message(&client);
+        assert_eq!(reply.code, Code::NotFound);
+        assert!(reply.options.get::<Observe>().is_none());
+        assert_eq!(observable.observers(), 0);
+    }
+
+    #[test]
+    fn malformed_observe_is_bad_option() {
+        let mut runtime = Runtime::new().unwrap();
+        let (_, handler) = counter();
+        let observable = Observable::new(handler);
+        let server_addr = serve(&mut runtime, &observable);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // Observe is at most 3 bytes long
+        let mut request = Message::new()
+            .with_mtype(Mtype::Confirmable)
+            .with_code(Code::Get)
+            .with_mid(0x0f)
+            .with_token(&[7]);
+        request.options.push_raw(Observe::NUMBER, vec![0; 5]);
+        send_message(&client, &request, server_addr);
+
+        let (reply, _) = recv_message(&client);
+        assert_eq!(reply.code, Code::BadOption);
+        assert_eq!(observable.observers(), 0);
+
+        // the server is still serving
+        let reply = register(&client, server_addr);
+        assert_eq!(reply.payload, b"0");
+    }
+
+    #[test]
+    fn client_observes_server() {
+        let mut runtime = Runtime::new().unwrap();
+        let (value, handler) = counter();
+        let observable = Observable::new(handler);
+        let server_addr = serve(&mut runtime, &observable);
+
+        let notifier = observable.clone();
+        thread::spawn(move || {
+            wait_for_observers(&notifier, 1);
+            value.store(1, Ordering::SeqCst);
+            notifier.notify();
+        });
+
+        let notifications = Client::get("coap://127.0.0.1/counter").unwrap()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .observe()
+            .take(2)
+            .collect();
+        let notifications = runtime.block_on(notifications).unwrap();
+
+        let payloads: Vec<_> = notifications.into_iter().map(|n| n.payload).collect();
+        assert_eq!(payloads, vec![b"0".to_vec(), b"1".to_vec()]);
+
+        // the client cancels once the stream is dropped
+        wait_for_observers(&observable, 0);
+    }
+}
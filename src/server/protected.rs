@@ -0,0 +1,241 @@
+//! Requests and responses protected end to end with OSCORE.
+//! RFC 8613: 8.  OSCORE Message Processing
+
+use std::option::Option as StdOption;
+
+use futures::future;
+use futures::prelude::*;
+
+use client::IoFuture;
+use error::{Error, OscoreError};
+use message::{Message, Code};
+use message::option::{Option, MaxAge};
+use oscore::ContextStore;
+use super::{is_readable, Handler, Request};
+
+/// Unprotects the OSCORE requests for the wrapped handler, and protects its
+/// responses to them. Requests without the OSCORE option, or all of them
+/// without any `contexts`, are passed through as they are.
+pub struct Protected<H> {
+    handler: H,
+    contexts: StdOption<ContextStore>,
+}
+
+impl<H: Handler> Protected<H> {
+    pub fn new(handler: H, contexts: StdOption<ContextStore>) -> Protected<H> {
+        Protected {
+            handler: handler,
+            contexts: contexts,
+        }
+    }
+}
+
+impl<H: Handler> Handler for Protected<H> {
+    fn handle(&self, request: Request) -> IoFuture<Message> {
+        let contexts = match self.contexts {
+            Some(ref contexts) => contexts,
+            None => return self.handler.handle(request),
+        };
+
+        let (message, exchange) = match contexts.unprotect_request(request.message()) {
+            Ok(Some(unprotected)) => unprotected,
+            Ok(None) => return self.handler.handle(request),
+            Err(e) => {
+                debug!("rejecting protected request from {}: {:?}", request.peer(), e);
+                return Box::new(future::ok(rejection(&e)));
+            }
+        };
+
+        if !is_readable(&message) {
+            debug!("rejecting protected request from {} with a malformed Uri-Path or Uri-Query", request.peer());
+            return Box::new(future::result(exchange.protect_response(&Message::new().with_code(Code::BadOption))));
+        }
+
+        let mut inner = Request::new(message, request.peer);
+        inner.socket = request.socket;
+        inner.oscore = Some(exchange.clone());
+
+        let response = self.handler.handle(inner).then(move |result| {
+            // the error response is protected like any other
+            let response = result.unwrap_or_else(|e| {
+                error!("handler failed: {:?}", e);
+                Message::new().with_code(Code::InternalServerError)
+            });
+
+            exchange.protect_response(&response)
+        });
+
+        Box::new(response)
+    }
+}
+
+/// The unprotected error response to a request that couldn't be verified,
+/// which mustn't be cached.
+/// RFC 8613: 8.2.  Verifying the Request
+fn rejection(e: &Error) -> Message {
+    let (code, diagnostic) = match *e {
+        Error::Oscore(OscoreError::UnknownContext) => (Code::Unauthorized, "Security context not found"),
+        Error::Oscore(OscoreError::Replay) => (Code::Unauthorized, "Replay detected"),
+        Error::Oscore(OscoreError::DecryptionFailed) => (Code::BadRequest, "Decryption failed"),
+        Error::Oscore(_) => (Code::BadOption, ""),
+        _ => (Code::InternalServerError, ""),
+    };
+
+    Message::new()
+        .with_code(code)
+        .with_option(MaxAge::new(0))
+        .with_payload(diagnostic.as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use client::Client;
+    use endpoint::Endpoint;
+    use error::Error;
+    use message::{Message, Code};
+    use message::option::{Observe, Oscore, UriPath};
+    use oscore::{ContextStore, SecurityContext};
+    use server::{Observable, Request, Server};
+
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::{Future, Stream};
+    use tokio::runtime::Runtime;
+
+    const MASTER_SECRET: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+    const MASTER_SALT: &[u8] = &[0x9e, 0x7c, 0xa9, 0x22, 0x23, 0x78, 0x63, 0x40];
+
+    fn client_context() -> SecurityContext {
+        SecurityContext::new(MASTER_SECRET, MASTER_SALT, None, &[], &[1]).unwrap()
+    }
+
+    fn server_contexts() -> ContextStore {
+        let contexts = ContextStore::new();
+        contexts.insert(SecurityContext::new(MASTER_SECRET, MASTER_SALT, None, &[1], &[]).unwrap());
+        contexts
+    }
+
+    /// Answers with the request path, and whether it came protected.
+    fn describe(request: Request) -> Result<Message, Error> {
+        let path = request.message().options.get::<UriPath>().unwrap_or_default();
+        let path: Vec<_> = path.into_iter().map(|segment| segment.value).collect();
+        let protected = if request.oscore_context().is_some() { "protected" } else { "plain" };
+
+        Ok(Message::new()
+            .with_code(Code::Content)
+            .with_payload(format!("{} {}", protected, path.join("/")).into_bytes()))
+    }
+
+    fn serve<H: ::server::Handler>(runtime: &mut Runtime, handler: H) -> SocketAddr {
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).unwrap()
+            .with_block_size(64)
+            .with_oscore(server_contexts());
+        let server_addr = server.local_addr();
+        runtime.spawn(server.serve(handler).map_err(|e| panic!("{:?}", e)));
+
+        server_addr
+    }
+
+    #[test]
+    fn protected_requests() {
+        let mut runtime = Runtime::new().unwrap();
+        let server_addr = serve(&mut runtime, describe);
+
+        let request = Client::get("coap://127.0.0.1/a/b").unwrap()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_oscore(client_context())
+            .send();
+        let response = runtime.block_on(request).unwrap();
+        assert_eq!(response.code, Code::Content);
+        assert_eq!(response.payload, b"protected a/b");
+        assert!(response.options.get_raw::<Oscore>().is_none());
+
+        let request = Client::get("coap://127.0.0.1/a/b").unwrap()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .send();
+        let response = runtime.block_on(request).unwrap();
+        assert_eq!(response.payload, b"plain a/b");
+
+        // a large response is sent in protected blocks
+        let large = |_| Ok::<_, Error>(Message::new().with_code(Code::Content).with_payload(vec![7; 1000]));
+        let server_addr = serve(&mut runtime, large);
+
+        let request = Client::get("coap://127.0.0.1/large").unwrap()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_oscore(client_context())
+            .send();
+        let response = runtime.block_on(request).unwrap();
+        assert_eq!(response.payload, vec![7; 1000]);
+    }
+
+    #[test]
+    fn unverified_requests_are_rejected() {
+        let mut runtime = Runtime::new().unwrap();
+        let server_addr = serve(&mut runtime, describe);
+
+        let stranger = SecurityContext::new(MASTER_SECRET, MASTER_SALT, None, &[2], &[]).unwrap();
+        let request = Client::get("coap://127.0.0.1/a").unwrap()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_oscore(stranger)
+            .send();
+        let response = runtime.block_on(request).unwrap();
+        assert_eq!(response.code, Code::Unauthorized);
+        assert_eq!(response.payload, b"Security context not found");
+
+        let impostor = SecurityContext::new(&[0; 16], MASTER_SALT, None, &[], &[1]).unwrap();
+        let request = Client::get("coap://127.0.0.1/a").unwrap()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_oscore(impostor)
+            .send();
+        let response = runtime.block_on(request).unwrap();
+        assert_eq!(response.code, Code::BadRequest);
+        assert_eq!(response.payload, b"Decryption failed");
+
+        // the sequence number starts over, so the server has seen it before
+        let client = client_context();
+        let codes: Vec<_> = (0..2).map(|_| {
+            client.set_sequence_number(0);
+
+            let request = Client::get("coap://127.0.0.1/a").unwrap()
+                .with_endpoint(Endpoint::Resolved(server_addr))
+                .with_oscore(client.clone())
+                .send();
+            let response = runtime.block_on(request).unwrap();
+
+            (response.code, response.payload)
+        }).collect();
+        assert_eq!(codes[0].0, Code::Content);
+        assert_eq!(codes[1], (Code::Unauthorized, b"Replay detected".to_vec()));
+    }
+
+    #[test]
+    fn protected_notifications() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let value = Arc::new(AtomicUsize::new(0));
+        let handler_value = value.clone();
+        let observable = Observable::new(move |_: Request| {
+            let payload = handler_value.load(Ordering::SeqCst).to_string();
+            Ok::<_, Error>(Message::new().with_code(Code::Content).with_payload(payload.into_bytes()))
+        });
+        let server_addr = serve(&mut runtime, observable.clone());
+
+        let notifications = Client::get("coap://127.0.0.1/counter").unwrap()
+            .with_endpoint(Endpoint::Resolved(server_addr))
+            .with_oscore(client_context())
+            .observe();
+
+        let (first, notifications) = runtime.block_on(notifications.into_future()).map_err(|(e, _)| e).unwrap();
+        let first = first.unwrap();
+        assert_eq!(first.payload, b"0");
+        assert!(first.options.get::<Observe>().is_some());
+
+        value.store(1, Ordering::SeqCst);
+        observable.notify();
+
+        let (second, _) = runtime.block_on(notifications.into_future()).map_err(|(e, _)| e).unwrap();
+        assert_eq!(second.unwrap().payload, b"1");
+    }
+}
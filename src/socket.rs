@@ -0,0 +1,839 @@
+//! A long-lived CoAP socket shared by many concurrent requests.
+//!
+//! A `Socket` is a cheap, clonable handle. The UDP socket itself is owned by a
+//! task that is spawned the first time the socket is used. It hands incoming
+//! messages to the pending request they belong to and keeps at most NSTART
+//! requests outstanding to any single peer, queueing the rest. Incoming
+//! requests go to the server listening on the socket, if there is one.
+//!
+//! The task ends once every handle, and every request using it, is dropped.
+//!
+//! A socket can also carry messages over TCP connections, one to each peer,
+//! in which case nothing is retransmitted, see `Socket::tcp`.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use arrayvec::ArrayVec;
+use futures::prelude::*;
+use futures::sync::{mpsc, oneshot};
+use tokio;
+use tokio::net::{UdpSocket, UdpFramed};
+
+use client::MessageIds;
+use codec::CoapCodec;
+#[cfg(feature = "dtls")]
+use dtls::{DtlsConfig, DtlsTransport, Identities, PeerIdentity};
+use error::Error;
+use message::{Message, Mtype, Code};
+use tcp::TcpTransport;
+use transmission::TransmissionParameters;
+
+pub type Token = ArrayVec<[u8; 8]>;
+
+/// How the driver gets messages to and from peers, plain UDP or DTLS.
+pub(crate) trait Transport: Stream<Item = (Message, SocketAddr), Error = Error>
+                          + Sink<SinkItem = (Message, SocketAddr), SinkError = Error>
+                          + Send {}
+
+impl<T> Transport for T
+    where T: Stream<Item = (Message, SocketAddr), Error = Error>
+           + Sink<SinkItem = (Message, SocketAddr), SinkError = Error>
+           + Send {}
+
+#[derive(Clone)]
+pub struct Socket {
+    local_addr: SocketAddr,
+    commands: mpsc::UnboundedSender<Command>,
+    /// the task driving the socket, until it has been spawned
+    driver: Arc<Mutex<Option<Driver>>>,
+    mids: MessageIds,
+    /// the ID the next registration is told apart from others by
+    registrations: Arc<AtomicUsize>,
+    params: TransmissionParameters,
+    /// whether messages go over connections, which deliver them reliably
+    reliable: bool,
+    /// who the peers of a DTLS or TLS socket authenticated as
+    #[cfg(feature = "dtls")]
+    identities: Identities,
+}
+
+impl fmt::Debug for Socket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Socket")
+            .field("local_addr", &self.local_addr)
+            .finish()
+    }
+}
+
+/// A request's registration with the socket, which lasts until it's dropped.
+pub(crate) struct Registration {
+    socket: Socket,
+    /// unique to the registration, so that one rejected for a token already
+    /// in use doesn't take the exchange it collided with down when dropped
+    id: usize,
+    peer: SocketAddr,
+    token: Token,
+    /// fires once the request may be transmitted without exceeding NSTART,
+    /// taken once it has
+    pub admitted: Option<oneshot::Receiver<()>>,
+    /// messages from the peer that belong to the request
+    pub incoming: mpsc::UnboundedReceiver<(Message, SocketAddr)>,
+}
+
+impl Registration {
+    /// The request has been acknowledged and no longer counts towards NSTART,
+    /// even though it may still be waiting on a response.
+    pub fn acknowledged(&self) {
+        self.socket.command(Command::Acknowledged {
+            id: self.id,
+            peer: self.peer,
+            token: self.token.clone(),
+        });
+    }
+}
+
+/// The acknowledgement or reset of a single message, until it's dropped.
+pub(crate) struct ExpectedReply {
+    socket: Socket,
+    peer: SocketAddr,
+    mid: u16,
+    pub reply: oneshot::Receiver<Message>,
+}
+
+impl Drop for ExpectedReply {
+    fn drop(&mut self) {
+        self.socket.command(Command::ForgetReply {
+            peer: self.peer,
+            mid: self.mid,
+        });
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.socket.command(Command::Deregister {
+            id: self.id,
+            peer: self.peer,
+            token: self.token.clone(),
+        });
+    }
+}
+
+enum Command {
+    Register {
+        id: usize,
+        peer: SocketAddr,
+        token: Token,
+        admit: oneshot::Sender<()>,
+        incoming: mpsc::UnboundedSender<(Message, SocketAddr)>,
+    },
+    Deregister {
+        id: usize,
+        peer: SocketAddr,
+        token: Token,
+    },
+    Acknowledged {
+        id: usize,
+        peer: SocketAddr,
+        token: Token,
+    },
+    ExpectReply {
+        peer: SocketAddr,
+        mid: u16,
+        reply: oneshot::Sender<Message>,
+    },
+    ForgetReply {
+        peer: SocketAddr,
+        mid: u16,
+    },
+    Send(Message, SocketAddr),
+    Listen(mpsc::UnboundedSender<(Message, SocketAddr)>),
+}
+
+impl Socket {
+    pub fn bind(addr: &SocketAddr) -> Result<Socket, Error> {
+        let sock = UdpSocket::bind(addr)?;
+        let local_addr = sock.local_addr()?;
+
+        Ok(Socket::new(Box::new(UdpFramed::new(sock, CoapCodec)), local_addr, false))
+    }
+
+    /// Bind a socket that secures every message with DTLS, for `coaps://`.
+    ///
+    /// Sessions with servers are started using the identity in `config`, and
+    /// sessions from clients are accepted if it has their key.
+    #[cfg(feature = "dtls")]
+    pub fn bind_dtls(addr: &SocketAddr, config: &DtlsConfig) -> Result<Socket, Error> {
+        let sock = UdpSocket::bind(addr)?;
+        let local_addr = sock.local_addr()?;
+
+        let transport = DtlsTransport::new(sock, config)?;
+        let identities = transport.identities();
+
+        let mut socket = Socket::new(Box::new(transport), local_addr, false);
+        socket.identities = identities;
+
+        Ok(socket)
+    }
+
+    /// A socket that sends messages over TCP, for `coap+tcp://`.
+    ///
+    /// A connection to a peer is opened the first time a message is sent to
+    /// it and used for every later one, share the socket between clients to
+    /// share the connection too. Requests without a socket of their own take
+    /// one of these from their client's `Connections`.
+    /// RFC 8323: 3.  CoAP over TCP
+    pub fn tcp() -> Socket {
+        Socket::new(Box::new(TcpTransport::new()), "0.0.0.0:0".parse().unwrap(), true)
+    }
+
+    /// A socket that sends messages over TLS, for `coaps+tcp://`.
+    ///
+    /// Connections are secured using the identity in `config`, and the
+    /// server is authenticated as it would be over DTLS.
+    /// RFC 8323: 9.  Securing CoAP
+    #[cfg(feature = "dtls")]
+    pub fn tls(config: &DtlsConfig) -> Result<Socket, Error> {
+        let transport = TcpTransport::tls(config)?;
+        let identities = transport.identities();
+
+        let mut socket = Socket::new(Box::new(transport), "0.0.0.0:0".parse().unwrap(), true);
+        socket.identities = identities;
+
+        Ok(socket)
+    }
+
+    fn new(transport: Box<Transport>, local_addr: SocketAddr, reliable: bool) -> Socket {
+        let params = TransmissionParameters::default();
+
+        let (commands_tx, commands_rx) = mpsc::unbounded();
+        let driver = Driver::new(transport, commands_rx, params.nstart, params.exchange_lifetime(), reliable);
+
+        Socket {
+            local_addr: local_addr,
+            commands: commands_tx,
+            driver: Arc::new(Mutex::new(Some(driver))),
+            mids: MessageIds::new(),
+            registrations: Arc::new(AtomicUsize::new(0)),
+            params: params,
+            reliable: reliable,
+            #[cfg(feature = "dtls")]
+            identities: Identities::default(),
+        }
+    }
+
+    /// Set the transmission parameters used by requests sent through this
+    /// socket. NSTART only takes effect if the socket hasn't been used yet.
+    pub fn with_parameters(mut self, params: TransmissionParameters) -> Self {
+        if let Some(ref mut driver) = *self.driver.lock().unwrap() {
+            driver.nstart = params.nstart;
+            driver.exchange_lifetime = params.exchange_lifetime();
+        }
+
+        self.params = params;
+        self
+    }
+
+    pub fn parameters(&self) -> &TransmissionParameters {
+        &self.params
+    }
+
+    /// The allocator all messages sent through this socket take their message
+    /// ID from.
+    pub fn message_ids(&self) -> MessageIds {
+        self.mids.clone()
+    }
+
+    /// The address the socket is bound to, unspecified for a TCP socket
+    /// whose connections each have their own.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Whether messages are sent over connections, which need neither
+    /// retransmissions nor acknowledgements.
+    pub(crate) fn is_reliable(&self) -> bool {
+        self.reliable
+    }
+
+    /// Who `peer` authenticated as, if this is a DTLS or TLS socket with a
+    /// session established with it.
+    #[cfg(feature = "dtls")]
+    pub fn peer_identity(&self, peer: &SocketAddr) -> Option<PeerIdentity> {
+        self.identities.lock().unwrap().get(peer).cloned()
+    }
+
+    /// Whether the task driving the socket is gone, having been dropped along
+    /// with the executor it was spawned on.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.commands.is_closed()
+    }
+
+    /// Spawn the task driving the socket if it isn't running yet.
+    ///
+    /// This must be called from within a task running on a tokio executor.
+    pub(crate) fn spawn(&self) {
+        if let Some(driver) = self.driver.lock().unwrap().take() {
+            tokio::spawn(driver.map_err(|e| error!("socket error: {:?}", e)));
+        }
+    }
+
+    /// Register interest in responses from `peer` carrying `token`.
+    pub(crate) fn register(&self, peer: SocketAddr, token: Token) -> Registration {
+        let (admit_tx, admit_rx) = oneshot::channel();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded();
+        let id = self.registrations.fetch_add(1, Ordering::Relaxed);
+
+        self.command(Command::Register {
+            id: id,
+            peer: peer,
+            token: token.clone(),
+            admit: admit_tx,
+            incoming: incoming_tx,
+        });
+
+        Registration {
+            socket: self.clone(),
+            id: id,
+            peer: peer,
+            token: token,
+            admitted: Some(admit_rx),
+            incoming: incoming_rx,
+        }
+    }
+
+    /// Wait for the acknowledgement or reset of the message `mid` sent to
+    /// `peer` on its own, outside of any request's exchange.
+    pub(crate) fn expect_reply(&self, peer: SocketAddr, mid: u16) -> ExpectedReply {
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        self.command(Command::ExpectReply {
+            peer: peer,
+            mid: mid,
+            reply: reply_tx,
+        });
+
+        ExpectedReply {
+            socket: self.clone(),
+            peer: peer,
+            mid: mid,
+            reply: reply_rx,
+        }
+    }
+
+    pub(crate) fn send(&self, msg: Message, peer: SocketAddr) {
+        self.command(Command::Send(msg, peer));
+    }
+
+    /// Receive the requests arriving on the socket, replacing any previous
+    /// listener. Without one they're rejected with a reset.
+    pub(crate) fn listen(&self) -> mpsc::UnboundedReceiver<(Message, SocketAddr)> {
+        let (requests_tx, requests_rx) = mpsc::unbounded();
+        self.command(Command::Listen(requests_tx));
+
+        requests_rx
+    }
+
+    fn command(&self, command: Command) {
+        // The driver only goes away once every handle has been dropped, so
+        // this can't fail while we hold one.
+        let _ = self.commands.unbounded_send(command);
+    }
+}
+
+/// A request waiting on responses.
+struct Exchange {
+    /// the registration the exchange belongs to
+    id: usize,
+    incoming: mpsc::UnboundedSender<(Message, SocketAddr)>,
+    admit: Option<oneshot::Sender<()>>,
+    admitted: bool,
+    /// the message ID last sent for the exchange
+    mid: Option<u16>,
+}
+
+/// Congestion control state for a single peer.
+/// RFC 7252: 4.7.  Congestion Control
+#[derive(Default)]
+struct Peer {
+    outstanding: u32,
+    waiting: VecDeque<Token>,
+}
+
+struct Driver {
+    socket: Box<Transport>,
+    commands: mpsc::UnboundedReceiver<Command>,
+    /// set once every handle to the socket has been dropped
+    closed: bool,
+    outgoing: VecDeque<(Message, SocketAddr)>,
+    exchanges: HashMap<(SocketAddr, Token), Exchange>,
+    /// the request each sent message ID belongs to, used to match
+    /// acknowledgements and resets; only the latest of each is kept
+    mids: HashMap<(SocketAddr, u16), Token>,
+    peers: HashMap<SocketAddr, Peer>,
+    nstart: u32,
+    /// the empty acknowledgements sent for confirmable responses, to be sent
+    /// again for duplicates of them that arrive after their request is done
+    acknowledgements: HashMap<(SocketAddr, u16), (Message, Instant)>,
+    /// when each of the acknowledgements is forgotten, oldest first
+    acknowledgement_expiry: VecDeque<(Instant, (SocketAddr, u16))>,
+    /// how long a confirmable response may be retransmitted for
+    exchange_lifetime: Duration,
+    /// whether messages go over connections, which an Abort stands for the
+    /// loss of
+    reliable: bool,
+    /// messages sent outside of an exchange whose reply is waited for
+    replies: HashMap<(SocketAddr, u16), oneshot::Sender<Message>>,
+    /// where incoming requests go
+    listener: Option<mpsc::UnboundedSender<(Message, SocketAddr)>>,
+}
+
+impl Driver {
+    fn new(socket: Box<Transport>,
+           commands: mpsc::UnboundedReceiver<Command>,
+           nstart: u32,
+           exchange_lifetime: Duration,
+           reliable: bool) -> Driver {
+        Driver {
+            socket: socket,
+            commands: commands,
+            closed: false,
+            outgoing: VecDeque::new(),
+            exchanges: HashMap::new(),
+            mids: HashMap::new(),
+            peers: HashMap::new(),
+            nstart: nstart,
+            acknowledgements: HashMap::new(),
+            acknowledgement_expiry: VecDeque::new(),
+            exchange_lifetime: exchange_lifetime,
+            reliable: reliable,
+            replies: HashMap::new(),
+            listener: None,
+        }
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Register { id, peer, token, admit, incoming } => {
+                let key = (peer, token);
+
+                if self.exchanges.contains_key(&key) {
+                    // Dropping `admit` fails the new request.
+                    warn!("token {:?} already in use with {}", key.1, peer);
+                    return;
+                }
+
+                self.exchanges.insert(key.clone(), Exchange {
+                    id: id,
+                    incoming: incoming,
+                    admit: Some(admit),
+                    admitted: false,
+                    mid: None,
+                });
+
+                let outstanding = self.peers.entry(peer).or_insert_with(Peer::default).outstanding;
+                if outstanding < self.nstart {
+                    self.admit(&key);
+                } else {
+                    debug!("deferring request to {}, NSTART reached", peer);
+                    self.peers.get_mut(&peer).unwrap().waiting.push_back(key.1);
+                }
+            }
+            Command::Deregister { id, peer, token } => {
+                // a registration rejected for its token has no exchange of
+                // its own, the one under its key belongs to another request
+                if self.exchanges.get(&(peer, token.clone())).map_or(false, |exchange| exchange.id == id) {
+                    self.deregister(peer, token);
+                }
+            }
+            Command::Acknowledged { id, peer, token } => {
+                let released = match self.exchanges.get_mut(&(peer, token)) {
+                    Some(ref mut exchange) if exchange.id == id && exchange.admitted => {
+                        exchange.admitted = false;
+                        true
+                    }
+                    _ => false,
+                };
+
+                if released {
+                    self.release(peer);
+                }
+            }
+            Command::Send(msg, peer) => {
+                let request = msg.mtype == Mtype::Confirmable || msg.mtype == Mtype::NonConfirmable;
+
+                if let Some(exchange) = self.exchanges.get_mut(&(peer, msg.token.clone())) {
+                    if request {
+                        if let Some(previous) = exchange.mid.take() {
+                            self.mids.remove(&(peer, previous));
+                        }
+
+                        exchange.mid = Some(msg.mid);
+                        self.mids.insert((peer, msg.mid), msg.token.clone());
+                    }
+                }
+
+                if msg.mtype == Mtype::Acknowledgement && msg.code == Code::Empty {
+                    self.remember_acknowledgement(msg.clone(), peer);
+                }
+
+                self.outgoing.push_back((msg, peer));
+            }
+            Command::ExpectReply { peer, mid, reply } => {
+                self.replies.insert((peer, mid), reply);
+            }
+            Command::ForgetReply { peer, mid } => {
+                self.replies.remove(&(peer, mid));
+            }
+            Command::Listen(listener) => {
+                self.listener = Some(listener);
+            }
+        }
+    }
+
+    /// Keep `ack` around for EXCHANGE_LIFETIME, the longest the response it
+    /// acknowledges may be retransmitted for.
+    /// RFC 7252: 4.5.  Message Deduplication
+    fn remember_acknowledgement(&mut self, ack: Message, peer: SocketAddr) {
+        let now = Instant::now();
+        while self.acknowledgement_expiry.front().map_or(false, |&(expiry, _)| expiry <= now) {
+            let (_, key) = self.acknowledgement_expiry.pop_front().unwrap();
+
+            // unless it was sent again since
+            if self.acknowledgements.get(&key).map_or(false, |&(_, expiry)| expiry <= now) {
+                self.acknowledgements.remove(&key);
+            }
+        }
+
+        let key = (peer, ack.mid);
+        if let Some(expiry) = now.checked_add(self.exchange_lifetime) {
+            self.acknowledgement_expiry.push_back((expiry, key));
+            self.acknowledgements.insert(key, (ack, expiry));
+        }
+    }
+
+    fn deregister(&mut self, peer: SocketAddr, token: Token) {
+        let key = (peer, token);
+
+        let exchange = match self.exchanges.remove(&key) {
+            Some(exchange) => exchange,
+            None => return,
+        };
+
+        if let Some(mid) = exchange.mid {
+            self.mids.remove(&(peer, mid));
+        }
+
+        if exchange.admitted {
+            self.release(peer);
+        } else if let Some(state) = self.peers.get_mut(&peer) {
+            state.waiting.retain(|token| *token != key.1);
+        }
+
+        if self.peers.get(&peer).map_or(false, |p| p.outstanding == 0 && p.waiting.is_empty()) {
+            self.peers.remove(&peer);
+        }
+    }
+
+    /// The connection to `peer` is gone, taking the requests sent over it
+    /// with it. Those still waiting to be sent go over the next one.
+    /// RFC 8323: 5.6.  Abort Messages
+    fn disconnected(&mut self, peer: SocketAddr) {
+        let lost: Vec<_> = self.exchanges.iter()
+            .filter(|&(key, exchange)| key.0 == peer && exchange.admit.is_none())
+            .map(|(key, _)| key.1.clone())
+            .collect();
+
+        // dropping an exchange ends the request's stream of incoming messages
+        for token in lost {
+            self.deregister(peer, token);
+        }
+    }
+
+    fn admit(&mut self, key: &(SocketAddr, Token)) {
+        if let Some(exchange) = self.exchanges.get_mut(key) {
+            exchange.admitted = true;
+
+            if let Some(admit) = exchange.admit.take() {
+                let _ = admit.send(());
+            }
+        }
+
+        self.peers.entry(key.0).or_insert_with(Peer::default).outstanding += 1;
+    }
+
+    /// An outstanding request to `peer` finished, admit the next one waiting.
+    fn release(&mut self, peer: SocketAddr) {
+        let next = match self.peers.get_mut(&peer) {
+            Some(state) => {
+                state.outstanding -= 1;
+                state.waiting.pop_front()
+            }
+            None => None,
+        };
+
+        if let Some(token) = next {
+            self.admit(&(peer, token));
+        }
+    }
+
+    /// Hand an incoming message to the request it belongs to, rejecting it if
+    /// there isn't one.
+    /// RFC 7252: 5.3.2.  Request/Response Matching Rules
+    fn dispatch(&mut self, msg: Message, addr: SocketAddr) {
+        let key = match msg.mtype {
+            _ if self.reliable && msg.code == Code::Abort => {
+                debug!("lost connection to {}", addr);
+                self.disconnected(addr);
+                return;
+            }
+            Mtype::Acknowledgement | Mtype::Reset => {
+                if let Some(reply) = self.replies.remove(&(addr, msg.mid)) {
+                    let _ = reply.send(msg);
+                    return;
+                }
+
+                match self.mids.get(&(addr, msg.mid)) {
+                    Some(token) => (addr, token.clone()),
+                    None => {
+                        debug!("ignoring unexpected message from {}: {:?}", addr, msg);
+                        return;
+                    }
+                }
+            }
+            Mtype::Confirmable | Mtype::NonConfirmable if msg.code.is_response() => {
+                (addr, msg.token.clone())
+            }
+            Mtype::Confirmable | Mtype::NonConfirmable if msg.code.is_request() && self.listener.is_some() => {
+                let listener = self.listener.take().unwrap();
+
+                match listener.unbounded_send((msg, addr)) {
+                    Ok(()) => self.listener = Some(listener),
+                    Err(e) => {
+                        let (msg, addr) = e.into_inner();
+                        warn!("rejecting request from {}, no longer listening: {:?}", addr, msg);
+                        self.outgoing.push_back((msg.new_reset(), addr));
+                    }
+                }
+
+                return;
+            }
+            Mtype::Confirmable | Mtype::NonConfirmable => {
+                warn!("rejecting unexpected message from {}: {:?}", addr, msg);
+                self.outgoing.push_back((msg.new_reset(), addr));
+                return;
+            }
+        };
+
+        let msg = match self.exchanges.get(&key) {
+            Some(exchange) => match exchange.incoming.unbounded_send((msg, addr)) {
+                Ok(()) => return,
+                Err(e) => e.into_inner().0,
+            },
+            None => msg,
+        };
+
+        // our acknowledgement of the response was lost, so it was sent again
+        if msg.mtype == Mtype::Confirmable {
+            let ack = self.acknowledgements.get(&(addr, msg.mid))
+                .filter(|&&(_, expiry)| expiry > Instant::now())
+                .map(|&(ref ack, _)| ack.clone());
+
+            if let Some(ack) = ack {
+                debug!("acknowledging duplicate response from {}", addr);
+                self.outgoing.push_back((ack, addr));
+                return;
+            }
+        }
+
+        warn!("rejecting unexpected message from {}: {:?}", addr, msg);
+        self.outgoing.push_back((msg.new_reset(), addr));
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        while let Some(outgoing) = self.outgoing.pop_front() {
+            if let AsyncSink::NotReady(outgoing) = self.socket.start_send(outgoing)? {
+                self.outgoing.push_front(outgoing);
+                break;
+            }
+        }
+
+        self.socket.poll_complete()?;
+
+        Ok(())
+    }
+}
+
+impl Future for Driver {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        while !self.closed {
+            match self.commands.poll().expect("receivers never fail") {
+                Async::Ready(Some(command)) => self.handle(command),
+                Async::Ready(None) => self.closed = true,
+                Async::NotReady => break,
+            }
+        }
+
+        while let Async::Ready(incoming) = self.socket.poll()? {
+            match incoming {
+                Some((msg, addr)) => self.dispatch(msg, addr),
+                None => debug!("dropping undecodable datagram"),
+            }
+        }
+
+        self.flush()?;
+
+        if self.closed && self.outgoing.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Socket;
+    use client::Client;
+    use endpoint::Endpoint;
+    use message::Code;
+    use testing::recv_message;
+    use transmission::TransmissionParameters;
+
+    use std::io::ErrorKind;
+    use std::net::UdpSocket;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::Future;
+    use futures::future::join_all;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn concurrent_requests_share_socket() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let requests: Vec<_> = (0..3).map(|_| recv_message(&server)).collect();
+
+            // every request came from the same port
+            assert!(requests.iter().all(|&(_, addr)| addr == requests[0].1));
+
+            for &(ref request, addr) in requests.iter().rev() {
+                let reply = request.new_reply()
+                    .with_code(Code::Content)
+                    .with_payload(request.payload.clone());
+                server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+            }
+        });
+
+        let params = TransmissionParameters::new().with_nstart(3);
+        let socket = Socket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap()
+            .with_parameters(params);
+
+        let requests: Vec<_> = (0..3u8).map(|i| {
+            Client::new()
+                .with_endpoint(Endpoint::Resolved(server_addr))
+                .with_socket(socket.clone())
+                .with_payload(vec![i])
+                .send()
+        }).collect();
+
+        let responses = Runtime::new().unwrap().block_on(join_all(requests)).unwrap();
+
+        for (i, response) in responses.iter().enumerate() {
+            assert_eq!(response.payload, vec![i as u8]);
+        }
+    }
+
+    #[test]
+    fn nstart_limits_outstanding_requests() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for _ in 0..2 {
+                let (request, addr) = recv_message(&server);
+
+                server.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+                let mut buf = [0; 1152];
+                let err = server.recv_from(&mut buf).unwrap_err();
+                assert!(err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut);
+                server.set_read_timeout(None).unwrap();
+
+                let reply = request.new_reply().with_code(Code::Content);
+                server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+            }
+        });
+
+        let params = TransmissionParameters::new().with_ack_timeout(Duration::from_secs(1));
+        let socket = Socket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap()
+            .with_parameters(params);
+
+        let requests: Vec<_> = (0..2).map(|_| {
+            Client::new()
+                .with_endpoint(Endpoint::Resolved(server_addr))
+                .with_socket(socket.clone())
+                .send()
+        }).collect();
+
+        let responses = Runtime::new().unwrap().block_on(join_all(requests)).unwrap();
+
+        assert_eq!(responses.len(), 2);
+    }
+
+    #[test]
+    fn token_collision_leaves_first_request_alone() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let (rejected_tx, rejected_rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (request, addr) = recv_message(&server);
+
+            // only answer once the colliding request has been dropped
+            rejected_rx.recv().unwrap();
+
+            let reply = request.new_reply().with_code(Code::Content);
+            server.send_to(&reply.to_bytes().unwrap(), addr).unwrap();
+        });
+
+        let params = TransmissionParameters::new()
+            .with_nstart(2)
+            .with_ack_timeout(Duration::from_secs(1))
+            .with_max_retransmit(0);
+        let socket = Socket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap()
+            .with_parameters(params);
+
+        // empty tokens, so the requests collide
+        let request = |socket: &Socket| {
+            Client::new()
+                .with_endpoint(Endpoint::Resolved(server_addr))
+                .with_socket(socket.clone())
+                .with_token_length(0)
+                .send()
+        };
+
+        let first = request(&socket);
+        let second = request(&socket).then(move |result| {
+            assert!(result.is_err());
+            rejected_tx.send(()).unwrap();
+
+            Ok(())
+        });
+
+        let (response, ()) = Runtime::new().unwrap().block_on(first.join(second)).unwrap();
+
+        assert_eq!(response.code, Code::Content);
+    }
+}
@@ -0,0 +1,754 @@
+//! CoAP over TCP, and over TLS, for `coap+tcp://` and `coaps+tcp://` URLs.
+//!
+//! Each peer gets its own connection, opened the first time a message is
+//! sent to it and used for every later one until either side closes it. Only
+//! the connecting side is supported, requests arriving over a connection are
+//! rejected like any other without a server listening for them.
+//! RFC 8323: CoAP over TCP, TLS, and WebSockets
+//!
+//! The transport takes care of the signaling messages, the driver of the
+//! socket only ever seeing an Abort: one from the peer, or one standing in for
+//! a connection that failed or was closed. It fails the requests waiting on
+//! the connection.
+
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "dtls")]
+use std::io::{Read, Write};
+use std::io;
+use std::net::SocketAddr;
+
+use bytes::BytesMut;
+use futures::prelude::*;
+use futures::task;
+#[cfg(feature = "dtls")]
+use openssl::ssl::{ErrorCode, Ssl, SslContext, SslStream};
+use tokio::net::TcpStream;
+use tokio::net::tcp::ConnectFuture;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Decoder, Encoder};
+
+use codec::{CoapStreamCodec, MAX_MESSAGE_SIZE};
+#[cfg(feature = "dtls")]
+use dtls::{self, DtlsConfig, Identities, PeerIdentity, Protocol};
+use error::Error;
+use message::{Message, Code};
+
+/// The Max-Message-Size option of a CSM.
+/// RFC 8323: 5.3.1.  Max-Message-Size Capability Option
+const MAX_MESSAGE_SIZE_OPTION: u16 = 2;
+
+/// The CSM every connection starts with, telling the peer how large a
+/// message we accept.
+/// RFC 8323: 5.3.  Capabilities and Settings Messages (CSMs)
+fn csm() -> Message {
+    let size = MAX_MESSAGE_SIZE as u32;
+    let value = (0..4).rev().map(|i| (size >> (i * 8)) as u8).skip_while(|&b| b == 0).collect();
+
+    let mut csm = Message::new().with_code(Code::Csm);
+    csm.options.push_raw(MAX_MESSAGE_SIZE_OPTION, value);
+
+    csm
+}
+
+fn protocol_error(description: &str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, description).into()
+}
+
+/// A TCP connection, while it's being opened and once it has been.
+enum Tcp {
+    Connecting(ConnectFuture),
+    Open(TcpStream),
+}
+
+/// The bytes of a TLS session, between it and the TCP connection.
+#[cfg(feature = "dtls")]
+#[derive(Default)]
+struct Records {
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+}
+
+#[cfg(feature = "dtls")]
+impl Read for Records {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.incoming.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let len = self.incoming.len().min(buf.len());
+        buf[..len].copy_from_slice(&self.incoming[..len]);
+        self.incoming.drain(..len);
+
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "dtls")]
+impl Write for Records {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "dtls")]
+struct Session {
+    stream: SslStream<Records>,
+    established: bool,
+    /// whether who the peer authenticated as has been recorded
+    identified: bool,
+}
+
+#[cfg(feature = "dtls")]
+fn tls_error<E: ::std::error::Error + Send + Sync + 'static>(e: E) -> Error {
+    io::Error::new(io::ErrorKind::Other, e).into()
+}
+
+struct Connection {
+    tcp: Tcp,
+    #[cfg(feature = "dtls")]
+    tls: Option<Session>,
+    /// bytes received, and decrypted, that don't make up a message yet
+    received: BytesMut,
+    /// messages encoded for the peer, to be encrypted
+    pending: BytesMut,
+    /// bytes to write to the connection
+    unsent: BytesMut,
+    /// whether the peer's CSM has arrived
+    csm: bool,
+    /// the peer has closed the connection
+    closed: bool,
+}
+
+impl Connection {
+    fn new(peer: SocketAddr) -> Connection {
+        let mut connection = Connection {
+            tcp: Tcp::Connecting(TcpStream::connect(&peer)),
+            #[cfg(feature = "dtls")]
+            tls: None,
+            received: BytesMut::new(),
+            pending: BytesMut::new(),
+            unsent: BytesMut::new(),
+            csm: false,
+            closed: false,
+        };
+
+        connection.queue(csm()).expect("the CSM is well formed");
+
+        connection
+    }
+
+    #[cfg(feature = "dtls")]
+    fn secured(peer: SocketAddr, context: &SslContext) -> Result<Connection, Error> {
+        let mut ssl = Ssl::new(context).map_err(tls_error)?;
+        ssl.set_connect_state();
+
+        let mut connection = Connection::new(peer);
+        connection.tls = Some(Session {
+            stream: SslStream::new(ssl, Records::default()).map_err(tls_error)?,
+            established: false,
+            identified: false,
+        });
+
+        Ok(connection)
+    }
+
+    fn queue(&mut self, msg: Message) -> Result<(), Error> {
+        CoapStreamCodec.encode(msg, &mut self.pending)
+    }
+
+    /// Make what progress can be made, adding the messages received to
+    /// `incoming`. Ready once the peer has closed the connection.
+    fn poll(&mut self, peer: SocketAddr, incoming: &mut VecDeque<(Message, SocketAddr)>) -> Poll<(), Error> {
+        let connected = match self.tcp {
+            Tcp::Connecting(ref mut connecting) => match connecting.poll()? {
+                Async::Ready(stream) => Some(stream),
+                Async::NotReady => return Ok(Async::NotReady),
+            },
+            Tcp::Open(_) => None,
+        };
+
+        if let Some(stream) = connected {
+            debug!("connected to {}", peer);
+            stream.set_nodelay(true)?;
+            self.tcp = Tcp::Open(stream);
+        }
+
+        let mut buf = [0; 4096];
+
+        loop {
+            let read = match self.tcp {
+                Tcp::Open(ref mut stream) => stream.poll_read(&mut buf)?,
+                Tcp::Connecting(_) => unreachable!(),
+            };
+
+            match read {
+                Async::Ready(0) => {
+                    self.closed = true;
+                    break;
+                }
+                Async::Ready(len) => self.receive(&buf[..len]),
+                Async::NotReady => break,
+            }
+        }
+
+        self.secure()?;
+
+        while let Some(msg) = CoapStreamCodec.decode(&mut self.received)? {
+            self.handle(msg, peer, incoming)?;
+        }
+
+        // the replies to any signaling messages
+        self.secure()?;
+        self.flush()?;
+
+        if self.closed {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Take care of a signaling message, passing any other on.
+    /// RFC 8323: 5.  Signaling
+    fn handle(&mut self, msg: Message, peer: SocketAddr, incoming: &mut VecDeque<(Message, SocketAddr)>) -> Result<(), Error> {
+        if !self.csm && msg.code != Code::Csm {
+            return Err(protocol_error("connection didn't start with a CSM"));
+        }
+
+        match msg.code {
+            Code::Csm => {
+                // every option we know of is elective
+                if msg.options.iter().any(|(number, _)| number % 2 == 1) {
+                    return Err(protocol_error("unsupported critical option in CSM"));
+                }
+
+                self.csm = true;
+            }
+            Code::Ping => self.queue(Message::new().with_code(Code::Pong).with_token(&msg.token))?,
+            Code::Pong => (),
+            Code::Release | Code::Abort => {
+                debug!("{} is closing the connection: {}", peer, String::from_utf8_lossy(&msg.payload));
+                self.closed = true;
+            }
+            _ if msg.code.is_signaling() => return Err(protocol_error("unknown signaling message")),
+            _ => incoming.push_back((msg, peer)),
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "dtls")]
+    fn receive(&mut self, bytes: &[u8]) {
+        match self.tls {
+            Some(ref mut session) => session.stream.get_mut().incoming.extend_from_slice(bytes),
+            None => self.received.extend_from_slice(bytes),
+        }
+    }
+
+    #[cfg(not(feature = "dtls"))]
+    fn receive(&mut self, bytes: &[u8]) {
+        self.received.extend_from_slice(bytes);
+    }
+
+    /// Decrypt what has been received and encrypt what's to be sent, once
+    /// the handshake allows.
+    #[cfg(feature = "dtls")]
+    fn secure(&mut self) -> Result<(), Error> {
+        let session = match self.tls {
+            Some(ref mut session) => session,
+            None => {
+                let pending = self.pending.take();
+                self.unsent.extend_from_slice(&pending);
+                return Ok(());
+            }
+        };
+
+        if !session.established {
+            match session.stream.do_handshake() {
+                Ok(()) => session.established = true,
+                Err(ref e) if dtls::would_block(e) => (),
+                Err(e) => return Err(tls_error(e)),
+            }
+        }
+
+        if session.established {
+            if !self.pending.is_empty() {
+                session.stream.ssl_write(&self.pending).map_err(tls_error)?;
+                self.pending.clear();
+            }
+
+            let mut buf = [0; 4096];
+
+            loop {
+                match session.stream.ssl_read(&mut buf) {
+                    Ok(len) => self.received.extend_from_slice(&buf[..len]),
+                    Err(ref e) if dtls::would_block(e) => break,
+                    Err(ref e) if e.code() == ErrorCode::ZERO_RETURN => {
+                        self.closed = true;
+                        break;
+                    }
+                    Err(e) => return Err(tls_error(e)),
+                }
+            }
+        }
+
+        let outgoing = &mut session.stream.get_mut().outgoing;
+        self.unsent.extend_from_slice(outgoing);
+        outgoing.clear();
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "dtls"))]
+    fn secure(&mut self) -> Result<(), Error> {
+        let pending = self.pending.take();
+        self.unsent.extend_from_slice(&pending);
+
+        Ok(())
+    }
+
+    /// Who the peer authenticated as, given the PSK identity we present and
+    /// the keys we pin, the first time it's asked once the TLS session has
+    /// been established.
+    #[cfg(feature = "dtls")]
+    fn identity(&mut self, psk_identity: &Option<Vec<u8>>, pinned: &[Vec<u8>]) -> Option<PeerIdentity> {
+        match self.tls {
+            Some(ref mut session) if session.established && !session.identified => {
+                session.identified = true;
+                dtls::peer_identity(session.stream.ssl(), psk_identity, pinned)
+            }
+            _ => None,
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let stream = match self.tcp {
+            Tcp::Open(ref mut stream) => stream,
+            Tcp::Connecting(_) => return Ok(()),
+        };
+
+        while !self.unsent.is_empty() {
+            match stream.poll_write(&self.unsent)? {
+                Async::Ready(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed").into()),
+                Async::Ready(len) => {
+                    self.unsent.split_to(len);
+                }
+                Async::NotReady => break,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A stream and sink of messages, like a `UdpFramed<CoapCodec>`, that sends
+/// them over a connection to each peer.
+pub(crate) struct TcpTransport {
+    connections: HashMap<SocketAddr, Connection>,
+    /// what connections are secured with, for coaps+tcp
+    #[cfg(feature = "dtls")]
+    tls: Option<SslContext>,
+    /// the PSK identity we present
+    #[cfg(feature = "dtls")]
+    psk_identity: Option<Vec<u8>>,
+    /// the raw public keys we trust, DER encoded
+    #[cfg(feature = "dtls")]
+    pinned: Vec<Vec<u8>>,
+    #[cfg(feature = "dtls")]
+    identities: Identities,
+    incoming: VecDeque<(Message, SocketAddr)>,
+}
+
+impl TcpTransport {
+    pub fn new() -> TcpTransport {
+        TcpTransport {
+            connections: HashMap::new(),
+            #[cfg(feature = "dtls")]
+            tls: None,
+            #[cfg(feature = "dtls")]
+            psk_identity: None,
+            #[cfg(feature = "dtls")]
+            pinned: Vec::new(),
+            #[cfg(feature = "dtls")]
+            identities: Identities::default(),
+            incoming: VecDeque::new(),
+        }
+    }
+
+    /// A transport that secures its connections with TLS, using the
+    /// credentials in `config` to connect with.
+    #[cfg(feature = "dtls")]
+    pub fn tls(config: &DtlsConfig) -> Result<TcpTransport, Error> {
+        let (client, _) = config.contexts(Protocol::Tls).map_err(tls_error)?;
+        let client = client.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no TLS identity to connect with"))?;
+
+        let mut transport = TcpTransport::new();
+        transport.tls = Some(client);
+        transport.psk_identity = config.psk_identity();
+        transport.pinned = config.pinned().map_err(tls_error)?;
+
+        Ok(transport)
+    }
+
+    #[cfg(feature = "dtls")]
+    pub fn identities(&self) -> Identities {
+        self.identities.clone()
+    }
+
+    /// Open a connection to `peer`. Who the peer of the previous one
+    /// authenticated as is kept until then, for the responses that came
+    /// over it.
+    #[cfg(feature = "dtls")]
+    fn connect(&self, peer: SocketAddr) -> Result<Connection, Error> {
+        self.identities.lock().unwrap().remove(&peer);
+
+        match self.tls {
+            Some(ref context) => Connection::secured(peer, context),
+            None => Ok(Connection::new(peer)),
+        }
+    }
+
+    #[cfg(not(feature = "dtls"))]
+    fn connect(&self, peer: SocketAddr) -> Result<Connection, Error> {
+        Ok(Connection::new(peer))
+    }
+
+    /// Progress every connection, dropping those that have failed or been
+    /// closed.
+    fn drive(&mut self) {
+        let mut gone = Vec::new();
+
+        for (&peer, connection) in self.connections.iter_mut() {
+            let result = connection.poll(peer, &mut self.incoming);
+
+            #[cfg(feature = "dtls")]
+            {
+                if let Some(identity) = connection.identity(&self.psk_identity, &self.pinned) {
+                    debug!("{} authenticated as {:?}", peer, identity);
+                    self.identities.lock().unwrap().insert(peer, identity);
+                }
+            }
+
+            match result {
+                Ok(Async::NotReady) => (),
+                Ok(Async::Ready(())) => {
+                    debug!("connection to {} closed", peer);
+                    gone.push(peer);
+                }
+                Err(e) => {
+                    warn!("connection to {} failed: {:?}", peer, e);
+                    gone.push(peer);
+                }
+            }
+        }
+
+        for peer in gone {
+            self.connections.remove(&peer);
+
+            // RFC 8323: 5.6.  Abort Messages
+            self.incoming.push_back((Message::new().with_code(Code::Abort), peer));
+        }
+    }
+}
+
+impl Stream for TcpTransport {
+    type Item = (Message, SocketAddr);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Error> {
+        self.drive();
+
+        match self.incoming.pop_front() {
+            Some(incoming) => Ok(Async::Ready(Some(incoming))),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Sink for TcpTransport {
+    type SinkItem = (Message, SocketAddr);
+    type SinkError = Error;
+
+    fn start_send(&mut self, (msg, peer): Self::SinkItem) -> StartSend<Self::SinkItem, Error> {
+        // the transport acknowledges every message, and there's nothing to
+        // reset
+        if msg.code == Code::Empty {
+            debug!("not sending empty message to {}: {:?}", peer, msg);
+            return Ok(AsyncSink::Ready);
+        }
+
+        if !self.connections.contains_key(&peer) {
+            debug!("connecting to {}", peer);
+
+            let connection = self.connect(peer)?;
+            self.connections.insert(peer, connection);
+        }
+
+        let queued = self.connections.get_mut(&peer).unwrap().queue(msg);
+        if let Err(e) = queued {
+            error!("failed to encode message: {:?}", e);
+        }
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        let received = self.incoming.len();
+        self.drive();
+
+        // what was received is only handed out by polling the stream again
+        if self.incoming.len() > received {
+            task::current().notify();
+        }
+
+        if self.connections.values().all(|connection| connection.unsent.is_empty()) {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use client::{Client, Connections};
+    use codec::CoapStreamCodec;
+    use error::Error;
+    use message::{Message, Code};
+    use socket::Socket;
+    use testing::spawn_tcp_server;
+
+    use std::io::{ErrorKind, Read, Write};
+
+    use bytes::BytesMut;
+    use tokio::runtime::Runtime;
+    use tokio_io::codec::{Decoder, Encoder};
+
+    fn read_message<S: Read>(stream: &mut S, buf: &mut BytesMut) -> Message {
+        loop {
+            if let Some(msg) = CoapStreamCodec.decode(buf).unwrap() {
+                return msg;
+            }
+
+            let mut chunk = [0; 1024];
+            let len = stream.read(&mut chunk).unwrap();
+            assert!(len > 0, "connection closed");
+            buf.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    fn write_message<S: Write>(stream: &mut S, msg: Message) {
+        let mut buf = BytesMut::new();
+        CoapStreamCodec.encode(msg, &mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+    }
+
+    /// Exchange CSMs with the client on `stream`.
+    fn handshake<S: Read + Write>(stream: &mut S, buf: &mut BytesMut) {
+        let csm = read_message(stream, buf);
+        assert_eq!(csm.code, Code::Csm);
+        assert_eq!(csm.options.iter().next(), Some((2, &[1, 0, 0][..])));
+
+        write_message(stream, Message::new().with_code(Code::Csm));
+    }
+
+    /// Answer `count` requests on `stream` with their payload.
+    fn echo<S: Read + Write>(stream: &mut S, buf: &mut BytesMut, count: usize) {
+        for _ in 0..count {
+            let request = read_message(stream, buf);
+            assert!(request.code.is_request());
+
+            let response = Message::new()
+                .with_code(Code::Content)
+                .with_token(&request.token)
+                .with_payload(request.payload);
+            write_message(stream, response);
+        }
+    }
+
+    #[test]
+    fn requests_share_a_connection() {
+        // only the one connection is ever accepted
+        let server_addr = spawn_tcp_server(|listener| {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = BytesMut::new();
+            handshake(&mut stream, &mut buf);
+            let request = read_message(&mut stream, &mut buf);
+
+            // pings are answered by the transport
+            write_message(&mut stream, Message::new().with_code(Code::Ping).with_token(&[9]));
+            let pong = read_message(&mut stream, &mut buf);
+            assert_eq!((pong.code, &pong.token[..]), (Code::Pong, &[9][..]));
+
+            write_message(&mut stream, Message::new().with_code(Code::Content).with_token(&request.token).with_payload(request.payload));
+            echo(&mut stream, &mut buf, 2);
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let socket = Socket::tcp();
+        let url = format!("coap+tcp://{}/echo", server_addr);
+
+        for i in 0..3u8 {
+            let request = Client::post(&url).unwrap()
+                .with_socket(socket.clone())
+                .with_payload(vec![i])
+                .send_with_attempts();
+            let (response, attempts) = runtime.block_on(request).unwrap();
+
+            assert_eq!(response.payload, [i]);
+            assert_eq!(attempts, 1);
+        }
+    }
+
+    #[test]
+    fn requests_without_a_socket_share_their_connections() {
+        let server_addr = spawn_tcp_server(|listener| {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = BytesMut::new();
+            handshake(&mut stream, &mut buf);
+            echo(&mut stream, &mut buf, 3);
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let connections = Connections::new();
+        let url = format!("coap+tcp://{}/echo", server_addr);
+
+        for i in 0..3u8 {
+            let request = Client::post(&url).unwrap()
+                .with_connections(connections.clone())
+                .with_payload(vec![i])
+                .send();
+            assert_eq!(runtime.block_on(request).unwrap().payload, [i]);
+        }
+    }
+
+    #[test]
+    fn lost_connections_fail_requests() {
+        let server_addr = spawn_tcp_server(|listener| {
+            // the first connection is closed without an answer
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = BytesMut::new();
+            handshake(&mut stream, &mut buf);
+            read_message(&mut stream, &mut buf);
+            drop(stream);
+
+            // and the second doesn't start with a CSM
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = BytesMut::new();
+            read_message(&mut stream, &mut buf);
+            echo(&mut stream, &mut buf, 1);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = BytesMut::new();
+            handshake(&mut stream, &mut buf);
+            echo(&mut stream, &mut buf, 1);
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let socket = Socket::tcp();
+        let url = format!("coap+tcp://{}/echo", server_addr);
+
+        for _ in 0..2 {
+            let request = Client::get(&url).unwrap().with_socket(socket.clone()).send();
+
+            match runtime.block_on(request) {
+                Err(Error::Io(ref e)) if e.kind() == ErrorKind::ConnectionAborted => (),
+                other => panic!("expected the connection to be aborted, got {:?}", other),
+            }
+        }
+
+        // the next request opens a new connection
+        let request = Client::get(&url).unwrap().with_socket(socket).with_payload(b"again".to_vec()).send();
+        assert_eq!(runtime.block_on(request).unwrap().payload, b"again");
+    }
+
+    #[test]
+    #[cfg(feature = "dtls")]
+    fn requests_over_tls() {
+        use dtls::{DtlsConfig, PeerIdentity, Protocol};
+        use openssl::ssl::Ssl;
+        use std::collections::HashMap;
+
+        let mut keys = HashMap::new();
+        keys.insert(b"Client_identity".to_vec(), b"secretPSK".to_vec());
+        let (_, context) = DtlsConfig::new().with_keys(keys).contexts(Protocol::Tls).unwrap();
+        let context = context.unwrap();
+
+        let server_addr = spawn_tcp_server(move |listener| {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = Ssl::new(&context).unwrap().accept(stream).unwrap();
+            let mut buf = BytesMut::new();
+
+            handshake(&mut stream, &mut buf);
+            echo(&mut stream, &mut buf, 1);
+        });
+
+        let request = Client::get(&format!("coaps+tcp://{}/echo", server_addr)).unwrap()
+            .with_dtls(DtlsConfig::new().with_identity(b"Client_identity", b"secretPSK"))
+            .with_payload(b"secret".to_vec())
+            .send_with_identity();
+        let (response, identity) = Runtime::new().unwrap().block_on(request).unwrap();
+
+        assert_eq!(response.payload, b"secret");
+        match identity {
+            Some(PeerIdentity::Psk(ref identity)) if identity == b"Client_identity" => (),
+            other => panic!("unexpected identity: {:?}", other),
+        }
+
+        // without credentials there's nothing to connect with
+        let request = Client::get(&format!("coaps+tcp://{}/", server_addr)).unwrap().send();
+        assert!(Runtime::new().unwrap().block_on(request).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "dtls")]
+    fn tls_connections_are_shared_per_config() {
+        use dtls::{DtlsConfig, PeerIdentity, Protocol};
+        use openssl::ssl::Ssl;
+        use std::collections::HashMap;
+
+        let mut keys = HashMap::new();
+        keys.insert(b"first".to_vec(), b"secretPSK".to_vec());
+        keys.insert(b"second".to_vec(), b"otherPSK".to_vec());
+        let (_, context) = DtlsConfig::new().with_keys(keys).contexts(Protocol::Tls).unwrap();
+        let context = context.unwrap();
+
+        // a connection for the two requests with the first identity, then
+        // one for the request with the second
+        let server_addr = spawn_tcp_server(move |listener| {
+            for &count in &[2, 1] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut stream = Ssl::new(&context).unwrap().accept(stream).unwrap();
+                let mut buf = BytesMut::new();
+
+                handshake(&mut stream, &mut buf);
+                echo(&mut stream, &mut buf, count);
+            }
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let connections = Connections::new();
+        let url = format!("coaps+tcp://{}/echo", server_addr);
+
+        let configs = [(&b"first"[..], &b"secretPSK"[..]), (b"first", b"secretPSK"), (b"second", b"otherPSK")];
+        for (i, &(identity, key)) in configs.iter().enumerate() {
+            let request = Client::post(&url).unwrap()
+                .with_connections(connections.clone())
+                .with_dtls(DtlsConfig::new().with_identity(identity, key))
+                .with_payload(vec![i as u8])
+                .send_with_identity();
+            let (response, peer) = runtime.block_on(request).unwrap();
+
+            assert_eq!(response.payload, [i as u8]);
+            match peer {
+                Some(PeerIdentity::Psk(ref psk)) if psk == identity => (),
+                other => panic!("unexpected identity: {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Fixtures shared by the tests of several modules, for playing the other
+//! end of an exchange over a plain socket.
+
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::thread;
+
+use message::Message;
+
+/// Run `serve` against a socket on a background thread, returning the
+/// address the socket is bound to.
+pub fn spawn_server<F>(serve: F) -> SocketAddr
+    where F: FnOnce(UdpSocket) + Send + 'static
+{
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    thread::spawn(move || serve(server));
+
+    server_addr
+}
+
+/// Run `serve` against a listener on a background thread, returning the
+/// address the listener is bound to.
+pub fn spawn_tcp_server<F>(serve: F) -> SocketAddr
+    where F: FnOnce(TcpListener) + Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || serve(listener));
+
+    server_addr
+}
+
+pub fn recv_message(socket: &UdpSocket) -> (Message, SocketAddr) {
+    let mut buf = [0; 1152];
+    let (len, addr) = socket.recv_from(&mut buf).unwrap();
+
+    (Message::from_bytes(&buf[..len]).unwrap(), addr)
+}
+
+pub fn send_message(socket: &UdpSocket, msg: &Message, addr: SocketAddr) {
+    socket.send_to(&msg.to_bytes().unwrap(), addr).unwrap();
+}
@@ -0,0 +1,240 @@
+//! RFC 7252: 4.8.  Transmission Parameters
+
+use std::cmp;
+use std::io;
+use std::time::Duration;
+
+use rand::{thread_rng, Rng};
+use tokio::timer;
+
+use error::Error;
+
+/// The largest MAX_RETRANSMIT accepted, beyond which the back-off would
+/// take longer than anyone waits for a response.
+const MAX_RETRANSMIT_LIMIT: u32 = 32;
+
+/// The longest a retransmission timeout is allowed to grow to by doubling.
+const MAX_TIMEOUT: Duration = Duration::from_secs(u32::MAX as u64);
+
+/// The timing parameters that govern message transmission.
+///
+/// The defaults are those given by RFC 7252, the derived values are computed
+/// from the configured ones as described in section 4.8.2.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransmissionParameters {
+    pub ack_timeout: Duration,
+    pub ack_random_factor: f64,
+    pub max_retransmit: u32,
+    pub nstart: u32,
+    pub default_leisure: Duration,
+    /// in bytes per second
+    pub probing_rate: u32,
+    /// the maximum time a datagram is expected to take from start of
+    /// transmission to completion of reception
+    pub max_latency: Duration,
+}
+
+impl Default for TransmissionParameters {
+    fn default() -> Self {
+        TransmissionParameters {
+            ack_timeout: Duration::from_secs(2),
+            ack_random_factor: 1.5,
+            max_retransmit: 4,
+            nstart: 1,
+            default_leisure: Duration::from_secs(5),
+            probing_rate: 1,
+            max_latency: Duration::from_secs(100),
+        }
+    }
+}
+
+impl TransmissionParameters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_ack_timeout(mut self, ack_timeout: Duration) -> Self {
+        self.ack_timeout = ack_timeout;
+        self
+    }
+
+    pub fn with_ack_random_factor(mut self, ack_random_factor: f64) -> Self {
+        assert!(ack_random_factor >= 1.0, "ACK_RANDOM_FACTOR must not be below 1.0");
+
+        self.ack_random_factor = ack_random_factor;
+        self
+    }
+
+    pub fn with_max_retransmit(mut self, max_retransmit: u32) -> Self {
+        assert!(max_retransmit <= MAX_RETRANSMIT_LIMIT, "MAX_RETRANSMIT must not be above 32");
+
+        self.max_retransmit = max_retransmit;
+        self
+    }
+
+    pub fn with_nstart(mut self, nstart: u32) -> Self {
+        assert!(nstart >= 1, "NSTART must be at least 1");
+
+        self.nstart = nstart;
+        self
+    }
+
+    pub fn with_default_leisure(mut self, default_leisure: Duration) -> Self {
+        self.default_leisure = default_leisure;
+        self
+    }
+
+    pub fn with_probing_rate(mut self, probing_rate: u32) -> Self {
+        self.probing_rate = probing_rate;
+        self
+    }
+
+    pub fn with_max_latency(mut self, max_latency: Duration) -> Self {
+        self.max_latency = max_latency;
+        self
+    }
+
+    /// The maximum time from the first transmission of a confirmable message
+    /// to its last retransmission.
+    pub fn max_transmit_span(&self) -> Duration {
+        let factor = (2f64.powf(self.max_retransmit as f64) - 1.0) * self.ack_random_factor;
+
+        saturating_mul_f64(self.ack_timeout, factor)
+    }
+
+    /// The maximum time from the first transmission of a confirmable message
+    /// to the time when the sender gives up on receiving an acknowledgement.
+    pub fn max_transmit_wait(&self) -> Duration {
+        let factor = (2f64.powf(self.max_retransmit as f64 + 1.0) - 1.0) * self.ack_random_factor;
+
+        saturating_mul_f64(self.ack_timeout, factor)
+    }
+
+    /// The time a node takes to turn around a confirmable message into an
+    /// acknowledgement.
+    pub fn processing_delay(&self) -> Duration {
+        self.ack_timeout
+    }
+
+    /// The maximum round-trip time.
+    pub fn max_rtt(&self) -> Duration {
+        self.max_latency.saturating_mul(2).saturating_add(self.processing_delay())
+    }
+
+    /// The time from starting to send a confirmable message to the time when
+    /// an acknowledgement is no longer expected and the message ID can be
+    /// safely reused.
+    pub fn exchange_lifetime(&self) -> Duration {
+        self.max_transmit_span().saturating_add(self.max_rtt())
+    }
+
+    /// The time from sending a non-confirmable message to the time its
+    /// message ID can be safely reused.
+    pub fn non_lifetime(&self) -> Duration {
+        self.max_transmit_span().saturating_add(self.max_latency)
+    }
+}
+
+/// `duration * factor`, or `Duration::MAX` if that's too long to represent.
+fn saturating_mul_f64(duration: Duration, factor: f64) -> Duration {
+    Duration::try_from_secs_f64(duration.as_secs_f64() * factor).unwrap_or(Duration::MAX)
+}
+
+/// A random duration between ACK_TIMEOUT and ACK_TIMEOUT * ACK_RANDOM_FACTOR.
+pub(crate) fn initial_timeout(params: &TransmissionParameters) -> Duration {
+    let max = params.ack_timeout.mul_f64(params.ack_random_factor);
+
+    thread_rng().gen_range(params.ack_timeout..=max)
+}
+
+/// The timeout after `timeout` has run out without an acknowledgement,
+/// doubled but no longer than MAX_TIMEOUT.
+pub(crate) fn back_off(timeout: Duration) -> Duration {
+    cmp::min(timeout.saturating_mul(2), MAX_TIMEOUT)
+}
+
+pub(crate) fn timer_error(e: timer::Error) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::Other, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{back_off, initial_timeout, TransmissionParameters, MAX_TIMEOUT};
+
+    use std::time::Duration;
+
+    #[test]
+    fn default_derived_values() {
+        let params = TransmissionParameters::default();
+
+        assert_eq!(params.max_transmit_span(), Duration::from_secs(45));
+        assert_eq!(params.max_transmit_wait(), Duration::from_secs(93));
+        assert_eq!(params.max_rtt(), Duration::from_secs(202));
+        assert_eq!(params.exchange_lifetime(), Duration::from_secs(247));
+        assert_eq!(params.non_lifetime(), Duration::from_secs(145));
+    }
+
+    #[test]
+    fn initial_timeout_within_random_factor() {
+        let params = TransmissionParameters::default();
+
+        for _ in 0..100 {
+            let timeout = initial_timeout(&params);
+
+            assert!(timeout >= params.ack_timeout);
+            assert!(timeout <= params.ack_timeout.mul_f64(params.ack_random_factor));
+        }
+    }
+
+    #[test]
+    fn derived_values_follow_configuration() {
+        let params = TransmissionParameters::new()
+            .with_ack_timeout(Duration::from_millis(100))
+            .with_ack_random_factor(1.0)
+            .with_max_retransmit(2)
+            .with_max_latency(Duration::from_secs(1));
+
+        assert_eq!(params.max_transmit_span(), Duration::from_millis(300));
+        assert_eq!(params.max_transmit_wait(), Duration::from_millis(700));
+        assert_eq!(params.exchange_lifetime(), Duration::from_millis(2400));
+        assert_eq!(params.non_lifetime(), Duration::from_millis(1300));
+    }
+
+    #[test]
+    fn derived_values_with_many_retransmissions() {
+        let params = TransmissionParameters::new()
+            .with_ack_timeout(Duration::from_secs(1))
+            .with_ack_random_factor(1.0)
+            .with_max_retransmit(31);
+
+        assert_eq!(params.max_transmit_span(), Duration::from_secs((1 << 31) - 1));
+        assert_eq!(params.max_transmit_wait(), Duration::from_secs((1 << 32) - 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "MAX_RETRANSMIT")]
+    fn max_retransmit_is_bounded() {
+        TransmissionParameters::new().with_max_retransmit(33);
+    }
+
+    #[test]
+    fn derived_values_saturate() {
+        let params = TransmissionParameters::new()
+            .with_ack_timeout(Duration::from_secs(u64::MAX / 2))
+            .with_max_retransmit(32)
+            .with_max_latency(Duration::MAX);
+
+        assert_eq!(params.max_transmit_span(), Duration::MAX);
+        assert_eq!(params.max_transmit_wait(), Duration::MAX);
+        assert_eq!(params.max_rtt(), Duration::MAX);
+        assert_eq!(params.exchange_lifetime(), Duration::MAX);
+        assert_eq!(params.non_lifetime(), Duration::MAX);
+    }
+
+    #[test]
+    fn back_off_is_bounded() {
+        assert_eq!(back_off(Duration::from_secs(2)), Duration::from_secs(4));
+        assert_eq!(back_off(MAX_TIMEOUT), MAX_TIMEOUT);
+        assert_eq!(back_off(Duration::MAX), MAX_TIMEOUT);
+    }
+}